@@ -7,7 +7,7 @@ use winit::EventsLoop;
 use dacite::core::{Pipeline, PipelineBindPoint,
                    CommandBuffer, //PipelineLayout,
                    PrimitiveTopology, CullModeFlags, FrontFace,
-                   Extent2D};
+                   ColorComponentFlags, Extent2D};
 use siege_render::{Renderer, Pass, BlendMode, Plugin,
                    Params, Stats, Config, Tonemapper, PipelineSetup};
 
@@ -58,6 +58,7 @@ pub fn main() {
 
 pub struct Colortest {
     pipeline: Pipeline,
+    transparent_pipeline: Pipeline,
     //pipeline_layout: PipelineLayout,
 }
 
@@ -72,17 +73,53 @@ impl Colortest {
                 fragment_shader_spec: None,
                 vertex_type: None, // no vertex type
                 topology: PrimitiveTopology::TriangleList,
+                primitive_restart: false,
+                line_width: 1.0,
                 cull_mode: CullModeFlags::NONE,
                 front_face: FrontFace::CounterClockwise,
+                depth_clamp: false,
                 test_depth: false,
                 write_depth: false,
+                depth_compare: None,
+                depth_bias: None,
+                sample_shading: None,
                 blend: vec![BlendMode::Off],
+                color_write_mask: ColorComponentFlags::R | ColorComponentFlags::G
+                    | ColorComponentFlags::B | ColorComponentFlags::A,
                 pass: Pass::Ui,
                 push_constant_ranges: vec![]
             }).unwrap();
 
+        // Depth tested but not written, as particles would be
+        let (_transparent_pipeline_layout, transparent_pipeline) = renderer.create_pipeline(
+            PipelineSetup {
+                desc_set_layouts: vec![],
+                vertex_shader: Some("colortest.vert"),
+                vertex_shader_spec: None,
+                fragment_shader: Some("colortest.frag"),
+                fragment_shader_spec: None,
+                vertex_type: None, // no vertex type
+                topology: PrimitiveTopology::TriangleList,
+                primitive_restart: false,
+                line_width: 1.0,
+                cull_mode: CullModeFlags::NONE,
+                front_face: FrontFace::CounterClockwise,
+                depth_clamp: false,
+                test_depth: true,
+                write_depth: false,
+                depth_compare: None,
+                depth_bias: None,
+                sample_shading: None,
+                blend: vec![BlendMode::Alpha],
+                color_write_mask: ColorComponentFlags::R | ColorComponentFlags::G
+                    | ColorComponentFlags::B | ColorComponentFlags::A,
+                pass: Pass::Transparent,
+                push_constant_ranges: vec![]
+            }).unwrap();
+
         Colortest {
             pipeline: pipeline,
+            transparent_pipeline: transparent_pipeline,
             //pipeline_layout: pipeline_layout,
         }
     }
@@ -92,7 +129,10 @@ impl Plugin for Colortest {
     fn record_geometry(&self, _command_buffer: CommandBuffer) {
     }
 
-    fn record_transparent(&self, _command_buffer: CommandBuffer) {
+    fn record_transparent(&self, command_buffer: CommandBuffer) {
+        command_buffer.bind_pipeline(PipelineBindPoint::Graphics,
+                                     &self.transparent_pipeline);
+        command_buffer.draw(3, 1, 0, 0);
     }
 
     fn record_ui(&self, command_buffer: CommandBuffer) {
@@ -0,0 +1,35 @@
+#version 450
+
+#extension GL_ARB_separate_shader_objects : enable
+#extension GL_ARB_shading_language_420pack : enable
+
+// An alpha cutout geometry pass shader (see Renderer::cutout_pipeline_setup)
+
+layout (set = 0, binding = 0) uniform sampler2D diffuseTex;
+
+layout (push_constant) uniform Cutout {
+  float alpha_cutoff;
+} cutout;
+
+layout (location = 0) in vec2 inUV;
+layout (location = 1) in vec3 inNormal; // view space
+
+layout (location = 0) out vec4 outDiffuse;
+layout (location = 1) out vec4 outNormals;
+layout (location = 2) out vec4 outMaterial;
+
+void main() {
+  vec4 diffuse = texture(diffuseTex, inUV);
+  if (diffuse.a < cutout.alpha_cutoff) {
+    discard;
+  }
+
+  // Both sides are drawn, so light the back side as its own surface
+  vec3 normal = normalize(gl_FrontFacing ? inNormal : -inNormal);
+
+  // (no blending, so alpha is written as is)
+  outDiffuse = vec4(diffuse.rgb, 1.0);
+  outNormals = vec4(normal * 0.5 + 0.5, 1.0);
+  // (in the default MaterialPacking: roughness, metallicity, AO, cavity)
+  outMaterial = vec4(0.8, 0.0, 1.0, 1.0);
+}
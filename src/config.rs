@@ -1,7 +1,8 @@
 
 use std::path::PathBuf;
 use std::fmt;
-use crate::renderer::{VulkanLogLevel, Tonemapper};
+use crate::renderer::{VulkanLogLevel, Tonemapper, GBufferFormat, HdrFormat, BloomQuality,
+                      GeometryLoadOps, MaterialPacking};
 use serde::Deserialize;
 
 #[inline] fn default_app_name() -> String { "Unspecified".to_owned() }
@@ -10,6 +11,8 @@ use serde::Deserialize;
 #[inline] fn default_patch_version() -> u32 { 0 }
 #[inline] fn default_asset_path() -> PathBuf { PathBuf::from("assets") }
 #[inline] fn default_vulkan_debug_output() -> bool { cfg!(debug_assertions) }
+#[inline] fn default_validate_pipelines() -> bool { cfg!(debug_assertions) }
+#[inline] fn default_gpu_timing() -> bool { true }
 #[inline] fn default_vulkan_log_level() -> VulkanLogLevel {
     if cfg!(debug_assertions) { VulkanLogLevel::Debug }
     else { VulkanLogLevel::PerformanceWarning }
@@ -25,15 +28,39 @@ use serde::Deserialize;
 #[inline] fn default_height() -> u32 { 600 }
 #[inline] fn default_display_luminance() -> u32 { 80 }
 #[inline] fn default_gpu_memory_required() -> u64 { 9216000 }
-#[inline] fn default_max_descriptor_sets() -> u32 { 16 }
+#[inline] fn default_max_descriptor_sets() -> u32 { 19 }
 #[inline] fn default_max_uniform_buffers() -> u32 { 2 }
 #[inline] fn default_max_uniform_texel_buffers() -> u32 { 2 }
 #[inline] fn default_max_dynamic_uniform_buffers() -> u32 { 2 }
-#[inline] fn default_max_samplers() -> u32 { 2 }
-#[inline] fn default_max_sampled_images() -> u32 { 2 }
-#[inline] fn default_max_combined_image_samplers() -> u32 { 10 }
+#[inline] fn default_max_samplers() -> u32 { 3 }
+#[inline] fn default_max_sampled_images() -> u32 { 6 }
+#[inline] fn default_max_combined_image_samplers() -> u32 { 17 }
+#[inline] fn default_max_storage_buffers() -> u32 { 1 }
+#[inline] fn default_max_storage_images() -> u32 { 2 }
 #[inline] fn default_timing_setup() -> bool { false }
 #[inline] fn default_tonemapper() -> Tonemapper { Tonemapper::HybridLogGamma }
+#[inline] fn default_max_api_version() -> [u32; 2] { [1, 0] }
+#[inline] fn default_diffuse_format() -> GBufferFormat { GBufferFormat::A2b10g10r10Unorm }
+#[inline] fn default_normals_format() -> GBufferFormat { GBufferFormat::A2b10g10r10Unorm }
+#[inline] fn default_material_format() -> GBufferFormat { GBufferFormat::R8g8b8a8Unorm }
+#[inline] fn default_material_packing() -> MaterialPacking { MaterialPacking::Rmac }
+#[inline] fn default_shading_format() -> HdrFormat { HdrFormat::R16g16b16a16Sfloat }
+#[inline] fn default_bloom_quality() -> BloomQuality { BloomQuality::Gaussian }
+#[inline] fn default_bloom_use_compute() -> bool { false }
+#[inline] fn default_shadows() -> bool { false }
+#[inline] fn default_linear_depth_target() -> bool { false }
+#[inline] fn default_shadow_map_size() -> u32 { 2048 }
+#[inline] fn default_enable_transparent_pass() -> bool { true }
+#[inline] fn default_transparent_clear() -> bool { false }
+#[inline] fn default_enable_ui_pass() -> bool { true }
+#[inline] fn default_dof_enabled() -> bool { false }
+#[inline] fn default_taa_enabled() -> bool { false }
+#[inline] fn default_motion_vectors() -> bool { false }
+#[inline] fn default_entity_id_target() -> bool { false }
+#[inline] fn default_near_plane() -> f32 { 0.1 }
+#[inline] fn default_far_plane() -> f32 { 1000.0 }
+#[inline] fn default_debug_line_width() -> f32 { 1.0 }
+#[inline] fn default_max_anisotropy() -> f32 { 16.0 }
 
 #[derive(Clone, Deserialize)]
 pub struct Config {
@@ -49,6 +76,22 @@ pub struct Config {
     pub asset_path: PathBuf,
     #[serde(default = "default_vulkan_debug_output")]
     pub vulkan_debug_output: bool,
+    // Check each plugin pipeline's shaders against its descriptor set
+    // layouts and push constant ranges (see Renderer::create_pipeline)
+    #[serde(default = "default_validate_pipelines")]
+    pub validate_pipelines: bool,
+    // Load compiled pipelines from this file at startup, and save them back
+    // at shutdown (and on Renderer::save_pipeline_cache), so later runs don't
+    // stutter compiling them again.  A cache from another device or driver
+    // is ignored.  None keeps the cache in memory only.
+    #[serde(default)]
+    pub pipeline_cache_path: Option<PathBuf>,
+    // Measure GPU pass timings (see Timings and PluginTimestamps) with
+    // timestamp queries.  Turn off in shipping builds to avoid their cost,
+    // which on some tiled GPUs includes flushing the pipeline (see
+    // Renderer::set_gpu_timing_enabled).
+    #[serde(default = "default_gpu_timing")]
+    pub gpu_timing: bool,
     #[serde(default = "default_vulkan_log_level")]
     pub vulkan_log_level: VulkanLogLevel,
     #[serde(default = "default_vulkan_layers")]
@@ -79,10 +122,106 @@ pub struct Config {
     pub max_sampled_images: u32,
     #[serde(default = "default_max_combined_image_samplers")]
     pub max_combined_image_samplers: u32,
+    #[serde(default = "default_max_storage_buffers")]
+    pub max_storage_buffers: u32,
+    #[serde(default = "default_max_storage_images")]
+    pub max_storage_images: u32,
     #[serde(default = "default_timing_setup")]
     pub timing_setup: bool,
     #[serde(default = "default_tonemapper")]
-    pub tonemapper: Tonemapper
+    pub tonemapper: Tonemapper,
+    #[serde(default = "default_max_api_version")]
+    pub max_api_version: [u32; 2],
+    #[serde(default = "default_diffuse_format")]
+    pub diffuse_format: GBufferFormat,
+    // r16g16b16a16_sfloat avoids banding on smooth surfaces, at twice the memory
+    #[serde(default = "default_normals_format")]
+    pub normals_format: GBufferFormat,
+    #[serde(default = "default_material_format")]
+    pub material_format: GBufferFormat,
+    // Which material g-buffer channels hold roughness, metallicity, ambient
+    // occlusion and cavity
+    #[serde(default = "default_material_packing")]
+    pub material_packing: MaterialPacking,
+    // Whether the geometry pass clears, loads or ignores each attachment's
+    // previous contents (all are cleared by default)
+    #[serde(default)]
+    pub geometry_load_ops: GeometryLoadOps,
+    // The shading and blur targets.  r32g32b32a32_sfloat lifts the f16
+    // range limit for very bright scenes, at twice the memory.
+    #[serde(default = "default_shading_format")]
+    pub shading_format: HdrFormat,
+    // Clamp shaded and bloomed luminance to this, rather than to the
+    // largest value the shading format holds (None)
+    #[serde(default)]
+    pub hdr_ceiling: Option<f32>,
+    // Kawase is much cheaper than Gaussian, for low-end hardware
+    #[serde(default = "default_bloom_quality")]
+    pub bloom_quality: BloomQuality,
+    // Run the bloom blur as compute dispatches instead of full-screen
+    // passes.  This looks the same and is often faster; compare the blur
+    // timings (Stats) on your hardware.  Requires the default shading format.
+    #[serde(default = "default_bloom_use_compute")]
+    pub bloom_use_compute: bool,
+    // Shadow the primary directional light (dlight 0) with a shadow map
+    #[serde(default = "default_shadows")]
+    pub shadows: bool,
+    #[serde(default = "default_shadow_map_size")]
+    pub shadow_map_size: u32,
+    // Also write linear view-space depth to an R32 target (see
+    // Renderer::linear_depth_image)
+    #[serde(default = "default_linear_depth_target")]
+    pub linear_depth_target: bool,
+    // The transparent and UI passes may be skipped entirely when unused
+    // (plugins' record_transparent/record_ui are then never called)
+    #[serde(default = "default_enable_transparent_pass")]
+    pub enable_transparent_pass: bool,
+    // Clear the shading target before the transparent pass instead of
+    // drawing over the lit scene (see Renderer::set_transparent_clear)
+    #[serde(default = "default_transparent_clear")]
+    pub transparent_clear: bool,
+    #[serde(default = "default_enable_ui_pass")]
+    pub enable_ui_pass: bool,
+    // Blur the image by distance from the focus plane in the post pass
+    // (see Renderer::set_depth_of_field)
+    #[serde(default = "default_dof_enabled")]
+    pub dof_enabled: bool,
+    // Temporal anti-aliasing: jitter each frame by a sub-pixel offset and
+    // blend with the previous frames, reprojected by depth (so camera motion
+    // is handled, but moving objects may ghost unless motion_vectors is
+    // also set).  Plugins' vertex shaders
+    // must apply Params::taa_jitter for this to anti-alias their geometry.
+    #[serde(default = "default_taa_enabled")]
+    pub taa_enabled: bool,
+    // Add an RG16 velocity target to the geometry pass (color attachment 3),
+    // which geometry plugins must write (see Params::view_projection).  With
+    // TAA, the history follows it, so moving objects no longer ghost.
+    #[serde(default = "default_motion_vectors")]
+    pub motion_vectors: bool,
+    // Add an R32_UINT entity ID target to the geometry pass (the color
+    // attachment after the velocity target, see
+    // Renderer::entity_id_attachment), which geometry plugins write, for
+    // picking objects with Renderer::pick.
+    #[serde(default = "default_entity_id_target")]
+    pub entity_id_target: bool,
+    // View-space distances to the near and far planes of the projection
+    // (f32::INFINITY for an infinite far plane).  These only feed depth
+    // linearization in shaders; keep them in step with the projection
+    // (see Renderer::set_near_far)
+    #[serde(default = "default_near_plane")]
+    pub near_plane: f32,
+    #[serde(default = "default_far_plane")]
+    pub far_plane: f32,
+    // Width of the lines drawn by Renderer::set_debug_lines, in render
+    // pixels.  Falls back to 1.0 if the device lacks wide line support.
+    #[serde(default = "default_debug_line_width")]
+    pub debug_line_width: f32,
+    // Upper limit on sampler anisotropy (the "texture filtering" setting,
+    // e.g. 4.0 for 4x), on top of the device limit.  Samplers made with
+    // Renderer::create_sampler are clamped to it (see
+    // Renderer::max_anisotrophy).
+    #[serde(default = "default_max_anisotropy")]
+    pub max_anisotropy: f32,
 }
 
 impl Default for Config {
@@ -94,6 +233,9 @@ impl Default for Config {
             patch_version: default_patch_version(),
             asset_path: default_asset_path(),
             vulkan_debug_output: default_vulkan_debug_output(),
+            validate_pipelines: default_validate_pipelines(),
+            pipeline_cache_path: None,
+            gpu_timing: default_gpu_timing(),
             vulkan_log_level: default_vulkan_log_level(),
             vulkan_layers: default_vulkan_layers(),
             fps_cap: default_fps_cap(),
@@ -109,8 +251,34 @@ impl Default for Config {
             max_samplers: default_max_samplers(),
             max_sampled_images: default_max_sampled_images(),
             max_combined_image_samplers: default_max_combined_image_samplers(),
+            max_storage_buffers: default_max_storage_buffers(),
+            max_storage_images: default_max_storage_images(),
             timing_setup: default_timing_setup(),
             tonemapper: default_tonemapper(),
+            max_api_version: default_max_api_version(),
+            diffuse_format: default_diffuse_format(),
+            normals_format: default_normals_format(),
+            material_format: default_material_format(),
+            material_packing: default_material_packing(),
+            geometry_load_ops: Default::default(),
+            shading_format: default_shading_format(),
+            hdr_ceiling: None,
+            bloom_quality: default_bloom_quality(),
+            bloom_use_compute: default_bloom_use_compute(),
+            shadows: default_shadows(),
+            shadow_map_size: default_shadow_map_size(),
+            linear_depth_target: default_linear_depth_target(),
+            enable_transparent_pass: default_enable_transparent_pass(),
+            transparent_clear: default_transparent_clear(),
+            enable_ui_pass: default_enable_ui_pass(),
+            dof_enabled: default_dof_enabled(),
+            taa_enabled: default_taa_enabled(),
+            motion_vectors: default_motion_vectors(),
+            entity_id_target: default_entity_id_target(),
+            near_plane: default_near_plane(),
+            far_plane: default_far_plane(),
+            debug_line_width: default_debug_line_width(),
+            max_anisotropy: default_max_anisotropy(),
         }
     }
 }
@@ -126,6 +294,9 @@ impl fmt::Debug for Config {
                  env!("CARGO_PKG_VERSION_PATCH"))?;
         writeln!(f, "    Asset path: {:?}", self.asset_path)?;
         writeln!(f, "    Vulkan debug output: {:?}", self.vulkan_debug_output)?;
+        writeln!(f, "    Validate pipelines: {:?}", self.validate_pipelines)?;
+        writeln!(f, "    Pipeline cache path: {:?}", self.pipeline_cache_path)?;
+        writeln!(f, "    GPU timing: {:?}", self.gpu_timing)?;
         writeln!(f, "    Vulkan log level: {:?}", self.vulkan_log_level)?;
         writeln!(f, "    Vulkan log layers:")?;
         for layer in &self.vulkan_layers {
@@ -144,8 +315,35 @@ impl fmt::Debug for Config {
         writeln!(f, "    Allocated desc for samplers: {}", self.max_samplers)?;
         writeln!(f, "    Allocated desc for sampled images: {}", self.max_sampled_images)?;
         writeln!(f, "    Allocated desc for combined image samplers: {}", self.max_combined_image_samplers)?;
+        writeln!(f, "    Allocated desc for storage buffers: {}", self.max_storage_buffers)?;
+        writeln!(f, "    Allocated desc for storage images: {}", self.max_storage_images)?;
         writeln!(f, "    Timing Setup: {}", self.timing_setup)?;
         writeln!(f, "    Tone mapper: {:?}", self.tonemapper)?;
+        writeln!(f, "    Max Vulkan API version: {}.{}",
+                 self.max_api_version[0], self.max_api_version[1])?;
+        writeln!(f, "    G-buffer formats: diffuse {:?}, normals {:?}, material {:?}",
+                 self.diffuse_format, self.normals_format, self.material_format)?;
+        writeln!(f, "    Material packing: {:?}", self.material_packing)?;
+        writeln!(f, "    Geometry load ops: depth {:?}, diffuse {:?}, normals {:?}, material {:?}",
+                 self.geometry_load_ops.depth, self.geometry_load_ops.diffuse,
+                 self.geometry_load_ops.normals, self.geometry_load_ops.material)?;
+        writeln!(f, "    Shading format: {:?}, HDR ceiling: {:?}",
+                 self.shading_format, self.hdr_ceiling)?;
+        writeln!(f, "    Bloom quality: {:?}, compute: {}",
+                 self.bloom_quality, self.bloom_use_compute)?;
+        writeln!(f, "    Shadows: {} ({}x{} shadow map)",
+                 self.shadows, self.shadow_map_size, self.shadow_map_size)?;
+        writeln!(f, "    Linear depth target: {}", self.linear_depth_target)?;
+        writeln!(f, "    Transparent pass: {}", self.enable_transparent_pass)?;
+        writeln!(f, "    Transparent clear: {}", self.transparent_clear)?;
+        writeln!(f, "    UI pass: {}", self.enable_ui_pass)?;
+        writeln!(f, "    Depth of field: {}", self.dof_enabled)?;
+        writeln!(f, "    Temporal anti-aliasing: {}", self.taa_enabled)?;
+        writeln!(f, "    Motion vectors: {}", self.motion_vectors)?;
+        writeln!(f, "    Entity ID target: {}", self.entity_id_target)?;
+        writeln!(f, "    Near/far planes: {} / {}", self.near_plane, self.far_plane)?;
+        writeln!(f, "    Debug line width: {}", self.debug_line_width)?;
+        writeln!(f, "    Max anisotropy: {}", self.max_anisotropy)?;
         Ok(())
     }
 }
@@ -22,6 +22,9 @@ pub enum Error {
     WrongVertexType,
     UnsupportedFormat,
     SwapchainTimeout,
+    DeviceLost,
+    TextureTooLarge { requested: u32, max: u32 },
+    ShaderLayoutMismatch(String),
 }
 
 impl fmt::Display for Error {
@@ -46,6 +49,10 @@ impl fmt::Display for Error {
             Error::WrongVertexType => write!(f, "Mesh has wrong vertex type"),
             Error::UnsupportedFormat => write!(f, "Unsupported or indeterminate file format"),
             Error::SwapchainTimeout => write!(f, "Swapchain acquire timed out (perhaps took longer than 4 seconds)"),
+            Error::DeviceLost => write!(f, "Graphics device lost (the renderer must be recreated)"),
+            Error::TextureTooLarge { requested, max } =>
+                write!(f, "Texture dimension {} exceeds the device maximum of {}", requested, max),
+            Error::ShaderLayoutMismatch(ref s) => write!(f, "Shader does not match pipeline layout: '{}'", s),
         }
     }
 }
@@ -105,7 +112,10 @@ impl From<winit::CreationError> for Error {
 
 impl From<dacite::core::Error> for Error {
     fn from(e: dacite::core::Error) -> Error {
-        Error::Dacite(e)
+        match e {
+            dacite::core::Error::DeviceLost => Error::DeviceLost,
+            e => Error::Dacite(e)
+        }
     }
 }
 
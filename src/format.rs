@@ -181,6 +181,123 @@ pub fn from_dxgi(format: DxgiFormat) -> Option<Format>
     }
 }
 
+// DXGI has no ETC2/EAC formats, so DDS files holding them are identified
+// by a FourCC code alone.  These are the codes our texture tools write.
+pub fn from_fourcc(fourcc: u32) -> Option<Format>
+{
+    trace!("Looking to match fourcc {:08x}", fourcc);
+    match &fourcc.to_le_bytes() {
+        b"ETC2" => Some(Format::ETC2_R8G8B8_UNorm_Block),
+        b"ETS2" => Some(Format::ETC2_R8G8B8_sRGB_Block),
+        b"ETP2" => Some(Format::ETC2_R8G8B8A1_UNorm_Block),
+        b"ETQ2" => Some(Format::ETC2_R8G8B8A1_sRGB_Block),
+        b"ETA2" => Some(Format::ETC2_R8G8B8A8_UNorm_Block),
+        b"ETB2" => Some(Format::ETC2_R8G8B8A8_sRGB_Block),
+        b"EAR1" => Some(Format::EAC_R11_UNorm_Block),
+        b"EAS1" => Some(Format::EAC_R11_SNorm_Block),
+        b"EAR2" => Some(Format::EAC_R11G11_UNorm_Block),
+        b"EAS2" => Some(Format::EAC_R11G11_SNorm_Block),
+        _ => None
+    }
+}
+
+// Bytes per 4x4 block of the ETC2/EAC formats.  ddsfile does not know
+// these formats, so we do the mip size math ourselves.
+pub fn etc2_block_size(format: Format) -> Option<u32>
+{
+    match format {
+        Format::ETC2_R8G8B8_UNorm_Block | Format::ETC2_R8G8B8_sRGB_Block |
+        Format::ETC2_R8G8B8A1_UNorm_Block | Format::ETC2_R8G8B8A1_sRGB_Block |
+        Format::EAC_R11_UNorm_Block | Format::EAC_R11_SNorm_Block => Some(8),
+        Format::ETC2_R8G8B8A8_UNorm_Block | Format::ETC2_R8G8B8A8_sRGB_Block |
+        Format::EAC_R11G11_UNorm_Block | Format::EAC_R11G11_SNorm_Block => Some(16),
+        _ => None
+    }
+}
+
+// The texel block width, height and bytes per block of the block-compressed
+// formats (BC, ETC2/EAC and ASTC).  A mip level smaller than a block still
+// takes a whole block, so mip sizes are rounded up to whole blocks.
+pub fn compressed_block(format: Format) -> Option<(u32, u32, u32)>
+{
+    if let Some(block_size) = etc2_block_size(format) {
+        return Some((4, 4, block_size));
+    }
+    match format {
+        Format::BC1_RGB_UNorm_Block | Format::BC1_RGB_sRGB_Block |
+        Format::BC1_RGBA_UNorm_Block | Format::BC1_RGBA_sRGB_Block |
+        Format::BC4_UNorm_Block | Format::BC4_SNorm_Block => Some((4, 4, 8)),
+        Format::BC2_UNorm_Block | Format::BC2_sRGB_Block |
+        Format::BC3_UNorm_Block | Format::BC3_sRGB_Block |
+        Format::BC5_UNorm_Block | Format::BC5_SNorm_Block |
+        Format::BC6H_UFloat_Block | Format::BC6H_SFloat_Block |
+        Format::BC7_UNorm_Block | Format::BC7_sRGB_Block => Some((4, 4, 16)),
+        // Every ASTC block is 16 bytes, whatever its footprint
+        Format::ASTC_4x4_UNorm_Block | Format::ASTC_4x4_sRGB_Block => Some((4, 4, 16)),
+        Format::ASTC_5x4_UNorm_Block | Format::ASTC_5x4_sRGB_Block => Some((5, 4, 16)),
+        Format::ASTC_5x5_UNorm_Block | Format::ASTC_5x5_sRGB_Block => Some((5, 5, 16)),
+        Format::ASTC_6x5_UNorm_Block | Format::ASTC_6x5_sRGB_Block => Some((6, 5, 16)),
+        Format::ASTC_6x6_UNorm_Block | Format::ASTC_6x6_sRGB_Block => Some((6, 6, 16)),
+        Format::ASTC_8x5_UNorm_Block | Format::ASTC_8x5_sRGB_Block => Some((8, 5, 16)),
+        Format::ASTC_8x6_UNorm_Block | Format::ASTC_8x6_sRGB_Block => Some((8, 6, 16)),
+        Format::ASTC_8x8_UNorm_Block | Format::ASTC_8x8_sRGB_Block => Some((8, 8, 16)),
+        Format::ASTC_10x5_UNorm_Block | Format::ASTC_10x5_sRGB_Block => Some((10, 5, 16)),
+        Format::ASTC_10x6_UNorm_Block | Format::ASTC_10x6_sRGB_Block => Some((10, 6, 16)),
+        Format::ASTC_10x8_UNorm_Block | Format::ASTC_10x8_sRGB_Block => Some((10, 8, 16)),
+        Format::ASTC_10x10_UNorm_Block | Format::ASTC_10x10_sRGB_Block => Some((10, 10, 16)),
+        Format::ASTC_12x10_UNorm_Block | Format::ASTC_12x10_sRGB_Block => Some((12, 10, 16)),
+        Format::ASTC_12x12_UNorm_Block | Format::ASTC_12x12_sRGB_Block => Some((12, 12, 16)),
+        _ => None
+    }
+}
+
+// Bytes per texel of the uncompressed formats the render targets use (see
+// Renderer::capture_gbuffer), and of the common texture formats (see
+// Renderer::create_texture)
+pub fn texel_size(format: Format) -> Option<u32>
+{
+    match format {
+        Format::R8_UNorm | Format::R8_SNorm | Format::R8_UInt |
+        Format::R8_SInt | Format::R8_sRGB => Some(1),
+        Format::R8G8_UNorm | Format::R8G8_SNorm | Format::R8G8_UInt |
+        Format::R8G8_SInt | Format::R8G8_sRGB |
+        Format::R16_UNorm | Format::R16_SNorm | Format::R16_UInt |
+        Format::R16_SInt | Format::R16_SFloat |
+        Format::B5G6R5_UNorm_Pack16 | Format::B5G5R5A1_UNorm_Pack16 => Some(2),
+        Format::R8G8B8A8_UNorm | Format::R8G8B8A8_sRGB |
+        Format::R8G8B8A8_SNorm | Format::R8G8B8A8_UInt | Format::R8G8B8A8_SInt |
+        Format::B8G8R8A8_UNorm | Format::B8G8R8A8_sRGB |
+        Format::A2B10G10R10_UNorm_Pack32 |
+        Format::R16G16_UNorm | Format::R16G16_SNorm | Format::R16G16_UInt |
+        Format::R16G16_SInt | Format::R16G16_SFloat |
+        Format::R32_UInt | Format::R32_SInt |
+        Format::R32_SFloat | Format::D32_SFloat => Some(4),
+        Format::R16G16B16A16_UNorm | Format::R16G16B16A16_SNorm |
+        Format::R16G16B16A16_UInt | Format::R16G16B16A16_SInt |
+        Format::R16G16B16A16_SFloat |
+        Format::R32G32_UInt | Format::R32G32_SInt | Format::R32G32_SFloat => Some(8),
+        Format::R32G32B32_UInt | Format::R32G32B32_SInt |
+        Format::R32G32B32_SFloat => Some(12),
+        Format::R32G32B32A32_UInt | Format::R32G32B32A32_SInt |
+        Format::R32G32B32A32_SFloat => Some(16),
+        _ => None
+    }
+}
+
+// The bytes of a tightly packed width x height image in `format`, in whole
+// blocks if it is compressed, or None if we don't know the format's size
+pub fn image_size(format: Format, width: u32, height: u32) -> Option<u32>
+{
+    match compressed_block(format) {
+        Some((block_width, block_height, block_size)) => {
+            let blocks_wide = (width + block_width - 1) / block_width;
+            let blocks_high = (height + block_height - 1) / block_height;
+            Some(blocks_wide * blocks_high * block_size)
+        },
+        None => texel_size(format).map(|size| width * height * size),
+    }
+}
+
 
 /*
 // Formats that aren't in DxgiFormat
@@ -270,16 +387,6 @@ pub fn from_dxgi(format: DxgiFormat) -> Option<Format>
     D16_UNorm_S8_UInt,
     BC1_RGBA_UNorm_Block,
     BC1_RGBA_sRGB_Block,
-    ETC2_R8G8B8_UNorm_Block,
-    ETC2_R8G8B8_sRGB_Block,
-    ETC2_R8G8B8A1_UNorm_Block,
-    ETC2_R8G8B8A1_sRGB_Block,
-    ETC2_R8G8B8A8_UNorm_Block,
-    ETC2_R8G8B8A8_sRGB_Block,
-    EAC_R11_UNorm_Block,
-    EAC_R11_SNorm_Block,
-    EAC_R11G11_UNorm_Block,
-    EAC_R11G11_SNorm_Block,
     ASTC_4x4_UNorm_Block,
     ASTC_4x4_sRGB_Block,
     ASTC_5x4_UNorm_Block,
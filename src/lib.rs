@@ -18,12 +18,21 @@ pub use crate::config::Config;
 
 pub mod renderer;
 pub use crate::renderer::{Renderer, Pass, ImageWrap,
-                   HostVisibleBuffer, DeviceLocalBuffer, VulkanMesh, Lifetime,
-                   BlendMode, Params, Stats, Timings, Tonemapper, PipelineSetup};
+                   HostVisibleBuffer, DeviceLocalBuffer, VulkanMesh, MeshMaterial, Lifetime,
+                   Linearity, FragmentationReport, MemoryReport, MemoryPressureHandler,
+                   BlendMode, DepthBias, Params, Stats, Timings, Tonemapper, PipelineSetup,
+                   CUBEMAP_FACE_DIRECTIONS, VulkanLogLevel, ValidationCallback,
+                   PluginId, PluginMessage, GBufferFormat, HdrFormat, SmoothingMode, OutputTransfer,
+                   BloomQuality, GBufferLoadOp, GeometryLoadOps, MaterialPacking,
+                   DescriptorSetWriter, DescriptorSetBinder, SetFrequency,
+                   PluginTimestamps, ViewportDepthRange,
+                   ScissorRegion, GBufferCapture, CapturedImage,
+                   ResourceLoader, DEBUG_LINE_VERTEX_COUNT};
 
 pub mod vertex;
 pub use crate::vertex::{VulkanVertex, ColoredVertex, StandardVertex, GuiRectangleVertex,
-                 GrayboxVertex, CheapV1Vertex, CheapV2Vertex, StarVertex, CubemapVertex};
+                 GrayboxVertex, CheapV1Vertex, CheapV2Vertex, StarVertex, CubemapVertex,
+                 ParticleVertex};
 
 pub mod format;
 
@@ -1,6 +1,6 @@
 
 use dacite::core::{CommandBuffer, Extent2D};
-use crate::renderer::{Params, Stats};
+use crate::renderer::{Params, Stats, PluginMessage, Pass};
 use crate::error::Error;
 
 /// This is a trait for Plugins to the Renderer.
@@ -31,26 +31,124 @@ pub trait Plugin {
     /// Fragment shader output should be alpha blended on top of current scene.
     /// You should use pre-multiplied alpha (since alpha blending is subtly
     /// different between sRGB and linear, and it could be either case).
+    ///
+    /// To clip UI elements (e.g. scrolling panels), use the handle from
+    /// renderer.scissor_region() and reset it when done, so that later
+    /// plugins aren't clipped as well.
     fn record_ui(&self, command_buffer: CommandBuffer);
 
+    /// Return true to have record_ui() recorded into the shading target at
+    /// the end of the transparent pass instead of after post-processing, so
+    /// that the UI is bloomed and tonemapped along with the scene (e.g. for
+    /// diegetic, in-world UI).  Pipelines must then be created for
+    /// `Pass::Transparent`, output is interpreted as Luminance as in
+    /// record_transparent(), and the depth buffer is available read-only.
+    ///
+    /// If `Config::enable_transparent_pass` is off, record_ui() is recorded
+    /// in the UI pass regardless.  This is checked whenever command buffers
+    /// are recorded.  The default implementation returns false.
+    fn ui_before_post(&self) -> bool {
+        false
+    }
+
+    /// Record objects into one face of a reflection probe (see
+    /// renderer.create_reflection_probe()).  This is a forward pass with a
+    /// depth buffer; pipelines must be created for `Pass::Probe`. `probe` is
+    /// the index returned when the probe was created and `face` is the cubemap
+    /// layer (0-5), see `CUBEMAP_FACE_DIRECTIONS` for the face orientations.
+    ///
+    /// Fragment shader output is interpreted as a Luminance value, as in the
+    /// geometry pass.  The default implementation records nothing.
+    fn record_probe(&self, _command_buffer: CommandBuffer, _probe: usize, _face: u32) {
+    }
+
+    /// Record shadow-casting objects from the primary directional light's
+    /// point of view, transforming them by `params.shadow_view_projection`.
+    /// This is a depth-only pass (no color attachments); pipelines must be
+    /// created for `Pass::Shadow` with no blend modes.  Consider enabling
+    /// `depth_clamp` so casters behind the light's near plane still shadow.
+    ///
+    /// This is only called if `Config::shadows` is set.  The default
+    /// implementation records nothing.
+    fn record_shadow(&self, _command_buffer: CommandBuffer) {
+    }
+
     /// This callback gives your plugin a chance to update itself, based on
     /// changed parameters or stats.  It also allows your plugin to change
     /// any of the render parameters.  This update happens in parallel with
     /// GPU drawing, so do not change GPU state during this call, use
     /// gpu_update() for that.
     ///
+    /// `stats.frame_index` and `stats.elapsed` give the current frame number
+    /// and the time since rendering started; use these for animation rather
+    /// than sampling the clock yourself.
+    ///
     /// Return true if you need to re-record your command buffers.  Otherwise
     /// return false.
     fn update(&mut self, params: &mut Params, stats: &Stats) -> Result<bool, Error>;
 
+    /// When update() returns true, this tells the renderer which passes your
+    /// recording changed in, so that the other plugins' recordings of the
+    /// other passes are kept.  Only the geometry, transparent and UI passes
+    /// are kept this way; the shadow and probe passes are re-recorded
+    /// whenever anything is.  It is called right after update().  The
+    /// default implementation returns None, meaning every pass.
+    fn changed_passes(&self) -> Option<Vec<Pass>> {
+        None
+    }
+
+    /// This callback receives data sent by the host application through
+    /// renderer.plugin_sender().  Downcast `message` to the type you expect
+    /// (e.g. with `message.downcast::<MyEvent>()`).  It is called for each
+    /// pending message, in order, just before update().  The default
+    /// implementation discards messages.
+    fn receive(&mut self, _message: PluginMessage) -> Result<(), Error> {
+        Ok(())
+    }
+
     /// This callback gives your plugin a chance to change GPU state based
     /// upon changed parameters or stats. Try to do most work in update(), and
     /// then change GPU state here in gpu_update(), which runs after the GPU
     /// has finished drawing the frame.
     fn gpu_update(&mut self) -> Result<(), Error>;
 
+    /// This callback is called right after the frame's command buffer has
+    /// been submitted to the GPU, and before the image is queued for
+    /// presentation.  `frame_index` is the frame number (as later given in
+    /// `stats.frame_index`).  Use this for frame pacing, latency measurement
+    /// or injecting overlays; keep it short, since it delays presentation.
+    /// The default implementation does nothing.
+    fn on_submitted(&mut self, _frame_index: u64) {
+    }
+
+    /// This callback is called right after the frame's image has been
+    /// queued for presentation.  Presentation itself happens later, once the
+    /// GPU has finished rendering.  The default implementation does nothing.
+    fn on_presented(&mut self, _frame_index: u64) {
+    }
+
+    /// This callback lets your plugin do small pieces of CPU work while the
+    /// renderer would otherwise be waiting for the GPU to finish the frame.
+    /// It is called repeatedly, once per plugin per round, until the frame is
+    /// rendered or every plugin returns false.  Do one short job per call
+    /// (long jobs delay the next frame) and return true if more jobs are
+    /// waiting.  Do not change GPU state here; use gpu_update() for that.
+    /// The default implementation has no work and returns false.
+    fn background_job(&mut self) -> Result<bool, Error> {
+        Ok(false)
+    }
+
     /// This callback is called whenever the window size changes. The window
     /// size is passed in as `extent`. Your command buffers will always be
     /// re-recorded on window resize, so no return value is required.
     fn rebuild(&mut self, extent: Extent2D) -> Result<(), Error>;
+
+    /// This callback is called when renderer.set_reversed_depth() switches
+    /// the depth convention.  Pipelines you made with create_pipeline() test
+    /// depth the old way, so recreate them (a reversed depth buffer holds 1.0
+    /// at the near plane).  The GPU is idle, and your command buffers will be
+    /// re-recorded afterwards.  The default implementation does nothing.
+    fn on_reversed_depth(&mut self, _reversed_depth_buffer: bool) -> Result<(), Error> {
+        Ok(())
+    }
 }
@@ -4,11 +4,28 @@ use dacite::core::{Device, DescriptorPool, DescriptorSet, DescriptorSetLayout,
                    DescriptorType, CommandBuffer, RenderPass, Viewport, Rect2D,
                    PipelineBindPoint, Pipeline, PipelineLayout, PrimitiveTopology,
                    CullModeFlags, FrontFace, ShaderModuleCreateFlags,
-                   ShaderModuleCreateInfo, ShaderModule};
+                   ShaderModuleCreateInfo, ShaderModule, ColorComponentFlags,
+                   SpecializationInfo, SpecializationMapEntry, PipelineCache};
 use crate::error::*;
+use serde::Deserialize;
 use super::target_data::TargetData;
+use super::descriptor_set_writer::DescriptorSetWriter;
 use super::{DepthHandling, BlendMode};
 
+// How the bloom blur is filtered (see Config::bloom_quality)
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum BloomQuality {
+    // A separable 11-tap Gaussian in each direction (22 bright-pass taps)
+    Gaussian = 0,
+    // Kawase-style dual filtering: a 5-tap downsample-style pass then an
+    // 8-tap upsample-style pass, each bilinear tap averaging 4 texels.  A
+    // similar, slightly softer look at a fraction of the cost.  Both passes
+    // run at the render extent, as the blur target has no mip chain to
+    // downsample into.
+    Kawase = 1,
+}
+
 pub struct BlurGfx {
     pipeline_v: Pipeline,
     pipeline_layout_v: PipelineLayout,
@@ -25,13 +42,16 @@ pub struct BlurGfx {
 
 impl BlurGfx {
     pub fn new(device: &Device,
+               pipeline_cache: &PipelineCache,
                descriptor_pool: DescriptorPool,
                target_data: &TargetData,
                blurh_render_pass: RenderPass,
                blurv_render_pass: RenderPass,
                viewport: Viewport,
                scissors: Rect2D,
-               params_layout: DescriptorSetLayout)
+               params_layout: DescriptorSetLayout,
+               hdr_ceiling: f32,
+               bloom_quality: BloomQuality)
                -> Result<BlurGfx, Error>
     {
         let sampler = {
@@ -110,39 +130,84 @@ impl BlurGfx {
 
         let vertex_shader_h = vertex_shader_h(device)?;
         let fragment_shader_h = fragment_shader_h(device)?;
+        let fragment_spec_h = SpecializationInfo {
+            map_entries: vec![
+                SpecializationMapEntry { // output ceiling
+                    constant_id: 0,
+                    offset: 0,
+                    size: ::std::mem::size_of::<f32>(),
+                },
+                SpecializationMapEntry { // bloom quality
+                    constant_id: 1,
+                    offset: 1 * ::std::mem::size_of::<f32>() as u32,
+                    size: ::std::mem::size_of::<u32>(),
+                },
+            ],
+            // hdr_ceiling then bloom_quality
+            data: {
+                let d: [u32; 2] = [hdr_ceiling.to_bits(), bloom_quality as u32];
+                unsafe {
+                    ::std::slice::from_raw_parts(
+                        d.as_ptr() as *const u8,
+                        2 * ::std::mem::size_of::<u32>()).to_vec()
+                }
+            }
+        };
 
         let (pipeline_layout_h, pipeline_h) =
             super::pipeline::create(
-                device, viewport, scissors,
+                device, Some(pipeline_cache), viewport, scissors,
                 true, // reversed depth buffer irrelevant for blur
                 blurh_render_pass, vec![
                     desc_layout.clone(),
                     params_layout.clone(),
                 ],
-                Some(vertex_shader_h), None, Some(fragment_shader_h), None,
+                Some(vertex_shader_h), None, Some(fragment_shader_h), Some(fragment_spec_h),
                 None,
                 PrimitiveTopology::TriangleList,
+                false, // no primitive restart
+                1.0, // line width
                 CullModeFlags::NONE, FrontFace::Clockwise,
+                false, // no depth clamp
                 DepthHandling::None,
+                None, // no depth bias
+                None, // no sample shading
                 vec![BlendMode::Off],
+                ColorComponentFlags::R | ColorComponentFlags::G | ColorComponentFlags::B,
                 vec![])?;
 
         let vertex_shader_v = vertex_shader_v(device)?;
         let fragment_shader_v = fragment_shader_v(device)?;
+        let fragment_spec_v = SpecializationInfo {
+            map_entries: vec![
+                SpecializationMapEntry { // bloom quality
+                    constant_id: 0,
+                    offset: 0,
+                    size: ::std::mem::size_of::<u32>(),
+                },
+            ],
+            data: (bloom_quality as u32).to_ne_bytes().to_vec(),
+        };
 
         let (pipeline_layout_v, pipeline_v) =
             super::pipeline::create(
-                device, viewport, scissors,
+                device, Some(pipeline_cache), viewport, scissors,
                 true, // reversed depth buffer irrelevant for blur
                 blurv_render_pass, vec![
                     desc_layout.clone(),
                     params_layout.clone()],
-                Some(vertex_shader_v), None, Some(fragment_shader_v), None,
+                Some(vertex_shader_v), None, Some(fragment_shader_v), Some(fragment_spec_v),
                 None,
                 PrimitiveTopology::TriangleList,
+                false, // no primitive restart
+                1.0, // line width
                 CullModeFlags::NONE, FrontFace::Clockwise,
+                false, // no depth clamp
                 DepthHandling::None,
+                None, // no depth bias
+                None, // no sample shading
                 vec![BlendMode::Add],
+                ColorComponentFlags::R | ColorComponentFlags::G | ColorComponentFlags::B,
                 vec![])?;
 
         let mut blur_gfx = BlurGfx {
@@ -178,46 +243,15 @@ impl BlurGfx {
 
     fn write(&mut self)
     {
-        use dacite::core::{WriteDescriptorSet, WriteDescriptorSetElements,
-                           DescriptorImageInfo};
-
-        DescriptorSet::update(
-            Some(&[
-                WriteDescriptorSet {
-                    dst_set: self.descriptor_set_h.clone(),
-                    dst_binding: 0,
-                    dst_array_element: 0, // only have 1 element
-                    descriptor_type: DescriptorType::CombinedImageSampler,
-                    elements: WriteDescriptorSetElements::ImageInfo(
-                        vec![
-                            DescriptorImageInfo {
-                                sampler: Some(self.sampler.clone()),
-                                image_view: Some(self.shading_image_view.clone()),
-                                image_layout: ImageLayout::ShaderReadOnlyOptimal,
-                            }
-                        ]
-                    ),
-                    chain: None,
-                },
-                WriteDescriptorSet {
-                    dst_set: self.descriptor_set_v.clone(),
-                    dst_binding: 0,
-                    dst_array_element: 0, // only have 1 element
-                    descriptor_type: DescriptorType::CombinedImageSampler,
-                    elements: WriteDescriptorSetElements::ImageInfo(
-                        vec![
-                            DescriptorImageInfo {
-                                sampler: Some(self.sampler.clone()),
-                                image_view: Some(self.blur_image_view.clone()),
-                                image_layout: ImageLayout::ShaderReadOnlyOptimal,
-                            }
-                        ]
-                    ),
-                    chain: None,
-                },
-            ]),
-            None
-        );
+        DescriptorSetWriter::new(self.descriptor_set_h.clone())
+            .image(0, self.sampler.clone(), self.shading_image_view.clone(),
+                   ImageLayout::ShaderReadOnlyOptimal)
+            .flush();
+
+        DescriptorSetWriter::new(self.descriptor_set_v.clone())
+            .image(0, self.sampler.clone(), self.blur_image_view.clone(),
+                   ImageLayout::ShaderReadOnlyOptimal)
+            .flush();
     }
 
     pub fn record_blurh(&self, command_buffer: CommandBuffer,
@@ -296,11 +330,18 @@ fn fragment_shader_h(device: &Device) -> Result<ShaderModule, Error>
 #extension GL_ARB_separate_shader_objects : enable
 #extension GL_ARB_shading_language_420pack : enable
 
+// Largest output value (the shading format's maximum, or lower)
+layout(constant_id = 0) const float hdr_ceiling = 65504.0;
+// 0 = Gaussian, 1 = Kawase
+layout(constant_id = 1) const int bloom_quality = 0;
+
 layout (binding = 0) uniform sampler2D samplerColor;
 
 layout (set = 1, binding = 0) uniform UBO
 {
   mat4 inv_projection;
+  mat4 inv_view;
+  mat4 shadow_view_projection;
   vec4 dlight_directions[2];
   vec4 dlight_irradiances[2];
   float bloom_strength;
@@ -332,10 +373,17 @@ vec3 samp(vec2 offset) {
   vec3 xyz = rgb2xyz * color;
   */
 
-  // New function, considers some lums will be very high
+  // Bright pass threshold with a soft knee.  Pixels with luminance below
+  // (cliff - knee) contribute nothing, pixels above (cliff + knee) contribute
+  // fully above the cliff, and in between we ease in quadratically so that
+  // there is no visible edge where blooming starts.
   // Output is in range [0,1]
-  //  float mult = 1 - pow(1.08, -color.y);
-  float mult = color.y / (color.y + 6);
+  float lum = dot(color, vec3(0.2126, 0.7152, 0.0722));
+  float knee = ubo.bloom_cliff * 0.5;
+  float soft = clamp(lum - ubo.bloom_cliff + knee, 0.0, 2.0 * knee);
+  soft = (soft * soft) / (4.0 * knee + 0.00001);
+  float mult = max(soft, lum - ubo.bloom_cliff) / max(lum, 0.00001);
+  mult = clamp(mult, 0.0, 1.0);
 
   // Adjust based on the bloom strength
   // Output will be in range [0,bloom_strength]
@@ -376,13 +424,26 @@ void main()
   weight[5] = 0.0075756805;
 
   vec2 tex_offset = 1.0 / textureSize(samplerColor, 0); // gets size of single texel
-  vec3 result = samp(vec2(0.0, 0.0)) * weight[0]; // current fragment's contribution
-  for (int i = 1; i < 6; ++i) {
-    result += samp(vec2(tex_offset.x * i, 0.0)) * weight[i] * ubo.bloom_cliff;
-    result += samp(vec2(-tex_offset.x * i, 0.0)) * weight[i] * ubo.bloom_cliff;
+  vec3 result;
+  if (bloom_quality == 1) {
+    // Dual filter downsample: the center and four diagonal bilinear taps
+    // (each averaging 4 texels).  Scaled to the Gaussian's total weight.
+    vec2 o = tex_offset * 1.5;
+    result = samp(vec2(0.0, 0.0)) * 4.0;
+    result += samp(vec2(-o.x, -o.y));
+    result += samp(vec2( o.x, -o.y));
+    result += samp(vec2(-o.x,  o.y));
+    result += samp(vec2( o.x,  o.y));
+    result *= 3.99 / 8.0;
+  } else {
+    result = samp(vec2(0.0, 0.0)) * weight[0]; // current fragment's contribution
+    for (int i = 1; i < 6; ++i) {
+      result += samp(vec2(tex_offset.x * i, 0.0)) * weight[i];
+      result += samp(vec2(-tex_offset.x * i, 0.0)) * weight[i];
+    }
   }
-  // do not go beyond maximum f16
-  outFragColor = vec4(min(result, 65504), 1.0);
+  // do not go beyond what the target holds
+  outFragColor = vec4(min(result, hdr_ceiling), 1.0);
 }
 "#);
 
@@ -434,11 +495,16 @@ fn fragment_shader_v(device: &Device) -> Result<ShaderModule, Error>
 #extension GL_ARB_separate_shader_objects : enable
 #extension GL_ARB_shading_language_420pack : enable
 
+// 0 = Gaussian, 1 = Kawase
+layout(constant_id = 0) const int bloom_quality = 0;
+
 layout (set = 0, binding = 0) uniform sampler2D samplerColor;
 
 layout (set = 1, binding = 0) uniform UBO
 {
   mat4 inv_projection;
+  mat4 inv_view;
+  mat4 shadow_view_projection;
   vec4 dlight_directions[2];
   vec4 dlight_irradiances[2];
   float bloom_strength;
@@ -466,10 +532,26 @@ void main()
   weight[5] = 0.0075756805;
 
   vec2 tex_offset = 1.0 / textureSize(samplerColor, 0); // gets size of single texel
-  vec3 result = samp(vec2(0.0, 0.0)) * weight[0]; // current fragment's contribution
-  for (int i = 1; i < 6; ++i) {
-    result += samp(vec2(0.0, tex_offset.y * i)) * weight[i] * ubo.bloom_cliff;
-    result += samp(vec2(0.0, -tex_offset.y * i)) * weight[i] * ubo.bloom_cliff;
+  vec3 result;
+  if (bloom_quality == 1) {
+    // Dual filter upsample: four edge taps and four (double weight)
+    // diagonal taps on a wider ring.  Scaled to the Gaussian's total weight.
+    vec2 o = tex_offset * 2.5;
+    result = samp(vec2(-o.x * 2.0, 0.0));
+    result += samp(vec2( o.x * 2.0, 0.0));
+    result += samp(vec2(0.0, -o.y * 2.0));
+    result += samp(vec2(0.0,  o.y * 2.0));
+    result += samp(vec2(-o.x, -o.y)) * 2.0;
+    result += samp(vec2( o.x, -o.y)) * 2.0;
+    result += samp(vec2(-o.x,  o.y)) * 2.0;
+    result += samp(vec2( o.x,  o.y)) * 2.0;
+    result *= 3.99 / 12.0;
+  } else {
+    result = samp(vec2(0.0, 0.0)) * weight[0]; // current fragment's contribution
+    for (int i = 1; i < 6; ++i) {
+      result += samp(vec2(0.0, tex_offset.y * i)) * weight[i];
+      result += samp(vec2(0.0, -tex_offset.y * i)) * weight[i];
+    }
   }
   outFragColor = vec4(result, 1.0);
 }
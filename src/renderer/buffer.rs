@@ -1,6 +1,7 @@
 
 use crate::error::Error;
 use std::io::{Write, Read};
+use std::sync::{Arc, Mutex};
 use dacite::core::{Buffer, Device, BufferUsageFlags, MemoryPropertyFlags,
                    BufferCopy, OptionalDeviceSize, Format, BufferView,
                    BufferViewCreateInfo};
@@ -94,6 +95,11 @@ impl HostVisibleBuffer {
         self.block.as_ptr_at_offset(offset)
     }
 
+    // Call after the GPU writes to this buffer and before reading it back
+    pub fn invalidate(&self) -> Result<(), Error> {
+        self.block.invalidate()
+    }
+
     pub fn write_one<T: Copy>(&mut self, data: &T, offset: Option<usize>)
                           -> Result<(), Error>
     {
@@ -123,6 +129,8 @@ impl Write for HostVisibleBuffer {
 pub struct DeviceLocalBuffer {
     buffer: Buffer,
     block: Block,
+    // Buffer views are created on first use (per format), and shared by clones.
+    view_cache: Arc<Mutex<Vec<(Format, BufferView)>>>,
 }
 
 impl DeviceLocalBuffer {
@@ -147,6 +155,7 @@ impl DeviceLocalBuffer {
         Ok(DeviceLocalBuffer {
             buffer: buffer,
             block: block,
+            view_cache: Arc::new(Mutex::new(Vec::new())),
         })
     }
 
@@ -219,7 +228,8 @@ impl DeviceLocalBuffer {
                 lifetime, reason, MemoryPropertyFlags::DEVICE_LOCAL)?;
             DeviceLocalBuffer {
                 buffer: buffer,
-                block: block
+                block: block,
+                view_cache: Arc::new(Mutex::new(Vec::new())),
             }
         };
 
@@ -272,12 +282,25 @@ impl DeviceLocalBuffer {
     }
      */
 
+    // Get a buffer view of the given format, creating it the first time.
     pub fn get_buffer_view(&self, device: &Device, format: Format) -> Result<BufferView, Error>
+    {
+        let mut cache = self.view_cache.lock().unwrap();
+        if let Some(&(_, ref view)) = cache.iter().find(|&&(f, _)| f == format) {
+            return Ok(view.clone());
+        }
+        let view = Self::create_buffer_view(&self.buffer, device, format)?;
+        cache.push((format, view.clone()));
+        Ok(view)
+    }
+
+    fn create_buffer_view(buffer: &Buffer, device: &Device, format: Format)
+                          -> Result<BufferView, Error>
     {
         Ok(device.create_buffer_view(
             &BufferViewCreateInfo {
                 flags: Default::default(),
-                buffer: self.buffer.clone(),
+                buffer: buffer.clone(),
                 format: format,
                 offset: 0,
                 range: OptionalDeviceSize::WholeSize,
@@ -331,7 +354,10 @@ fn copy(
         chain: None
     };
     Fence::reset_fences(&[fence.clone()])?;
-    commander.xfr_queue.submit( Some(&[submit_info]), Some(&fence) )?;
+    {
+        let _queues = commander.lock_queues();
+        commander.xfr_queue.submit( Some(&[submit_info]), Some(&fence) )?;
+    }
     Fence::wait_for_fences(&[fence], true, Timeout::Infinite)?;
     Ok(())
 }
@@ -1,9 +1,16 @@
 
+use std::sync::{Arc, Mutex, MutexGuard};
 use dacite::core::{Device, Queue, CommandPool, CommandBuffer};
 
 use crate::error::Error;
 use super::setup::QueueIndices;
 
+// NOTE: xfr_queue may be the very same queue as gfx_queue (on devices with a
+// single queue family and queue).  A ResourceLoader has a Commander of its
+// own (command pools can't be shared between threads) but submits to the
+// same queues from its worker thread, and Vulkan requires queue access to be
+// externally synchronized; so hold lock_queues() around every submit and
+// present, and use wait_idle() rather than device.wait_idle().
 pub struct Commander {
     pub gfx_queue: Queue,
     pub gfx_command_buffer_stale: Vec<bool>,
@@ -12,13 +19,18 @@ pub struct Commander {
     pub xfr_queue: Queue,
     pub xfr_command_buffer: CommandBuffer,
     pub xfr_command_pool: CommandPool,
+    // For one-off graphics work outside the per-frame command buffers (see
+    // begin_oneshot)
+    oneshot_command_pool: CommandPool,
+    queue_lock: Arc<Mutex<()>>,
 }
 
 impl Commander {
     pub fn new(
         device: &Device,
         queue_indices: &QueueIndices,
-        num_framebuffers: u32)
+        num_framebuffers: u32,
+        queue_lock: Arc<Mutex<()>>)
         -> Result<Commander, Error>
     {
         let xfr_command_pool = {
@@ -80,6 +92,17 @@ impl Commander {
         let gfx_queue = device.get_queue(queue_indices.graphics_family,
                                          queue_indices.graphics_index);
 
+        let oneshot_command_pool = {
+            use dacite::core::{CommandPoolCreateInfo, CommandPoolCreateFlags};
+
+            let create_info = CommandPoolCreateInfo {
+                flags: CommandPoolCreateFlags::TRANSIENT,
+                queue_family_index: queue_indices.graphics_family,
+                chain: None,
+            };
+            device.create_command_pool(&create_info, None)?
+        };
+
         Ok(Commander {
             gfx_queue: gfx_queue,
             gfx_command_buffer_stale: gfx_command_buffer_stale,
@@ -88,6 +111,81 @@ impl Commander {
             xfr_queue: xfr_queue,
             xfr_command_buffer: xfr_command_buffer,
             xfr_command_pool: xfr_command_pool,
+            oneshot_command_pool: oneshot_command_pool,
+            queue_lock: queue_lock,
         })
     }
+
+    // A Commander for another thread, with its own command pools but sharing
+    // this one's queues (and queue lock).  It has one gfx command buffer.
+    pub fn new_for_thread(&self, device: &Device, queue_indices: &QueueIndices)
+                          -> Result<Commander, Error>
+    {
+        Commander::new(device, queue_indices, 1, self.queue_lock.clone())
+    }
+
+    pub fn lock_queues(&self) -> MutexGuard<()> {
+        self.queue_lock.lock().unwrap()
+    }
+
+    pub fn wait_idle(&self, device: &Device) -> Result<(), Error> {
+        let _queues = self.lock_queues();
+        device.wait_idle()?;
+        Ok(())
+    }
+
+    // Begin a new command buffer for one-off work on the graphics queue
+    // (e.g. a layout transition or a readback), to be submitted with
+    // submit_oneshot()
+    pub fn begin_oneshot(&self) -> Result<CommandBuffer, Error> {
+        use dacite::core::{CommandBufferAllocateInfo, CommandBufferLevel,
+                           CommandBufferBeginInfo, CommandBufferUsageFlags};
+
+        let allocate_info = CommandBufferAllocateInfo {
+            command_pool: self.oneshot_command_pool.clone(),
+            level: CommandBufferLevel::Primary,
+            command_buffer_count: 1,
+            chain: None,
+        };
+        let command_buffer = CommandPool::allocate_command_buffers(&allocate_info)?
+            .pop().unwrap();
+
+        command_buffer.begin(&CommandBufferBeginInfo {
+            flags: CommandBufferUsageFlags::ONE_TIME_SUBMIT,
+            inheritance_info: None,
+            chain: None,
+        })?;
+
+        Ok(command_buffer)
+    }
+
+    // End a command buffer from begin_oneshot(), submit it to the graphics
+    // queue and wait for it to complete.  The command buffer is freed when
+    // it is dropped.
+    pub fn submit_oneshot(&self, device: &Device, command_buffer: CommandBuffer)
+                          -> Result<(), Error>
+    {
+        use dacite::core::{FenceCreateInfo, FenceCreateFlags, SubmitInfo, Timeout};
+
+        command_buffer.end()?;
+
+        let fence = device.create_fence(&FenceCreateInfo {
+            flags: FenceCreateFlags::empty(),
+            chain: None
+        }, None)?;
+        let submit_info = SubmitInfo {
+            wait_semaphores: vec![],
+            wait_dst_stage_mask: vec![],
+            command_buffers: vec![command_buffer],
+            signal_semaphores: vec![],
+            chain: None,
+        };
+        {
+            let _queues = self.lock_queues();
+            self.gfx_queue.submit(Some(&[submit_info]), Some(&fence))?;
+        }
+        fence.wait_for(Timeout::Infinite)?;
+
+        Ok(())
+    }
 }
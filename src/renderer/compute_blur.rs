@@ -0,0 +1,474 @@
+
+use dacite::core::{Device, DescriptorPool, DescriptorSet, DescriptorSetLayout,
+                   DescriptorSetLayoutBinding, Sampler, ImageView, ImageLayout,
+                   DescriptorType, CommandBuffer, PipelineBindPoint, Pipeline,
+                   PipelineLayout, ShaderModuleCreateFlags, ShaderModuleCreateInfo,
+                   ShaderModule, SpecializationInfo, SpecializationMapEntry, Extent2D,
+                   PipelineCache};
+use crate::error::Error;
+use super::target_data::TargetData;
+use super::descriptor_set_writer::DescriptorSetWriter;
+use super::blur::BloomQuality;
+
+// Workgroup sizes, which must match the shaders
+const BLURH_GROUP_WIDTH: u32 = 128;
+const BLURV_GROUP_SIZE: u32 = 16;
+
+// The bloom blur in compute shaders (see Config::bloom_use_compute), with
+// the same parameters and result as BlurGfx.  The horizontal pass
+// bright-pass filters each texel of a workgroup's row span once into shared
+// memory, rather than once per tap, and writes the blur image as a storage
+// image.  The vertical pass adds its result straight into the shading image,
+// so neither pass needs a render pass.
+//
+// The shaders declare the images as rgba16f, so this requires the default
+// shading format (Config::shading_format r16g16b16a16_sfloat).
+pub struct ComputeBlurGfx {
+    pipeline_v: Pipeline,
+    pipeline_h: Pipeline,
+    pipeline_layout: PipelineLayout,
+    descriptor_set_v: DescriptorSet,
+    descriptor_set_h: DescriptorSet,
+    #[allow(dead_code)] // this must remain alive
+    desc_layout: DescriptorSetLayout,
+    shading_image_view: ImageView,
+    blur_image_view: ImageView,
+    sampler: Sampler,
+    extent: Extent2D,
+}
+
+impl ComputeBlurGfx {
+    pub fn new(device: &Device,
+               pipeline_cache: &PipelineCache,
+               descriptor_pool: DescriptorPool,
+               target_data: &TargetData,
+               params_layout: DescriptorSetLayout,
+               hdr_ceiling: f32,
+               bloom_quality: BloomQuality)
+               -> Result<ComputeBlurGfx, Error>
+    {
+        let sampler = {
+            use dacite::core::{SamplerCreateInfo, SamplerMipmapMode, SamplerAddressMode,
+                               BorderColor, Filter, CompareOp};
+
+            device.create_sampler(&SamplerCreateInfo {
+                flags: Default::default(),
+                mag_filter: Filter::Linear,
+                min_filter: Filter::Linear,
+                mipmap_mode: SamplerMipmapMode::Nearest,
+                address_mode_u: SamplerAddressMode::ClampToEdge,
+                address_mode_v: SamplerAddressMode::ClampToEdge,
+                address_mode_w: SamplerAddressMode::ClampToEdge,
+                mip_lod_bias: 0.0,
+                anisotropy_enable: false,
+                max_anisotropy: 1.0,
+                compare_enable: false,
+                compare_op: CompareOp::Never,
+                min_lod: 0.0,
+                max_lod: 0.0,
+                border_color: BorderColor::FloatOpaqueWhite,
+                unnormalized_coordinates: false,
+                chain: None
+            }, None)?
+        };
+
+        let shading_image_view = target_data.shading_image.
+            get_image_view(device)?;
+
+        let blur_image_view = target_data.blur_image.
+            get_image_view(device)?;
+
+        // Both passes read one image and write the other
+        let desc_layout = {
+            use dacite::core::{DescriptorSetLayoutCreateInfo, ShaderStageFlags};
+
+            let create_info = DescriptorSetLayoutCreateInfo {
+                flags: Default::default(),
+                bindings: vec![
+                    DescriptorSetLayoutBinding {
+                        binding: 0,
+                        descriptor_type: DescriptorType::CombinedImageSampler,
+                        descriptor_count: 1,
+                        stage_flags: ShaderStageFlags::COMPUTE,
+                        immutable_samplers: vec![],
+                    },
+                    DescriptorSetLayoutBinding {
+                        binding: 1,
+                        descriptor_type: DescriptorType::StorageImage,
+                        descriptor_count: 1,
+                        stage_flags: ShaderStageFlags::COMPUTE,
+                        immutable_samplers: vec![],
+                    },
+                ],
+                chain: None,
+            };
+            device.create_descriptor_set_layout(&create_info, None)?
+        };
+
+        let (descriptor_set_h, descriptor_set_v) = {
+            use dacite::core::DescriptorSetAllocateInfo;
+
+            let alloc_info = DescriptorSetAllocateInfo {
+                descriptor_pool: descriptor_pool.clone(),
+                set_layouts: vec![
+                    desc_layout.clone(),
+                    desc_layout.clone()
+                ],
+                chain: None,
+            };
+
+            let mut descriptor_sets = DescriptorPool::allocate_descriptor_sets(&alloc_info)?;
+
+            let dsh = descriptor_sets.pop().unwrap();
+            let dsv = descriptor_sets.pop().unwrap();
+            (dsh, dsv)
+        };
+
+        let pipeline_layout = {
+            use dacite::core::{PipelineLayoutCreateInfo, PipelineLayoutCreateFlags};
+
+            device.create_pipeline_layout(&PipelineLayoutCreateInfo {
+                flags: PipelineLayoutCreateFlags::empty(),
+                set_layouts: vec![desc_layout.clone(), params_layout],
+                push_constant_ranges: vec![],
+                chain: None,
+            }, None)?
+        };
+
+        // Both shaders take the same constants, as the fragment shaders do
+        let spec = SpecializationInfo {
+            map_entries: vec![
+                SpecializationMapEntry { // output ceiling
+                    constant_id: 0,
+                    offset: 0,
+                    size: ::std::mem::size_of::<f32>(),
+                },
+                SpecializationMapEntry { // bloom quality
+                    constant_id: 1,
+                    offset: 1 * ::std::mem::size_of::<f32>() as u32,
+                    size: ::std::mem::size_of::<u32>(),
+                },
+            ],
+            // hdr_ceiling then bloom_quality
+            data: {
+                let d: [u32; 2] = [hdr_ceiling.to_bits(), bloom_quality as u32];
+                unsafe {
+                    ::std::slice::from_raw_parts(
+                        d.as_ptr() as *const u8,
+                        2 * ::std::mem::size_of::<u32>()).to_vec()
+                }
+            }
+        };
+
+        let (pipeline_h, pipeline_v) = {
+            use dacite::core::{ComputePipelineCreateInfo, PipelineCreateFlags,
+                               PipelineShaderStageCreateInfo,
+                               PipelineShaderStageCreateFlags, ShaderStageFlagBits};
+
+            let create_info = |module: ShaderModule| ComputePipelineCreateInfo {
+                flags: PipelineCreateFlags::empty(),
+                stage: PipelineShaderStageCreateInfo {
+                    flags: PipelineShaderStageCreateFlags::empty(),
+                    stage: ShaderStageFlagBits::Compute,
+                    module: module,
+                    name: "main".to_owned(),
+                    specialization_info: Some(spec.clone()),
+                    chain: None,
+                },
+                layout: pipeline_layout.clone(),
+                base_pipeline: None,
+                base_pipeline_index: None,
+                chain: None,
+            };
+
+            let create_infos = vec![
+                create_info(compute_shader_h(device)?),
+                create_info(compute_shader_v(device)?),
+            ];
+            let pipelines = device.create_compute_pipelines(
+                Some(pipeline_cache), &create_infos, None)
+                .map_err(|(e, _)| e)?;
+            (pipelines[0].clone(), pipelines[1].clone())
+        };
+
+        let mut compute_blur_gfx = ComputeBlurGfx {
+            pipeline_v: pipeline_v,
+            pipeline_h: pipeline_h,
+            pipeline_layout: pipeline_layout,
+            descriptor_set_v: descriptor_set_v,
+            descriptor_set_h: descriptor_set_h,
+            desc_layout: desc_layout,
+            shading_image_view: shading_image_view,
+            blur_image_view: blur_image_view,
+            sampler: sampler,
+            extent: target_data.extent,
+        };
+
+        compute_blur_gfx.write();
+
+        Ok(compute_blur_gfx)
+    }
+
+    pub fn rebuild(&mut self, device: &Device, target_data: &TargetData)
+        -> Result<(), Error>
+    {
+        self.shading_image_view = target_data.shading_image.
+            get_image_view(device)?;
+        self.blur_image_view = target_data.blur_image.
+            get_image_view(device)?;
+        self.extent = target_data.extent;
+
+        self.write();
+
+        Ok(())
+    }
+
+    fn write(&mut self)
+    {
+        DescriptorSetWriter::new(self.descriptor_set_h.clone())
+            .image(0, self.sampler.clone(), self.shading_image_view.clone(),
+                   ImageLayout::ShaderReadOnlyOptimal)
+            .storage_image(1, self.blur_image_view.clone(), ImageLayout::General)
+            .flush();
+
+        DescriptorSetWriter::new(self.descriptor_set_v.clone())
+            .image(0, self.sampler.clone(), self.blur_image_view.clone(),
+                   ImageLayout::ShaderReadOnlyOptimal)
+            .storage_image(1, self.shading_image_view.clone(), ImageLayout::General)
+            .flush();
+    }
+
+    // Record the horizontal pass (see TargetData::transition_for_compute_blurh)
+    pub fn record_blurh(&self, command_buffer: CommandBuffer,
+                        params_desc_set: DescriptorSet)
+    {
+        command_buffer.bind_pipeline(PipelineBindPoint::Compute, &self.pipeline_h);
+
+        command_buffer.bind_descriptor_sets(
+            PipelineBindPoint::Compute,
+            &self.pipeline_layout,
+            0, // starting with first set
+            &[self.descriptor_set_h.clone(),
+              params_desc_set],
+            None,
+        );
+
+        command_buffer.dispatch(
+            (self.extent.width + BLURH_GROUP_WIDTH - 1) / BLURH_GROUP_WIDTH,
+            self.extent.height,
+            1);
+    }
+
+    // Record the vertical pass (see TargetData::transition_for_compute_blurv)
+    pub fn record_blurv(&self, command_buffer: CommandBuffer,
+                        params_desc_set: DescriptorSet)
+    {
+        command_buffer.bind_pipeline(PipelineBindPoint::Compute, &self.pipeline_v);
+
+        command_buffer.bind_descriptor_sets(
+            PipelineBindPoint::Compute,
+            &self.pipeline_layout,
+            0, // starting with first set
+            &[self.descriptor_set_v.clone(),
+              params_desc_set],
+            None,
+        );
+
+        command_buffer.dispatch(
+            (self.extent.width + BLURV_GROUP_SIZE - 1) / BLURV_GROUP_SIZE,
+            (self.extent.height + BLURV_GROUP_SIZE - 1) / BLURV_GROUP_SIZE,
+            1);
+    }
+}
+
+fn compute_shader_h(device: &Device) -> Result<ShaderModule, Error>
+{
+    let bytes: &[u8] = glsl_cs!(r#"#version 450
+
+#extension GL_ARB_separate_shader_objects : enable
+#extension GL_ARB_shading_language_420pack : enable
+
+layout (local_size_x = 128, local_size_y = 1) in;
+
+// Largest output value (the shading format's maximum, or lower)
+layout(constant_id = 0) const float hdr_ceiling = 65504.0;
+// 0 = Gaussian, 1 = Kawase
+layout(constant_id = 1) const int bloom_quality = 0;
+
+layout (set = 0, binding = 0) uniform sampler2D samplerColor;
+layout (set = 0, binding = 1, rgba16f) uniform writeonly image2D blurImage;
+
+layout (set = 1, binding = 0) uniform UBO
+{
+  mat4 inv_projection;
+  mat4 inv_view;
+  mat4 shadow_view_projection;
+  vec4 dlight_directions[2];
+  vec4 dlight_irradiances[2];
+  float bloom_strength;
+  float bloom_cliff;
+  float blur_level;
+  float white_point;
+} ubo;
+
+// This workgroup's row span, with a 5 texel apron on each side
+shared vec3 row[128 + 10];
+
+// Bright pass filter (see the graphics blur's fragment shader)
+vec3 bright(vec3 color) {
+  float lum = dot(color, vec3(0.2126, 0.7152, 0.0722));
+  float knee = ubo.bloom_cliff * 0.5;
+  float soft = clamp(lum - ubo.bloom_cliff + knee, 0.0, 2.0 * knee);
+  soft = (soft * soft) / (4.0 * knee + 0.00001);
+  float mult = max(soft, lum - ubo.bloom_cliff) / max(lum, 0.00001);
+  mult = clamp(mult, 0.0, 1.0);
+  mult *= ubo.bloom_strength;
+  mult = clamp(mult + ubo.blur_level, 0, 1);
+  color *= mult;
+  return color / (color + 1); // reinhard
+}
+
+vec3 samp(vec2 uv) {
+  return bright(textureLod(samplerColor, uv, 0.0).rgb);
+}
+
+void main()
+{
+  ivec2 size = textureSize(samplerColor, 0);
+  ivec2 pos = ivec2(gl_GlobalInvocationID.xy);
+
+  vec3 result;
+  if (bloom_quality == 1) {
+    // Dual filter downsample, as in the fragment shader.  The taps are 2D,
+    // so there is nothing to share between invocations.
+    if (pos.x >= size.x) {
+      return;
+    }
+    vec2 tex_offset = 1.0 / vec2(size);
+    vec2 uv = (vec2(pos) + 0.5) * tex_offset;
+    vec2 o = tex_offset * 1.5;
+    result = samp(uv) * 4.0;
+    result += samp(uv + vec2(-o.x, -o.y));
+    result += samp(uv + vec2( o.x, -o.y));
+    result += samp(uv + vec2(-o.x,  o.y));
+    result += samp(uv + vec2( o.x,  o.y));
+    result *= 3.99 / 8.0;
+  } else {
+    float weight[6];
+    weight[0] = 1.0;
+    weight[1] = 0.8225776;
+    weight[2] = 0.45783338;
+    weight[3] = 0.17242163;
+    weight[4] = 0.04393694;
+    weight[5] = 0.0075756805;
+
+    // Filter each texel of the span once (clamped to the edge, as the
+    // sampler is)
+    int base = int(gl_WorkGroupID.x) * 128 - 5;
+    for (int i = int(gl_LocalInvocationID.x); i < 128 + 10; i += 128) {
+      int x = clamp(base + i, 0, size.x - 1);
+      row[i] = bright(texelFetch(samplerColor, ivec2(x, pos.y), 0).rgb);
+    }
+    memoryBarrierShared();
+    barrier();
+
+    if (pos.x >= size.x) {
+      return;
+    }
+    int c = int(gl_LocalInvocationID.x) + 5;
+    result = row[c] * weight[0];
+    for (int i = 1; i < 6; ++i) {
+      result += (row[c + i] + row[c - i]) * weight[i];
+    }
+  }
+
+  // do not go beyond what the target holds
+  imageStore(blurImage, pos, vec4(min(result, hdr_ceiling), 1.0));
+}
+"#);
+
+    let create_info = ShaderModuleCreateInfo {
+        flags: ShaderModuleCreateFlags::empty(),
+        code: bytes.to_vec(),
+        chain: None,
+    };
+
+    Ok(device.create_shader_module(&create_info, None)?)
+}
+
+fn compute_shader_v(device: &Device) -> Result<ShaderModule, Error>
+{
+    let bytes: &[u8] = glsl_cs!(r#"#version 450
+
+#extension GL_ARB_separate_shader_objects : enable
+#extension GL_ARB_shading_language_420pack : enable
+
+layout (local_size_x = 16, local_size_y = 16) in;
+
+// Largest output value (the shading format's maximum, or lower)
+layout(constant_id = 0) const float hdr_ceiling = 65504.0;
+// 0 = Gaussian, 1 = Kawase
+layout(constant_id = 1) const int bloom_quality = 0;
+
+layout (set = 0, binding = 0) uniform sampler2D samplerColor;
+layout (set = 0, binding = 1, rgba16f) uniform image2D shadingImage;
+
+vec2 uv;
+
+vec3 samp(vec2 offset) {
+  return textureLod(samplerColor, uv + offset, 0.0).rgb;
+}
+
+void main()
+{
+  ivec2 size = imageSize(shadingImage);
+  ivec2 pos = ivec2(gl_GlobalInvocationID.xy);
+  if (pos.x >= size.x || pos.y >= size.y) {
+    return;
+  }
+
+  float weight[6];
+  weight[0] = 1.0;
+  weight[1] = 0.8225776;
+  weight[2] = 0.45783338;
+  weight[3] = 0.17242163;
+  weight[4] = 0.04393694;
+  weight[5] = 0.0075756805;
+
+  vec2 tex_offset = 1.0 / vec2(size);
+  uv = (vec2(pos) + 0.5) * tex_offset;
+  vec3 result;
+  if (bloom_quality == 1) {
+    // Dual filter upsample, as in the fragment shader
+    vec2 o = tex_offset * 2.5;
+    result = samp(vec2(-o.x * 2.0, 0.0));
+    result += samp(vec2( o.x * 2.0, 0.0));
+    result += samp(vec2(0.0, -o.y * 2.0));
+    result += samp(vec2(0.0,  o.y * 2.0));
+    result += samp(vec2(-o.x, -o.y)) * 2.0;
+    result += samp(vec2( o.x, -o.y)) * 2.0;
+    result += samp(vec2(-o.x,  o.y)) * 2.0;
+    result += samp(vec2( o.x,  o.y)) * 2.0;
+    result *= 3.99 / 12.0;
+  } else {
+    result = samp(vec2(0.0, 0.0)) * weight[0];
+    for (int i = 1; i < 6; ++i) {
+      result += samp(vec2(0.0, tex_offset.y * i)) * weight[i];
+      result += samp(vec2(0.0, -tex_offset.y * i)) * weight[i];
+    }
+  }
+
+  // Add onto the scene (as the graphics path blends), keeping alpha
+  vec4 shading = imageLoad(shadingImage, pos);
+  imageStore(shadingImage, pos, vec4(min(shading.rgb + result, hdr_ceiling), shading.a));
+}
+"#);
+
+    let create_info = ShaderModuleCreateInfo {
+        flags: ShaderModuleCreateFlags::empty(),
+        code: bytes.to_vec(),
+        chain: None,
+    };
+
+    Ok(device.create_shader_module(&create_info, None)?)
+}
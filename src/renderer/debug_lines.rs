@@ -0,0 +1,240 @@
+
+use dacite::core::{Device, DescriptorSet, DescriptorSetLayout, CommandBuffer,
+                   RenderPass, Viewport, Rect2D, PipelineBindPoint, Pipeline,
+                   PipelineLayout, PrimitiveTopology, CullModeFlags, FrontFace,
+                   ShaderModuleCreateFlags, ShaderModuleCreateInfo, ShaderModule,
+                   ColorComponentFlags, BufferUsageFlags, PipelineCache};
+use crate::error::Error;
+use crate::vertex::{VulkanVertex, ColoredVertex};
+use super::buffer::HostVisibleBuffer;
+use super::memory::{Memory, Lifetime};
+use super::{DepthHandling, BlendMode};
+
+// The most line vertices (two per line) drawn at once
+pub const DEBUG_LINE_VERTEX_COUNT: usize = 16384;
+
+// VkDrawIndirectCommand
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct DrawIndirectCommand {
+    vertex_count: u32,
+    instance_count: u32,
+    first_vertex: u32,
+    first_instance: u32,
+}
+
+// Draws a batch of world-space debug lines (bounding boxes, normals, physics
+// shapes...) at the end of the transparent pass.  The lines live in host
+// visible buffers and are drawn indirectly, so replacing them (see
+// Renderer::set_debug_lines) doesn't require re-recording command buffers.
+// Line colors are luminance, as in the transparent pass.
+pub struct DebugLinesGfx {
+    pipeline: Pipeline,
+    pipeline_layout: PipelineLayout,
+    pipeline_cache: PipelineCache,
+    render_pass: RenderPass,
+    viewport: Viewport,
+    scissors: Rect2D,
+    params_layout: DescriptorSetLayout,
+    line_width: f32,
+    vertex_buffer: HostVisibleBuffer,
+    indirect_buffer: HostVisibleBuffer,
+}
+
+impl DebugLinesGfx {
+    pub fn new(device: &Device,
+               pipeline_cache: PipelineCache,
+               memory: &mut Memory,
+               render_pass: RenderPass,
+               viewport: Viewport,
+               scissors: Rect2D,
+               params_layout: DescriptorSetLayout,
+               reversed_depth_buffer: bool,
+               line_width: f32)
+               -> Result<DebugLinesGfx, Error>
+    {
+        let (pipeline_layout, pipeline) = build_pipeline(
+            device, &pipeline_cache, render_pass.clone(), viewport, scissors.clone(),
+            params_layout.clone(), reversed_depth_buffer, line_width)?;
+
+        let vertex_buffer = HostVisibleBuffer::new::<ColoredVertex>(
+            device, memory, DEBUG_LINE_VERTEX_COUNT,
+            BufferUsageFlags::VERTEX_BUFFER,
+            Lifetime::Permanent,
+            "Debug Lines")?;
+
+        let mut indirect_buffer = HostVisibleBuffer::new::<DrawIndirectCommand>(
+            device, memory, 1,
+            BufferUsageFlags::INDIRECT_BUFFER,
+            Lifetime::Permanent,
+            "Debug Lines Draw")?;
+        indirect_buffer.write_one(&DrawIndirectCommand {
+            vertex_count: 0,
+            instance_count: 1,
+            first_vertex: 0,
+            first_instance: 0,
+        }, None)?;
+
+        Ok(DebugLinesGfx {
+            pipeline: pipeline,
+            pipeline_layout: pipeline_layout,
+            pipeline_cache: pipeline_cache,
+            render_pass: render_pass,
+            viewport: viewport,
+            scissors: scissors,
+            params_layout: params_layout,
+            line_width: line_width,
+            vertex_buffer: vertex_buffer,
+            indirect_buffer: indirect_buffer,
+        })
+    }
+
+    // Switch the depth convention (see Config::reversed_depth_buffer). This
+    // rebuilds the pipeline, so the caller must ensure it is not in use and
+    // re-record command buffers.
+    pub fn set_reversed_depth_buffer(&mut self, device: &Device,
+                                     reversed_depth_buffer: bool)
+        -> Result<(), Error>
+    {
+        let (pipeline_layout, pipeline) = build_pipeline(
+            device, &self.pipeline_cache, self.render_pass.clone(), self.viewport,
+            self.scissors.clone(), self.params_layout.clone(), reversed_depth_buffer,
+            self.line_width)?;
+
+        self.pipeline_layout = pipeline_layout;
+        self.pipeline = pipeline;
+
+        Ok(())
+    }
+
+    // Replace the lines: each pair of vertices is one line.  Returns how
+    // many vertices will be drawn (at most DEBUG_LINE_VERTEX_COUNT).
+    pub fn set_lines(&mut self, vertices: &[ColoredVertex]) -> Result<usize, Error>
+    {
+        let count = vertices.len().min(DEBUG_LINE_VERTEX_COUNT) & !1;
+        self.vertex_buffer.write_array(&vertices[..count], None)?;
+
+        let command = self.indirect_buffer.as_ptr::<DrawIndirectCommand>().unwrap();
+        command.vertex_count = count as u32;
+        Ok(count)
+    }
+
+    pub fn record(&self, command_buffer: CommandBuffer,
+                  params_desc_set: DescriptorSet)
+    {
+        command_buffer.bind_pipeline(PipelineBindPoint::Graphics, &self.pipeline);
+
+        command_buffer.bind_descriptor_sets(
+            PipelineBindPoint::Graphics,
+            &self.pipeline_layout,
+            0, // starting with first set
+            &[params_desc_set],
+            None,
+        );
+
+        command_buffer.bind_vertex_buffers(0, &[self.vertex_buffer.inner()], &[0]);
+        command_buffer.draw_indirect(&self.indirect_buffer.inner(), 0, 1,
+                                     ::std::mem::size_of::<DrawIndirectCommand>() as u32);
+    }
+}
+
+fn build_pipeline(device: &Device,
+                  pipeline_cache: &PipelineCache,
+                  render_pass: RenderPass,
+                  viewport: Viewport,
+                  scissors: Rect2D,
+                  params_layout: DescriptorSetLayout,
+                  reversed_depth_buffer: bool,
+                  line_width: f32)
+                  -> Result<(PipelineLayout, Pipeline), Error>
+{
+    let vertex_shader = vertex_shader(device)?;
+    let fragment_shader = fragment_shader(device)?;
+
+    super::pipeline::create(
+        device, Some(pipeline_cache), viewport, scissors,
+        reversed_depth_buffer,
+        render_pass, vec![params_layout],
+        Some(vertex_shader), None, Some(fragment_shader), None,
+        Some(ColoredVertex::get_input_state_create_info()),
+        PrimitiveTopology::LineList,
+        false, // no primitive restart
+        line_width,
+        CullModeFlags::NONE, FrontFace::Clockwise,
+        false, // no depth clamp
+        DepthHandling::Some(true, false, None), // hidden by opaque geometry
+        None, // no depth bias
+        None, // no sample shading
+        vec![BlendMode::Alpha],
+        ColorComponentFlags::R | ColorComponentFlags::G | ColorComponentFlags::B,
+        vec![])
+}
+
+fn vertex_shader(device: &Device) -> Result<ShaderModule, Error>
+{
+    let bytes: &[u8] = glsl_vs!(r#"
+#version 450
+
+#extension GL_ARB_separate_shader_objects : enable
+#extension GL_ARB_shading_language_420pack : enable
+
+layout (set = 0, binding = 0) uniform UBO
+{
+  mat4 inv_projection;
+  mat4 inv_view;
+} params;
+
+layout (location = 0) in vec3 inPos;
+layout (location = 1) in vec3 inColor;
+layout (location = 2) in vec3 inNormal;
+
+layout (location = 0) out vec3 outColor;
+
+out gl_PerVertex
+{
+  vec4 gl_Position;
+};
+
+void main()
+{
+  // Params only carries the inverse matrices
+  vec4 view_pos = inverse(params.inv_view) * vec4(inPos, 1.0);
+  gl_Position = inverse(params.inv_projection) * view_pos;
+  outColor = inColor;
+}
+"#);
+
+    let create_info = ShaderModuleCreateInfo {
+        flags: ShaderModuleCreateFlags::empty(),
+        code: bytes.to_vec(),
+        chain: None,
+    };
+
+    Ok(device.create_shader_module(&create_info, None)?)
+}
+
+fn fragment_shader(device: &Device) -> Result<ShaderModule, Error>
+{
+    let bytes: &[u8] = glsl_fs!(r#"#version 450
+
+#extension GL_ARB_separate_shader_objects : enable
+#extension GL_ARB_shading_language_420pack : enable
+
+layout (location = 0) in vec3 inColor;
+
+layout (location = 0) out vec4 outFragColor;
+
+void main()
+{
+  outFragColor = vec4(inColor, 1.0);
+}
+"#);
+
+    let create_info = ShaderModuleCreateInfo {
+        flags: ShaderModuleCreateFlags::empty(),
+        code: bytes.to_vec(),
+        chain: None,
+    };
+
+    Ok(device.create_shader_module(&create_info, None)?)
+}
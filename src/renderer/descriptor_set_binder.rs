@@ -0,0 +1,106 @@
+
+use dacite::core::{CommandBuffer, PipelineLayout, PipelineBindPoint, DescriptorSet};
+
+// How often a descriptor set changes between draws, which by convention is
+// also its set number in plugin pipeline layouts:
+//
+//     layout(set = 0, ...)  per frame: Renderer::params_descriptor_set(),
+//                           shadow maps, environment, etc.
+//     layout(set = 1, ...)  per material: textures and material constants
+//     layout(set = 2, ...)  per object: e.g. a dynamic UBO of model matrices
+//
+// Pipeline layouts that share the lower sets' layouts are compatible for
+// those sets, so after switching pipelines only the sets that differ need
+// to be bound again (see DescriptorSetBinder).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SetFrequency {
+    PerFrame = 0,
+    PerMaterial = 1,
+    PerObject = 2,
+}
+
+impl SetFrequency {
+    pub fn set_number(&self) -> u32 {
+        *self as u32
+    }
+}
+
+// Binds descriptor sets following the SetFrequency convention, skipping
+// sets that are already bound, so that a plugin drawing many objects binds
+// its per-frame set once and its per-material sets only when the material
+// changes:
+//
+//     let mut binder = DescriptorSetBinder::new(command_buffer.clone(),
+//                                               self.pipeline_layout.clone());
+//     binder.bind(SetFrequency::PerFrame, &self.frame_set);
+//     for object in &self.objects {
+//         binder.bind(SetFrequency::PerMaterial, &object.material_set);
+//         binder.bind_dynamic(SetFrequency::PerObject, &self.object_set,
+//                             &[object.ubo_offset]);
+//         command_buffer.draw_indexed(...);
+//     }
+pub struct DescriptorSetBinder {
+    command_buffer: CommandBuffer,
+    pipeline_layout: PipelineLayout,
+    bound: [Option<DescriptorSet>; 3],
+}
+
+impl DescriptorSetBinder {
+    pub fn new(command_buffer: CommandBuffer, pipeline_layout: PipelineLayout)
+               -> DescriptorSetBinder
+    {
+        DescriptorSetBinder {
+            command_buffer: command_buffer,
+            pipeline_layout: pipeline_layout,
+            bound: [None, None, None],
+        }
+    }
+
+    // Switch to the layout of a newly bound pipeline.  Sets below
+    // `compatible` stay bound, which holds if both pipeline layouts were
+    // created with the same set layouts (and push constant ranges) up to
+    // that set; the rest must be bound again.
+    pub fn set_pipeline_layout(&mut self, pipeline_layout: PipelineLayout,
+                               compatible: SetFrequency)
+    {
+        self.pipeline_layout = pipeline_layout;
+        for bound in self.bound[compatible.set_number() as usize..].iter_mut() {
+            *bound = None;
+        }
+    }
+
+    // Bind `set` at `frequency` unless it is already bound there.  Returns
+    // whether it was bound.
+    pub fn bind(&mut self, frequency: SetFrequency, set: &DescriptorSet) -> bool
+    {
+        let index = frequency.set_number() as usize;
+        if self.bound[index].as_ref() == Some(set) {
+            return false;
+        }
+
+        self.command_buffer.bind_descriptor_sets(
+            PipelineBindPoint::Graphics,
+            &self.pipeline_layout,
+            frequency.set_number(),
+            &[set.clone()],
+            None,
+        );
+        self.bound[index] = Some(set.clone());
+        true
+    }
+
+    // Bind a set with dynamic uniform or storage buffers.  The offsets
+    // usually change per draw, so this always binds.
+    pub fn bind_dynamic(&mut self, frequency: SetFrequency, set: &DescriptorSet,
+                        dynamic_offsets: &[u32])
+    {
+        self.command_buffer.bind_descriptor_sets(
+            PipelineBindPoint::Graphics,
+            &self.pipeline_layout,
+            frequency.set_number(),
+            &[set.clone()],
+            Some(dynamic_offsets),
+        );
+        self.bound[frequency.set_number() as usize] = Some(set.clone());
+    }
+}
@@ -0,0 +1,122 @@
+
+use dacite::core::{DescriptorSet, DescriptorType, Sampler, ImageView, ImageLayout,
+                   Buffer, OptionalDeviceSize, WriteDescriptorSet,
+                   WriteDescriptorSetElements, DescriptorImageInfo,
+                   DescriptorBufferInfo};
+
+// Accumulates the writes to a descriptor set, one binding at a time, and
+// applies them all with a single DescriptorSet::update() in flush().
+//
+//     DescriptorSetWriter::new(descriptor_set.clone())
+//         .image(0, sampler.clone(), image_view.clone(),
+//                ImageLayout::ShaderReadOnlyOptimal)
+//         .buffer(1, DescriptorType::UniformBuffer, ubo.inner(), 0,
+//                 OptionalDeviceSize::WholeSize)
+//         .flush();
+pub struct DescriptorSetWriter {
+    descriptor_set: DescriptorSet,
+    writes: Vec<WriteDescriptorSet>,
+}
+
+impl DescriptorSetWriter {
+    pub fn new(descriptor_set: DescriptorSet) -> DescriptorSetWriter {
+        DescriptorSetWriter {
+            descriptor_set: descriptor_set,
+            writes: Vec::new(),
+        }
+    }
+
+    // Write a combined image sampler
+    pub fn image(self, binding: u32, sampler: Sampler, image_view: ImageView,
+                 layout: ImageLayout) -> DescriptorSetWriter
+    {
+        self.write(binding, DescriptorType::CombinedImageSampler,
+                   WriteDescriptorSetElements::ImageInfo(vec![
+                       DescriptorImageInfo {
+                           sampler: Some(sampler),
+                           image_view: Some(image_view),
+                           image_layout: layout,
+                       }
+                   ]))
+    }
+
+    // Write a sampler on its own, to be combined in the shader with
+    // separately bound images (GLSL: `uniform sampler`)
+    pub fn sampler(self, binding: u32, sampler: Sampler) -> DescriptorSetWriter
+    {
+        self.write(binding, DescriptorType::Sampler,
+                   WriteDescriptorSetElements::ImageInfo(vec![
+                       DescriptorImageInfo {
+                           sampler: Some(sampler),
+                           image_view: None,
+                           image_layout: ImageLayout::Undefined,
+                       }
+                   ]))
+    }
+
+    // Write a sampled image without a sampler (GLSL: `uniform texture2D`),
+    // sampled with a sampler from another binding, as in
+    // `texture(sampler2D(image, sampler), uv)`
+    pub fn sampled_image(self, binding: u32, image_view: ImageView,
+                         layout: ImageLayout) -> DescriptorSetWriter
+    {
+        self.write(binding, DescriptorType::SampledImage,
+                   WriteDescriptorSetElements::ImageInfo(vec![
+                       DescriptorImageInfo {
+                           sampler: None,
+                           image_view: Some(image_view),
+                           image_layout: layout,
+                       }
+                   ]))
+    }
+
+    // Write a storage image (which has no sampler)
+    pub fn storage_image(self, binding: u32, image_view: ImageView,
+                         layout: ImageLayout) -> DescriptorSetWriter
+    {
+        self.write(binding, DescriptorType::StorageImage,
+                   WriteDescriptorSetElements::ImageInfo(vec![
+                       DescriptorImageInfo {
+                           sampler: None,
+                           image_view: Some(image_view),
+                           image_layout: layout,
+                       }
+                   ]))
+    }
+
+    // Write a buffer descriptor (uniform or storage, dynamic or not)
+    pub fn buffer(self, binding: u32, descriptor_type: DescriptorType,
+                  buffer: Buffer, offset: u64, range: OptionalDeviceSize)
+                  -> DescriptorSetWriter
+    {
+        self.write(binding, descriptor_type,
+                   WriteDescriptorSetElements::BufferInfo(vec![
+                       DescriptorBufferInfo {
+                           buffer: buffer,
+                           offset: offset,
+                           range: range,
+                       }
+                   ]))
+    }
+
+    fn write(mut self, binding: u32, descriptor_type: DescriptorType,
+             elements: WriteDescriptorSetElements) -> DescriptorSetWriter
+    {
+        self.writes.push(WriteDescriptorSet {
+            dst_set: self.descriptor_set.clone(),
+            dst_binding: binding,
+            dst_array_element: 0, // only have 1 element
+            descriptor_type: descriptor_type,
+            elements: elements,
+            chain: None,
+        });
+        self
+    }
+
+    pub fn flush(self)
+    {
+        if !self.writes.is_empty() {
+            DescriptorSet::update(Some(&self.writes), None);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+
+use dacite::core::{Device, DescriptorPool, DescriptorSet, DescriptorSetLayout,
+                   DescriptorSetLayoutBinding, Sampler, ImageView, ImageLayout,
+                   DescriptorType, CommandBuffer, PipelineBindPoint, Pipeline,
+                   PipelineLayout, ShaderModuleCreateFlags, ShaderModuleCreateInfo,
+                   ShaderModule, BufferUsageFlags, PipelineCache};
+use crate::error::Error;
+use super::buffer::HostVisibleBuffer;
+use super::memory::{Memory, Lifetime};
+use super::target_data::TargetData;
+use super::descriptor_set_writer::DescriptorSetWriter;
+
+// Measures the average scene luminance for auto-exposure (see
+// Renderer::set_auto_exposure).  A single compute workgroup samples the
+// shading image on a 64x64 grid (bilinearly, so each sample averages a few
+// texels), reduces the log luminances in shared memory, and writes their
+// mean to a host visible buffer that is read back once the frame is done.
+// The result is the log2 of the geometric mean luminance, which is not
+// thrown off by a few very bright pixels the way the arithmetic mean is.
+pub struct ExposureGfx {
+    pipeline: Pipeline,
+    pipeline_layout: PipelineLayout,
+    descriptor_set: DescriptorSet,
+    #[allow(dead_code)] // this must remain alive
+    desc_layout: DescriptorSetLayout,
+    shading_image_view: ImageView,
+    sampler: Sampler,
+    result_buffer: HostVisibleBuffer,
+}
+
+impl ExposureGfx {
+    pub fn new(device: &Device,
+               pipeline_cache: &PipelineCache,
+               memory: &mut Memory,
+               descriptor_pool: DescriptorPool,
+               target_data: &TargetData)
+               -> Result<ExposureGfx, Error>
+    {
+        let sampler = {
+            use dacite::core::{SamplerCreateInfo, SamplerMipmapMode, SamplerAddressMode,
+                               BorderColor, Filter, CompareOp};
+
+            device.create_sampler(&SamplerCreateInfo {
+                flags: Default::default(),
+                mag_filter: Filter::Linear,
+                min_filter: Filter::Linear,
+                mipmap_mode: SamplerMipmapMode::Nearest,
+                address_mode_u: SamplerAddressMode::ClampToEdge,
+                address_mode_v: SamplerAddressMode::ClampToEdge,
+                address_mode_w: SamplerAddressMode::ClampToEdge,
+                mip_lod_bias: 0.0,
+                anisotropy_enable: false,
+                max_anisotropy: 1.0,
+                compare_enable: false,
+                compare_op: CompareOp::Never,
+                min_lod: 0.0,
+                max_lod: 0.0,
+                border_color: BorderColor::FloatOpaqueBlack,
+                unnormalized_coordinates: false,
+                chain: None
+            }, None)?
+        };
+
+        let shading_image_view = target_data.shading_image.
+            get_image_view(device)?;
+
+        let mut result_buffer = HostVisibleBuffer::new::<f32>(
+            device, memory, 1,
+            BufferUsageFlags::STORAGE_BUFFER,
+            Lifetime::Permanent,
+            "Average Log Luminance")?;
+        result_buffer.write_one(&0.0_f32, None)?;
+
+        let desc_layout = {
+            use dacite::core::{DescriptorSetLayoutCreateInfo, ShaderStageFlags};
+
+            let create_info = DescriptorSetLayoutCreateInfo {
+                flags: Default::default(),
+                bindings: vec![
+                    DescriptorSetLayoutBinding {
+                        binding: 0,
+                        descriptor_type: DescriptorType::CombinedImageSampler,
+                        descriptor_count: 1,
+                        stage_flags: ShaderStageFlags::COMPUTE,
+                        immutable_samplers: vec![],
+                    },
+                    DescriptorSetLayoutBinding {
+                        binding: 1,
+                        descriptor_type: DescriptorType::StorageBuffer,
+                        descriptor_count: 1,
+                        stage_flags: ShaderStageFlags::COMPUTE,
+                        immutable_samplers: vec![],
+                    },
+                ],
+                chain: None,
+            };
+            device.create_descriptor_set_layout(&create_info, None)?
+        };
+
+        let descriptor_set = {
+            use dacite::core::DescriptorSetAllocateInfo;
+
+            let alloc_info = DescriptorSetAllocateInfo {
+                descriptor_pool: descriptor_pool.clone(),
+                set_layouts: vec![desc_layout.clone()],
+                chain: None,
+            };
+
+            let mut descriptor_sets = DescriptorPool::allocate_descriptor_sets(&alloc_info)?;
+            descriptor_sets.pop().unwrap()
+        };
+
+        let pipeline_layout = {
+            use dacite::core::{PipelineLayoutCreateInfo, PipelineLayoutCreateFlags};
+
+            device.create_pipeline_layout(&PipelineLayoutCreateInfo {
+                flags: PipelineLayoutCreateFlags::empty(),
+                set_layouts: vec![desc_layout.clone()],
+                push_constant_ranges: vec![],
+                chain: None,
+            }, None)?
+        };
+
+        let pipeline = {
+            use dacite::core::{ComputePipelineCreateInfo, PipelineCreateFlags,
+                               PipelineShaderStageCreateInfo,
+                               PipelineShaderStageCreateFlags, ShaderStageFlagBits};
+
+            let create_infos = vec![ComputePipelineCreateInfo {
+                flags: PipelineCreateFlags::empty(),
+                stage: PipelineShaderStageCreateInfo {
+                    flags: PipelineShaderStageCreateFlags::empty(),
+                    stage: ShaderStageFlagBits::Compute,
+                    module: compute_shader(device)?,
+                    name: "main".to_owned(),
+                    specialization_info: None,
+                    chain: None,
+                },
+                layout: pipeline_layout.clone(),
+                base_pipeline: None,
+                base_pipeline_index: None,
+                chain: None,
+            }];
+            let pipelines = device.create_compute_pipelines(
+                Some(pipeline_cache), &create_infos, None)
+                .map_err(|(e, _)| e)?;
+            pipelines[0].clone()
+        };
+
+        let mut exposure_gfx = ExposureGfx {
+            pipeline: pipeline,
+            pipeline_layout: pipeline_layout,
+            descriptor_set: descriptor_set,
+            desc_layout: desc_layout,
+            shading_image_view: shading_image_view,
+            sampler: sampler,
+            result_buffer: result_buffer,
+        };
+
+        exposure_gfx.write();
+
+        Ok(exposure_gfx)
+    }
+
+    pub fn rebuild(&mut self, device: &Device, target_data: &TargetData)
+        -> Result<(), Error>
+    {
+        self.shading_image_view = target_data.shading_image.
+            get_image_view(device)?;
+
+        self.write();
+
+        Ok(())
+    }
+
+    fn write(&mut self)
+    {
+        use dacite::core::OptionalDeviceSize;
+
+        DescriptorSetWriter::new(self.descriptor_set.clone())
+            .image(0, self.sampler.clone(), self.shading_image_view.clone(),
+                   ImageLayout::ShaderReadOnlyOptimal)
+            .buffer(1, DescriptorType::StorageBuffer, self.result_buffer.inner(), 0,
+                    OptionalDeviceSize::Size(::std::mem::size_of::<f32>() as u64))
+            .flush();
+    }
+
+    // Record the measurement.  This must be outside of any render pass,
+    // with the shading image in ShaderReadOnlyOptimal layout and visible to
+    // compute shaders.
+    pub fn record(&self, command_buffer: CommandBuffer)
+    {
+        use dacite::core::{BufferMemoryBarrier, AccessFlags, PipelineStageFlags,
+                           DependencyFlags, QueueFamilyIndex, OptionalDeviceSize};
+
+        command_buffer.bind_pipeline(PipelineBindPoint::Compute, &self.pipeline);
+
+        command_buffer.bind_descriptor_sets(
+            PipelineBindPoint::Compute,
+            &self.pipeline_layout,
+            0, // starting with first set
+            &[self.descriptor_set.clone()],
+            None,
+        );
+
+        command_buffer.dispatch(1, 1, 1);
+
+        // Make the result visible to the host once the frame's fence signals
+        let barrier = BufferMemoryBarrier {
+            src_access_mask: AccessFlags::SHADER_WRITE,
+            dst_access_mask: AccessFlags::HOST_READ,
+            src_queue_family_index: QueueFamilyIndex::Ignored,
+            dst_queue_family_index: QueueFamilyIndex::Ignored,
+            buffer: self.result_buffer.inner(),
+            offset: 0,
+            size: OptionalDeviceSize::WholeSize,
+            chain: None,
+        };
+        command_buffer.pipeline_barrier(
+            PipelineStageFlags::COMPUTE_SHADER,
+            PipelineStageFlags::HOST,
+            DependencyFlags::empty(),
+            None, // memory barriers
+            Some(&[barrier]), // buffer memory barriers
+            None); // image memory barriers
+    }
+
+    // log2 of the average luminance measured by the last completed frame
+    pub fn average_log_luminance(&self) -> f32
+    {
+        *self.result_buffer.as_ptr::<f32>().unwrap()
+    }
+}
+
+fn compute_shader(device: &Device) -> Result<ShaderModule, Error>
+{
+    let bytes: &[u8] = glsl_cs!(r#"#version 450
+
+#extension GL_ARB_separate_shader_objects : enable
+#extension GL_ARB_shading_language_420pack : enable
+
+layout (local_size_x = 16, local_size_y = 16) in;
+
+layout (binding = 0) uniform sampler2D shadingTex;
+
+layout (binding = 1) buffer Result
+{
+  float average_log_luminance;
+} result;
+
+shared float partial[256];
+
+void main()
+{
+  // Each invocation covers a 4x4 block of the 64x64 sample grid
+  float sum = 0.0;
+  for (uint y = 0; y < 4; y++) {
+    for (uint x = 0; x < 4; x++) {
+      vec2 cell = vec2(gl_LocalInvocationID.xy * 4 + uvec2(x, y));
+      vec3 color = textureLod(shadingTex, (cell + 0.5) / 64.0, 0.0).rgb;
+      float lum = dot(vec3(0.2126729, 0.7151522, 0.0721750), color);
+      // Keep black (e.g. empty space) from dragging the mean to -infinity
+      sum += log2(max(lum, 0.0001));
+    }
+  }
+
+  uint i = gl_LocalInvocationIndex;
+  partial[i] = sum / 16.0;
+  memoryBarrierShared();
+  barrier();
+
+  for (uint stride = 128; stride > 0; stride >>= 1) {
+    if (i < stride) {
+      partial[i] += partial[i + stride];
+    }
+    memoryBarrierShared();
+    barrier();
+  }
+
+  if (i == 0) {
+    result.average_log_luminance = partial[0] / 256.0;
+  }
+}
+"#);
+
+    let create_info = ShaderModuleCreateInfo {
+        flags: ShaderModuleCreateFlags::empty(),
+        code: bytes.to_vec(),
+        chain: None,
+    };
+
+    Ok(device.create_shader_module(&create_info, None)?)
+}
@@ -1,14 +1,96 @@
 
+use std::sync::{Arc, Mutex};
 use crate::error::Error;
 use dacite::core::{Image, Format, ImageUsageFlags, Device, ImageView,
                    Extent3D, ImageLayout, ImageTiling, AccessFlags,
                    ImageSubresourceRange, Buffer, PipelineStageFlags,
                    ComponentMapping, AttachmentDescription,
                    AttachmentLoadOp, AttachmentStoreOp, ClearValue,
-                   CommandBuffer, DeviceMemory};
+                   CommandBuffer, DeviceMemory, Offset3D, BufferImageCopy,
+                   ImageSubresourceLayers};
 use super::memory::{Memory, Block, Lifetime, Linearity};
 use super::commander::Commander;
 
+// The extent of mip `level` of an image of `extent`.  Each dimension halves,
+// rounding down, but never below 1, so those of a non power of two image
+// don't halve evenly: the mips of a 257x129 image are 128x64, 64x32, ...
+// 4x2, 2x1, then 1x1.
+pub fn mip_extent(extent: Extent3D, level: u32) -> Extent3D
+{
+    use std::cmp::max;
+
+    Extent3D {
+        width: max(1, extent.width >> level),
+        height: max(1, extent.height >> level),
+        depth: max(1, extent.depth >> level),
+    }
+}
+
+// The bytes of mip `level` of one layer of an image of `extent` and
+// `format`, tightly packed.  Compressed levels are rounded up to whole
+// blocks (the 2x1 and 1x1 tail mips of a BC1 texture are an 8 byte block
+// each); the texel size of other formats is found from
+// `main_texture_size`, the size of mip 0.
+pub fn mip_size(extent: Extent3D, format: Format, main_texture_size: u32, level: u32)
+                -> u32
+{
+    let mip = mip_extent(extent, level);
+    match crate::format::compressed_block(format) {
+        Some((block_width, block_height, block_size)) => {
+            let blocks_wide = (mip.width + block_width - 1) / block_width;
+            let blocks_high = (mip.height + block_height - 1) / block_height;
+            blocks_wide * blocks_high * mip.depth * block_size
+        },
+        None => {
+            let texels = extent.width * extent.height * extent.depth;
+            let texel_size = main_texture_size / texels;
+            mip.width * mip.height * mip.depth * texel_size
+        }
+    }
+}
+
+// One copy per mip level of an image of `extent` and `format` into the
+// `layers` (whose mip_level is ignored), with the mips packed from
+// `buffer_offset`.  `main_texture_size` is the size of mip 0.
+fn mip_copy_regions(
+    extent: Extent3D,
+    format: Format,
+    mip_levels: u32,
+    layers: &ImageSubresourceLayers,
+    mut buffer_offset: u32,
+    main_texture_size: u32)
+    -> Vec<BufferImageCopy>
+{
+    let mut buffer_copy_regions = Vec::new();
+    for mip in 0..mip_levels {
+        // The level's own extent, not rounded up to whole blocks: a copy
+        // may end part way through a block at the edge of the image
+        let image_extent = mip_extent(extent, mip);
+        buffer_copy_regions.push( BufferImageCopy {
+            buffer_offset: buffer_offset as u64,
+            // 0 means 'tightly packed' according to image_extent,
+            buffer_row_length: 0,
+            // 0 means 'tightly packed' according to image_extent,
+            buffer_image_height: 0,
+            image_subresource: ImageSubresourceLayers {
+                aspect_mask: layers.aspect_mask,
+                mip_level: mip,
+                base_array_layer: layers.base_array_layer,
+                layer_count: layers.layer_count,
+            },
+            image_offset: Offset3D {
+                x: 0,
+                y: 0,
+                z: 0
+            },
+            image_extent: image_extent,
+        });
+
+        buffer_offset += mip_size(extent, format, main_texture_size, mip);
+    }
+    buffer_copy_regions
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ImageWrapType {
     Depth,
@@ -37,6 +119,11 @@ pub struct ImageWrap {
     pub block: Option<Block>,
     pub solo: Option<DeviceMemory>,
     pub swizzle: ComponentMapping,
+    // The image view is created on first use, and shared by clones.
+    pub view_cache: Arc<Mutex<Option<ImageView>>>,
+    // The layout the last recorded transition left the image in (see
+    // track_layout()), shared by clones.  None if unknown.
+    pub tracked_layout: Arc<Mutex<Option<ImageLayout>>>,
 }
 
 impl ImageWrap {
@@ -133,11 +220,52 @@ impl ImageWrap {
             size: memory_requirements.size,
             block: block,
             solo: solo,
-            swizzle: swizzle
+            swizzle: swizzle,
+            view_cache: Arc::new(Mutex::new(None)),
+            tracked_layout: Arc::new(Mutex::new(Some(initial_layout))),
         })
     }
 
+    // Note a layout transition, in recording order.  In debug builds this
+    // panics if `src_layout` is not the layout the previous transition left
+    // the image in, which would otherwise show up only as corruption or
+    // validation errors in some configurations.  Transitioning from
+    // Undefined (discarding the contents) is always accepted.
+    //
+    // Every transition must be noted, including raw ImageMemoryBarriers
+    // (transition_layout() notes its own).  Render passes whose attachments
+    // change layout are not seen, so they should only do so from Undefined.
+    // Layouts are tracked per image, not per subresource.
+    pub fn track_layout(&self, src_layout: ImageLayout, dst_layout: ImageLayout)
+    {
+        let mut tracked = self.tracked_layout.lock().unwrap();
+        if cfg!(debug_assertions) && src_layout != ImageLayout::Undefined {
+            if let Some(current) = *tracked {
+                if current != src_layout {
+                    panic!("{:?} image ({:?}) transitioned from {:?} to {:?}, but it is in {:?}",
+                           self.image_wrap_type, self.format,
+                           src_layout, dst_layout, current);
+                }
+            }
+        }
+        *tracked = Some(dst_layout);
+    }
+
+    // Get the image view, creating it the first time. Since the image never
+    // changes (images are rebuilt as new ImageWraps), the view is valid for
+    // the life of this ImageWrap.
     pub fn get_image_view(&self, device: &Device) -> Result<ImageView, Error>
+    {
+        let mut cache = self.view_cache.lock().unwrap();
+        if let Some(ref view) = *cache {
+            return Ok(view.clone());
+        }
+        let view = self.create_image_view(device)?;
+        *cache = Some(view.clone());
+        Ok(view)
+    }
+
+    fn create_image_view(&self, device: &Device) -> Result<ImageView, Error>
     {
         use dacite::core::{ImageViewCreateInfo, ImageViewType,
                            ImageAspectFlags,
@@ -172,6 +300,48 @@ impl ImageWrap {
         Ok(device.create_image_view(&create_info, None)?)
     }
 
+    // Create a 2D image view of a single array layer (e.g. one face of a
+    // cubemap), for use as a framebuffer attachment. These are not cached;
+    // the caller owns the view.
+    pub fn get_layer_image_view(&self, device: &Device, layer: u32)
+                                -> Result<ImageView, Error>
+    {
+        use dacite::core::{ImageViewCreateInfo, ImageViewType,
+                           ImageAspectFlags,
+                           OptionalMipLevels, OptionalArrayLayers};
+
+        let layers = match self.image_wrap_type {
+            ImageWrapType::Cubemap => 6,
+            _ => 1,
+        };
+        if layer >= layers {
+            return Err(Error::General(
+                format!("Image layer {} out of range ({} layers)", layer, layers)));
+        }
+
+        let create_info = ImageViewCreateInfo {
+            flags: Default::default(),
+            image: self.image.clone(),
+            view_type: ImageViewType::Type2D,
+            format: self.format,
+            components: self.swizzle,
+            subresource_range: ImageSubresourceRange {
+                aspect_mask: if self.image_wrap_type == ImageWrapType::Depth {
+                    ImageAspectFlags::DEPTH
+                } else {
+                    ImageAspectFlags::COLOR
+                },
+                base_mip_level: 0,
+                level_count: OptionalMipLevels::MipLevels(1),
+                base_array_layer: layer,
+                layer_count: OptionalArrayLayers::ArrayLayers(1),
+            },
+            chain: None,
+        };
+
+        Ok(device.create_image_view(&create_info, None)?)
+    }
+
     pub fn transition_layout_now(&mut self,
                                  device: &Device,
                                  src_layout: ImageLayout, dst_layout: ImageLayout,
@@ -181,49 +351,16 @@ impl ImageWrap {
                                  commander: &Commander)
                                  -> Result<(), Error>
     {
-        use dacite::core::{CommandBufferBeginInfo, CommandBufferUsageFlags,
-                           CommandBufferResetFlags,
-                           Fence, FenceCreateInfo, FenceCreateFlags,
-                           SubmitInfo, Timeout};
-
-        commander.gfx_command_buffers[0].reset(CommandBufferResetFlags::RELEASE_RESOURCES)?;
-
-        let command_buffer_begin_info = CommandBufferBeginInfo {
-            flags: CommandBufferUsageFlags::ONE_TIME_SUBMIT,
-            inheritance_info: None,
-            chain: None
-        };
-        commander.gfx_command_buffers[0].begin(&command_buffer_begin_info)?;
+        let command_buffer = commander.begin_oneshot()?;
 
         self.transition_layout(
-            commander.gfx_command_buffers[0].clone(),
+            command_buffer.clone(),
             src_layout, dst_layout,
             src_access, dst_access,
             src_stage, dst_stage,
             subresource_range)?;
 
-        commander.gfx_command_buffers[0].end()?;
-
-        let fence = {
-            let create_info = FenceCreateInfo {
-                flags: FenceCreateFlags::empty(),
-                chain: None
-            };
-            device.create_fence(&create_info, None)?
-        };
-
-        let submit_info = SubmitInfo {
-            wait_semaphores: vec![],
-            wait_dst_stage_mask: vec![PipelineStageFlags::BOTTOM_OF_PIPE],
-            command_buffers: vec![commander.gfx_command_buffers[0].clone()],
-            signal_semaphores: vec![],
-            chain: None
-        };
-        Fence::reset_fences(&[fence.clone()])?;
-        commander.gfx_queue.submit( Some(&[submit_info]), Some(&fence) )?;
-        Fence::wait_for_fences(&[fence], true, Timeout::Infinite)?;
-
-        Ok(())
+        commander.submit_oneshot(device, command_buffer)
     }
 
     pub fn transition_layout(&mut self,
@@ -237,6 +374,8 @@ impl ImageWrap {
         use dacite::core::{ImageMemoryBarrier, QueueFamilyIndex,
                            DependencyFlags};
 
+        self.track_layout(src_layout, dst_layout);
+
         let layout_transition_barrier = ImageMemoryBarrier {
             src_access_mask: src_access,
             dst_access_mask: dst_access,
@@ -260,15 +399,84 @@ impl ImageWrap {
         Ok(())
     }
 
-    // Currently this copies the entire buffer to an entire image;
-    // we could improve it by coping regions/extents.
+    // This copies the entire buffer to an entire image; see
+    // copy_region_from_buffer() for updating part of an existing image.
     pub fn copy_in_from_buffer(
         &mut self,
         device: &Device,
         commander: &Commander,
         buffer: &Buffer,
-        main_texture_size: u32,
-        min_mipmap_size: u32)
+        main_texture_size: u32)
+        -> Result<(), Error>
+    {
+        let layers = match self.image_wrap_type {
+            ImageWrapType::Cubemap => 6,
+            _ => 1,
+        };
+        let regions = self.mip_copy_regions(0, 0, layers, main_texture_size);
+
+        self.copy_in_regions_from_buffer(device, commander, buffer, &regions)
+    }
+
+    // This copies a buffer holding each face in turn, each with its mip
+    // chain (i.e. six 2D textures back to back), to an entire cubemap.
+    // `face_size` is the size of each face with all of its mips.
+    pub fn copy_in_faces_from_buffer(
+        &mut self,
+        device: &Device,
+        commander: &Commander,
+        buffer: &Buffer,
+        face_size: u32,
+        main_texture_size: u32)
+        -> Result<(), Error>
+    {
+        if self.image_wrap_type != ImageWrapType::Cubemap {
+            return Err(Error::General("Only cubemaps have faces".to_owned()));
+        }
+
+        let mut regions = Vec::new();
+        for face in 0..6 {
+            regions.extend(self.mip_copy_regions(
+                face * face_size, face, 1, main_texture_size));
+        }
+
+        self.copy_in_regions_from_buffer(device, commander, buffer, &regions)
+    }
+
+    // One copy per mip level of `layer_count` layers starting at
+    // `base_array_layer`, with the mips packed from `buffer_offset`.
+    // `main_texture_size` is the size of mip 0 (see mip_size).
+    fn mip_copy_regions(
+        &self,
+        buffer_offset: u32,
+        base_array_layer: u32,
+        layer_count: u32,
+        main_texture_size: u32)
+        -> Vec<BufferImageCopy>
+    {
+        use dacite::core::ImageAspectFlags;
+
+        let layers = ImageSubresourceLayers {
+            aspect_mask: if self.image_wrap_type == ImageWrapType::Depth {
+                ImageAspectFlags::DEPTH
+            } else {
+                ImageAspectFlags::COLOR
+            },
+            mip_level: 0,
+            base_array_layer: base_array_layer,
+            layer_count: layer_count,
+        };
+
+        mip_copy_regions(self.extent, self.format, self.mip_levels, &layers,
+                         buffer_offset, main_texture_size)
+    }
+
+    fn copy_in_regions_from_buffer(
+        &mut self,
+        device: &Device,
+        commander: &Commander,
+        buffer: &Buffer,
+        regions: &[BufferImageCopy])
         -> Result<(), Error>
     {
         use dacite::core::{CommandBufferBeginInfo, CommandBufferUsageFlags,
@@ -277,8 +485,7 @@ impl ImageWrap {
                            DependencyFlags,
                            FenceCreateInfo, FenceCreateFlags,
                            SubmitInfo, Timeout, ImageAspectFlags, OptionalMipLevels,
-                           OptionalArrayLayers, BufferImageCopy,
-                           ImageSubresourceLayers, Offset3D};
+                           OptionalArrayLayers};
 
         commander.xfr_command_buffer.reset(CommandBufferResetFlags::RELEASE_RESOURCES)?;
 
@@ -313,6 +520,7 @@ impl ImageWrap {
             },
             chain: None,
         };
+        self.track_layout(ImageLayout::Undefined, ImageLayout::TransferDstOptimal);
         commander.xfr_command_buffer.pipeline_barrier(
             PipelineStageFlags::TRANSFER,
             PipelineStageFlags::TRANSFER,
@@ -321,52 +529,11 @@ impl ImageWrap {
             None,
             Some(&[image_barrier]));
 
-        let mut buffer_copy_regions = Vec::new();
-        let mut buffer_offset: u32 = 0;
-        let mut thissize = main_texture_size;
-        let mut image_extent = self.extent;
-        for mip in 0..self.mip_levels {
-            buffer_copy_regions.push( BufferImageCopy {
-                buffer_offset: buffer_offset as u64,
-                // 0 means 'tightly packed' according to image_extent,
-                buffer_row_length: 0,
-                // 0 means 'tightly packed' according to image_extent,
-                buffer_image_height: 0,
-                image_subresource: ImageSubresourceLayers {
-                    aspect_mask: if self.image_wrap_type == ImageWrapType::Depth {
-                        ImageAspectFlags::DEPTH
-                    } else {
-                        ImageAspectFlags::COLOR
-                    },
-                    mip_level: mip,
-                    base_array_layer: 0,
-                    layer_count: match self.image_wrap_type {
-                        ImageWrapType::Cubemap => 6,
-                        _ => 1,
-                    },
-                },
-                image_offset: Offset3D {
-                    x: 0,
-                    y: 0,
-                    z: 0
-                },
-                image_extent: image_extent,
-            });
-
-            buffer_offset += thissize;
-            thissize /= 4;
-            if thissize < min_mipmap_size {
-                thissize = min_mipmap_size;
-            }
-            image_extent.width /= 2;
-            image_extent.height /= 2;
-        }
-
         commander.xfr_command_buffer.copy_buffer_to_image(
             buffer, //src_buffer
             &self.image, // dst_image
             ImageLayout::TransferDstOptimal, // dst_image_layout
-            &*buffer_copy_regions, // regions
+            regions, // regions
         );
 
         commander.xfr_command_buffer.end()?;
@@ -388,11 +555,137 @@ impl ImageWrap {
             signal_semaphores: vec![],
             chain: None,
         };
-        commander.xfr_queue.submit(Some(&[submit_info]), Some(&fence))?;
+        {
+            let _queues = commander.lock_queues();
+            commander.xfr_queue.submit(Some(&[submit_info]), Some(&fence))?;
+        }
         let _success = fence.wait_for(Timeout::Infinite)?;
         Ok(())
     }
 
+    // Copies a tightly packed region from `buffer` (starting at
+    // `buffer_offset`) into a sub-rectangle of one mip level of one array
+    // layer of this image.  The image must already be in
+    // ShaderReadOnlyOptimal (e.g. a previously uploaded texture); only the
+    // targeted subresource is transitioned to TransferDstOptimal and back,
+    // so the rest of the image is preserved.  For compressed formats the
+    // region must be whole blocks, except where it reaches the edge of the
+    // mip level.  This blocks until the copy completes.
+    pub fn copy_region_from_buffer(
+        &mut self,
+        device: &Device,
+        commander: &Commander,
+        buffer: &Buffer,
+        buffer_offset: u64,
+        image_offset: Offset3D,
+        extent: Extent3D,
+        mip_level: u32,
+        array_layer: u32)
+        -> Result<(), Error>
+    {
+        use dacite::core::{ImageAspectFlags, OptionalMipLevels, OptionalArrayLayers};
+
+        if mip_level >= self.mip_levels {
+            return Err(Error::General(
+                format!("Mip level {} out of range ({} levels)",
+                        mip_level, self.mip_levels)));
+        }
+        let layers = match self.image_wrap_type {
+            ImageWrapType::Cubemap => 6,
+            _ => 1,
+        };
+        if array_layer >= layers {
+            return Err(Error::General(
+                format!("Array layer {} out of range ({} layers)",
+                        array_layer, layers)));
+        }
+        let mip = mip_extent(self.extent, mip_level);
+        let (mip_width, mip_height) = (mip.width, mip.height);
+        let out_of_range = || Error::General(
+            format!("Copy region {:?} {:?} exceeds mip level {} extent {}x{}",
+                    image_offset, extent, mip_level, mip_width, mip_height));
+        if image_offset.x < 0 || image_offset.y < 0 || image_offset.z != 0
+            || extent.depth != 1
+        {
+            return Err(out_of_range());
+        }
+        let (end_x, end_y) = match ((image_offset.x as u32).checked_add(extent.width),
+                                    (image_offset.y as u32).checked_add(extent.height)) {
+            (Some(end_x), Some(end_y)) => (end_x, end_y),
+            _ => return Err(out_of_range()),
+        };
+        if end_x > mip_width || end_y > mip_height {
+            return Err(out_of_range());
+        }
+        if let Some((block_width, block_height, _)) = crate::format::compressed_block(self.format) {
+            if image_offset.x as u32 % block_width != 0
+                || image_offset.y as u32 % block_height != 0
+                || (extent.width % block_width != 0 && end_x != mip_width)
+                || (extent.height % block_height != 0 && end_y != mip_height)
+            {
+                return Err(Error::General(
+                    format!("Copy region {:?} {:?} is not aligned to the {}x{} blocks of {:?}",
+                            image_offset, extent, block_width, block_height, self.format)));
+            }
+        }
+
+        let aspect_mask = if self.image_wrap_type == ImageWrapType::Depth {
+            ImageAspectFlags::DEPTH
+        } else {
+            ImageAspectFlags::COLOR
+        };
+        let subresource_range = ImageSubresourceRange {
+            aspect_mask: aspect_mask,
+            base_mip_level: mip_level,
+            level_count: OptionalMipLevels::MipLevels(1),
+            base_array_layer: array_layer,
+            layer_count: OptionalArrayLayers::ArrayLayers(1),
+        };
+
+        let command_buffer = commander.begin_oneshot()?;
+
+        // Keep the existing contents: transition from the layout it is
+        // sampled in rather than from Undefined
+        self.transition_layout(
+            command_buffer.clone(),
+            ImageLayout::ShaderReadOnlyOptimal, ImageLayout::TransferDstOptimal,
+            AccessFlags::SHADER_READ, AccessFlags::TRANSFER_WRITE,
+            PipelineStageFlags::TOP_OF_PIPE, PipelineStageFlags::TRANSFER,
+            subresource_range.clone())?;
+
+        let region = BufferImageCopy {
+            buffer_offset: buffer_offset,
+            // 0 means 'tightly packed' according to image_extent,
+            buffer_row_length: 0,
+            // 0 means 'tightly packed' according to image_extent,
+            buffer_image_height: 0,
+            image_subresource: ImageSubresourceLayers {
+                aspect_mask: aspect_mask,
+                mip_level: mip_level,
+                base_array_layer: array_layer,
+                layer_count: 1,
+            },
+            image_offset: image_offset,
+            image_extent: extent,
+        };
+
+        command_buffer.copy_buffer_to_image(
+            buffer, //src_buffer
+            &self.image, // dst_image
+            ImageLayout::TransferDstOptimal, // dst_image_layout
+            &[region], // regions
+        );
+
+        self.transition_layout(
+            command_buffer.clone(),
+            ImageLayout::TransferDstOptimal, ImageLayout::ShaderReadOnlyOptimal,
+            AccessFlags::TRANSFER_WRITE, AccessFlags::SHADER_READ,
+            PipelineStageFlags::TRANSFER, PipelineStageFlags::BOTTOM_OF_PIPE,
+            subresource_range)?;
+
+        commander.submit_oneshot(device, command_buffer)
+    }
+
     pub fn get_attachment_description(&self,
                                       load_op: AttachmentLoadOp,
                                       store_op: AttachmentStoreOp,
@@ -432,3 +725,71 @@ impl ImageWrap {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use dacite::core::{Extent3D, Format, ImageAspectFlags, ImageSubresourceLayers};
+    use super::{mip_size, mip_copy_regions};
+
+    const EXTENT: Extent3D = Extent3D { width: 257, height: 129, depth: 1 };
+
+    fn layers() -> ImageSubresourceLayers {
+        ImageSubresourceLayers {
+            aspect_mask: ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        }
+    }
+
+    #[test]
+    fn bc1_mip_chain() {
+        // (width, height, offset, size) of each level
+        let expected = [(257, 129, 0, 17160),
+                        (128, 64, 17160, 4096),
+                        (64, 32, 21256, 1024),
+                        (32, 16, 22280, 256),
+                        (16, 8, 22536, 64),
+                        (8, 4, 22600, 16),
+                        (4, 2, 22616, 8),
+                        (2, 1, 22624, 8),
+                        (1, 1, 22632, 8)];
+        let format = Format::BC1_RGB_UNorm_Block;
+        let regions = mip_copy_regions(EXTENT, format, 9, &layers(), 0, 17160);
+        assert_eq!(regions.len(), expected.len());
+        for (level, (region, &(width, height, offset, size)))
+            in regions.iter().zip(expected.iter()).enumerate()
+        {
+            assert_eq!(region.image_subresource.mip_level, level as u32);
+            assert_eq!(region.image_extent.width, width);
+            assert_eq!(region.image_extent.height, height);
+            assert_eq!(region.image_extent.depth, 1);
+            assert_eq!(region.buffer_offset, offset);
+            assert_eq!(mip_size(EXTENT, format, 17160, level as u32), size);
+        }
+    }
+
+    #[test]
+    fn bc1_tail_mips_are_one_block() {
+        let format = Format::BC1_RGBA_sRGB_Block;
+        for level in 6..9 {
+            assert_eq!(mip_size(EXTENT, format, 17160, level), 8);
+        }
+    }
+
+    #[test]
+    fn uncompressed_mip_chain() {
+        let format = Format::R8G8B8A8_UNorm;
+        let main_texture_size = 257 * 129 * 4;
+        let regions = mip_copy_regions(EXTENT, format, 9, &layers(), 64, main_texture_size);
+        let mut offset = 64;
+        for (level, region) in regions.iter().enumerate() {
+            assert_eq!(region.buffer_offset, offset as u64);
+            let size = mip_size(EXTENT, format, main_texture_size, level as u32);
+            assert_eq!(size, region.image_extent.width * region.image_extent.height * 4);
+            offset += size;
+        }
+        assert_eq!(regions[8].image_extent.width, 1);
+        assert_eq!(regions[8].image_extent.height, 1);
+    }
+}
@@ -23,6 +23,13 @@ pub struct Block {
     pub dirty: Arc<AtomicBool>,
 }
 
+// The pointer is into a chunk's persistently mapped memory, which lives as
+// long as the chunk's DeviceMemory (kept alive by `memory`).  Blocks (and so
+// buffers, images and meshes) may be moved to other threads, e.g. from a
+// ResourceLoader's thread to the render thread; the freelist and dirty flag
+// are already thread safe.
+unsafe impl Send for Block {}
+
 impl Drop for Block {
     fn drop(&mut self) {
         // Mark our offset in the freelist before we drop
@@ -32,6 +39,27 @@ impl Drop for Block {
 }
 
 impl Block {
+    // Make device writes to this block (e.g. a copy into a readback
+    // buffer) visible to the host before reading it.  Coherent memory needs
+    // nothing.
+    pub fn invalidate(&self) -> Result<(), Error> {
+        use dacite::core::{MappedMemoryRange, OptionalDeviceSize};
+
+        if self.is_coherent || self.ptr.is_none() {
+            return Ok(());
+        }
+
+        // The whole chunk (which is all mapped), as a range of just this
+        // block would have to be aligned to non_coherent_atom_size
+        DeviceMemory::invalidate(&[MappedMemoryRange {
+            memory: self.memory.clone(),
+            offset: 0,
+            size: OptionalDeviceSize::WholeSize,
+            chain: None,
+        }])?;
+        Ok(())
+    }
+
     pub fn as_ptr<T>(&self) -> Option<&mut T> {
         // mark dirty, under the presumption that the caller will write
         self.dirty.store(true, Ordering::Relaxed);
@@ -32,6 +32,20 @@ impl BlockInfo {
     }
 }
 
+// How one chunk's space is split up (see Memory::fragmentation_reports).
+// Temporary blocks are allocated upwards from the bottom of the chunk and
+// permanent blocks downwards from the top; the free space is what lies
+// between, including holes left by dropped temporary blocks.
+#[derive(Debug, Clone, Copy)]
+pub struct FragmentationReport {
+    pub memory_type_index: u32,
+    pub linearity: Linearity,
+    pub chunk: usize,
+    pub block_count: usize, // live temporary and permanent blocks
+    pub free_bytes: u64,
+    pub largest_free: u64, // largest contiguous free region
+}
+
 pub struct Chunk {
     pub memory: DeviceMemory,
     pub mapped_memory: Option<MappedMemory>,
@@ -45,6 +59,10 @@ pub struct Chunk {
     pub dirty: Arc<AtomicBool>,
 }
 
+// The mapping is only dereferenced through Blocks (see Block's Send), and
+// Memory, which owns the chunks, is shared between threads behind a Mutex.
+unsafe impl Send for Chunk {}
+
 impl Chunk {
     /// Create a new chunk by asking Vulkan for more memory in the given
     /// memory_type index.
@@ -122,16 +140,7 @@ impl Chunk {
                        reason: &str)
                     -> Option<Block>
     {
-        // Clear the freelist
-        {
-            let mut freelist = self.freelist.write().unwrap();
-
-            // FIXME OPTIMAL: this is a slow operation, shifting parts
-            // of the vector back.
-            self.blocks.retain(|b| !freelist.contains(&b.offset));
-
-            freelist.clear();
-        }
+        self.reclaim();
 
         let mut p = 0;
 
@@ -155,6 +164,24 @@ impl Chunk {
         }
     }
 
+    /// Forget blocks which have been dropped, making their space available
+    pub fn reclaim(&mut self)
+    {
+        let mut freelist = self.freelist.write().unwrap();
+
+        // FIXME OPTIMAL: this is a slow operation, shifting parts
+        // of the vector back.
+        self.blocks.retain(|b| !freelist.contains(&b.offset));
+
+        freelist.clear();
+    }
+
+    /// Whether no blocks are live (call reclaim() first)
+    pub fn is_empty(&self) -> bool
+    {
+        self.blocks.is_empty() && self.perm_blocks.is_empty()
+    }
+
     fn make_block(&mut self, offset: u64, size: u64,
                   element_alignment: u64, reason: &str,
                   insert_block_at: Option<usize>) -> Block
@@ -229,7 +256,11 @@ impl Chunk {
                   linearity);
         }
 
-        info!("  Chunk {} ({})", chunk_number, CHUNK_SIZE.separated_string());
+        let report = self.fragmentation_report(chunk_number, linearity);
+        info!("  Chunk {} ({}), free {} (largest region {})",
+              chunk_number, CHUNK_SIZE.separated_string(),
+              report.free_bytes.separated_string(),
+              report.largest_free.separated_string());
         for block in &self.blocks {
             info!("     size={:>12}      ({:2.0}%): {}",
                   block.size.separated_string(),
@@ -244,6 +275,38 @@ impl Chunk {
         }
     }
 
+    pub fn fragmentation_report(&self, chunk_number: usize, linearity: Linearity)
+                                -> FragmentationReport
+    {
+        // Dropped blocks are only removed from self.blocks on the next
+        // allocation, so skip them here
+        let freelist = self.freelist.read().unwrap();
+
+        let mut block_count = self.perm_blocks.len();
+        let mut free_bytes = 0;
+        let mut largest_free = 0;
+        let mut p = 0;
+        for block in self.blocks.iter().filter(|b| !freelist.contains(&b.offset)) {
+            let hole = block.offset.saturating_sub(p);
+            free_bytes += hole;
+            largest_free = largest_free.max(hole);
+            p = block.end();
+            block_count += 1;
+        }
+        let hole = self.start_of_perm.saturating_sub(p);
+        free_bytes += hole;
+        largest_free = largest_free.max(hole);
+
+        FragmentationReport {
+            memory_type_index: self.memory_type_index,
+            linearity: linearity,
+            chunk: chunk_number,
+            block_count: block_count,
+            free_bytes: free_bytes,
+            largest_free: largest_free,
+        }
+    }
+
     pub fn flush(&self) -> Result<(), Error>
     {
         // only if something is mapped
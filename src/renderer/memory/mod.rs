@@ -1,6 +1,6 @@
 
 mod chunk;
-pub use self::chunk::CHUNK_SIZE;
+pub use self::chunk::{CHUNK_SIZE, FragmentationReport};
 
 mod block;
 pub use self::block::Block;
@@ -9,7 +9,7 @@ use std::collections::HashMap;
 use std::fmt::{self, Display};
 use separator::Separatable;
 use dacite::core::{Device, PhysicalDeviceMemoryProperties,
-                   PhysicalDeviceProperties,
+                   PhysicalDeviceProperties, PhysicalDeviceLimits,
                    MemoryRequirements, MemoryPropertyFlags,
                    BufferUsageFlags, MemoryType, DeviceMemory};
 
@@ -48,12 +48,35 @@ pub struct SoloInfo {
     pub reason: String,
 }
 
+// What was being allocated when graphics memory ran out, and the state of
+// the allocator, as given to a memory pressure handler
+#[derive(Debug, Clone)]
+pub struct MemoryReport {
+    pub requested: u64, // bytes
+    pub reason: String,
+    // The memory type that ran out, or None if no memory type suits
+    pub memory_type_index: Option<u32>,
+    // Bytes allocated from the driver, in chunks and solo allocations
+    pub allocated: u64,
+    pub chunks: Vec<FragmentationReport>,
+}
+
+// Called when an allocation is about to fail with OutOfGraphicsMemory (see
+// Memory::set_pressure_handler).  Return true after freeing something to
+// retry the allocation, or false to let it fail.
+pub type MemoryPressureHandler = Box<dyn FnMut(&MemoryReport) -> bool + Send>;
+
+// How many times one allocation asks the pressure handler to evict before
+// failing anyway, in case it keeps returning true without freeing enough
+const MAX_PRESSURE_RETRIES: u32 = 8;
+
 pub struct Memory {
     // This maps from heap_index to the chunk set
     chunks: [HashMap<u32, Vec<Chunk>>; 2],
     memory_properties: PhysicalDeviceMemoryProperties,
     properties: PhysicalDeviceProperties,
     solos: Vec<SoloInfo>,
+    pressure_handler: Option<MemoryPressureHandler>,
 }
 
 impl Memory {
@@ -66,9 +89,21 @@ impl Memory {
             memory_properties: memory_properties,
             properties: properties,
             solos: Vec::new(),
+            pressure_handler: None,
         }
     }
 
+    // Let `handler` free memory (e.g. evict cached assets) when an
+    // allocation would otherwise fail with OutOfGraphicsMemory; while it
+    // returns true, the allocation is retried (up to MAX_PRESSURE_RETRIES
+    // times).  It runs with this allocator locked, possibly on a
+    // ResourceLoader's thread, so it must only drop resources (which needs
+    // no lock), not create any or call the Renderer.
+    // Those resources must not be in use by a frame in flight.
+    pub fn set_pressure_handler(&mut self, handler: Option<MemoryPressureHandler>) {
+        self.pressure_handler = handler;
+    }
+
     pub fn allocate_solo_device_memory(
         &mut self,
         device: &Device,
@@ -76,6 +111,36 @@ impl Memory {
         memory_property_flags: MemoryPropertyFlags,
         reason: &str)
         -> Result<DeviceMemory, Error>
+    {
+        let mut attempt = 0;
+        let mut first_error = None;
+        loop {
+            match self.try_allocate_solo_device_memory(
+                device, memory_requirements, memory_property_flags, reason)
+            {
+                Err(e) => {
+                    let retry = self.relieve_pressure(&e, attempt, memory_requirements,
+                                                      memory_property_flags, reason);
+                    // Report the failure that started it, not the last retry's
+                    let e = first_error.take().unwrap_or(e);
+                    if !retry {
+                        return Err(e);
+                    }
+                    first_error = Some(e);
+                    attempt += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+
+    fn try_allocate_solo_device_memory(
+        &mut self,
+        device: &Device,
+        memory_requirements: &MemoryRequirements,
+        memory_property_flags: MemoryPropertyFlags,
+        reason: &str)
+        -> Result<DeviceMemory, Error>
     {
         use dacite::core::MemoryAllocateInfo;
 
@@ -116,6 +181,40 @@ impl Memory {
         lifetime: Lifetime,
         reason: &str)
         -> Result<Block, Error>
+    {
+        let mut attempt = 0;
+        let mut first_error = None;
+        loop {
+            match self.try_allocate_device_memory(
+                device, memory_requirements, memory_property_flags,
+                buffer_usage, linearity, lifetime, reason)
+            {
+                Err(e) => {
+                    let retry = self.relieve_pressure(&e, attempt, memory_requirements,
+                                                      memory_property_flags, reason);
+                    // Report the failure that started it, not the last retry's
+                    let e = first_error.take().unwrap_or(e);
+                    if !retry {
+                        return Err(e);
+                    }
+                    first_error = Some(e);
+                    attempt += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+
+    fn try_allocate_device_memory(
+        &mut self,
+        device: &Device,
+        memory_requirements: &MemoryRequirements,
+        memory_property_flags: MemoryPropertyFlags,
+        buffer_usage: Option<BufferUsageFlags>,
+        linearity: Linearity,
+        lifetime: Lifetime,
+        reason: &str)
+        -> Result<Block, Error>
     {
         let l = linearity as usize;
 
@@ -180,6 +279,50 @@ impl Memory {
         }
     }
 
+    // After an allocation failed with `error` on its `attempt`th retry (0
+    // for the first try), give the pressure handler (if any) a chance to
+    // free memory.  Returns whether to retry.
+    fn relieve_pressure(&mut self, error: &Error, attempt: u32,
+                        memory_requirements: &MemoryRequirements,
+                        memory_property_flags: MemoryPropertyFlags,
+                        reason: &str) -> bool
+    {
+        match *error {
+            Error::OutOfGraphicsMemory
+                | Error::Dacite(::dacite::core::Error::OutOfDeviceMemory) => { },
+            _ => return false,
+        }
+        if self.pressure_handler.is_none() {
+            return false;
+        }
+        if attempt >= MAX_PRESSURE_RETRIES {
+            warn!("Still out of graphics memory for {} after {} evictions, giving up",
+                  reason, attempt);
+            return false;
+        }
+
+        let memory_type_index = self.find_memory_type_index(
+            memory_requirements.memory_type_bits, memory_property_flags);
+        let chunks = self.fragmentation_reports();
+        let allocated = chunks.len() as u64 * CHUNK_SIZE
+            + self.solos.iter().map(|solo| solo.size).sum::<u64>();
+        let report = MemoryReport {
+            requested: memory_requirements.size,
+            reason: reason.to_owned(),
+            memory_type_index: memory_type_index,
+            allocated: allocated,
+            chunks: chunks,
+        };
+        warn!("Out of graphics memory allocating {} bytes for {}, asking to evict",
+              report.requested, reason);
+
+        // Blocks dropped by the handler go back to their chunks' freelists,
+        // which the retry searches.  Empty chunks are not released to the
+        // driver here, since frames in flight may still use them (see
+        // Renderer::release_unused_memory).
+        (self.pressure_handler.as_mut().unwrap())(&report)
+    }
+
     pub fn log_usage(&self) {
         for (i,solo) in self.solos.iter().enumerate() {
             if i==0 {
@@ -217,34 +360,52 @@ impl Memory {
         }
     }
 
-    pub fn element_alignment(&self, buffer_usage: Option<BufferUsageFlags>)
-                             -> u64
-    {
-        // Determine element_alignment
-        let mut element_alignment = 1;
-        if let Some(bu) = buffer_usage {
-            if bu.contains(BufferUsageFlags::UNIFORM_BUFFER) {
-                element_alignment = element_alignment.max(
-                    self.properties.limits.min_uniform_buffer_offset_alignment);
-            }
-            if bu.contains(BufferUsageFlags::STORAGE_BUFFER) {
-                element_alignment = element_alignment.max(
-                    self.properties.limits.min_storage_buffer_offset_alignment);
+    // Reclaim the space of dropped blocks in every chunk, and give chunks
+    // with no live blocks back to Vulkan.  Returns the number of bytes
+    // released.  This does not defragment: live blocks stay where they are.
+    pub fn release_empty_chunks(&mut self) -> u64 {
+        let mut released = 0;
+        for chunk_map in self.chunks.iter_mut() {
+            for (_, chunkvec) in chunk_map.iter_mut() {
+                for chunk in chunkvec.iter_mut() {
+                    chunk.reclaim();
+                }
+                let before = chunkvec.len();
+                chunkvec.retain(|chunk| !chunk.is_empty());
+                released += (before - chunkvec.len()) as u64 * CHUNK_SIZE;
             }
-            if bu.contains(BufferUsageFlags::UNIFORM_TEXEL_BUFFER) {
-                element_alignment = element_alignment.max(
-                    self.properties.limits.min_uniform_buffer_offset_alignment);
-                element_alignment = element_alignment.max(
-                    self.properties.limits.min_texel_buffer_offset_alignment);
+        }
+        released
+    }
+
+    pub fn memory_properties(&self) -> &PhysicalDeviceMemoryProperties {
+        &self.memory_properties
+    }
+
+    // Report free space per chunk.  A chunk whose free_bytes is large but
+    // whose largest_free is small is fragmented: allocations bigger than
+    // largest_free won't fit in it despite the free space.
+    pub fn fragmentation_reports(&self) -> Vec<FragmentationReport> {
+        let mut reports = Vec::new();
+        for (_, chunkvec) in &self.chunks[0] {
+            for (i, chunk) in chunkvec.iter().enumerate() {
+                reports.push(chunk.fragmentation_report(i, Linearity::Linear));
             }
-            if bu.contains(BufferUsageFlags::STORAGE_TEXEL_BUFFER) {
-                element_alignment = element_alignment.max(
-                    self.properties.limits.min_storage_buffer_offset_alignment);
-                element_alignment = element_alignment.max(
-                    self.properties.limits.min_texel_buffer_offset_alignment);
+        }
+        for (_, chunkvec) in &self.chunks[1] {
+            for (i, chunk) in chunkvec.iter().enumerate() {
+                reports.push(chunk.fragmentation_report(i, Linearity::Nonlinear));
             }
         }
-        element_alignment
+        reports
+    }
+
+    // The alignment each element of an array of buffer elements needs;
+    // see element_alignment()
+    pub fn element_alignment(&self, buffer_usage: Option<BufferUsageFlags>)
+                             -> u64
+    {
+        element_alignment(&self.properties.limits, buffer_usage)
     }
 
     pub fn stride(&self, size_one: usize, buffer_usage: Option<BufferUsageFlags>)
@@ -316,12 +477,120 @@ impl Memory {
     }
 }
 
+// The alignment each element of an array of buffer elements needs so
+// that any one element can be bound by offset (e.g. with a dynamic
+// uniform buffer offset, or a texel buffer view per element):
+//   UNIFORM_BUFFER       min_uniform_buffer_offset_alignment
+//   STORAGE_BUFFER       min_storage_buffer_offset_alignment
+//   UNIFORM_TEXEL_BUFFER min_uniform_buffer_offset_alignment and
+//                        min_texel_buffer_offset_alignment
+//   STORAGE_TEXEL_BUFFER min_storage_buffer_offset_alignment and
+//                        min_texel_buffer_offset_alignment
+// taking the largest of those that apply.  Every other usage
+// (VERTEX_BUFFER, INDEX_BUFFER, INDIRECT_BUFFER, TRANSFER_SRC/DST) has
+// no offset alignment beyond the element's own size, so contributes 1:
+// vertex and index data stay tightly packed.  Index buffer offsets must
+// still be a multiple of the index size (2 or 4 bytes), which they are
+// when indexing by whole elements.
+fn element_alignment(limits: &PhysicalDeviceLimits, buffer_usage: Option<BufferUsageFlags>)
+                     -> u64
+{
+    let mut element_alignment = 1;
+    if let Some(bu) = buffer_usage {
+        if bu.contains(BufferUsageFlags::UNIFORM_BUFFER) {
+            element_alignment = element_alignment.max(
+                limits.min_uniform_buffer_offset_alignment);
+        }
+        if bu.contains(BufferUsageFlags::STORAGE_BUFFER) {
+            element_alignment = element_alignment.max(
+                limits.min_storage_buffer_offset_alignment);
+        }
+        if bu.contains(BufferUsageFlags::UNIFORM_TEXEL_BUFFER) {
+            element_alignment = element_alignment.max(
+                limits.min_uniform_buffer_offset_alignment);
+            element_alignment = element_alignment.max(
+                limits.min_texel_buffer_offset_alignment);
+        }
+        if bu.contains(BufferUsageFlags::STORAGE_TEXEL_BUFFER) {
+            element_alignment = element_alignment.max(
+                limits.min_storage_buffer_offset_alignment);
+            element_alignment = element_alignment.max(
+                limits.min_texel_buffer_offset_alignment);
+        }
+    }
+    element_alignment
+}
+
 fn _stride(size_one: usize, element_alignment: usize)
            -> usize
 {
-    if element_alignment<=1 {
+    if element_alignment<=1 || size_one == 0 {
         size_one
     } else {
         element_alignment * (1 + ( (size_one-1)/element_alignment ) )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use dacite::core::{BufferUsageFlags, PhysicalDeviceLimits};
+    use super::{element_alignment, _stride};
+
+    fn limits() -> PhysicalDeviceLimits {
+        PhysicalDeviceLimits {
+            min_uniform_buffer_offset_alignment: 256,
+            min_storage_buffer_offset_alignment: 64,
+            min_texel_buffer_offset_alignment: 16,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn element_alignment_per_usage() {
+        let limits = limits();
+        assert_eq!(element_alignment(&limits, None), 1);
+        assert_eq!(element_alignment(&limits, Some(BufferUsageFlags::UNIFORM_BUFFER)), 256);
+        assert_eq!(element_alignment(&limits, Some(BufferUsageFlags::STORAGE_BUFFER)), 64);
+        assert_eq!(element_alignment(&limits, Some(BufferUsageFlags::UNIFORM_TEXEL_BUFFER)), 256);
+        assert_eq!(element_alignment(&limits, Some(BufferUsageFlags::STORAGE_TEXEL_BUFFER)), 64);
+        assert_eq!(element_alignment(&limits, Some(BufferUsageFlags::VERTEX_BUFFER)), 1);
+        assert_eq!(element_alignment(&limits, Some(BufferUsageFlags::INDEX_BUFFER)), 1);
+        assert_eq!(element_alignment(&limits, Some(BufferUsageFlags::INDIRECT_BUFFER)), 1);
+        assert_eq!(element_alignment(&limits, Some(BufferUsageFlags::TRANSFER_SRC)), 1);
+        assert_eq!(element_alignment(&limits, Some(BufferUsageFlags::TRANSFER_DST)), 1);
+    }
+
+    #[test]
+    fn element_alignment_texel_buffers_take_the_larger_limit() {
+        let limits = PhysicalDeviceLimits {
+            min_uniform_buffer_offset_alignment: 4,
+            min_storage_buffer_offset_alignment: 4,
+            min_texel_buffer_offset_alignment: 32,
+            ..Default::default()
+        };
+        assert_eq!(element_alignment(&limits, Some(BufferUsageFlags::UNIFORM_TEXEL_BUFFER)), 32);
+        assert_eq!(element_alignment(&limits, Some(BufferUsageFlags::STORAGE_TEXEL_BUFFER)), 32);
+    }
+
+    #[test]
+    fn element_alignment_combined_usage() {
+        let limits = limits();
+        assert_eq!(element_alignment(&limits, Some(BufferUsageFlags::VERTEX_BUFFER
+                                                   | BufferUsageFlags::STORAGE_BUFFER)), 64);
+        assert_eq!(element_alignment(&limits, Some(BufferUsageFlags::STORAGE_BUFFER
+                                                   | BufferUsageFlags::UNIFORM_BUFFER)), 256);
+        assert_eq!(element_alignment(&limits, Some(BufferUsageFlags::VERTEX_BUFFER
+                                                   | BufferUsageFlags::INDEX_BUFFER
+                                                   | BufferUsageFlags::TRANSFER_DST)), 1);
+    }
+
+    #[test]
+    fn stride() {
+        assert_eq!(_stride(0, 256), 0);
+        assert_eq!(_stride(0, 1), 0);
+        assert_eq!(_stride(12, 1), 12);
+        assert_eq!(_stride(12, 16), 16);
+        assert_eq!(_stride(16, 16), 16);
+        assert_eq!(_stride(17, 16), 32);
+    }
+}
@@ -1,12 +1,93 @@
 
 use crate::error::*;
-use dacite::core::Device;
+use dacite::core::{Device, CommandBuffer, IndexType};
 use siege_math::Point3;
 use siege_mesh::{Mesh, Vertex};
 use super::buffer::{DeviceLocalBuffer, HostVisibleBuffer};
 use super::memory::{Memory, Lifetime};
 use super::commander::Commander;
 
+// The material a mesh should be drawn with: the names of its textures (as
+// passed to Renderer::load_texture) and the PBR scalars to use where a
+// texture is absent.  Renderer::load_mesh reads it from a `<name>.material`
+// file beside `<name>.mesh`, if there is one (see MeshMaterial::parse).
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeshMaterial {
+    pub diffuse_texture: Option<String>,
+    pub normal_texture: Option<String>,
+    pub material_texture: Option<String>,
+    pub base_color: [f32; 4],
+    pub roughness: f32,
+    pub metallic: f32,
+}
+
+impl Default for MeshMaterial {
+    fn default() -> MeshMaterial {
+        MeshMaterial {
+            diffuse_texture: None,
+            normal_texture: None,
+            material_texture: None,
+            base_color: [1.0, 1.0, 1.0, 1.0],
+            roughness: 0.5,
+            metallic: 0.0,
+        }
+    }
+}
+
+impl MeshMaterial {
+    // Parse a material file: one `key = value` per line, with blank lines
+    // and lines starting with '#' ignored.  Keys left out keep their
+    // defaults.
+    //
+    //     diffuse_texture = brick_diffuse
+    //     normal_texture = brick_normal
+    //     material_texture = brick_material
+    //     base_color = 1.0 0.9 0.8 1.0
+    //     roughness = 0.6
+    //     metallic = 0.0
+    pub fn parse(text: &str) -> Result<MeshMaterial, Error>
+    {
+        let mut material = MeshMaterial::default();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad_line = || Error::General(format!(
+                "Bad material line {}: {}", number + 1, line));
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap().trim();
+            let value = match parts.next() {
+                Some(value) => value.trim(),
+                None => return Err(bad_line()),
+            };
+            let float = |s: &str| s.parse::<f32>().map_err(|_| bad_line());
+
+            match key {
+                "diffuse_texture" => material.diffuse_texture = Some(value.to_owned()),
+                "normal_texture" => material.normal_texture = Some(value.to_owned()),
+                "material_texture" => material.material_texture = Some(value.to_owned()),
+                "base_color" => {
+                    let components = value.split_whitespace()
+                        .map(|s| float(s))
+                        .collect::<Result<Vec<f32>, Error>>()?;
+                    if components.len() != 4 {
+                        return Err(bad_line());
+                    }
+                    material.base_color.copy_from_slice(&components);
+                },
+                "roughness" => material.roughness = float(value)?,
+                "metallic" => material.metallic = float(value)?,
+                _ => return Err(bad_line()),
+            }
+        }
+
+        Ok(material)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct VulkanMesh {
     pub vertex_buffer: DeviceLocalBuffer,
@@ -14,11 +95,13 @@ pub struct VulkanMesh {
 
     pub num_vertices: u32,
     pub num_indices: u32,
+    pub index_type: IndexType,
 
     pub bounding_sphere: Option<(Point3<f32>, f32)>,
     pub bounding_cuboid: Option<[Point3<f32>; 8]>,
 
-    // TBD: texture images
+    // Optional; meshes without a material file have none
+    pub material: Option<MeshMaterial>,
 
     // TBD: uniforms buffer
     //   (e.g. maybe we have other per-mesh values like floats and vec4s stored in mesh files)
@@ -54,8 +137,90 @@ impl VulkanMesh {
             index_buffer: index_buffer,
             num_vertices: mesh.vertices.len() as u32,
             num_indices: (mesh.indices.len() * 3) as u32, // we group them in 3s
+            index_type: match element_size(&mesh.indices) / 3 {
+                2 => IndexType::UInt16,
+                _ => IndexType::UInt32,
+            },
             bounding_sphere: mesh.bounding_sphere.clone(),
             bounding_cuboid: mesh.bounding_cuboid.clone(),
+            material: None,
         })
     }
+
+    pub fn material(&self) -> Option<&MeshMaterial> {
+        self.material.as_ref()
+    }
+
+    // Bind this mesh and draw it with parameters taken from `indirect_buffer`
+    // (which needs BufferUsageFlags::INDIRECT_BUFFER), e.g. as written by a
+    // compute culling pass.  The buffer holds `draw_count`
+    // DrawIndexedIndirectCommand structures, `stride` bytes apart, starting
+    // at `offset`.  More than one draw requires the multi_draw_indirect
+    // device feature; pass Renderer::has_multi_draw_indirect().
+    pub fn draw_indirect(&self,
+                         command_buffer: CommandBuffer,
+                         indirect_buffer: &DeviceLocalBuffer,
+                         offset: u64,
+                         draw_count: u32,
+                         stride: u32,
+                         multi_draw_indirect: bool)
+                         -> Result<(), Error>
+    {
+        if offset % 4 != 0 {
+            return Err(Error::General(
+                format!("Indirect draw offset {} is not a multiple of 4", offset)));
+        }
+        if draw_count > 1 {
+            if !multi_draw_indirect {
+                return Err(Error::General(
+                    "Device does not support multi_draw_indirect".to_owned()));
+            }
+            // sizeof(VkDrawIndexedIndirectCommand) is 20
+            if stride < 20 || stride % 4 != 0 {
+                return Err(Error::General(
+                    format!("Invalid indirect draw stride {}", stride)));
+            }
+        }
+
+        command_buffer.bind_vertex_buffers(0, &[self.vertex_buffer.inner()], &[0]);
+        command_buffer.bind_index_buffer(&self.index_buffer.inner(), 0, self.index_type);
+        command_buffer.draw_indexed_indirect(&indirect_buffer.inner(), offset,
+                                             draw_count, stride);
+
+        Ok(())
+    }
+}
+
+fn element_size<T>(_: &[T]) -> usize
+{
+    ::std::mem::size_of::<T>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MeshMaterial;
+
+    #[test]
+    fn parse_material() {
+        let material = MeshMaterial::parse(
+            "# brick\n\
+             diffuse_texture = brick_diffuse\n\
+             \n\
+             base_color = 1.0 0.5 0.25 1.0\n\
+             roughness = 0.75\n").unwrap();
+        assert_eq!(material, MeshMaterial {
+            diffuse_texture: Some("brick_diffuse".to_owned()),
+            base_color: [1.0, 0.5, 0.25, 1.0],
+            roughness: 0.75,
+            ..MeshMaterial::default()
+        });
+    }
+
+    #[test]
+    fn parse_material_errors() {
+        assert!(MeshMaterial::parse("roughness").is_err());
+        assert!(MeshMaterial::parse("roughness = rough").is_err());
+        assert!(MeshMaterial::parse("base_color = 1.0 1.0 1.0").is_err());
+        assert!(MeshMaterial::parse("shininess = 1.0").is_err());
+    }
 }
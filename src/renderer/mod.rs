@@ -15,50 +15,90 @@ mod shade;
 mod post;
 mod blur;
 mod stats;
+mod descriptor_set_writer;
+mod descriptor_set_binder;
+mod spirv;
+mod stars;
+mod plugin_timestamps;
+mod debug_lines;
+mod exposure;
+mod compute_blur;
+mod taa;
+mod viewport_depth_range;
+mod scissor_region;
+mod resource_loader;
+mod pass_commands;
 
 pub use self::buffer::{HostVisibleBuffer, DeviceLocalBuffer};
 pub use self::image_wrap::ImageWrap;
-pub use self::mesh::VulkanMesh;
-pub use self::memory::Lifetime;
-pub use self::post::Tonemapper;
-pub use self::stats::{Timings, Stats};
-
-use std::sync::Arc;
+pub use self::mesh::{VulkanMesh, MeshMaterial};
+pub use self::memory::{Lifetime, Linearity, FragmentationReport, MemoryReport,
+                       MemoryPressureHandler};
+pub use self::post::{Tonemapper, OutputTransfer};
+pub use self::blur::BloomQuality;
+pub use self::shade::MaterialPacking;
+pub use self::stats::{Timings, Stats, SmoothingMode};
+pub use self::descriptor_set_writer::DescriptorSetWriter;
+pub use self::descriptor_set_binder::{DescriptorSetBinder, SetFrequency};
+pub use self::passes::probe::CUBEMAP_FACE_DIRECTIONS;
+pub use self::plugin_timestamps::{PluginTimestamps, PLUGIN_TS_QUERY_COUNT};
+pub use self::debug_lines::DEBUG_LINE_VERTEX_COUNT;
+pub use self::viewport_depth_range::ViewportDepthRange;
+pub use self::scissor_region::ScissorRegion;
+pub use self::resource_loader::ResourceLoader;
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver};
 use std::time::{Duration, Instant};
 use std::path::PathBuf;
 use dacite::core::{Instance, PhysicalDevice, Device, Queue, Extent2D,
                    ShaderModule, Rect2D, Viewport, Offset2D,
-                   DescriptorPool, Semaphore, Fence,
+                   DescriptorPool, Semaphore, Fence, AttachmentLoadOp,
                    BufferUsageFlags, DescriptorSetLayoutCreateInfo,
                    DescriptorSetLayout, DescriptorSet, Pipeline, PipelineLayout,
                    Timeout, SamplerCreateInfo, Sampler,
                    PipelineVertexInputStateCreateInfo, PrimitiveTopology,
                    CullModeFlags, FrontFace, ImageView,
                    DescriptorSetAllocateInfo, DescriptorType, ShaderStageFlags,
-                   WriteDescriptorSetElements, DescriptorSetLayoutBinding,
+                   DescriptorSetLayoutBinding,
                    PhysicalDeviceFeatures, PhysicalDeviceProperties,
                    Format, BufferView, SpecializationInfo, QueryPool,
                    QueryPoolCreateInfo, QueryType, QueryPipelineStatisticFlags,
                    QueryResultFlags, PipelineStageFlagBits, QueryResult,
-                   PushConstantRange};
+                   PushConstantRange, CompareOp, ColorComponentFlags,
+                   CommandBuffer, Offset3D, Extent3D, FormatProperties,
+                   FormatFeatureFlags, ImageTiling, BlendFactor, BlendOp,
+                   PhysicalDeviceLimits, PhysicalDeviceType, PipelineCache,
+                   ImageAspectFlags, ImageLayout, AccessFlags, PipelineStageFlags};
 use dacite::ext_debug_report::DebugReportCallbackExt;
-use dacite::khr_surface::SurfaceKhr;
+use dacite::khr_surface::{SurfaceKhr, PresentModeKhr, ColorSpaceKhr};
 use serde::Deserialize;
-use siege_math::{Vec4, Mat4};
+use siege_math::{Vec3, Vec4, Mat4};
+use siege_mesh::{Vertex, Mesh};
 use winit::Window;
 
-use self::setup::Physical;
+use self::setup::{Physical, ValidationOutput, QueueIndices};
 use self::memory::Memory;
 use self::swapchain_data::SwapchainData;
 use self::commander::Commander;
 use self::resource_manager::ResourceManager;
-use self::target_data::TargetData;
+use self::pass_commands::PassCommands;
+use self::target_data::{TargetData, GBufferFormats};
 use self::passes::{GeometryPass, ShadingPass, TransparentPass,
-                   BlurHPass, BlurVPass, PostPass, UiPass};
+                   BlurHPass, BlurVPass, PostPass, UiPass, ProbePass,
+                   ShadowPass, TaaPass};
 use self::shade::ShadeGfx;
 use self::post::PostGfx;
 use self::blur::BlurGfx;
+use self::stars::StarGfx;
+use self::debug_lines::DebugLinesGfx;
+use self::exposure::ExposureGfx;
+use self::compute_blur::ComputeBlurGfx;
+use self::taa::TaaGfx;
+use crate::vertex::ColoredVertex;
 use super::plugin::Plugin;
 use crate::error::Error;
 use crate::config::Config;
@@ -73,23 +113,150 @@ pub enum VulkanLogLevel {
     Debug
 }
 
+// Formats that may be chosen for the geometry pass color attachments
+// (see Config::diffuse_format, normals_format and material_format).
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GBufferFormat {
+    A2b10g10r10Unorm,
+    R8g8b8a8Unorm,
+    R16g16b16a16Sfloat,
+}
+
+impl GBufferFormat {
+    pub fn format(&self) -> Format {
+        match *self {
+            GBufferFormat::A2b10g10r10Unorm => Format::A2B10G10R10_UNorm_Pack32,
+            GBufferFormat::R8g8b8a8Unorm => Format::R8G8B8A8_UNorm,
+            GBufferFormat::R16g16b16a16Sfloat => Format::R16G16B16A16_SFloat,
+        }
+    }
+}
+
+// What the geometry pass does with an attachment's previous contents as it
+// begins.  Load keeps what the last frame left there (e.g. for temporal
+// accumulation); DontCare leaves it undefined, for attachments that plugins
+// fully overwrite every frame.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GBufferLoadOp {
+    Clear,
+    Load,
+    DontCare,
+}
+
+impl GBufferLoadOp {
+    pub fn load_op(&self) -> AttachmentLoadOp {
+        match *self {
+            GBufferLoadOp::Clear => AttachmentLoadOp::Clear,
+            GBufferLoadOp::Load => AttachmentLoadOp::Load,
+            GBufferLoadOp::DontCare => AttachmentLoadOp::DontCare,
+        }
+    }
+}
+
+// The geometry pass load op of each attachment (see
+// Config::geometry_load_ops and Renderer::set_geometry_load_ops)
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct GeometryLoadOps {
+    pub depth: GBufferLoadOp,
+    pub diffuse: GBufferLoadOp,
+    pub normals: GBufferLoadOp,
+    pub material: GBufferLoadOp,
+}
+
+impl Default for GeometryLoadOps {
+    fn default() -> GeometryLoadOps {
+        GeometryLoadOps {
+            depth: GBufferLoadOp::Clear,
+            diffuse: GBufferLoadOp::Clear,
+            normals: GBufferLoadOp::Clear,
+            material: GBufferLoadOp::Clear,
+        }
+    }
+}
+
+// Formats that may be chosen for the shading and blur targets (see
+// Config::shading_format).  Shaders writing them clamp to max_value() so
+// that very bright pixels don't overflow to infinity.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HdrFormat {
+    R16g16b16a16Sfloat,
+    R32g32b32a32Sfloat,
+}
+
+impl HdrFormat {
+    pub fn format(&self) -> Format {
+        match *self {
+            HdrFormat::R16g16b16a16Sfloat => Format::R16G16B16A16_SFloat,
+            HdrFormat::R32g32b32a32Sfloat => Format::R32G32B32A32_SFloat,
+        }
+    }
+
+    // The largest finite value the format holds
+    pub fn max_value(&self) -> f32 {
+        match *self {
+            HdrFormat::R16g16b16a16Sfloat => 65504.0,
+            HdrFormat::R32g32b32a32Sfloat => ::std::f32::MAX,
+        }
+    }
+}
+
+// A client function that receives Vulkan validation messages (in addition
+// to the log).  See Renderer::set_validation_callback().
+pub type ValidationCallback = Box<dyn Fn(VulkanLogLevel, &str) + Send>;
+
 // Passes that consumers of the library can plug into
 pub enum Pass {
     Geometry,
     Transparent,
-    Ui
+    Ui,
+    Probe, // reflection probe faces (see Renderer::create_reflection_probe)
+    Shadow, // depth only, from the primary light (see Config::shadows)
 }
 
 pub enum DepthHandling {
     None,
-    Some(bool, bool) // test, write
+    Some(bool, bool, Option<CompareOp>) // test, write, compare (None for default)
+}
+
+// Offsets fragment depths by constant_factor * (the smallest resolvable
+// depth difference) + slope_factor * (the polygon's max depth slope).
+// Negative values move fragments nearer, unless the depth buffer is
+// reversed, in which case positive values do; Renderer::decal_pipeline_setup
+// picks the sign for you.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DepthBias {
+    pub constant_factor: f32,
+    pub slope_factor: f32,
 }
 
 pub enum BlendMode {
     Off,
     Alpha,
     PreMultiplied,
-    Add
+    Add,
+    // Darkens by the source color (e.g. tinted glass): dst * src
+    Multiply,
+    // Lightens by the source color: src + dst * (1 - src)
+    Screen,
+    // Fades the whole draw by the blend constants' alpha (see
+    // Renderer::set_blend_constants): src * a + dst * (1 - a)
+    ConstantAlpha,
+    // As ConstantAlpha, but per channel by the blend constants' color:
+    // src * c + dst * (1 - c)
+    ConstantColor,
+    // Any other blend equation, with the factors and ops used as given
+    Custom {
+        src_color: BlendFactor,
+        dst_color: BlendFactor,
+        color_op: BlendOp,
+        src_alpha: BlendFactor,
+        dst_alpha: BlendFactor,
+        alpha_op: BlendOp,
+    },
 }
 
 #[repr(u32)]
@@ -122,14 +289,101 @@ const TS_QUERY_COUNT: u32 = 16;
 #[derive(Debug, Clone, Copy)]
 pub struct Params {
     pub inv_projection: Mat4<f32>,
+    pub inv_view: Mat4<f32>, // view space to world space (for environment lookups)
+    // World space to the primary light's clip space, as used to render the
+    // shadow map (see Config::shadows and Plugin::record_shadow)
+    pub shadow_view_projection: Mat4<f32>,
     pub dlight_directions: [Vec4<f32>; 2],
     pub dlight_irradiances: [Vec4<f32>; 2],
     pub bloom_strength: f32, // 0.65
-    pub bloom_cliff: f32, // 0.7
+    pub bloom_cliff: f32, // 0.7 (luminance threshold where bloom starts)
     pub blur_level: f32, // 0.0
     pub ambient: f32,
     pub white_level: f32,
     pub tonemapper: Tonemapper,
+    // Depth tolerance (in shadow map depth units) before a surface shadows
+    // itself. Raise it to remove shadow acne, lower it if shadows detach
+    // from their casters.
+    pub shadow_bias: f32,
+    // Depth of field (see Config::dof_enabled and set_depth_of_field)
+    pub focus_distance: f32, // view-space distance that is in focus
+    pub aperture: f32, // max blur radius in render pixels, 0.0 = off
+    // Starfield (see Renderer::set_starfield and set_star_params)
+    pub star_brightness: f32, // multiplies each star's irradiance
+    pub star_size: f32, // point sprite diameter in render pixels
+    // Projection planes (see Renderer::set_near_far).  A raw depth buffer
+    // value d (reversed or not) is at view-space depth
+    //     depth_linearize_scale / (d + depth_linearize_offset)
+    pub near_plane: f32,
+    pub far_plane: f32,
+    pub depth_linearize_scale: f32,
+    pub depth_linearize_offset: f32,
+    // Multiplies scene luminance before tonemapping (see
+    // Renderer::set_exposure and set_auto_exposure)
+    pub exposure: f32,
+    // Temporal anti-aliasing (see Config::taa_enabled).  These are set by
+    // the renderer every frame: the previous frame's inv_projection and
+    // inv_view (for reprojecting the history), the sub-pixel jitter to add
+    // to clip-space positions, and the weight of the history (0.0 when there
+    // is none yet).  Geometry and transparent pass vertex shaders apply the
+    // jitter as
+    //     gl_Position.xy += params.taa_jitter * gl_Position.w;
+    // It stays zero without TAA.
+    pub prev_inv_projection: Mat4<f32>,
+    pub prev_inv_view: Mat4<f32>,
+    pub taa_jitter: [f32; 2],
+    pub taa_feedback: f32,
+    // Motion vectors (see Config::motion_vectors; this is 1 when the
+    // velocity target exists).  Plugins set view_projection (world to clip
+    // space, without the TAA jitter) whenever they set inv_view and
+    // inv_projection; the renderer sets prev_view_projection to the previous
+    // frame's every frame.  Geometry plugins write color attachment 3 as
+    //     (cur.xy / cur.w - prev.xy / prev.w) * 0.5
+    // where cur = view_projection * model * pos and prev =
+    // prev_view_projection * prev_model * pos, with their own previous model
+    // matrix (the same one for static geometry).
+    pub motion_vectors: u32,
+    pub view_projection: Mat4<f32>,
+    pub prev_view_projection: Mat4<f32>,
+    // What the shading pass outputs where no geometry was drawn (depth is
+    // still at the far plane), in the same units as the light irradiances.
+    // A lightweight sky or ambient background, for scenes without a skybox.
+    // The alpha is ignored.
+    pub background_color: Vec4<f32>, // black
+    // Alpha cutout (see Renderer::cutout_pipeline_setup): the default for
+    // plugins to push to their cutout shaders, which discard fragments whose
+    // alpha is below it
+    pub alpha_cutoff: f32, // 0.5
+}
+
+// An image read back by Renderer::capture_gbuffer(): rows of texels in
+// `format`, tightly packed, top row first
+#[derive(Debug, Clone)]
+pub struct CapturedImage {
+    pub format: Format,
+    pub extent: Extent2D,
+    pub data: Vec<u8>,
+}
+
+// The g-buffers and depth buffer as the last frame left them
+#[derive(Debug, Clone)]
+pub struct GBufferCapture {
+    pub diffuse: CapturedImage,
+    pub normals: CapturedImage,
+    // (packed per Config::material_packing)
+    pub material: CapturedImage,
+    // Raw depths (1.0 is near with a reversed depth buffer)
+    pub depth: CapturedImage,
+}
+
+// An image for Renderer::read_back_image_region(), with the aspect to copy
+// and the layout, access and stages it is left in between frames
+struct ReadbackSource {
+    image: ImageWrap,
+    aspect: ImageAspectFlags,
+    layout: ImageLayout,
+    access: AccessFlags,
+    stages: PipelineStageFlags,
 }
 
 pub struct PipelineSetup {
@@ -140,25 +394,92 @@ pub struct PipelineSetup {
     pub fragment_shader_spec: Option<SpecializationInfo>,
     pub vertex_type: Option<PipelineVertexInputStateCreateInfo>,
     pub topology: PrimitiveTopology,
+    // Treat the maximum index value (0xFFFF or 0xFFFFFFFF) as the end of one
+    // strip or fan and the start of the next.  Only valid for strip and fan
+    // topologies.
+    pub primitive_restart: bool,
+    // Rasterized width of line primitives, in render pixels.  Anything other
+    // than 1.0 requires the wide_lines device feature, and must be within
+    // the device's line_width_range limit.
+    pub line_width: f32,
     pub cull_mode: CullModeFlags,
     pub front_face: FrontFace,
+    // Clamp fragment depth to the depth range instead of clipping at the
+    // near and far planes, so that (for example) shadow casters behind the
+    // near plane still render. Requires the depth_clamp device feature.
+    pub depth_clamp: bool,
     pub test_depth: bool,
+    // Transparent pass pipelines usually test depth without writing it
+    // (test_depth: true, write_depth: false), e.g. for particles.
     pub write_depth: bool,
+    // Overrides the default depth compare op (GreaterOrEqual for a reversed
+    // depth buffer, LessOrEqual otherwise), e.g. Equal for decals or Always.
+    pub depth_compare: Option<CompareOp>,
+    // Offsets fragment depths, e.g. so decals win against the surfaces they
+    // lie on (see Renderer::decal_pipeline_setup)
+    pub depth_bias: Option<DepthBias>,
+    // Run the fragment shader per sample rather than per pixel, for at
+    // least this fraction (0.0 to 1.0) of the samples, which reduces
+    // specular aliasing in forward-shaded (e.g. transparent pass) pipelines.
+    // It only has an effect on multisampled targets.  Requires the
+    // sample_rate_shading device feature.
+    pub sample_shading: Option<f32>,
     pub blend: Vec<BlendMode>,
+    // Which channels are written (applies to every color attachment).
+    // Geometry pass plugins must include A to write the material alpha
+    // channel (cavity, unless Config::material_packing says otherwise).
+    pub color_write_mask: ColorComponentFlags,
     pub pass: Pass,
     pub push_constant_ranges: Vec<PushConstantRange>,
 }
 
+// Identifies a plugin registered with Renderer::plugin()
+pub type PluginId = usize;
+
+// Data sent from the host application to a plugin (see
+// Renderer::plugin_sender); the plugin downcasts it to the type it expects.
+pub type PluginMessage = Box<dyn Any + Send>;
+
+struct PluginSlot {
+    plugin: Box<dyn Plugin>,
+    enabled: bool,
+    sender: Sender<PluginMessage>,
+    receiver: Receiver<PluginMessage>,
+}
+
 pub struct Renderer {
-    plugins: Vec<Box<dyn Plugin>>,
+    plugins: Vec<PluginSlot>,
+    // The order plugins record in, by id (see set_plugin_render_order)
+    plugin_order: Vec<PluginId>,
+    probes: Vec<ProbePass>,
+    shadow_pass: ShadowPass,
     post_gfx: PostGfx,
+    star_gfx: StarGfx,
+    debug_lines_gfx: DebugLinesGfx,
+    exposure_gfx: ExposureGfx,
+    // Both present when Config::taa_enabled is set
+    taa_pass: Option<TaaPass>,
+    taa_gfx: Option<TaaGfx>,
+    taa_phase: u32,
+    // The inv_projection and inv_view of the last frame submitted, or None
+    // if there is no history to reproject
+    taa_prev_matrices: Option<(Mat4<f32>, Mat4<f32>)>,
+    // The view_projection of the last frame submitted, or None before the
+    // first frame
+    prev_view_projection: Option<Mat4<f32>>,
+    // (target_ev, adapt_speed) while auto-exposure is on
+    auto_exposure: Option<(f32, f32)>,
     blur_gfx: BlurGfx,
+    compute_blur_gfx: Option<ComputeBlurGfx>, // see Config::bloom_use_compute
     shade_gfx: ShadeGfx,
     params_desc_set: DescriptorSet,
     #[allow(dead_code)]
     params_desc_layout: DescriptorSetLayout,
     #[allow(dead_code)]
     params_ubo: HostVisibleBuffer,
+    view: Mat4<f32>,
+    // Directional lights set in world space, re-transformed on view change
+    world_light_directions: [Option<Vec4<f32>>; 2],
     ui_pass: UiPass,
     post_pass: PostPass,
     blur_v_pass: BlurVPass,
@@ -167,31 +488,61 @@ pub struct Renderer {
     shading_pass: ShadingPass,
     geometry_pass: GeometryPass,
     target_data: TargetData,
-    timestamp_query_pool: QueryPool,
+    timestamp_query_pool: Option<QueryPool>, // None if timestamps are unsupported
+    plugin_timestamps: PluginTimestamps,
+    viewport_depth_range: ViewportDepthRange,
+    scissor_region: ScissorRegion,
+    // The plugin timestamp queries written by each swapchain image's
+    // command buffer
+    plugin_timestamps_written: Vec<Vec<u32>>,
     rendered_fence: Fence,
     image_rendered: Semaphore,
     image_acquired: Semaphore,
     descriptor_pool: DescriptorPool,
+    // Used for every pipeline, see Config::pipeline_cache_path
+    pipeline_cache: PipelineCache,
+    // The bindings of each live layout made by create_descriptor_set(), so
+    // that create_pipeline() can check shaders against them.  Layouts no one
+    // else holds are dropped on the next create_descriptor_set().
+    desc_set_layout_bindings: Vec<(DescriptorSetLayout, Vec<DescriptorSetLayoutBinding>)>,
+    ui_scissors: Vec<Rect2D>,
+    ui_viewports: Vec<Viewport>,
     scissors: Vec<Rect2D>,
     viewports: Vec<Viewport>,
+    // Set at the start of every command buffer that plugins record into
+    // (see set_blend_constants)
+    blend_constants: [f32; 4],
+    resolution_scale: f32,
+    // Overrides the resolution scale (see set_render_extent)
+    fixed_render_extent: Option<Extent2D>,
     staging_buffer: HostVisibleBuffer,
     resource_manager: ResourceManager,
+    // What plugins record in these passes, per swapchain image
+    geometry_commands: PassCommands,
+    transparent_commands: PassCommands,
+    ui_commands: PassCommands,
     commander: Commander,
     present_queue: Queue,
     swapchain_data: SwapchainData,
-    memory: Memory,
+    // (shared with ResourceLoaders)
+    memory: Arc<Mutex<Memory>>,
     device: Device,
-    //queue_indices: QueueIndices,
+    queue_indices: QueueIndices,
     ph_feats: PhysicalDeviceFeatures,
+    debug_markers: bool,
     ph_props: PhysicalDeviceProperties,
     ph: PhysicalDevice,
     surface: SurfaceKhr,
     #[allow(dead_code)] // We don't use this directly, FFI uses it
     debug_callback: Option<DebugReportCallbackExt>,
+    validation_output: Arc<ValidationOutput>,
+    active_layers: Vec<String>,
     #[allow(dead_code)] // This must stay alive until we shut down
     instance: Instance,
     shutdown: Arc<AtomicBool>,
     resized: Arc<AtomicBool>,
+    minimized: bool,
+    device_lost: bool,
     stats: Stats,
     window: Arc<Window>,
     config: Config,
@@ -203,9 +554,11 @@ impl Renderer {
                shutdown: Arc<AtomicBool>)
                -> Result<Renderer, Error>
     {
-        let instance = setup::setup_instance(&config, &window)?;
+        let (instance, active_layers) = setup::setup_instance(&config, &window)?;
 
-        let debug_callback = setup::setup_debug_callback(&config, &instance)?;
+        let validation_output = Arc::new(ValidationOutput::new());
+        let debug_callback = setup::setup_debug_callback(&config, &instance,
+                                                         validation_output.clone())?;
 
         let surface = setup::setup_surface(&window, &instance)?;
 
@@ -216,11 +569,13 @@ impl Renderer {
             physical_device_features,
             physical_device_memory_properties,
             queue_indices,
-            device_extensions
+            device_extensions,
+            debug_markers
         } = setup::find_suitable_device( &config, &instance, &surface)?;
 
         let device = setup::create_device(
-            &physical_device, device_extensions, &queue_indices)?;
+            &physical_device, device_extensions, &queue_indices,
+            &physical_device_features)?;
 
         let mut memory = Memory::new(physical_device_memory_properties,
                                      physical_device_properties.clone());
@@ -238,10 +593,20 @@ impl Renderer {
 
         let commander = Commander::new(
             &device, &queue_indices,
-            swapchain_data.images.len() as u32)?;
+            swapchain_data.images.len() as u32,
+            Arc::new(Mutex::new(())))?;
+
+        let geometry_commands = PassCommands::new(
+            &commander.gfx_command_pool, swapchain_data.images.len() as u32)?;
+        let transparent_commands = PassCommands::new(
+            &commander.gfx_command_pool, swapchain_data.images.len() as u32)?;
+        let ui_commands = PassCommands::new(
+            &commander.gfx_command_pool, swapchain_data.images.len() as u32)?;
 
         let resource_manager = ResourceManager::new(
-            config.asset_path.clone());
+            config.asset_path.clone(),
+            physical_device_features.texture_compression_etc2,
+            &physical_device_properties.limits);
 
         let staging_buffer = HostVisibleBuffer::new::<u8>(
             &device, &mut memory,
@@ -250,6 +615,9 @@ impl Renderer {
             Lifetime::Permanent, "Staging Buffer"
         )?;
 
+        // We start rendering at the full swapchain resolution
+        let resolution_scale = 1.0;
+
         let viewports = vec![Viewport {
             x: 0.0,
             y: 0.0,
@@ -262,42 +630,91 @@ impl Renderer {
             offset: Offset2D { x: 0, y: 0 },
             extent: swapchain_data.extent.clone(),
         }];
+        let ui_viewports = viewports.clone();
+        let ui_scissors = scissors.clone();
 
         let descriptor_pool = setup::get_descriptor_pool(&device, &config)?;
 
+        let pipeline_cache = setup::get_pipeline_cache(
+            &device, &config, &physical_device_properties)?;
+
         let (image_acquired, image_rendered) = setup::get_semaphores(&device)?;
 
         let rendered_fence = setup::get_graphics_fence(&device, false)?;
 
-        let timestamp_query_pool = device.create_query_pool(&QueryPoolCreateInfo {
-            flags: Default::default(),
-            query_type: QueryType::Timestamp,
-            query_count: TS_QUERY_COUNT,
-            pipeline_statistics: QueryPipelineStatisticFlags::empty(),
-            chain: None,
-        }, None)?;
+        let timestamp_query_pool = if setup::timestamps_supported(
+            &physical_device_properties, &queue_indices)
+        {
+            Some(device.create_query_pool(&QueryPoolCreateInfo {
+                flags: Default::default(),
+                query_type: QueryType::Timestamp,
+                query_count: TS_QUERY_COUNT,
+                pipeline_statistics: QueryPipelineStatisticFlags::empty(),
+                chain: None,
+            }, None)?)
+        } else {
+            None
+        };
+
+        let plugin_timestamps = PluginTimestamps::new(
+            match timestamp_query_pool {
+                Some(_) => Some(device.create_query_pool(&QueryPoolCreateInfo {
+                    flags: Default::default(),
+                    query_type: QueryType::Timestamp,
+                    query_count: PLUGIN_TS_QUERY_COUNT,
+                    pipeline_statistics: QueryPipelineStatisticFlags::empty(),
+                    chain: None,
+                }, None)?),
+                None => None
+            },
+            config.gpu_timing);
+
+        // The compute blur shaders are written for the default shading format
+        let compute_blur = if !config.bloom_use_compute {
+            false
+        } else if config.shading_format != HdrFormat::R16g16b16a16Sfloat {
+            warn!("The compute bloom blur requires the r16g16b16a16_sfloat shading format, using the graphics blur");
+            false
+        } else {
+            true
+        };
 
         let target_data = TargetData::create(
-            &device, &mut memory, &commander, swapchain_data.extent)?;
+            &device, &mut memory, &commander,
+            GBufferFormats::from_config(&config),
+            config.linear_depth_target,
+            compute_blur,
+            config.taa_enabled,
+            config.motion_vectors,
+            config.entity_id_target,
+            swapchain_data.extent, swapchain_data.extent)?;
 
         let geometry_pass = GeometryPass::new(
             &device, &target_data.depth_image, &target_data.diffuse_image,
             &target_data.normals_image, &target_data.material_image,
-            config.reversed_depth_buffer)?;
+            target_data.velocity_image.as_ref(),
+            target_data.entity_id_image.as_ref(),
+            config.reversed_depth_buffer, config.geometry_load_ops)?;
         let shading_pass = ShadingPass::new(
             &device, &target_data.depth_image, &target_data.diffuse_image,
             &target_data.normals_image, &target_data.material_image,
-            &target_data.shading_image)?;
+            &target_data.shading_image, target_data.linear_depth_image.as_ref())?;
         let transparent_pass = TransparentPass::new(
-            &device, &target_data.depth_image, &target_data.shading_image)?;
+            &device, &target_data.depth_image, &target_data.shading_image,
+            config.transparent_clear)?;
         let blur_h_pass = BlurHPass::new(
             &device, &target_data.shading_image, &target_data.blur_image)?;
         let blur_v_pass = BlurVPass::new(
             &device, &target_data.blur_image, &target_data.shading_image)?;
         let post_pass = PostPass::new(
-            &device, &target_data.shading_image, &swapchain_data)?;
+            &device, &swapchain_data)?;
         let ui_pass = UiPass::new(
-            &device, &target_data.depth_image, &swapchain_data)?;
+            &device, &target_data.ui_depth_image, &swapchain_data)?;
+        // Without shadows the (unused) shadow map is kept minimal
+        let shadow_pass = ShadowPass::new(
+            &device, &mut memory, &commander,
+            if config.shadows { config.shadow_map_size } else { 1 },
+            config.reversed_depth_buffer)?;
 
         let mut params_ubo = HostVisibleBuffer::new::<Params>(
             &device, &mut memory, 1,
@@ -307,8 +724,12 @@ impl Renderer {
 
         // write initial data
         {
+            let (linearize_scale, linearize_offset) = depth_linearization(
+                config.near_plane, config.far_plane, config.reversed_depth_buffer);
             let params = Params {
                 inv_projection: Mat4::identity(),
+                inv_view: Mat4::identity(),
+                shadow_view_projection: Mat4::identity(),
                 dlight_directions: [
                     Default::default(),
                     Default::default() ],
@@ -321,6 +742,25 @@ impl Renderer {
                 ambient: 0.001,
                 white_level: 0.1,
                 tonemapper: Tonemapper::Reinhard,
+                shadow_bias: 0.002,
+                focus_distance: 10.0,
+                aperture: 0.0,
+                star_brightness: 1.0,
+                star_size: if physical_device_features.large_points { 2.0 } else { 1.0 },
+                near_plane: config.near_plane,
+                far_plane: config.far_plane,
+                depth_linearize_scale: linearize_scale,
+                depth_linearize_offset: linearize_offset,
+                exposure: 1.0,
+                prev_inv_projection: Mat4::identity(),
+                prev_inv_view: Mat4::identity(),
+                taa_jitter: [0.0, 0.0],
+                taa_feedback: 0.0,
+                motion_vectors: config.motion_vectors as u32,
+                view_projection: Mat4::identity(),
+                prev_view_projection: Mat4::identity(),
+                background_color: Vec4::new(0.0, 0.0, 0.0, 1.0),
+                alpha_cutoff: 0.5,
             };
             params_ubo.write_one(&params, None)?;
         }
@@ -334,7 +774,9 @@ impl Renderer {
                             binding: 0,
                             descriptor_type: DescriptorType::UniformBuffer,
                             descriptor_count: 1, // just one UBO
-                            stage_flags: ShaderStageFlags::FRAGMENT,
+                            // (compute for the compute blur)
+                            stage_flags: ShaderStageFlags::VERTEX | ShaderStageFlags::FRAGMENT
+                                | ShaderStageFlags::COMPUTE,
                             immutable_samplers: vec![],
                         },
                     ],
@@ -351,64 +793,146 @@ impl Renderer {
             let mut dsets = DescriptorPool::allocate_descriptor_sets(&alloc_info)?;
             let descriptor_set = dsets.pop().unwrap();
 
-            use dacite::core::{OptionalDeviceSize, DescriptorBufferInfo,
-                               WriteDescriptorSet};
-            DescriptorSet::update(
-                Some(&[
-                    WriteDescriptorSet {
-                        dst_set: descriptor_set.clone(),
-                        dst_binding: 0,
-                        dst_array_element: 0, // only have 1 element
-                        descriptor_type: DescriptorType::UniformBuffer,
-                        elements: WriteDescriptorSetElements::BufferInfo(
-                            vec![
-                                DescriptorBufferInfo {
-                                    buffer: params_ubo.inner(),
-                                    offset: 0,
-                                    range: OptionalDeviceSize::Size(
-                                        ::std::mem::size_of::<Params>() as u64
-                                    ),
-                                }
-                            ]
-                        ),
-                        chain: None,
-                    }
-                ]),
-                None
-            );
+            use dacite::core::OptionalDeviceSize;
+            DescriptorSetWriter::new(descriptor_set.clone())
+                .buffer(0, DescriptorType::UniformBuffer, params_ubo.inner(), 0,
+                        OptionalDeviceSize::Size(::std::mem::size_of::<Params>() as u64))
+                .flush();
 
             (layout, descriptor_set)
         };
 
-        let shade_gfx = ShadeGfx::new(&device, descriptor_pool.clone(),
+        // Never beyond what the shading target can hold
+        let hdr_ceiling = {
+            let max = config.shading_format.max_value();
+            config.hdr_ceiling.map(|c| c.min(max)).unwrap_or(max)
+        };
+
+        let shade_gfx = ShadeGfx::new(&device, pipeline_cache.clone(),
+                                      &mut memory, &commander,
+                                      descriptor_pool.clone(),
                                       &target_data,
                                       shading_pass.render_pass.clone(),
                                       viewports[0].clone(), scissors[0].clone(),
                                       params_desc_layout.clone(),
-                                      config.reversed_depth_buffer)?;
-
-        let blur_gfx = BlurGfx::new(&device, descriptor_pool.clone(),
+                                      config.reversed_depth_buffer,
+                                      shadow_pass.depth_image_view.clone(),
+                                      config.shadows,
+                                      config.linear_depth_target,
+                                      hdr_ceiling,
+                                      config.material_packing)?;
+
+        let blur_gfx = BlurGfx::new(&device, &pipeline_cache, descriptor_pool.clone(),
                                     &target_data,
                                     blur_h_pass.render_pass.clone(),
                                     blur_v_pass.render_pass.clone(),
                                     viewports[0].clone(), scissors[0].clone(),
-                                    params_desc_layout.clone())?;
+                                    params_desc_layout.clone(),
+                                    hdr_ceiling,
+                                    config.bloom_quality)?;
+
+        let compute_blur_gfx = if compute_blur {
+            Some(ComputeBlurGfx::new(&device, &pipeline_cache, descriptor_pool.clone(),
+                                     &target_data,
+                                     params_desc_layout.clone(),
+                                     hdr_ceiling,
+                                     config.bloom_quality)?)
+        } else {
+            None
+        };
 
-        let post_gfx = PostGfx::new(&device, descriptor_pool.clone(),
+        let post_gfx = PostGfx::new(&device, pipeline_cache.clone(),
+                                    descriptor_pool.clone(),
                                     &target_data, post_pass.render_pass.clone(),
                                     viewports[0].clone(), scissors[0].clone(),
                                     config.display_luminance,
                                     params_desc_layout.clone(),
-                                    swapchain_data.surface_data.needs_gamma)?;
+                                    swapchain_data.surface_data.needs_gamma,
+                                    config.reversed_depth_buffer,
+                                    config.dof_enabled)?;
+
+        let star_gfx = StarGfx::new(&device, pipeline_cache.clone(),
+                                    transparent_pass.render_pass.clone(),
+                                    viewports[0].clone(), scissors[0].clone(),
+                                    params_desc_layout.clone(),
+                                    config.reversed_depth_buffer)?;
+
+        let debug_line_width = {
+            let range = physical_device_properties.limits.line_width_range;
+            if config.debug_line_width == 1.0 {
+                1.0
+            } else if !physical_device_features.wide_lines {
+                warn!("Wide lines are not supported, drawing debug lines 1.0 wide");
+                1.0
+            } else {
+                config.debug_line_width.max(range[0]).min(range[1])
+            }
+        };
+        let debug_lines_gfx = DebugLinesGfx::new(&device, pipeline_cache.clone(),
+                                                 &mut memory,
+                                                 transparent_pass.render_pass.clone(),
+                                                 viewports[0].clone(), scissors[0].clone(),
+                                                 params_desc_layout.clone(),
+                                                 config.reversed_depth_buffer,
+                                                 debug_line_width)?;
+
+        let exposure_gfx = ExposureGfx::new(&device, &pipeline_cache, &mut memory,
+                                            descriptor_pool.clone(),
+                                            &target_data)?;
+
+        let (taa_pass, taa_gfx) = match target_data.taa_image {
+            Some(ref taa_image) => {
+                let taa_pass = TaaPass::new(&device, taa_image)?;
+                let taa_gfx = TaaGfx::new(&device, pipeline_cache.clone(),
+                                          descriptor_pool.clone(),
+                                          &target_data,
+                                          taa_pass.render_pass.clone(),
+                                          viewports[0].clone(), scissors[0].clone(),
+                                          params_desc_layout.clone(),
+                                          config.reversed_depth_buffer)?;
+                (Some(taa_pass), Some(taa_gfx))
+            },
+            None => (None, None),
+        };
+
+        let viewport_depth_range = ViewportDepthRange::new(
+            viewports[0], ui_viewports[0],
+            Viewport {
+                x: 0.0,
+                y: 0.0,
+                width: shadow_pass.extent.width as f32,
+                height: shadow_pass.extent.height as f32,
+                min_depth: viewports[0].min_depth,
+                max_depth: viewports[0].max_depth,
+            },
+            config.reversed_depth_buffer);
+
+        let scissor_region = ScissorRegion::new(
+            scissors[0].extent, ui_scissors[0].extent, shadow_pass.extent);
 
         Ok(Renderer {
             plugins: Vec::new(),
+            plugin_order: Vec::new(),
+            probes: Vec::new(),
+            shadow_pass: shadow_pass,
             post_gfx: post_gfx,
+            star_gfx: star_gfx,
+            debug_lines_gfx: debug_lines_gfx,
+            exposure_gfx: exposure_gfx,
+            taa_pass: taa_pass,
+            taa_gfx: taa_gfx,
+            taa_phase: 0,
+            taa_prev_matrices: None,
+            prev_view_projection: None,
+            auto_exposure: None,
             blur_gfx: blur_gfx,
+            compute_blur_gfx: compute_blur_gfx,
             shade_gfx: shade_gfx,
             params_desc_set: params_desc_set,
             params_desc_layout: params_desc_layout,
             params_ubo: params_ubo,
+            view: Mat4::identity(),
+            world_light_directions: [None, None],
             ui_pass: ui_pass,
             post_pass: post_pass,
             blur_v_pass: blur_v_pass,
@@ -418,59 +942,137 @@ impl Renderer {
             geometry_pass: geometry_pass,
             target_data: target_data,
             timestamp_query_pool: timestamp_query_pool,
+            plugin_timestamps: plugin_timestamps,
+            viewport_depth_range: viewport_depth_range,
+            scissor_region: scissor_region,
+            plugin_timestamps_written: Vec::new(),
             rendered_fence: rendered_fence,
             image_rendered: image_rendered,
             image_acquired: image_acquired,
             descriptor_pool: descriptor_pool,
+            pipeline_cache: pipeline_cache,
+            desc_set_layout_bindings: Vec::new(),
+            ui_scissors: ui_scissors,
+            ui_viewports: ui_viewports,
             scissors: scissors,
             viewports: viewports,
+            blend_constants: [1.0, 1.0, 1.0, 1.0],
+            resolution_scale: resolution_scale,
+            fixed_render_extent: None,
             staging_buffer: staging_buffer,
             resource_manager: resource_manager,
+            geometry_commands: geometry_commands,
+            transparent_commands: transparent_commands,
+            ui_commands: ui_commands,
             commander: commander,
             present_queue: present_queue,
             swapchain_data: swapchain_data,
-            memory: memory,
+            memory: Arc::new(Mutex::new(memory)),
             device: device,
-            //queue_indices: queue_indices,
+            queue_indices: queue_indices,
             ph_feats: physical_device_features,
+            debug_markers: debug_markers,
             ph_props: physical_device_properties,
             ph: physical_device,
             surface: surface,
             debug_callback: debug_callback,
+            validation_output: validation_output,
+            active_layers: active_layers,
             instance: instance,
             shutdown: shutdown,
             resized: resized,
+            minimized: false,
+            device_lost: false,
             stats: Default::default(),
             window: window,
             config: config
         })
     }
 
+    // A handle for loading assets on another thread (see ResourceLoader)
+    pub fn resource_loader(&self) -> Result<ResourceLoader, Error>
+    {
+        ResourceLoader::new(&self.device, self.memory.clone(), &self.commander,
+                            &self.queue_indices, &self.resource_manager)
+    }
+
     pub fn load_shader(&mut self, name: &str) -> Result<ShaderModule, Error>
     {
         self.resource_manager.load_shader(&self.device, name)
     }
 
+    // Load `meshes/<dir>/<name>.mesh`, with the material in
+    // `<name>.material` beside it if present (see MeshMaterial::parse)
     pub fn load_mesh(&mut self, dir: &str, name: &str) -> Result<VulkanMesh, Error>
     {
         self.resource_manager.load_mesh(
-            &self.device, &mut self.memory, &self.commander,
+            &self.device, &self.memory, &self.commander,
             &mut self.staging_buffer, dir, name)
     }
 
+    // Teach load_mesh() a vertex format: mesh files whose header carries
+    // `vertex_type_id` (a custom id siege-mesh doesn't know, or a built-in
+    // one to override) are deserialized by `deserialize` (given the bytes
+    // after the header) and uploaded as meshes of V.
+    pub fn register_vertex_deserializer<V, F>(&mut self, vertex_type_id: u32,
+                                              deserialize: F)
+        where V: Vertex + 'static,
+              F: Fn(&[u8]) -> Result<Mesh<V>, Error> + Send + Sync + 'static
+    {
+        self.resource_manager.register_vertex_deserializer(vertex_type_id, deserialize);
+    }
+
     pub fn load_texture(&mut self, name: &str) -> Result<ImageWrap, Error>
     {
         self.resource_manager.load_texture(
-            &self.device, &mut self.memory, &self.commander,
+            &self.device, &self.memory, &self.commander,
             &mut self.staging_buffer, name)
     }
 
+    // Load a cubemap from six separate face textures, in the order +X, -X,
+    // +Y, -Y, +Z, -Z (see CUBEMAP_FACE_DIRECTIONS).  Cubemap DDS files can
+    // be loaded with load_texture() instead.
+    pub fn load_cubemap(&mut self, names: [&str; 6]) -> Result<ImageWrap, Error>
+    {
+        self.resource_manager.load_cubemap(
+            &self.device, &self.memory, &self.commander,
+            &mut self.staging_buffer, names)
+    }
+
+    // Create a texture from raw pixel data, which must be tightly packed
+    // in the given format and exactly the size of the texture (whole
+    // blocks for compressed formats); anything else is an error.
+    pub fn create_texture(&mut self, format: Format, extent: Extent2D, data: &[u8])
+                          -> Result<ImageWrap, Error>
+    {
+        self.resource_manager.create_texture(
+            &self.device, &mut self.memory.lock().unwrap(), &self.commander,
+            &mut self.staging_buffer, format, extent, data)
+    }
+
+    // Overwrite part of one mip level and array layer of an existing texture
+    // with raw pixel data, which must be tightly packed in the texture's
+    // format and exactly fill the region (in whole blocks for compressed
+    // formats, whose regions must also start on a block). This avoids
+    // re-uploading a whole texture for small updates.
+    pub fn update_texture_region(&mut self, image: &mut ImageWrap,
+                                 offset: Offset3D, extent: Extent3D,
+                                 mip_level: u32, array_layer: u32,
+                                 data: &[u8])
+                                 -> Result<(), Error>
+    {
+        self.resource_manager.update_texture_region(
+            &self.device, &mut self.memory.lock().unwrap(), &self.commander,
+            &mut self.staging_buffer, image, offset, extent,
+            mip_level, array_layer, data)
+    }
+
     pub fn load_buffer(&mut self,
                        usage: BufferUsageFlags,
                        name: &str) -> Result<DeviceLocalBuffer, Error>
     {
         self.resource_manager.load_buffer(
-            &self.device, &mut self.memory, &self.commander,
+            &self.device, &mut self.memory.lock().unwrap(), &self.commander,
             &mut self.staging_buffer, usage, name)
     }
 
@@ -482,7 +1084,7 @@ impl Renderer {
         -> Result<DeviceLocalBuffer, Error>
     {
         self.resource_manager.make_buffer(
-            &self.device, &mut self.memory, &self.commander,
+            &self.device, &mut self.memory.lock().unwrap(), &self.commander,
             &mut self.staging_buffer, data,
             usage, name)
     }
@@ -496,6 +1098,58 @@ impl Renderer {
         image.get_image_view(&self.device)
     }
 
+    // The linear view-space depth target, if Config::linear_depth_target is
+    // set. It holds the positive distance along the view axis, at the render
+    // extent, and may be sampled (in ShaderReadOnlyOptimal layout) from the
+    // transparent pass onwards. It is replaced on rebuild, so fetch it again
+    // from Plugin::rebuild().
+    pub fn linear_depth_image(&self) -> Option<&ImageWrap> {
+        self.target_data.linear_depth_image.as_ref()
+    }
+
+    // The velocity target, if Config::motion_vectors is set (see
+    // Params::motion_vectors for what it holds).  Like the linear depth
+    // target it may be sampled from the shading pass onwards, e.g. for a
+    // per-object motion blur, and is replaced on rebuild.
+    pub fn velocity_image(&self) -> Option<&ImageWrap> {
+        self.target_data.velocity_image.as_ref()
+    }
+
+    // The entity ID target, if Config::entity_id_target is set.  It is
+    // replaced on rebuild; read it with pick() rather than sampling it.
+    pub fn entity_id_image(&self) -> Option<&ImageWrap> {
+        self.target_data.entity_id_image.as_ref()
+    }
+
+    // The geometry pass color attachment (fragment shader output location)
+    // of the entity ID target, if Config::entity_id_target is set.  Geometry
+    // plugins write a nonzero ID for each object they draw there, usually
+    // from a push constant, and pick() reads it back:
+    //     layout(push_constant) uniform Object { uint id; } object;
+    //     layout(location = N) out uint out_entity_id;  // N from here
+    //     ...
+    //     out_entity_id = object.id;
+    // Zero means nothing was drawn.  The target is an integer format, so it
+    // must not be blended (BlendMode::Off).
+    pub fn entity_id_attachment(&self) -> Option<u32> {
+        if self.target_data.entity_id_image.is_some() {
+            Some(self.geometry_color_attachments() as u32 - 1)
+        } else {
+            None
+        }
+    }
+
+    // The number of color attachments in the geometry pass, which every
+    // geometry pipeline's PipelineSetup::blend must match: 3 (diffuse,
+    // normals, material), plus one each for the velocity and entity ID
+    // targets
+    pub fn geometry_color_attachments(&self) -> usize {
+        let mut count = 3;
+        if self.target_data.velocity_image.is_some() { count += 1; }
+        if self.target_data.entity_id_image.is_some() { count += 1; }
+        count
+    }
+
     pub fn get_buffer_view(&self, buffer: &DeviceLocalBuffer, format: Format)
         -> Result<BufferView, Error>
     {
@@ -506,30 +1160,395 @@ impl Renderer {
         self.swapchain_data.extent
     }
 
+    // The extent that the g-buffers through the blur passes render at.
+    // This is the swapchain extent scaled by the resolution scale, or the
+    // extent given to set_render_extent().
+    pub fn get_render_extent(&self) -> Extent2D {
+        self.target_data.extent
+    }
+
+    pub fn get_resolution_scale(&self) -> f32 {
+        self.resolution_scale
+    }
+
+    // Render the geometry through blur passes at a fraction of the swapchain
+    // resolution (0.5 - 1.0).  The post pass upscales to the full swapchain.
+    pub fn set_resolution_scale(&mut self, scale: f32) -> Result<(), Error> {
+        let scale = if scale < 0.5 {
+            warn!("Resolution scale {} is too small, using 0.5", scale);
+            0.5
+        } else if scale > 1.0 {
+            warn!("Resolution scale {} is too large, using 1.0", scale);
+            1.0
+        } else {
+            scale
+        };
+
+        if scale == self.resolution_scale {
+            return Ok(());
+        }
+        self.resolution_scale = scale;
+
+        if self.fixed_render_extent.is_some() {
+            // Takes effect after reset_render_extent()
+            return Ok(());
+        }
+
+        self.rebuild()
+    }
+
+    // Render the geometry through blur passes at exactly `extent`,
+    // regardless of the window size, until reset_render_extent() is called.
+    // The swapchain stays at the window size, and the post pass upscales to
+    // it.  The extent is clamped to the swapchain extent (now and whenever
+    // the window is resized), since the post pass does not downscale.
+    // This overrides the resolution scale.
+    pub fn set_render_extent(&mut self, extent: Extent2D) -> Result<(), Error> {
+        if extent.width == 0 || extent.height == 0 {
+            return Err(Error::General(
+                format!("Render extent {}x{} is empty", extent.width, extent.height)));
+        }
+        if extent.width > self.swapchain_data.extent.width
+            || extent.height > self.swapchain_data.extent.height
+        {
+            warn!("Render extent {}x{} is larger than the swapchain ({}x{}), clamping",
+                  extent.width, extent.height,
+                  self.swapchain_data.extent.width, self.swapchain_data.extent.height);
+        }
+
+        if self.fixed_render_extent == Some(extent) {
+            return Ok(());
+        }
+        self.fixed_render_extent = Some(extent);
+
+        self.rebuild()
+    }
+
+    // Go back to rendering at the swapchain extent scaled by the resolution
+    // scale
+    pub fn reset_render_extent(&mut self) -> Result<(), Error> {
+        if self.fixed_render_extent.is_none() {
+            return Ok(());
+        }
+        self.fixed_render_extent = None;
+
+        self.rebuild()
+    }
+
+    // The render extent to use with a swapchain of `extent`
+    fn scaled_extent(&self, extent: Extent2D) -> Extent2D {
+        if let Some(fixed) = self.fixed_render_extent {
+            return Extent2D {
+                width: fixed.width.min(extent.width).max(1),
+                height: fixed.height.min(extent.height).max(1),
+            };
+        }
+        Extent2D {
+            width: ((extent.width as f32 * self.resolution_scale) as u32).max(1),
+            height: ((extent.height as f32 * self.resolution_scale) as u32).max(1),
+        }
+    }
+
     pub fn get_viewport(&self) -> Viewport {
         self.viewports[0]
     }
 
+    pub fn get_ui_viewport(&self) -> Viewport {
+        self.ui_viewports[0]
+    }
+
     pub fn ui_needs_gamma(&self) -> bool {
         self.swapchain_data.surface_data.needs_gamma
     }
 
+    // The swapchain image format, which the post and UI passes render to.
+    // Plugins that build their own render passes or pipelines targeting the
+    // swapchain must use this.  It is chosen when the renderer is created
+    // and doesn't change on rebuild.
+    pub fn swapchain_format(&self) -> Format {
+        self.swapchain_data.format()
+    }
+
+    // The color space the swapchain images are presented in, chosen along
+    // with swapchain_format().  With SRGBNonLinear and a UNorm format,
+    // shaders writing to the swapchain apply gamma themselves (see
+    // ui_needs_gamma()).
+    pub fn swapchain_color_space(&self) -> ColorSpaceKhr {
+        self.swapchain_data.color_space()
+    }
+
+    // The present mode in use
+    pub fn present_mode(&self) -> PresentModeKhr {
+        self.swapchain_data.surface_data.present_mode
+    }
+
+    // Every present mode the surface supports (Fifo always is), e.g. to
+    // offer only the vsync choices the hardware has
+    pub fn supported_present_modes(&self) -> Vec<PresentModeKhr> {
+        self.swapchain_data.surface_data.present_modes.clone()
+    }
+
+    // Present with `present_mode`, rebuilding the swapchain.  It must be one
+    // of supported_present_modes().
+    pub fn set_present_mode(&mut self, present_mode: PresentModeKhr) -> Result<(), Error> {
+        if !self.swapchain_data.surface_data.present_modes.contains(&present_mode) {
+            return Err(Error::General(
+                format!("Present mode {:?} is not supported by the surface", present_mode)));
+        }
+
+        if present_mode == self.swapchain_data.surface_data.present_mode {
+            return Ok(());
+        }
+        self.swapchain_data.surface_data.present_mode = present_mode;
+        info!("Present mode: {:?}", present_mode);
+
+        self.rebuild()
+    }
+
+    // Whether presentation waits for vertical blank (Fifo or FifoRelaxed)
+    pub fn vsync(&self) -> bool {
+        match self.swapchain_data.surface_data.present_mode {
+            PresentModeKhr::Fifo | PresentModeKhr::FifoRelaxed => true,
+            _ => false,
+        }
+    }
+
+    // Whether frames can be presented without waiting for vertical blank,
+    // at the cost of tearing (the Immediate present mode)
+    pub fn supports_tearing(&self) -> bool {
+        self.swapchain_data.surface_data.present_modes.contains(&PresentModeKhr::Immediate)
+    }
+
+    // Turn vsync on (Fifo) or off (Immediate, which may tear, or Mailbox if
+    // the surface can't tear), rebuilding the swapchain.  If the surface
+    // supports neither, vsync stays on and a warning is logged.  For finer
+    // control use set_present_mode().
+    //
+    // Config::fps_cap still throttles the render loop either way: with vsync
+    // on, frames come at the lower of the refresh rate and the cap; with it
+    // off, the cap is the only limit (set it to 0 to render as fast as
+    // possible).
+    pub fn set_vsync(&mut self, vsync: bool) -> Result<(), Error> {
+        let present_mode = if vsync {
+            PresentModeKhr::Fifo
+        } else {
+            let present_modes = &self.swapchain_data.surface_data.present_modes;
+            if present_modes.contains(&PresentModeKhr::Immediate) {
+                PresentModeKhr::Immediate
+            } else if present_modes.contains(&PresentModeKhr::Mailbox) {
+                PresentModeKhr::Mailbox
+            } else {
+                warn!("The surface can only present with vsync");
+                PresentModeKhr::Fifo
+            }
+        };
+
+        self.set_present_mode(present_mode)
+    }
+
+    // Whether samplers may use anisotropic filtering (see max_anisotrophy)
     pub fn has_anisotrophy(&self) -> bool {
         self.ph_feats.sampler_anisotropy
     }
 
+    // Whether indirect draws may have a draw_count greater than 1
+    pub fn has_multi_draw_indirect(&self) -> bool {
+        self.ph_feats.multi_draw_indirect
+    }
+
+    // Whether PipelineSetup::depth_clamp may be used
+    pub fn has_depth_clamp(&self) -> bool {
+        self.ph_feats.depth_clamp
+    }
+
+    // Whether PipelineSetup::sample_shading may be used
+    pub fn has_sample_shading(&self) -> bool {
+        self.ph_feats.sample_rate_shading
+    }
+
+    // Whether PipelineSetup::line_width may be other than 1.0
+    pub fn has_wide_lines(&self) -> bool {
+        self.ph_feats.wide_lines
+    }
+
+    // Whether points may be drawn larger than 1 pixel (gl_PointSize).  If
+    // not, stars are drawn 1 pixel across whatever their size.
+    pub fn has_large_points(&self) -> bool {
+        self.ph_feats.large_points
+    }
+
+    pub fn format_properties(&self, format: Format) -> FormatProperties {
+        self.ph.get_format_properties(format)
+    }
+
+    // Whether images of `format` with the given tiling support all of
+    // `features` on this device
+    pub fn is_format_supported(&self, format: Format, tiling: ImageTiling,
+                               features: FormatFeatureFlags) -> bool
+    {
+        let properties = self.ph.get_format_properties(format);
+        match tiling {
+            ImageTiling::Optimal => properties.optimal_tiling_features.contains(features),
+            ImageTiling::Linear => properties.linear_tiling_features.contains(features),
+            _ => false,
+        }
+    }
+
+    // The anisotropy samplers may use: the device limit, capped by
+    // Config::max_anisotropy
     pub fn max_anisotrophy(&self) -> f32 {
         if self.has_anisotrophy() {
             self.ph_props.limits.max_sampler_anisotropy
+                .min(self.config.max_anisotropy)
+                .max(1.0)
         } else {
             1.0
         }
     }
 
+    // A starting PipelineSetup for decals drawn on top of already rendered
+    // surfaces in `pass` (Pass::Geometry for decals that modify the g-buffer,
+    // or Pass::Transparent), to be completed with struct update syntax:
+    //     PipelineSetup { vertex_shader: Some("decal.vert"), ..
+    //                     renderer.decal_pipeline_setup(Pass::Geometry, false)? }
+    //
+    // Depth is tested but not written.  If `coplanar` is true the decal is
+    // drawn with exactly the same vertex positions and transform as the
+    // surface beneath it, so an Equal depth test picks out that surface
+    // without any bias (the vertex shader must compute gl_Position the same
+    // way, or use `invariant`).  Otherwise the decal is separate geometry
+    // lying on the surface, and is depth biased towards the camera, in the
+    // direction that matches Config::reversed_depth_buffer, with a
+    // LessOrEqual (or, reversed, GreaterOrEqual) test.
+    //
+    // Every color attachment is alpha blended; in the geometry pass that is
+    // diffuse, normals and material, so write alpha 0 (or mask channels with
+    // color_write_mask) to leave a g-buffer channel as it is.  The velocity
+    // target (if any) has no alpha channel, so decals must write the motion
+    // of the surface beneath them there.  The entity ID target (if any) is
+    // an integer format and can't be blended, so it is left unblended; mask
+    // it with color_write_mask to keep the ID of the surface beneath.
+    pub fn decal_pipeline_setup(&self, pass: Pass, coplanar: bool)
+                                -> Result<PipelineSetup, Error>
+    {
+        let blend = match pass {
+            Pass::Geometry => {
+                let entity_id = self.entity_id_attachment().map(|a| a as usize);
+                (0..self.geometry_color_attachments())
+                    .map(|i| if Some(i) == entity_id {
+                        BlendMode::Off
+                    } else {
+                        BlendMode::Alpha
+                    }).collect()
+            },
+            Pass::Transparent => vec![BlendMode::Alpha],
+            _ => return Err(Error::General(
+                "Decals are only supported in the geometry and transparent passes"
+                    .to_owned())),
+        };
+
+        let (depth_compare, depth_bias) = if coplanar {
+            (CompareOp::Equal, None)
+        } else {
+            // Pull fragments towards the camera by a couple of depth units
+            // plus the polygon's slope, which covers grazing angles
+            let sign = if self.config.reversed_depth_buffer { 1.0 } else { -1.0 };
+            let compare = if self.config.reversed_depth_buffer {
+                CompareOp::GreaterOrEqual
+            } else {
+                CompareOp::LessOrEqual
+            };
+            (compare, Some(DepthBias {
+                constant_factor: sign * 2.0,
+                slope_factor: sign * 1.0,
+            }))
+        };
+
+        Ok(PipelineSetup {
+            desc_set_layouts: vec![],
+            vertex_shader: None,
+            vertex_shader_spec: None,
+            fragment_shader: None,
+            fragment_shader_spec: None,
+            vertex_type: None,
+            topology: PrimitiveTopology::TriangleList,
+            primitive_restart: false,
+            line_width: 1.0,
+            cull_mode: CullModeFlags::BACK,
+            front_face: FrontFace::CounterClockwise,
+            depth_clamp: false,
+            test_depth: true,
+            write_depth: false,
+            depth_compare: Some(depth_compare),
+            depth_bias: depth_bias,
+            sample_shading: None,
+            blend: blend,
+            color_write_mask: ColorComponentFlags::R | ColorComponentFlags::G
+                | ColorComponentFlags::B | ColorComponentFlags::A,
+            pass: pass,
+            push_constant_ranges: vec![],
+        })
+    }
+
+    // A starting PipelineSetup for alpha cutout geometry (foliage, fences,
+    // chain-link...) in the geometry pass, to be completed with struct
+    // update syntax as for decal_pipeline_setup().
+    //
+    // Cutout geometry is opaque: it writes depth and the g-buffer without
+    // blending, so it shades and sorts like any other surface.  The
+    // fragment shader samples the alpha texture and discards fragments below
+    // the cutoff, which is a fragment push constant at offset 0:
+    //     layout (push_constant) uniform Cutout { float alpha_cutoff; } cutout;
+    //     if (texture(diffuseTex, inUV).a < cutout.alpha_cutoff) discard;
+    // (see examples/shaders/cutout.frag).  Push Params::alpha_cutoff (as
+    // given to Plugin::update) unless a material needs its own; extend the
+    // push constant range for more.  Faces are not culled, since such
+    // geometry is usually single sheets seen from both sides; flip the normal
+    // with gl_FrontFacing.  Shadow pipelines for the same geometry must
+    // discard the same way, or the holes will cast shadows.
+    pub fn cutout_pipeline_setup(&self) -> PipelineSetup
+    {
+        PipelineSetup {
+            desc_set_layouts: vec![],
+            vertex_shader: None,
+            vertex_shader_spec: None,
+            fragment_shader: None,
+            fragment_shader_spec: None,
+            vertex_type: None,
+            topology: PrimitiveTopology::TriangleList,
+            primitive_restart: false,
+            line_width: 1.0,
+            cull_mode: CullModeFlags::NONE,
+            front_face: FrontFace::CounterClockwise,
+            depth_clamp: false,
+            test_depth: true,
+            write_depth: true,
+            depth_compare: None,
+            depth_bias: None,
+            sample_shading: None,
+            blend: (0..self.geometry_color_attachments())
+                .map(|_| BlendMode::Off).collect(),
+            color_write_mask: ColorComponentFlags::R | ColorComponentFlags::G
+                | ColorComponentFlags::B | ColorComponentFlags::A,
+            pass: Pass::Geometry,
+            push_constant_ranges: vec![PushConstantRange {
+                stage_flags: ShaderStageFlags::FRAGMENT,
+                offset: 0,
+                size: ::std::mem::size_of::<f32>() as u32,
+            }],
+        }
+    }
+
     pub fn create_pipeline(&mut self,
                            setup: PipelineSetup)
                            -> Result<(PipelineLayout, Pipeline), Error>
     {
+        self.check_pipeline_setup(&setup)?;
+
+        if self.config.validate_pipelines {
+            self.validate_pipeline_setup(&setup)?;
+        }
+
         let vs = match setup.vertex_shader {
             Some(vs) => Some(self.load_shader(vs)?),
             None => None
@@ -539,93 +1558,1694 @@ impl Renderer {
             None => None
         };
 
-        pipeline::create(
-            &self.device, self.viewports[0].clone(), self.scissors[0].clone(),
-            self.config.reversed_depth_buffer,
-            match setup.pass {
-                Pass::Geometry => self.geometry_pass.render_pass.clone(),
-                Pass::Transparent => self.transparent_pass.render_pass.clone(),
-                Pass::Ui => self.ui_pass.render_pass.clone(),
-            },
-            setup.desc_set_layouts,
-            vs, setup.vertex_shader_spec,
-            fs, setup.fragment_shader_spec,
-            setup.vertex_type, setup.topology, setup.cull_mode, setup.front_face,
-            DepthHandling::Some(setup.test_depth, setup.write_depth),
-            setup.blend,
-            setup.push_constant_ranges)
-    }
-
-    pub fn create_sampler(&mut self,
-                          create_info: SamplerCreateInfo)
-                          -> Result<Sampler, Error>
-    {
-        Ok(self.device.create_sampler(&create_info, None)?)
-    }
-
-    pub fn create_host_visible_buffer<T>(
-        &mut self, count: usize, usage: BufferUsageFlags,
-        lifetime: Lifetime, reason: &str)
-        -> Result<HostVisibleBuffer, Error>
-    {
-        HostVisibleBuffer::new::<T>(
-            &self.device, &mut self.memory,
-            count, usage, lifetime, reason)
-    }
-
-    pub fn create_device_local_buffer<T: Copy>(
-        &mut self, usage: BufferUsageFlags,
-        lifetime: Lifetime, reason: &str, data: &[T])
-        -> Result<DeviceLocalBuffer, Error>
-    {
-        DeviceLocalBuffer::new_uploaded::<T>(
-            &self.device, &mut self.memory, &self.commander,
-            &mut self.staging_buffer, data, usage,
-            lifetime, reason)
+        self.create_pipeline_with_shaders(setup, vs, fs)
     }
 
-    pub fn get_stride<T>(&self, usage: BufferUsageFlags) -> usize
+    // Create a shader module from SPIR-V in memory (e.g. embedded with
+    // include_bytes! or generated at runtime) rather than from a file in the
+    // asset directory.  The module is not cached.
+    pub fn create_shader_module(&self, spirv: &[u8]) -> Result<ShaderModule, Error>
     {
-        self.memory.stride(::std::mem::size_of::<T>(), Some(usage))
-    }
+        use dacite::core::{ShaderModuleCreateFlags, ShaderModuleCreateInfo};
 
-    pub fn create_descriptor_set(&mut self, create_info: DescriptorSetLayoutCreateInfo)
-                                        -> Result<(DescriptorSetLayout, DescriptorSet), Error>
-    {
-        let layout = self.device.create_descriptor_set_layout(&create_info, None)?;
+        if spirv.len() % 4 != 0 {
+            return Err(Error::General(
+                format!("SPIR-V length {} is not a multiple of 4", spirv.len())));
+        }
 
-        let alloc_info = DescriptorSetAllocateInfo {
-            descriptor_pool: self.descriptor_pool.clone(),
-            set_layouts: vec![layout.clone()],
+        let create_info = ShaderModuleCreateInfo {
+            flags: ShaderModuleCreateFlags::empty(),
+            code: spirv.to_vec(),
             chain: None,
         };
-        let mut descriptor_sets = DescriptorPool::allocate_descriptor_sets(&alloc_info)?;
-        let set = descriptor_sets.pop().unwrap();
 
-        Ok((layout, set))
+        Ok(self.device.create_shader_module(&create_info, None)?)
     }
 
-    pub fn plugin(&mut self, plugin: Box<dyn Plugin>) -> Result<(), Error>
+    // As create_pipeline(), but with already-created shader modules (see
+    // create_shader_module()) instead of shader file names, so that pipeline
+    // creation doesn't depend on the asset directory.  The names in `setup`
+    // must be None.  Config::validate_pipelines does not check these
+    // shaders.
+    pub fn create_pipeline_from_modules(&mut self,
+                                        setup: PipelineSetup,
+                                        vertex_shader: Option<ShaderModule>,
+                                        fragment_shader: Option<ShaderModule>)
+                                        -> Result<(PipelineLayout, Pipeline), Error>
     {
-        self.plugins.push(plugin);
-        Ok(())
-    }
+        if setup.vertex_shader.is_some() || setup.fragment_shader.is_some() {
+            return Err(Error::General(
+                "Shader names in the PipelineSetup conflict with the shader modules given"
+                    .to_owned()));
+        }
 
-    pub fn set_params(&mut self, params: &Params) -> Result<(), Error>
-    {
-        self.params_ubo.write_one::<Params>(&params, None)
+        self.check_pipeline_setup(&setup)?;
+
+        self.create_pipeline_with_shaders(setup, vertex_shader, fragment_shader)
     }
 
-    // This will hog the current thread and wont return until the renderer shuts down.
-    pub fn run(&mut self) -> Result<(), Error>
+    // Reject setups the device or topology cannot support
+    fn check_pipeline_setup(&self, setup: &PipelineSetup) -> Result<(), Error>
     {
-        use dacite::core::Error::OutOfDateKhr;
+        if setup.depth_clamp && !self.ph_feats.depth_clamp {
+            return Err(Error::General(
+                "Depth clamp is not supported by this device".to_owned()));
+        }
 
-        self.window.show();
+        if let Some(min_sample_shading) = setup.sample_shading {
+            if !self.ph_feats.sample_rate_shading {
+                return Err(Error::General(
+                    "Sample shading is not supported by this device".to_owned()));
+            }
+            if !(min_sample_shading >= 0.0 && min_sample_shading <= 1.0) {
+                return Err(Error::General(
+                    format!("Invalid minimum sample shading fraction: {}",
+                            min_sample_shading)));
+            }
+        }
+
+        if setup.primitive_restart {
+            match setup.topology {
+                PrimitiveTopology::LineStrip | PrimitiveTopology::TriangleStrip
+                    | PrimitiveTopology::TriangleFan
+                    | PrimitiveTopology::LineStripWithAdjacency
+                    | PrimitiveTopology::TriangleStripWithAdjacency => { },
+                _ => return Err(Error::General(
+                    format!("Primitive restart is not valid with {:?} topology",
+                            setup.topology))),
+            }
+        }
+
+        if setup.line_width != 1.0 {
+            if !self.ph_feats.wide_lines {
+                return Err(Error::General(
+                    "Wide lines are not supported by this device".to_owned()));
+            }
+            let range = self.ph_props.limits.line_width_range;
+            if setup.line_width < range[0] || setup.line_width > range[1] {
+                return Err(Error::General(
+                    format!("Line width {} is outside the supported range {} to {}",
+                            setup.line_width, range[0], range[1])));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn create_pipeline_with_shaders(&mut self,
+                                    setup: PipelineSetup,
+                                    vs: Option<ShaderModule>,
+                                    fs: Option<ShaderModule>)
+                                    -> Result<(PipelineLayout, Pipeline), Error>
+    {
+        let (viewport, scissors) = match setup.pass {
+            Pass::Ui => (self.ui_viewports[0].clone(), self.ui_scissors[0].clone()),
+            _ => (self.viewports[0].clone(), self.scissors[0].clone()),
+        };
+
+        let render_pass = match setup.pass {
+            Pass::Geometry => self.geometry_pass.render_pass.clone(),
+            Pass::Transparent => self.transparent_pass.render_pass.clone(),
+            Pass::Ui => self.ui_pass.render_pass.clone(),
+            // All probe render passes are compatible, so any of them will do
+            Pass::Probe => match self.probes.first() {
+                Some(probe) => probe.render_pass.clone(),
+                None => return Err(Error::General(
+                    "Create a reflection probe before its pipelines".to_owned())),
+            },
+            Pass::Shadow => self.shadow_pass.render_pass.clone(),
+        };
+
+        pipeline::create(
+            &self.device, Some(&self.pipeline_cache), viewport, scissors,
+            self.config.reversed_depth_buffer,
+            render_pass,
+            setup.desc_set_layouts,
+            vs, setup.vertex_shader_spec,
+            fs, setup.fragment_shader_spec,
+            setup.vertex_type, setup.topology, setup.primitive_restart,
+            setup.line_width,
+            setup.cull_mode, setup.front_face,
+            setup.depth_clamp,
+            DepthHandling::Some(setup.test_depth, setup.write_depth,
+                                setup.depth_compare),
+            setup.depth_bias,
+            setup.sample_shading,
+            setup.blend,
+            setup.color_write_mask,
+            setup.push_constant_ranges)
+    }
+
+    // Write the pipeline cache to Config::pipeline_cache_path, which it is
+    // also loaded from at startup.  This happens at shutdown anyway; call it
+    // after creating pipelines to keep them should the program not exit
+    // cleanly.  The file is replaced atomically (via a temporary file beside
+    // it), so a crash while saving leaves the previous cache.
+    pub fn save_pipeline_cache(&self) -> Result<(), Error>
+    {
+        let path = match self.config.pipeline_cache_path {
+            Some(ref path) => path,
+            None => return Err(Error::General(
+                "Saving the pipeline cache requires Config::pipeline_cache_path"
+                    .to_owned())),
+        };
+
+        let data = self.pipeline_cache.get_data()?;
+        let mut temp_path = path.clone().into_os_string();
+        temp_path.push(".tmp");
+        ::std::fs::write(&temp_path, &data)?;
+        ::std::fs::rename(&temp_path, path)?;
+        info!("Saved {} byte pipeline cache to {}", data.len(), path.display());
+
+        Ok(())
+    }
+
+    // Nothing is lost but time if the cache can't be saved, so only warn
+    fn save_pipeline_cache_at_shutdown(&self)
+    {
+        if self.config.pipeline_cache_path.is_some() {
+            if let Err(e) = self.save_pipeline_cache() {
+                warn!("Unable to save the pipeline cache: {}", e);
+            }
+        }
+    }
+
+    // Cross-check the shaders' descriptor bindings and push constants against
+    // the layouts and ranges in the setup, since a mismatch is not caught at
+    // pipeline creation but produces garbage (or worse) when drawing.
+    // Layouts not made by create_descriptor_set() are only checked to exist.
+    fn validate_pipeline_setup(&mut self, setup: &PipelineSetup) -> Result<(), Error>
+    {
+        let shaders = [(setup.vertex_shader, ShaderStageFlags::VERTEX),
+                       (setup.fragment_shader, ShaderStageFlags::FRAGMENT)];
+
+        for &(name, stage) in shaders.iter() {
+            let name = match name {
+                Some(name) => name,
+                None => continue,
+            };
+            let interface = self.resource_manager.shader_interface(name)?;
+
+            for b in &interface.bindings {
+                let layout = match setup.desc_set_layouts.get(b.set as usize) {
+                    Some(layout) => layout,
+                    None => return Err(Error::ShaderLayoutMismatch(format!(
+                        "{} uses set {} but only {} descriptor set layouts were given",
+                        name, b.set, setup.desc_set_layouts.len()))),
+                };
+                let bindings = match self.desc_set_layout_bindings.iter()
+                    .find(|&&(ref l, _)| l == layout)
+                {
+                    Some(&(_, ref bindings)) => bindings,
+                    None => continue,
+                };
+                match bindings.iter().find(|lb| lb.binding == b.binding) {
+                    None => return Err(Error::ShaderLayoutMismatch(format!(
+                        "{} uses set {} binding {}, which the layout lacks",
+                        name, b.set, b.binding))),
+                    Some(lb) if !b.kind.matches(lb.descriptor_type) =>
+                        return Err(Error::ShaderLayoutMismatch(format!(
+                            "{} expects a {:?} at set {} binding {}, but the layout has a {:?}",
+                            name, b.kind, b.set, b.binding, lb.descriptor_type))),
+                    Some(lb) if !lb.stage_flags.contains(stage) =>
+                        return Err(Error::ShaderLayoutMismatch(format!(
+                            "Set {} binding {} is not visible to the {:?} stage of {}",
+                            b.set, b.binding, stage, name))),
+                    Some(_) => { },
+                }
+            }
+
+            // The ranges visible to this stage must cover the whole block
+            if let Some(block) = interface.push_constants {
+                let mut covered = block.start;
+                let mut ranges: Vec<&PushConstantRange> = setup.push_constant_ranges.iter()
+                    .filter(|r| r.stage_flags.contains(stage))
+                    .collect();
+                ranges.sort_by_key(|r| r.offset);
+                for r in ranges {
+                    if r.offset <= covered {
+                        covered = covered.max(r.offset + r.size);
+                    }
+                }
+                if covered < block.end {
+                    return Err(Error::ShaderLayoutMismatch(format!(
+                        "{} reads push constant bytes {}..{}, but the ranges for the {:?} stage only cover up to {}",
+                        name, block.start, block.end, stage, covered)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Set a screen-space ambient occlusion texture that is multiplied into
+    // the ambient term during shading, or None to remove it.
+    pub fn set_ao_texture(&mut self, image: Option<&ImageWrap>) -> Result<(), Error>
+    {
+        let view = match image {
+            Some(image) => Some(image.get_image_view(&self.device)?),
+            None => None
+        };
+
+        // The shade pipeline is rebuilt, so it must not be in use
+        self.commander.wait_idle(&self.device)?;
+        self.shade_gfx.set_ao_image_view(&self.device, view)?;
+
+        // Re-record command buffers (the shade pipeline is new)
+        for i in 0..self.swapchain_data.images.len() {
+            self.record_command_buffer(i)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn geometry_load_ops(&self) -> GeometryLoadOps {
+        self.geometry_pass.load_ops
+    }
+
+    // Choose what the geometry pass does with each attachment's previous
+    // contents (see GeometryLoadOps).  Pipelines made for the geometry pass
+    // remain valid, since load ops don't affect render pass compatibility.
+    pub fn set_geometry_load_ops(&mut self, load_ops: GeometryLoadOps)
+                                 -> Result<(), Error>
+    {
+        if load_ops == self.geometry_pass.load_ops {
+            return Ok(());
+        }
+
+        // The render pass is rebuilt, so it must not be in use
+        self.commander.wait_idle(&self.device)?;
+        self.geometry_pass.set_load_ops(&self.device,
+                                        &self.target_data.depth_image,
+                                        &self.target_data.diffuse_image,
+                                        &self.target_data.normals_image,
+                                        &self.target_data.material_image,
+                                        self.target_data.velocity_image.as_ref(),
+                                        self.target_data.entity_id_image.as_ref(),
+                                        load_ops)?;
+        self.config.geometry_load_ops = load_ops;
+
+        // Re-record command buffers (the render pass is new)
+        for i in 0..self.swapchain_data.images.len() {
+            self.record_command_buffer(i)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn transparent_clear(&self) -> bool {
+        self.transparent_pass.clear
+    }
+
+    // Clear the shading target (to transparent black) at the start of the
+    // transparent pass, so that only the transparent objects are drawn,
+    // e.g. to capture or composite them in isolation (see
+    // Config::transparent_clear).  Pipelines made for the transparent pass
+    // remain valid, since load ops don't affect render pass compatibility.
+    pub fn set_transparent_clear(&mut self, clear: bool) -> Result<(), Error>
+    {
+        if clear == self.transparent_pass.clear {
+            return Ok(());
+        }
+
+        // The render pass is rebuilt, so it must not be in use
+        self.commander.wait_idle(&self.device)?;
+        self.transparent_pass.set_clear(&self.device,
+                                        &self.target_data.depth_image,
+                                        &self.target_data.shading_image,
+                                        clear)?;
+        self.config.transparent_clear = clear;
+
+        // Re-record command buffers (the render pass is new)
+        for i in 0..self.swapchain_data.images.len() {
+            self.record_command_buffer(i)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn material_packing(&self) -> MaterialPacking {
+        self.shade_gfx.material_packing()
+    }
+
+    // Change which channels of the material g-buffer the shading pass reads
+    // roughness, metallicity, ambient occlusion and cavity from, to match
+    // how plugins pack their material textures.
+    pub fn set_material_packing(&mut self, material_packing: MaterialPacking)
+                                -> Result<(), Error>
+    {
+        let channels = material_packing.channels();
+        if channels.iter().any(|&c| c > 3) {
+            return Err(Error::General(
+                format!("Material channels {:?} are not all within 0 - 3", channels)));
+        }
+
+        // The shade pipeline is rebuilt, so it must not be in use
+        self.commander.wait_idle(&self.device)?;
+        self.shade_gfx.set_material_packing(&self.device, material_packing)?;
+
+        // Re-record command buffers (the shade pipeline is new)
+        for i in 0..self.swapchain_data.images.len() {
+            self.record_command_buffer(i)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn output_transfer(&self) -> OutputTransfer {
+        self.post_gfx.output_transfer()
+    }
+
+    // Override the transfer function used to encode the final image for the
+    // display (by default sRGB, matching the swapchain surface).
+    pub fn set_output_transfer(&mut self, output_transfer: OutputTransfer)
+                               -> Result<(), Error>
+    {
+        // The post pipeline is rebuilt, so it must not be in use
+        self.commander.wait_idle(&self.device)?;
+        self.post_gfx.set_output_transfer(&self.device, output_transfer)?;
+
+        // Re-record command buffers (the post pipeline is new)
+        for i in 0..self.swapchain_data.images.len() {
+            self.record_command_buffer(i)?;
+        }
+
+        Ok(())
+    }
+
+    // Create a reflection probe: a cubemap of `size` x `size` faces that is
+    // re-rendered every frame (before the geometry pass) by calling each
+    // plugin's record_probe() once per face.  Returns the probe index (as
+    // passed to record_probe) and the cubemap, which plugins may sample.
+    // See CUBEMAP_FACE_DIRECTIONS for setting up the face view matrices.
+    pub fn create_reflection_probe(&mut self, size: u32)
+                                   -> Result<(usize, ImageWrap), Error>
+    {
+        let max_size = self.ph_props.limits.max_image_dimension_cube;
+        if size == 0 || size > max_size {
+            return Err(Error::General(
+                format!("Reflection probe size {} is not within 1 - {}", size, max_size)));
+        }
+
+        let probe = ProbePass::new(&self.device, &mut self.memory.lock().unwrap(),
+                                   &self.commander,
+                                   size, self.config.reversed_depth_buffer)?;
+        let cubemap = probe.cubemap.clone();
+        self.probes.push(probe);
+
+        // Command buffers must be re-recorded to render the new probe
+        for elem in self.commander.gfx_command_buffer_stale.iter_mut() {
+            *elem=true;
+        }
+
+        Ok((self.probes.len() - 1, cubemap))
+    }
+
+    // Set a prefiltered environment cubemap (roughness mapped across its mip
+    // levels, in world space) and a BRDF lookup table (indexed by N.V and
+    // roughness, giving the Fresnel scale and bias in r and g), which add
+    // image-based specular reflections during shading.  Set
+    // Params::inv_view so reflections can be looked up in world space.
+    pub fn set_environment(&mut self, environment: &ImageWrap, brdf_lut: &ImageWrap)
+                           -> Result<(), Error>
+    {
+        use self::image_wrap::ImageWrapType;
+
+        if environment.image_wrap_type != ImageWrapType::Cubemap {
+            return Err(Error::General("The environment must be a cubemap".to_owned()));
+        }
+
+        let views = (environment.get_image_view(&self.device)?,
+                     brdf_lut.get_image_view(&self.device)?);
+
+        self.update_environment(Some(views))
+    }
+
+    // Remove image-based lighting, leaving only the flat ambient term.
+    pub fn clear_environment(&mut self) -> Result<(), Error>
+    {
+        self.update_environment(None)
+    }
+
+    fn update_environment(&mut self, views: Option<(ImageView, ImageView)>)
+                          -> Result<(), Error>
+    {
+        // The shade pipeline is rebuilt, so it must not be in use
+        self.commander.wait_idle(&self.device)?;
+        self.shade_gfx.set_environment_image_views(&self.device, views)?;
+
+        // Re-record command buffers (the shade pipeline is new)
+        for i in 0..self.swapchain_data.images.len() {
+            self.record_command_buffer(i)?;
+        }
+
+        Ok(())
+    }
+
+    // Anisotropic filtering is clamped to max_anisotrophy(), and turned off
+    // if the device lacks it
+    pub fn create_sampler(&mut self,
+                          mut create_info: SamplerCreateInfo)
+                          -> Result<Sampler, Error>
+    {
+        if create_info.anisotropy_enable {
+            if self.has_anisotrophy() {
+                create_info.max_anisotropy =
+                    create_info.max_anisotropy.min(self.max_anisotrophy());
+            } else {
+                create_info.anisotropy_enable = false;
+                create_info.max_anisotropy = 1.0;
+            }
+        }
+
+        Ok(self.device.create_sampler(&create_info, None)?)
+    }
+
+    pub fn create_host_visible_buffer<T>(
+        &mut self, count: usize, usage: BufferUsageFlags,
+        lifetime: Lifetime, reason: &str)
+        -> Result<HostVisibleBuffer, Error>
+    {
+        HostVisibleBuffer::new::<T>(
+            &self.device, &mut self.memory.lock().unwrap(),
+            count, usage, lifetime, reason)
+    }
+
+    pub fn create_device_local_buffer<T: Copy>(
+        &mut self, usage: BufferUsageFlags,
+        lifetime: Lifetime, reason: &str, data: &[T])
+        -> Result<DeviceLocalBuffer, Error>
+    {
+        DeviceLocalBuffer::new_uploaded::<T>(
+            &self.device, &mut self.memory.lock().unwrap(), &self.commander,
+            &mut self.staging_buffer, data, usage,
+            lifetime, reason)
+    }
+
+    // The distance in bytes between consecutive T's in a buffer with
+    // `usage`: size_of::<T>() rounded up to the device's offset alignment
+    // for uniform, storage and texel buffers (the largest that applies), so
+    // each element can be bound on its own, e.g. as a dynamic uniform buffer
+    // offset.  Vertex, index, indirect and transfer usages don't round, so
+    // for them this is just size_of::<T>().  Buffers made with
+    // HostVisibleBuffer::new and make_buffer are sized with this stride.
+    pub fn get_stride<T>(&self, usage: BufferUsageFlags) -> usize
+    {
+        self.memory.lock().unwrap().stride(::std::mem::size_of::<T>(), Some(usage))
+    }
+
+    // Descriptors come from a single pool sized by the Config::max_*
+    // settings.  Textures can be bound either as CombinedImageSampler (one
+    // sampler per texture, DescriptorSetWriter::image) or, when several
+    // textures share a sampler or one texture is sampled several ways, as
+    // separate SampledImage and Sampler bindings (sampled_image() and
+    // sampler()), combined in GLSL with `sampler2D(image, sampler)`.  By
+    // convention the shared samplers come after the images in the set, as
+    // in the shading pass (g-buffers at bindings 0-3, their sampler at 8).
+    // Plugin pipeline layouts number their sets by how often they change
+    // (see SetFrequency): set 0 per frame, set 1 per material, set 2 per
+    // object, bound with a DescriptorSetBinder.
+    pub fn create_descriptor_set(&mut self, create_info: DescriptorSetLayoutCreateInfo)
+                                        -> Result<(DescriptorSetLayout, DescriptorSet), Error>
+    {
+        use dacite::VulkanObject;
+
+        // Forget (and so release) the layouts whose owners have dropped
+        // them; ours is then the last reference
+        let entries = ::std::mem::replace(&mut self.desc_set_layout_bindings, Vec::new());
+        for (layout, bindings) in entries {
+            if let Err(layout) = layout.try_destroy() {
+                self.desc_set_layout_bindings.push((layout, bindings));
+            }
+        }
+
+        let layout = self.device.create_descriptor_set_layout(&create_info, None)?;
+        self.desc_set_layout_bindings.push((layout.clone(), create_info.bindings.clone()));
+
+        let alloc_info = DescriptorSetAllocateInfo {
+            descriptor_pool: self.descriptor_pool.clone(),
+            set_layouts: vec![layout.clone()],
+            chain: None,
+        };
+        let mut descriptor_sets = DescriptorPool::allocate_descriptor_sets(&alloc_info)?;
+        let set = descriptor_sets.pop().unwrap();
+
+        Ok((layout, set))
+    }
+
+    // The descriptor set holding the Params uniform buffer (at binding 0,
+    // visible to vertex, fragment and compute shaders), for plugins to use
+    // as, or as the first binding of, their per-frame set 0 (see
+    // SetFrequency) instead of keeping a copy of the Params themselves.
+    // Declare the block in GLSL with the same layout as Params.
+    pub fn params_descriptor_set(&self) -> (DescriptorSetLayout, DescriptorSet)
+    {
+        (self.params_desc_layout.clone(), self.params_desc_set.clone())
+    }
+
+    pub fn plugin(&mut self, plugin: Box<dyn Plugin>) -> Result<PluginId, Error>
+    {
+        let (sender, receiver) = channel();
+        self.plugins.push(PluginSlot {
+            plugin: plugin,
+            enabled: true,
+            sender: sender,
+            receiver: receiver,
+        });
+        let id = self.plugins.len() - 1;
+        self.plugin_order.push(id);
+        Ok(id)
+    }
+
+    // The order plugins are recorded in, by id: registration order unless
+    // changed with set_plugin_render_order()
+    pub fn plugin_render_order(&self) -> &[PluginId]
+    {
+        &self.plugin_order
+    }
+
+    // Change the order plugins are recorded in, e.g. to bring an overlay to
+    // the front when a debug mode is switched on.  `order` must list every
+    // plugin id exactly once, from first recorded (drawn underneath, where
+    // blended) to last.  The order applies to every pass (shadow, probe,
+    // geometry, transparent and UI) alike; plugins are still updated in
+    // registration order.
+    pub fn set_plugin_render_order(&mut self, order: &[PluginId]) -> Result<(), Error>
+    {
+        let mut seen = vec![false; self.plugins.len()];
+        for &id in order {
+            match seen.get_mut(id) {
+                Some(seen) if !*seen => *seen = true,
+                Some(_) => return Err(Error::General(format!(
+                    "Plugin {} appears more than once in the render order", id))),
+                None => return Err(Error::General(format!("No plugin with id {}", id))),
+            }
+        }
+        if order.len() != self.plugins.len() {
+            return Err(Error::General(format!(
+                "The render order lists {} of the {} plugins",
+                order.len(), self.plugins.len())));
+        }
+        if order == &self.plugin_order[..] {
+            return Ok(());
+        }
+        self.plugin_order = order.to_vec();
+
+        // mark all command buffers stale
+        for elem in self.commander.gfx_command_buffer_stale.iter_mut() {
+            *elem=true;
+        }
+        self.geometry_commands.mark_stale();
+        self.transparent_commands.mark_stale();
+        self.ui_commands.mark_stale();
+
+        Ok(())
+    }
+
+    // The enabled plugins, in the order they record in
+    fn plugins_in_render_order<'a>(&'a self) -> impl Iterator<Item = &'a PluginSlot> + 'a
+    {
+        let plugins = &self.plugins;
+        self.plugin_order.iter()
+            .map(move |&id| &plugins[id])
+            .filter(|slot| slot.enabled)
+    }
+
+    // Get a sender for passing data (camera state, input, game events...)
+    // from the host application to a plugin, from any thread.  Messages are
+    // delivered in order to Plugin::receive() just before Plugin::update()
+    // each frame (and are held while the plugin is disabled).
+    pub fn plugin_sender(&self, id: PluginId) -> Result<Sender<PluginMessage>, Error>
+    {
+        match self.plugins.get(id) {
+            Some(slot) => Ok(slot.sender.clone()),
+            None => Err(Error::General(format!("No plugin with id {}", id))),
+        }
+    }
+
+    // Disabled plugins keep their resources but are not recorded or updated
+    // (they are still told about rebuilds, so they are ready when enabled).
+    pub fn set_plugin_enabled(&mut self, id: PluginId, enabled: bool) -> Result<(), Error>
+    {
+        let slot = match self.plugins.get_mut(id) {
+            Some(slot) => slot,
+            None => return Err(Error::General(format!("No plugin with id {}", id))),
+        };
+        if slot.enabled == enabled {
+            return Ok(());
+        }
+        slot.enabled = enabled;
+
+        // mark all command buffers stale
+        for elem in self.commander.gfx_command_buffer_stale.iter_mut() {
+            *elem=true;
+        }
+        self.geometry_commands.mark_stale();
+        self.transparent_commands.mark_stale();
+        self.ui_commands.mark_stale();
+
+        Ok(())
+    }
+
+    pub fn is_plugin_enabled(&self, id: PluginId) -> bool
+    {
+        self.plugins.get(id).map(|slot| slot.enabled).unwrap_or(false)
+    }
+
+    // The graphics device in use, as reported by the driver (e.g. for a
+    // settings screen or crash reports)
+    pub fn gpu_name(&self) -> String
+    {
+        self.ph_props.device_name.clone()
+    }
+
+    // Whether the graphics device is integrated, discrete, virtual, etc.
+    pub fn gpu_type(&self) -> PhysicalDeviceType
+    {
+        self.ph_props.device_type
+    }
+
+    pub fn gpu_limits(&self) -> PhysicalDeviceLimits
+    {
+        self.ph_props.limits.clone()
+    }
+
+    // A human-readable dump of the device, formats, swapchain, features,
+    // memory heaps, config and current Params, for bug reports
+    pub fn diagnostics(&self) -> String
+    {
+        use std::fmt::Write;
+        use dacite::core::MemoryHeapFlags;
+
+        // (writing to a String can't fail)
+        let mut s = String::new();
+
+        let api = &self.ph_props.api_version;
+        writeln!(s, "GPU: {} ({:?})", self.ph_props.device_name,
+                 self.ph_props.device_type).unwrap();
+        writeln!(s, "  Vendor ID 0x{:04x}, device ID 0x{:04x}",
+                 self.ph_props.vendor_id, self.ph_props.device_id).unwrap();
+        writeln!(s, "  Vulkan {}.{}.{}, driver version 0x{:08x}",
+                 api.major, api.minor, api.patch,
+                 self.ph_props.driver_version).unwrap();
+
+        writeln!(s, "Formats:").unwrap();
+        let targets = [
+            ("depth", Some(&self.target_data.depth_image)),
+            ("diffuse", Some(&self.target_data.diffuse_image)),
+            ("normals", Some(&self.target_data.normals_image)),
+            ("material", Some(&self.target_data.material_image)),
+            ("shading", Some(&self.target_data.shading_image)),
+            ("linear depth", self.target_data.linear_depth_image.as_ref()),
+            ("velocity", self.target_data.velocity_image.as_ref()),
+            ("entity id", self.target_data.entity_id_image.as_ref()),
+            ("taa", self.target_data.taa_image.as_ref()),
+        ];
+        for &(name, image) in targets.iter() {
+            if let Some(image) = image {
+                writeln!(s, "  {}: {:?} ({}x{})", name, image.format,
+                         image.extent.width, image.extent.height).unwrap();
+            }
+        }
+        writeln!(s, "  swapchain: {:?} in {:?} ({}x{})",
+                 self.swapchain_data.format(), self.swapchain_data.color_space(),
+                 self.swapchain_data.extent.width,
+                 self.swapchain_data.extent.height).unwrap();
+
+        writeln!(s, "Present mode: {:?} with {} swapchain images",
+                 self.swapchain_data.surface_data.present_mode,
+                 self.swapchain_data.images.len()).unwrap();
+        writeln!(s, "Supported present modes: {:?}",
+                 self.swapchain_data.surface_data.present_modes).unwrap();
+        // (its size is logged when it is loaded and saved, as getting it
+        // here would copy the whole cache)
+        match self.config.pipeline_cache_path {
+            Some(ref path) => writeln!(s, "Pipeline cache: {}", path.display()).unwrap(),
+            None => writeln!(s, "Pipeline cache: not saved").unwrap(),
+        }
+
+        let mut extensions = vec!["VK_KHR_swapchain"];
+        if self.debug_markers {
+            extensions.push("VK_EXT_debug_marker");
+        }
+        writeln!(s, "Device extensions: {}", extensions.join(", ")).unwrap();
+        writeln!(s, "Layers: {}", self.active_layers.join(", ")).unwrap();
+        let missing = setup::missing_preferred_features(&self.ph_feats);
+        writeln!(s, "Missing preferred features: {}",
+                 if missing.is_empty() { "none".to_owned() } else { missing.join(", ") })
+            .unwrap();
+
+        writeln!(s, "Memory heaps:").unwrap();
+        {
+            let memory = self.memory.lock().unwrap();
+            for (i, heap) in memory.memory_properties().memory_heaps.iter().enumerate() {
+                writeln!(s, "  {}: {}MB {}", i, heap.size / 1048576,
+                         if heap.flags.contains(MemoryHeapFlags::DEVICE_LOCAL) {
+                             "device local"
+                         } else {
+                             "shared with host"
+                         }).unwrap();
+            }
+        }
+
+        writeln!(s, "{:?}", self.config).unwrap();
+
+        let params = self.params_ubo.as_ptr::<Params>().unwrap();
+        writeln!(s, "{:#?}", params).unwrap();
+
+        s
+    }
+
+    // A handle for plugins to time their own sub-stages on the GPU (see
+    // PluginTimestamps).  Results appear in Stats::plugin_timestamps.
+    pub fn plugin_timestamps(&self) -> PluginTimestamps
+    {
+        self.plugin_timestamps.clone()
+    }
+
+    // A handle for plugins to draw into a narrower viewport depth range
+    // while recording (see ViewportDepthRange).  Pipelines need no changes,
+    // as the viewport is dynamic state.
+    pub fn viewport_depth_range(&self) -> ViewportDepthRange
+    {
+        self.viewport_depth_range.clone()
+    }
+
+    // A handle for plugins to clip their draws to a rectangle while
+    // recording (see ScissorRegion).  Pipelines need no changes, as the
+    // scissor is dynamic state.
+    pub fn scissor_region(&self) -> ScissorRegion
+    {
+        self.scissor_region.clone()
+    }
+
+    // Set the blend constants used by BlendMode::ConstantAlpha and
+    // BlendMode::ConstantColor, e.g. to fade a whole layer in or out.  They
+    // apply to every plugin pass, and are recorded into the command buffers,
+    // so every command buffer is re-recorded; to vary them between draws
+    // instead, call CommandBuffer::set_blend_constants() while recording
+    // (the renderer sets these again at the start of each pass).
+    pub fn set_blend_constants(&mut self, blend_constants: [f32; 4])
+    {
+        if self.blend_constants == blend_constants {
+            return;
+        }
+        self.blend_constants = blend_constants;
+
+        for elem in self.commander.gfx_command_buffer_stale.iter_mut() {
+            *elem = true;
+        }
+        self.geometry_commands.mark_stale();
+        self.transparent_commands.mark_stale();
+        self.ui_commands.mark_stale();
+    }
+
+    pub fn blend_constants(&self) -> [f32; 4]
+    {
+        self.blend_constants
+    }
+
+    // Whether GPU pass timings can be measured on this device.  If not, or
+    // while they are disabled (see set_gpu_timing_enabled), the GPU fields
+    // of Timings are zero and Timings::gpu_available is false.
+    pub fn has_gpu_timings(&self) -> bool
+    {
+        self.timestamp_query_pool.is_some()
+    }
+
+    pub fn gpu_timing_enabled(&self) -> bool
+    {
+        self.config.gpu_timing
+    }
+
+    // Turn GPU pass timing (Config::gpu_timing) on or off.  While off, no
+    // timestamp queries are written or read, plugin timestamps included,
+    // so Stats report zero GPU timings (with Timings::gpu_available false).
+    // Command buffers are re-recorded.
+    pub fn set_gpu_timing_enabled(&mut self, enabled: bool) -> Result<(), Error>
+    {
+        if enabled == self.config.gpu_timing {
+            return Ok(());
+        }
+
+        // Frames in flight may still write the queries
+        self.commander.wait_idle(&self.device)?;
+
+        self.config.gpu_timing = enabled;
+        self.plugin_timestamps.set_enabled(enabled);
+        info!("GPU timing: {}", enabled);
+
+        for i in 0..self.swapchain_data.images.len() {
+            self.record_command_buffer(i)?;
+        }
+
+        Ok(())
+    }
+
+    // The Vulkan layers that were actually enabled.  Layers requested in
+    // the config that are not installed are left out.
+    pub fn active_layers(&self) -> Vec<String>
+    {
+        self.active_layers.clone()
+    }
+
+    // Receive Vulkan validation messages (e.g. to show them in a developer
+    // console).  Messages are only reported if config.vulkan_debug_output
+    // is set, and only at or above config.vulkan_log_level.
+    pub fn set_validation_callback(&mut self, callback: Option<ValidationCallback>)
+    {
+        *self.validation_output.callback.lock().unwrap() = callback;
+    }
+
+    // Switch validation reporting (to the log and the callback) on or off.
+    // The layers themselves stay loaded; they can only be chosen at startup.
+    pub fn enable_validation(&mut self, enabled: bool)
+    {
+        self.validation_output.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    // The timings of the most recently completed frame, unaveraged. This is
+    // a plain copy, cheap enough to call every frame (e.g. to record a
+    // frame-time trace).
+    pub fn latest_timings(&self) -> Timings
+    {
+        self.stats.latest
+    }
+
+    // Choose how the smoothed timings passed to plugins (Stats::smoothed)
+    // are computed
+    pub fn set_stats_smoothing(&mut self, smoothing: SmoothingMode)
+    {
+        self.stats.set_smoothing(smoothing);
+    }
+
+    // Clear the accumulated timings (latest, smoothed and the 60 and 600
+    // frame windows), e.g. once startup spikes have settled, so that
+    // benchmark numbers cover only what follows
+    pub fn reset_stats(&mut self)
+    {
+        self.stats.reset();
+    }
+
+    pub fn frame_index(&self) -> u64
+    {
+        self.stats.frame_index
+    }
+
+    pub fn elapsed(&self) -> Duration
+    {
+        self.stats.elapsed
+    }
+
+    // Free space in each memory chunk, for deciding whether memory is
+    // fragmented (e.g. after an OutOfGraphicsMemory error)
+    pub fn memory_fragmentation(&self) -> Vec<FragmentationReport>
+    {
+        self.memory.lock().unwrap().fragmentation_reports()
+    }
+
+    // Register a handler called when graphics memory runs out, before the
+    // allocation fails with OutOfGraphicsMemory.  It gets a report of what
+    // was being allocated, and may evict cached resources (e.g. drop
+    // textures not in the current scene) and return true to retry the
+    // allocation, or return false to let it fail.  It runs with the memory
+    // allocator locked, possibly on a ResourceLoader thread, so it must not
+    // call the Renderer or create resources, and must not drop anything a
+    // frame in flight still uses.
+    pub fn set_memory_pressure_handler(&mut self, handler: MemoryPressureHandler)
+    {
+        self.memory.lock().unwrap().set_pressure_handler(Some(handler));
+    }
+
+    pub fn clear_memory_pressure_handler(&mut self)
+    {
+        self.memory.lock().unwrap().set_pressure_handler(None);
+    }
+
+    // Reclaim the memory of dropped buffers and textures, returning empty
+    // memory chunks to the driver.  Returns the number of bytes released.
+    // Live resources are not moved, so a chunk with even one left in it is
+    // kept.  This waits for the device to be idle, so call it between scenes
+    // or after a burst of unloading rather than every frame.
+    pub fn release_unused_memory(&mut self) -> Result<u64, Error>
+    {
+        // Dropped resources may still be referenced by frames in flight
+        self.commander.wait_idle(&self.device)?;
+        let released = self.memory.lock().unwrap().release_empty_chunks();
+        if released > 0 {
+            info!("Released {} bytes of graphics memory", released);
+        }
+        Ok(released)
+    }
+
+    // Drop every cached shader, mesh, texture and buffer (see load_shader()
+    // and friends), so that the next load reads the asset again, e.g. after
+    // assets change on disk or the device is lost.  Drop your own handles
+    // to them (and anything built from them, such as descriptor sets) first:
+    // their memory is reclaimed here and will be reused.  Returns the number
+    // of bytes of graphics memory released, as release_unused_memory() does.
+    pub fn clear_caches(&mut self) -> Result<u64, Error>
+    {
+        // Cached resources may still be referenced by frames in flight.  A
+        // lost device runs nothing, so there is nothing to wait for.
+        match self.commander.wait_idle(&self.device) {
+            Ok(()) | Err(Error::DeviceLost) => { },
+            Err(e) => return Err(e),
+        }
+
+        self.resource_manager.clear_all();
+
+        let released = self.memory.lock().unwrap().release_empty_chunks();
+        info!("Cleared resource caches, releasing {} bytes of graphics memory",
+              released);
+        Ok(released)
+    }
+
+    pub fn set_params(&mut self, params: &Params) -> Result<(), Error>
+    {
+        self.params_ubo.write_one::<Params>(&params, None)
+    }
+
+    // Set the world-to-view matrix and its inverse, which is written to
+    // Params::inv_view so that environment lookups and TAA reprojection use
+    // the same view.  Directional lights that were set with
+    // set_directional_light_world() are transformed into view space again.
+    pub fn set_view_matrix(&mut self, view: Mat4<f32>, inv_view: Mat4<f32>)
+                           -> Result<(), Error>
+    {
+        self.view = view;
+        {
+            let params = self.params_ubo.as_ptr::<Params>().unwrap();
+            params.inv_view = inv_view;
+        }
+        for index in 0..self.world_light_directions.len() {
+            self.write_light_direction(index)?;
+        }
+        Ok(())
+    }
+
+    // Set a directional light using a world-space direction (pointing
+    // towards the light).  The shade pass works in view space, so this is
+    // transformed by the view matrix (see set_view_matrix()) now and
+    // whenever the view matrix changes.
+    pub fn set_directional_light_world(&mut self, index: usize,
+                                       world_direction: Vec3<f32>,
+                                       irradiance: Vec4<f32>)
+                                       -> Result<(), Error>
+    {
+        if index >= self.world_light_directions.len() {
+            return Err(Error::General(
+                format!("No directional light {}", index)));
+        }
+
+        self.world_light_directions[index] = Some(Vec4::new(
+            world_direction.x, world_direction.y, world_direction.z, 0.0));
+        self.write_light_direction(index)?;
+
+        let params = self.params_ubo.as_ptr::<Params>().unwrap();
+        params.dlight_irradiances[index] = irradiance;
+        Ok(())
+    }
+
+    fn write_light_direction(&mut self, index: usize) -> Result<(), Error>
+    {
+        if let Some(world_direction) = self.world_light_directions[index] {
+            let params = self.params_ubo.as_ptr::<Params>().unwrap();
+            params.dlight_directions[index] = self.view * world_direction;
+        }
+        Ok(())
+    }
+
+    // Focus the depth-of-field effect (see Config::dof_enabled) at
+    // `focus_distance` (view-space units from the camera).  `aperture` is the
+    // largest blur radius, in render pixels, reached by points far from the
+    // focus plane; 0.0 keeps the image sharp.
+    pub fn set_depth_of_field(&mut self, focus_distance: f32, aperture: f32)
+    {
+        let params = self.params_ubo.as_ptr::<Params>().unwrap();
+        params.focus_distance = focus_distance.max(0.0);
+        params.aperture = aperture.max(0.0);
+    }
+
+    // Set the view-space distances to the projection's near and far planes
+    // (far may be f32::INFINITY), which shaders use to linearize depth.
+    // The projection itself is only known to the renderer through
+    // Params::inv_projection, so call this whenever the projection changes
+    // (near_far_from_projection() can recover the planes from it).
+    pub fn set_near_far(&mut self, near: f32, far: f32) -> Result<(), Error>
+    {
+        if !(near > 0.0) || !(far > near) {
+            return Err(Error::General(
+                format!("Invalid near/far planes: {} / {}", near, far)));
+        }
+
+        let (scale, offset) = depth_linearization(
+            near, far, self.config.reversed_depth_buffer);
+
+        self.config.near_plane = near;
+        self.config.far_plane = far;
+
+        let params = self.params_ubo.as_ptr::<Params>().unwrap();
+        params.near_plane = near;
+        params.far_plane = far;
+        params.depth_linearize_scale = scale;
+        params.depth_linearize_offset = offset;
+        Ok(())
+    }
+
+    pub fn reversed_depth(&self) -> bool
+    {
+        self.config.reversed_depth_buffer
+    }
+
+    // Switch between a reversed depth buffer (1.0 at the near plane, for far
+    // better precision) and a conventional one, as Config::reversed_depth_buffer
+    // would at startup.  The viewports, depth clear values and the renderer's
+    // own pipelines are updated, and plugins are told through
+    // Plugin::on_reversed_depth() so they can recreate the pipelines they made
+    // with create_pipeline() (whose depth compare ops follow the convention).
+    // Every command buffer is re-recorded.
+    pub fn set_reversed_depth(&mut self, reversed_depth_buffer: bool) -> Result<(), Error>
+    {
+        if reversed_depth_buffer == self.config.reversed_depth_buffer {
+            return Ok(());
+        }
+
+        // Pipelines are rebuilt, so they must not be in use
+        self.commander.wait_idle(&self.device)?;
+
+        self.config.reversed_depth_buffer = reversed_depth_buffer;
+        info!("Reversed depth buffer: {}", reversed_depth_buffer);
+
+        let (min_depth, max_depth) = if reversed_depth_buffer { (1.0, 0.0) } else { (0.0, 1.0) };
+        for viewport in self.viewports.iter_mut().chain(self.ui_viewports.iter_mut()) {
+            viewport.min_depth = min_depth;
+            viewport.max_depth = max_depth;
+        }
+        self.viewport_depth_range.set_reversed_depth_buffer(reversed_depth_buffer);
+
+        // Depth clear values
+        self.geometry_pass.depth_clear_value =
+            self.target_data.depth_image.get_clear_value(reversed_depth_buffer);
+        self.shadow_pass.reversed_depth_buffer = reversed_depth_buffer;
+        for probe in self.probes.iter_mut() {
+            probe.reversed_depth_buffer = reversed_depth_buffer;
+        }
+
+        // Depth linearization
+        let (scale, offset) = depth_linearization(
+            self.config.near_plane, self.config.far_plane, reversed_depth_buffer);
+        {
+            let params = self.params_ubo.as_ptr::<Params>().unwrap();
+            params.depth_linearize_scale = scale;
+            params.depth_linearize_offset = offset;
+        }
+
+        // Our pipelines
+        self.shade_gfx.set_reversed_depth_buffer(&self.device, reversed_depth_buffer)?;
+        self.post_gfx.set_reversed_depth_buffer(&self.device, reversed_depth_buffer)?;
+        self.star_gfx.set_reversed_depth_buffer(&self.device, reversed_depth_buffer)?;
+        self.debug_lines_gfx.set_reversed_depth_buffer(&self.device, reversed_depth_buffer)?;
+        if let Some(ref mut taa_gfx) = self.taa_gfx {
+            taa_gfx.set_reversed_depth_buffer(&self.device, reversed_depth_buffer)?;
+        }
+
+        // Plugin pipelines
+        for slot in &mut self.plugins {
+            slot.plugin.on_reversed_depth(reversed_depth_buffer)?;
+        }
+
+        // Re-record command buffers (the pipelines are new)
+        for i in 0..self.swapchain_data.images.len() {
+            self.record_command_buffer(i)?;
+        }
+
+        Ok(())
+    }
+
+    // Recover the (near, far) plane distances from a perspective projection
+    // matrix mapping view-space depth to Vulkan's 0..1 clip depth (near to
+    // 0, far to 1), regardless of handedness.  An infinite far plane gives
+    // f32::INFINITY.  Depth reversal is done by the viewport, so
+    // projections are never reversed.
+    pub fn near_far_from_projection(projection: &Mat4<f32>) -> (f32, f32)
+    {
+        // Only the z and w rows matter: clip z = a*z + b, clip w = c*z + d
+        let at_0 = *projection * Vec4::new(0.0, 0.0, 0.0, 1.0);
+        let at_1 = *projection * Vec4::new(0.0, 0.0, 1.0, 1.0);
+        let (b, d) = (at_0.z, at_0.w);
+        let (a, c) = (at_1.z - b, at_1.w - d);
+
+        // Clip depth 0: a*z + b = 0.  Clip depth 1: a*z + b = c*z + d
+        let near = (-b / a).abs();
+        let far = if a == c {
+            ::std::f32::INFINITY
+        } else {
+            ((d - b) / (a - c)).abs()
+        };
+        (near, far)
+    }
+
+    // Draw a starfield mesh (loaded from a Star mesh file with load_mesh)
+    // into the transparent pass, behind all opaque geometry, or None to
+    // remove it.  Stars are drawn only if the transparent pass is enabled.
+    pub fn set_starfield(&mut self, mesh: Option<VulkanMesh>) -> Result<(), Error>
+    {
+        // Command buffers reference the old mesh, so they must not be in use
+        self.commander.wait_idle(&self.device)?;
+        self.star_gfx.set_mesh(mesh);
+
+        for i in 0..self.swapchain_data.images.len() {
+            self.record_command_buffer(i)?;
+        }
+
+        Ok(())
+    }
+
+    // Scale the starfield's irradiance by `brightness`, and draw each star
+    // as a round sprite `size` render pixels across.
+    pub fn set_star_params(&mut self, brightness: f32, size: f32)
+    {
+        let params = self.params_ubo.as_ptr::<Params>().unwrap();
+        params.star_brightness = brightness.max(0.0);
+        params.star_size = if self.has_large_points() {
+            size.max(1.0).min(self.ph_props.limits.point_size_range[1])
+        } else {
+            1.0
+        };
+    }
+
+    // Fill the background, where no geometry was drawn, with `color` (see
+    // Params::background_color) instead of black
+    pub fn set_background_color(&mut self, color: Vec3<f32>)
+    {
+        let params = self.params_ubo.as_ptr::<Params>().unwrap();
+        params.background_color = Vec4::new(color.x.max(0.0), color.y.max(0.0),
+                                            color.z.max(0.0), 1.0);
+    }
+
+    // Multiply scene luminance by `exposure` before tonemapping, and turn
+    // auto-exposure off.
+    pub fn set_exposure(&mut self, exposure: f32) -> Result<(), Error>
+    {
+        if self.auto_exposure.is_some() {
+            // Command buffers record the measurement, so they must not be in use
+            self.commander.wait_idle(&self.device)?;
+            self.auto_exposure = None;
+
+            for i in 0..self.swapchain_data.images.len() {
+                self.record_command_buffer(i)?;
+            }
+        }
+
+        let params = self.params_ubo.as_ptr::<Params>().unwrap();
+        params.exposure = exposure.max(0.0);
+        Ok(())
+    }
+
+    // Adapt the exposure every frame, like an eye does, so that the scene's
+    // average luminance lands at middle grey (0.18) shifted by `target_ev`
+    // stops (positive is brighter).  The average is the geometric mean of
+    // the shading image, measured on the GPU and applied one frame later.
+    // `adapt_speed` is the rate (per second) at which the exposure closes
+    // the gap to its target, in stops: at 1.0 it closes about 63% of the gap
+    // each second.  Call set_exposure() to turn auto-exposure off.  While it
+    // is on, changes to Params::exposure are overwritten.
+    pub fn set_auto_exposure(&mut self, target_ev: f32, adapt_speed: f32)
+                             -> Result<(), Error>
+    {
+        let was_on = self.auto_exposure.is_some();
+        self.auto_exposure = Some((target_ev, adapt_speed.max(0.0)));
+
+        if !was_on {
+            // Command buffers must not be in use while re-recording
+            self.commander.wait_idle(&self.device)?;
+
+            for i in 0..self.swapchain_data.images.len() {
+                self.record_command_buffer(i)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The exposure in effect (the latest adapted value under auto-exposure)
+    pub fn exposure(&self) -> f32
+    {
+        self.params_ubo.as_ptr::<Params>().unwrap().exposure
+    }
+
+    // Move the exposure toward the auto-exposure target, given the time
+    // since the last frame.  The measurement from the frame just rendered
+    // is read, so this must be called after its fence has signalled.
+    fn adapt_exposure(&mut self, elapsed: Duration)
+    {
+        let (target_ev, adapt_speed) = match self.auto_exposure {
+            Some(auto_exposure) => auto_exposure,
+            None => return,
+        };
+
+        let seconds = elapsed.as_secs() as f32
+            + elapsed.subsec_nanos() as f32 * 0.000_000_001;
+
+        // Work in stops, so that brightening and darkening adapt alike
+        let target = target_ev + 0.18_f32.log2()
+            - self.exposure_gfx.average_log_luminance();
+        let params = self.params_ubo.as_ptr::<Params>().unwrap();
+        let current = params.exposure.max(0.000_001).log2();
+        let t = 1.0 - (-seconds * adapt_speed).exp();
+        params.exposure = (current + (target - current) * t).exp2();
+    }
+
+    // Replace the debug lines drawn at the end of the transparent pass
+    // (after plugins' transparent geometry, depth tested against the opaque
+    // scene).  Each consecutive pair of vertices is one world-space line;
+    // vertex normals are ignored and colors are luminance.  At most
+    // DEBUG_LINE_VERTEX_COUNT vertices are drawn, and an empty slice clears
+    // the lines.  Command buffers are not re-recorded, so this is cheap
+    // enough to call every frame, but it must not be called while a frame
+    // is in flight that still draws the previous lines (e.g. call it before
+    // render() rather than during plugin callbacks).  Lines are drawn only
+    // if the transparent pass is enabled; their width is
+    // Config::debug_line_width.
+    pub fn set_debug_lines(&mut self, vertices: &[ColoredVertex]) -> Result<(), Error>
+    {
+        if vertices.len() % 2 != 0 {
+            return Err(Error::General(
+                "Debug lines need an even number of vertices".to_owned()));
+        }
+
+        let count = self.debug_lines_gfx.set_lines(vertices)?;
+        if count < vertices.len() {
+            warn!("Too many debug line vertices ({}), drawing the first {}",
+                  vertices.len(), count);
+        }
+        Ok(())
+    }
+
+    // Render the scene through the post pass into `target` instead of the
+    // swapchain, blocking until it is done. The ui pass is not included.
+    //
+    // The target must be a Standard image with COLOR_ATTACHMENT usage (and
+    // SAMPLED usage to be useful) in the swapchain's format, since the post
+    // pipeline is built for it; its contents are encoded as for the swapchain
+    // (see ui_needs_gamma()). The scene is rendered at the current render
+    // extent and resampled to the target's extent, so match their aspect
+    // ratios. The target is left in ShaderReadOnlyOptimal layout.
+    pub fn render_to_texture(&mut self, target: &ImageWrap) -> Result<(), Error>
+    {
+        use dacite::core::{FramebufferCreateInfo, FramebufferCreateFlags,
+                           ImageUsageFlags,
+                           OptionalMipLevels, OptionalArrayLayers,
+                           ImageSubresourceRange};
+        use self::image_wrap::ImageWrapType;
+
+        if self.device_lost {
+            return Err(Error::DeviceLost);
+        }
+        if target.format != self.swapchain_data.format() {
+            return Err(Error::General(format!(
+                "Render texture format {:?} must match the swapchain format {:?}",
+                target.format, self.swapchain_data.format())));
+        }
+        if target.image_wrap_type != ImageWrapType::Standard
+            || !target.usage.contains(ImageUsageFlags::COLOR_ATTACHMENT)
+        {
+            return Err(Error::General(
+                "Render texture must be a standard image usable as a color attachment"
+                    .to_owned()));
+        }
+
+        let extent = Extent2D {
+            width: target.extent.width,
+            height: target.extent.height
+        };
+        let framebuffer = self.device.create_framebuffer(&FramebufferCreateInfo {
+            flags: FramebufferCreateFlags::empty(),
+            render_pass: self.post_pass.render_pass.clone(),
+            attachments: vec![target.get_image_view(&self.device)?],
+            width: extent.width,
+            height: extent.height,
+            layers: 1,
+            chain: None,
+        }, None)?;
+
+        // The targets may still be in use by the frame in flight
+        self.commander.wait_idle(&self.device)?;
+
+        let command_buffer = self.commander.begin_oneshot()?;
+
+        let range = ImageSubresourceRange {
+            aspect_mask: ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: OptionalMipLevels::MipLevels(1),
+            base_array_layer: 0,
+            layer_count: OptionalArrayLayers::ArrayLayers(1),
+        };
+        let mut target = target.clone();
+        target.transition_layout(
+            command_buffer.clone(),
+            ImageLayout::Undefined, ImageLayout::ColorAttachmentOptimal,
+            AccessFlags::empty(), AccessFlags::COLOR_ATTACHMENT_WRITE,
+            PipelineStageFlags::TOP_OF_PIPE, PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            range.clone())?;
+
+        // Timestamp queries are only reset in the per-frame command buffers,
+        // so we can't write them here.  The plugin pass command buffers of
+        // swapchain image 0 are borrowed, re-recorded without their plugin
+        // timestamps, and re-recorded again (with them) for its next frame.
+        let timestamp_query_pool = self.timestamp_query_pool.take();
+        self.geometry_commands.mark_image_stale(0);
+        self.transparent_commands.mark_image_stale(0);
+        let recorded = self.record_scene(&command_buffer, 0);
+        self.geometry_commands.mark_image_stale(0);
+        self.transparent_commands.mark_image_stale(0);
+        self.commander.gfx_command_buffer_stale[0] = true;
+        self.timestamp_query_pool = timestamp_query_pool;
+        recorded?;
+
+        self.target_data.transition_for_post(command_buffer.clone())?;
+
+        let mut viewport = self.ui_viewports[0];
+        viewport.width = extent.width as f32;
+        viewport.height = extent.height as f32;
+        command_buffer.set_viewport(0, &[viewport]);
+        command_buffer.set_scissor(0, &[Rect2D {
+            offset: Offset2D { x: 0, y: 0 },
+            extent: extent,
+        }]);
+
+        self.post_pass.record_entry_framebuffer(command_buffer.clone(),
+                                                &framebuffer, extent);
+        self.post_gfx.record(command_buffer.clone(),
+                             self.params_desc_set.clone());
+        self.post_pass.record_exit(command_buffer.clone());
+
+        self.target_data.transition_after_post(command_buffer.clone())?;
+
+        target.transition_layout(
+            command_buffer.clone(),
+            ImageLayout::ColorAttachmentOptimal, ImageLayout::ShaderReadOnlyOptimal,
+            AccessFlags::COLOR_ATTACHMENT_WRITE, AccessFlags::SHADER_READ,
+            PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, PipelineStageFlags::FRAGMENT_SHADER,
+            range)?;
+
+        self.commander.submit_oneshot(&self.device, command_buffer)?;
+
+        Ok(())
+    }
+
+    // Run one-off GPU work outside the render loop (e.g. a blit, clear or
+    // compute dispatch to initialize a texture): `record` records it into a
+    // fresh primary command buffer, which is then submitted to the graphics
+    // queue.  This returns once the GPU has finished it.  Record any
+    // barriers the work needs, and leave images in the layouts they are
+    // used in.  The frames in flight are not waited for.
+    pub fn submit_oneshot<F>(&mut self, record: F) -> Result<(), Error>
+        where F: FnOnce(CommandBuffer)
+    {
+        if self.device_lost {
+            return Err(Error::DeviceLost);
+        }
+
+        let command_buffer = self.commander.begin_oneshot()?;
+        record(command_buffer.clone());
+        self.commander.submit_oneshot(&self.device, command_buffer)
+    }
+
+    // Read back the depth buffer over `region` (in render pixels, i.e. the
+    // window size times the resolution scale), row by row.  Values are raw
+    // depths as written by the geometry and transparent passes (1.0 is near
+    // with a reversed depth buffer); unproject them with the inverse
+    // projection matrix for view-space positions.
+    // This waits for the device to be idle, so it is for occasional queries
+    // such as mouse picking, not for every frame.
+    pub fn read_depth(&mut self, region: Rect2D) -> Result<Vec<f32>, Error>
+    {
+        if self.device_lost {
+            return Err(Error::DeviceLost);
+        }
+        let extent = self.target_data.extent;
+        if region.offset.x < 0 || region.offset.y < 0
+            || region.extent.width == 0 || region.extent.height == 0
+            || region.offset.x as u32 + region.extent.width > extent.width
+            || region.offset.y as u32 + region.extent.height > extent.height
+        {
+            return Err(Error::General(format!(
+                "Depth region {:?} is outside the {}x{} render extent",
+                region, extent.width, extent.height)));
+        }
+
+        // Between frames, the depth buffer is left in
+        // DepthStencilAttachmentOptimal (see transition_after_post)
+        let mut sources = [ReadbackSource {
+            image: self.target_data.depth_image.clone(),
+            aspect: ImageAspectFlags::DEPTH,
+            layout: ImageLayout::DepthStencilAttachmentOptimal,
+            access: AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                | AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            stages: PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                | PipelineStageFlags::LATE_FRAGMENT_TESTS,
+        }];
+        let data = self.read_back_image_region(&mut sources, region)?.pop().unwrap();
+
+        Ok(data.chunks(4)
+           .map(|c| f32::from_ne_bytes([c[0], c[1], c[2], c[3]]))
+           .collect())
+    }
+
+    // The entity ID drawn at render pixel (x, y) in the last frame (see
+    // entity_id_attachment), or None where nothing with an ID was drawn.
+    // Like read_depth this waits for the device to be idle, so it is meant
+    // for mouse clicks, not every frame.
+    pub fn pick(&mut self, x: u32, y: u32) -> Result<Option<u32>, Error>
+    {
+        if self.device_lost {
+            return Err(Error::DeviceLost);
+        }
+        let entity_id_image = match self.target_data.entity_id_image {
+            Some(ref image) => image.clone(),
+            None => return Err(Error::General(
+                "Picking requires Config::entity_id_target".to_owned())),
+        };
+        let extent = self.target_data.extent;
+        if x >= extent.width || y >= extent.height {
+            return Err(Error::General(format!(
+                "Pick position ({}, {}) is outside the {}x{} render extent",
+                x, y, extent.width, extent.height)));
+        }
+
+        // Between frames, the entity ID target is left in
+        // ColorAttachmentOptimal
+        let mut sources = [ReadbackSource {
+            image: entity_id_image,
+            aspect: ImageAspectFlags::COLOR,
+            layout: ImageLayout::ColorAttachmentOptimal,
+            access: AccessFlags::COLOR_ATTACHMENT_WRITE,
+            stages: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        }];
+        let region = Rect2D {
+            offset: Offset2D { x: x as i32, y: y as i32 },
+            extent: Extent2D { width: 1, height: 1 },
+        };
+        let data = self.read_back_image_region(&mut sources, region)?.pop().unwrap();
+
+        let id = u32::from_ne_bytes([data[0], data[1], data[2], data[3]]);
+        Ok(if id == 0 { None } else { Some(id) })
+    }
+
+    // Read back the g-buffers and depth buffer of the last frame rendered,
+    // texel for texel in their own formats, for debugging shading offline
+    // (e.g. why a surface shades black).  The g-buffers keep what the
+    // geometry pass wrote until the next frame begins; depth also has what
+    // the transparent pass wrote.  This waits for the device to be idle.
+    pub fn capture_gbuffer(&mut self) -> Result<GBufferCapture, Error>
+    {
+        if self.device_lost {
+            return Err(Error::DeviceLost);
+        }
+        let extent = self.target_data.extent;
+
+        // Each image, with the layout, access and stages it is left in
+        // between frames (see transition_for_shading and
+        // transition_after_post)
+        let gbuffer = |image: &ImageWrap| ReadbackSource {
+            image: image.clone(),
+            aspect: ImageAspectFlags::COLOR,
+            layout: ImageLayout::ShaderReadOnlyOptimal,
+            access: AccessFlags::SHADER_READ,
+            stages: PipelineStageFlags::FRAGMENT_SHADER,
+        };
+        let mut sources = [
+            gbuffer(&self.target_data.diffuse_image),
+            gbuffer(&self.target_data.normals_image),
+            gbuffer(&self.target_data.material_image),
+            ReadbackSource {
+                image: self.target_data.depth_image.clone(),
+                aspect: ImageAspectFlags::DEPTH,
+                layout: ImageLayout::DepthStencilAttachmentOptimal,
+                access: AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                    | AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                stages: PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                    | PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            },
+        ];
+        let region = Rect2D {
+            offset: Offset2D { x: 0, y: 0 },
+            extent: extent,
+        };
+        let data = self.read_back_image_region(&mut sources, region)?;
+
+        let mut images = sources.iter().zip(data.into_iter())
+            .map(|(source, data)| CapturedImage {
+                format: source.image.format,
+                extent: extent,
+                data: data,
+            });
+        Ok(GBufferCapture {
+            diffuse: images.next().unwrap(),
+            normals: images.next().unwrap(),
+            material: images.next().unwrap(),
+            depth: images.next().unwrap(),
+        })
+    }
+
+    // Copy `region` (in render pixels) of each source image to the host,
+    // in one submission, returning each one's texels tightly packed in its
+    // own format, top row first.  Each image is transitioned to
+    // TransferSrcOptimal for the copy and then back as its source
+    // describes.  This waits for the device to be idle.
+    fn read_back_image_region(&mut self, sources: &mut [ReadbackSource], region: Rect2D)
+                              -> Result<Vec<Vec<u8>>, Error>
+    {
+        use dacite::core::{OptionalMipLevels, OptionalArrayLayers,
+                           ImageSubresourceRange, ImageSubresourceLayers,
+                           BufferImageCopy};
+
+        let mut buffers: Vec<(HostVisibleBuffer, usize)> = Vec::new();
+        for source in sources.iter() {
+            let texel_size = match crate::format::texel_size(source.image.format) {
+                Some(size) => size,
+                None => return Err(Error::General(format!(
+                    "Cannot read back format {:?}", source.image.format))),
+            };
+            let size = (region.extent.width * region.extent.height * texel_size) as usize;
+            let buffer = HostVisibleBuffer::new::<u8>(
+                &self.device, &mut self.memory.lock().unwrap(), size,
+                BufferUsageFlags::TRANSFER_DST, Lifetime::Temporary,
+                "Image Readback")?;
+            buffers.push((buffer, size));
+        }
+
+        self.commander.wait_idle(&self.device)?;
+
+        let command_buffer = self.commander.begin_oneshot()?;
+
+        for (source, &(ref buffer, _)) in sources.iter_mut().zip(buffers.iter()) {
+            let range = ImageSubresourceRange {
+                aspect_mask: source.aspect,
+                base_mip_level: 0,
+                level_count: OptionalMipLevels::MipLevels(1),
+                base_array_layer: 0,
+                layer_count: OptionalArrayLayers::ArrayLayers(1),
+            };
+            source.image.transition_layout(
+                command_buffer.clone(),
+                source.layout, ImageLayout::TransferSrcOptimal,
+                source.access, AccessFlags::TRANSFER_READ,
+                source.stages, PipelineStageFlags::TRANSFER,
+                range.clone())?;
+
+            command_buffer.copy_image_to_buffer(
+                &source.image.image, ImageLayout::TransferSrcOptimal,
+                &buffer.inner(),
+                &[BufferImageCopy {
+                    buffer_offset: 0,
+                    buffer_row_length: 0, // tightly packed
+                    buffer_image_height: 0,
+                    image_subresource: ImageSubresourceLayers {
+                        aspect_mask: source.aspect,
+                        mip_level: 0,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    },
+                    image_offset: Offset3D { x: region.offset.x, y: region.offset.y, z: 0 },
+                    image_extent: Extent3D {
+                        width: region.extent.width,
+                        height: region.extent.height,
+                        depth: 1
+                    },
+                }]);
+
+            source.image.transition_layout(
+                command_buffer.clone(),
+                ImageLayout::TransferSrcOptimal, source.layout,
+                AccessFlags::TRANSFER_READ, source.access,
+                PipelineStageFlags::TRANSFER, source.stages,
+                range)?;
+        }
+
+        self.commander.submit_oneshot(&self.device, command_buffer)?;
+
+        let mut data = Vec::new();
+        for &(ref buffer, size) in buffers.iter() {
+            // The device wrote it; make that visible to us
+            buffer.invalidate()?;
+            data.push(match buffer.as_ptr::<u8>() {
+                Some(p) => unsafe {
+                    ::std::slice::from_raw_parts(p as *const u8, size).to_vec()
+                },
+                None => return Err(Error::MemoryNotHostWritable),
+            });
+        }
+
+        Ok(data)
+    }
+
+    // This will hog the current thread and wont return until the renderer shuts down.
+    //
+    // If the graphics device is lost (e.g. a driver reset), this returns
+    // Error::DeviceLost.  The renderer cannot recover from that; drop it and
+    // create a new Renderer (plugins will need to recreate their resources).
+    pub fn run(&mut self) -> Result<(), Error>
+    {
+        if self.device_lost {
+            return Err(Error::DeviceLost);
+        }
+
+        match self.run_loop() {
+            Err(Error::DeviceLost) => {
+                error!("Graphics device lost");
+                self.device_lost = true;
+                Err(Error::DeviceLost)
+            },
+            other => other
+        }
+    }
+
+    // Block until every upload (load_texture, load_mesh, make_buffer, ...)
+    // has finished on the GPU.  Each of these currently waits for its own
+    // transfer, so this is cheap, but it is the synchronization point to
+    // rely on before rendering with freshly loaded resources.
+    pub fn wait_for_uploads(&mut self) -> Result<(), Error>
+    {
+        let _queues = self.commander.lock_queues();
+        self.commander.xfr_queue.wait_idle()?;
+        Ok(())
+    }
+
+    fn run_loop(&mut self) -> Result<(), Error>
+    {
+        use dacite::core::Error::OutOfDateKhr;
+
+        // The first frames must not reference resources still in transit
+        self.wait_for_uploads()?;
+
+        self.window.show();
         for i in 0..self.swapchain_data.images.len() {
             self.record_command_buffer(i)?;
         }
-        self.memory.log_usage();
+        self.memory.lock().unwrap().log_usage();
 
         let mut framenumber: u64 = 0;
 
@@ -635,16 +3255,33 @@ impl Renderer {
             Duration::new(0, 0)
         };
 
-        let mut timings_60 = Timings::new();
-        let mut timings_600 = Timings::new();
-
+        let run_start: Instant = Instant::now();
         let mut last_loop_start: Instant;
-        let mut loop_start: Instant = Instant::now();
+        let mut loop_start: Instant = run_start;
         loop {
             last_loop_start = loop_start;
             loop_start = Instant::now();
             let looptime_1 = loop_start.duration_since(last_loop_start);
 
+            // While the window is minimized its surface has no size, so we
+            // can't build a swapchain.  Pause until it is restored.
+            if self.minimized {
+                if self.shutdown.load(Ordering::Relaxed) {
+                    info!("Graphics is shutting down...");
+                    self.save_pipeline_cache_at_shutdown();
+                    self.window.hide();
+                    return Ok(());
+                }
+                if self.surface_is_minimized()? {
+                    ::std::thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+                self.minimized = false;
+                self.rebuild()?;
+                self.resized.store(false, Ordering::Relaxed);
+                continue;
+            }
+
             // On windows (at least, perhaps also elsewhere), vulkan won't give us an
             // OutOfDateKhr error on a window resize.  But the window will remain black
             // after resizing.  We have to detect resizes and rebuild the swapchain.
@@ -655,10 +3292,13 @@ impl Renderer {
             }
 
             // Be sure any outstanding memory transfers are completed.
-            self.memory.flush()?;
+            self.memory.lock().unwrap().flush()?;
+
+            self.update_motion_params();
+            self.update_taa_params();
 
             // Issue the commands to render a frame (this does not wait)
-            let present_image = match self.start_render() {
+            let present_image = match self.start_render(framenumber) {
                 Err(e) => {
                     if let Error::Dacite(OutOfDateKhr) = e {
                         // Rebuild the swapchain if Vulkan complains that it is out of date.
@@ -675,13 +3315,35 @@ impl Renderer {
                 Ok(i) => i
             };
 
+            // Give plugins a consistent time source for this frame
+            self.stats.update_frame(framenumber, loop_start.duration_since(run_start));
+
             // Update plugins. If any of them needs a re-record, we mark all of the
-            // command buffers as stale.
+            // command buffers as stale, along with the plugin command buffers of
+            // the passes that changed.
             let mut need_rerecord = false;
-            for plugin in &mut self.plugins {
+            let (mut geometry, mut transparent, mut ui) = (false, false, false);
+            let ui_before_post = self.config.enable_transparent_pass;
+            for slot in self.plugins.iter_mut().filter(|slot| slot.enabled) {
+                while let Ok(message) = slot.receiver.try_recv() {
+                    slot.plugin.receive(message)?;
+                }
                 let params = self.params_ubo.as_ptr::<Params>().unwrap();
-                if plugin.update(params, &self.stats)? {
+                if slot.plugin.update(params, &self.stats)? {
                     need_rerecord = true;
+                    let passes = slot.plugin.changed_passes().unwrap_or(
+                        vec![Pass::Geometry, Pass::Transparent, Pass::Ui]);
+                    for pass in passes {
+                        match pass {
+                            Pass::Geometry => geometry = true,
+                            Pass::Transparent => transparent = true,
+                            Pass::Ui if ui_before_post && slot.plugin.ui_before_post() =>
+                                transparent = true,
+                            Pass::Ui => ui = true,
+                            // (recorded into the frame's command buffer itself)
+                            Pass::Probe | Pass::Shadow => { },
+                        }
+                    }
                 }
             }
             if need_rerecord {
@@ -689,6 +3351,9 @@ impl Renderer {
                 for elem in self.commander.gfx_command_buffer_stale.iter_mut() {
                     *elem=true;
                 }
+                if geometry { self.geometry_commands.mark_stale(); }
+                if transparent { self.transparent_commands.mark_stale(); }
+                if ui { self.ui_commands.mark_stale(); }
             }
 
             // Re-record all stale non-in-flight command buffers
@@ -700,29 +3365,30 @@ impl Renderer {
                     continue;
                 }
                 if self.commander.gfx_command_buffer_stale[i] {
-                    self.record_command_buffer(i)?;
+                    self.record_frame(i)?;
                     self.commander.gfx_command_buffer_stale[i] = false;
                 }
             }
 
-            // PLACEHOLDER FOR OPTIONAL JOBS
-            // Here we can query the fence status, and if not signalled, we can go do
-            // some job that is waiting, ala:
-            /*
+            // Run plugin background jobs while the GPU is still rendering,
+            // one small job per plugin per round, until the frame is done or
+            // no plugin has any more work.
             loop {
-              fence_status = vkGetFenceStatus(device, fences[nextImageIndex]);
-              if fence_status==VK_SUCCESS {
-                break;
-              }
-              if smallJobQueue.empty() {
-                break;
-              }
-              //execute next small job
+                if self.rendered_fence.get_status()? {
+                    break;
+                }
+                let mut more_work = false;
+                for slot in self.plugins.iter_mut().filter(|slot| slot.enabled) {
+                    if slot.plugin.background_job()? {
+                        more_work = true;
+                    }
+                }
+                if !more_work {
+                    break;
+                }
             }
-            */
 
             // Wait until the current frame is rendered, so that objects tied
-            // into that render remain alive during the render, and also to
             // wait for the Query pool results. This does not wait for
             // presentation (however rendering had to wait for acquisition and
             // this might not have been ready until vsync, depending on
@@ -731,10 +3397,12 @@ impl Renderer {
             self.rendered_fence.wait_for(Timeout::Infinite)?;
             let cpu_exclude_time = x.elapsed();
 
+            self.adapt_exposure(looptime_1);
+
             // Run plugin gpu_update() functions now that the GPU has finished
             // rendering
-            for plugin in &mut self.plugins {
-                plugin.gpu_update()?;
+            for slot in self.plugins.iter_mut().filter(|slot| slot.enabled) {
+                slot.plugin.gpu_update()?;
             }
 
             framenumber += 1;
@@ -742,22 +3410,29 @@ impl Renderer {
             // Shutdown when it is time to do so
             if self.shutdown.load(Ordering::Relaxed) {
                 info!("Graphics is shutting down...");
-                self.device.wait_idle()?;
+                self.commander.wait_idle(&self.device)?;
+                self.save_pipeline_cache_at_shutdown();
                 self.window.hide();
                 return Ok(());
             }
 
             // Query render timings
-            let timings_1 = {
+            let (timings_1, plugin_timings) = {
                 let mut results: [QueryResult; TS_QUERY_COUNT as usize]
                     = [QueryResult::U32(0); TS_QUERY_COUNT as usize];
-                self.timestamp_query_pool.get_results(
-                    0, // first query
-                    TS_QUERY_COUNT, // query count
-                    1, // stride (dacite takes this and multiplies by size of u32 or u64
-                    QueryResultFlags::WAIT,
-                    &mut results
-                )?;
+                let have_results = match self.active_timestamp_query_pool() {
+                    Some(query_pool) => {
+                        query_pool.get_results(
+                            0, // first query
+                            TS_QUERY_COUNT, // query count
+                            1, // stride (dacite takes this and multiplies by size of u32 or u64
+                            QueryResultFlags::WAIT,
+                            &mut results
+                        )?;
+                        true
+                    },
+                    None => false
+                };
 
                 // This skips the render wait, and the throttle (below), but also
                 // the update statistics (although that is short).
@@ -766,11 +3441,22 @@ impl Renderer {
                 let cputime_ms = cputime.as_secs() as f32 * 1000.0
                     + cputime.subsec_nanos() as f32 * 0.000_001;
 
-                Timings::one(
+                let plugin_timings = if have_results {
+                    let full_start = match results[Timestamp::FullStart as usize] {
+                        QueryResult::U32(u) => u as u64,
+                        QueryResult::U64(u) => u,
+                    };
+                    self.read_plugin_timestamps(present_image, full_start)?
+                } else {
+                    HashMap::new()
+                };
+
+                (Timings::one(
                     &looptime_1,
-                    &results,
+                    if have_results { Some(&results) } else { None },
                     cputime_ms,
-                    self.ph_props.limits.timestamp_period)
+                    self.ph_props.limits.timestamp_period),
+                 plugin_timings)
             };
 
             // Throttle FPS
@@ -780,23 +3466,53 @@ impl Renderer {
             }
 
             // Update statistics
-            timings_60.accumulate(&timings_1);
-            timings_600.accumulate(&timings_1);
+            self.stats.update(timings_1);
+            self.stats.plugin_timestamps = plugin_timings;
+        }
+    }
 
-            if framenumber % 600 == 0 {
-                let pass = ::std::mem::replace(&mut timings_600, Timings::new());
-                self.stats.update_600(pass);
-            }
-            if framenumber % 60 == 0 {
-                let pass = ::std::mem::replace(&mut timings_60, Timings::new());
-                self.stats.update_60(pass);
-            }
+    // Advance the TAA jitter and record the matrices for reprojection.  This
+    // runs before each frame is submitted, while the GPU is idle.
+    fn update_motion_params(&mut self)
+    {
+        let params = self.params_ubo.as_ptr::<Params>().unwrap();
+        params.prev_view_projection = match self.prev_view_projection {
+            Some(prev_view_projection) => prev_view_projection,
+            None => params.view_projection,
+        };
+        self.prev_view_projection = Some(params.view_projection);
+    }
+
+    fn update_taa_params(&mut self)
+    {
+        if self.taa_gfx.is_none() {
+            return;
+        }
+
+        let params = self.params_ubo.as_ptr::<Params>().unwrap();
+        match self.taa_prev_matrices {
+            Some((prev_inv_projection, prev_inv_view)) => {
+                params.prev_inv_projection = prev_inv_projection;
+                params.prev_inv_view = prev_inv_view;
+                params.taa_feedback = taa::TAA_FEEDBACK;
+            },
+            None => {
+                params.prev_inv_projection = params.inv_projection;
+                params.prev_inv_view = params.inv_view;
+                params.taa_feedback = 0.0;
+            },
         }
+        self.taa_prev_matrices = Some((params.inv_projection, params.inv_view));
+
+        self.taa_phase = (self.taa_phase + 1) % taa::TAA_JITTER_PHASES;
+        params.taa_jitter = taa::taa_jitter(self.taa_phase,
+                                            self.target_data.extent.width,
+                                            self.target_data.extent.height);
     }
 
-    fn start_render(&mut self) -> Result<usize, Error>
+    fn start_render(&mut self, framenumber: u64) -> Result<usize, Error>
     {
-        use dacite::core::{SubmitInfo, PipelineStageFlags};
+        use dacite::core::SubmitInfo;
         use dacite::khr_swapchain::{AcquireNextImageResultKhr, PresentInfoKhr};
 
         // Get next image
@@ -843,12 +3559,22 @@ impl Renderer {
         ];
 
         self.rendered_fence.reset()?;
-        self.commander.gfx_queue.submit(Some(&submit_infos), Some(&self.rendered_fence))?;
+        {
+            let _queues = self.commander.lock_queues();
+            self.commander.gfx_queue.submit(Some(&submit_infos), Some(&self.rendered_fence))?;
+        }
+
+        // The queues are unlocked here, so plugins may submit their own work
+        // (e.g. overlays) before the image is presented
+        for slot in self.plugins.iter_mut().filter(|slot| slot.enabled) {
+            slot.plugin.on_submitted(framenumber);
+        }
 
         // Present this image once semaphore is available
         // The CPU is not stalled here, the graphics card will hold this until the semaphore
         // is signalled, and then do the presentation.
         {
+            let _queues = self.commander.lock_queues();
             let mut present_info = PresentInfoKhr {
                 wait_semaphores: vec![self.image_rendered.clone()],
                 swapchains: vec![self.swapchain_data.swapchain.clone()],
@@ -860,22 +3586,38 @@ impl Renderer {
             self.present_queue.queue_present_khr(&mut present_info)?;
         }
 
+        for slot in self.plugins.iter_mut().filter(|slot| slot.enabled) {
+            slot.plugin.on_presented(framenumber);
+        }
+
         Ok(next_image)
     }
 
+    // Re-record everything for swapchain image `present_index`
     fn record_command_buffer(&mut self, present_index: usize) -> Result<(), Error>
+    {
+        self.geometry_commands.mark_image_stale(present_index);
+        self.transparent_commands.mark_image_stale(present_index);
+        self.ui_commands.mark_image_stale(present_index);
+        self.record_frame(present_index)
+    }
+
+    // Record the per-frame command buffer for swapchain image
+    // `present_index`, re-recording only the stale plugin command buffers
+    // (see PassCommands)
+    fn record_frame(&mut self, present_index: usize) -> Result<(), Error>
     {
         // NOTE: recording a command buffer is well known as one of the slower
         // parts of Vulkan, so we should attempt to do as little recording
         // as possible.
 
         use dacite::core::{CommandBufferBeginInfo, CommandBufferUsageFlags,
-                           CommandBufferResetFlags, ImageLayout,
-                           AccessFlags, PipelineStageFlags, ImageAspectFlags,
+                           CommandBufferResetFlags,
                            OptionalMipLevels, OptionalArrayLayers,
-                           ImageSubresourceRange};
+                           ImageSubresourceRange, SubpassContents};
 
-        let command_buffer = &self.commander.gfx_command_buffers[present_index];
+        // (cloned, as recording the scene needs self mutably)
+        let command_buffer = &self.commander.gfx_command_buffers[present_index].clone();
 
         // Not sure this is required - was working with out it.  Also, not sure
         // if releasing resources is the smartest plan either.
@@ -888,12 +3630,12 @@ impl Renderer {
         };
         command_buffer.begin(&begin_info)?;
 
-        command_buffer.reset_query_pool(&self.timestamp_query_pool, 0, TS_QUERY_COUNT);
+        if let Some(query_pool) = self.active_timestamp_query_pool() {
+            command_buffer.reset_query_pool(query_pool, 0, TS_QUERY_COUNT);
+        }
+        self.plugin_timestamps.begin_recording(command_buffer);
 
-        command_buffer.write_timestamp(
-            PipelineStageFlagBits::TopOfPipe,
-            &self.timestamp_query_pool,
-            Timestamp::FullStart as u32);
+        self.write_timestamp(command_buffer, Timestamp::FullStart);
 
         // Transition swapchain image to ColorAttachmentOptimal
         // (from whatever it was - usually it is PresentImageKhr, but the
@@ -912,42 +3654,197 @@ impl Renderer {
             }
         )?;
 
+        self.record_scene(command_buffer, present_index)?;
+
+        self.target_data.transition_for_post(command_buffer.clone())?;
+
+        if self.auto_exposure.is_some() {
+            self.exposure_gfx.record(command_buffer.clone());
+        }
+
+        // Post and Ui passes render at the swapchain resolution
+        command_buffer.set_viewport(0, &self.ui_viewports);
+        command_buffer.set_scissor(0, &self.ui_scissors);
+
+        // Post pass
+        {
+            self.push_debug_label(command_buffer.clone(), "Post", [0.5, 0.5, 0.5, 1.0]);
+            self.write_timestamp(command_buffer, Timestamp::PostStart);
+
+            self.post_pass.record_entry(command_buffer.clone(),
+                                        present_index);
+
+            self.post_gfx.record(command_buffer.clone(),
+                                 self.params_desc_set.clone());
+
+            self.post_pass.record_exit(command_buffer.clone());
+
+            self.write_timestamp(command_buffer, Timestamp::PostEnd);
+            self.pop_debug_label(command_buffer.clone());
+        }
+
+        self.target_data.transition_after_post(command_buffer.clone())?;
+
+        self.target_data.transition_for_ui(command_buffer.clone())?;
+
+        // Ui pass (timestamps are written regardless, to keep Timings valid)
+        if !self.config.enable_ui_pass {
+            self.write_timestamp(command_buffer, Timestamp::UiStart);
+            self.write_timestamp(command_buffer, Timestamp::UiEnd);
+        } else {
+            self.push_debug_label(command_buffer.clone(), "UI", [1.0, 1.0, 1.0, 1.0]);
+            self.write_timestamp(command_buffer, Timestamp::UiStart);
+
+            self.ui_pass.record_entry(command_buffer.clone(),
+                                      present_index,
+                                      SubpassContents::SecondaryCommandBuffers);
+
+            let ui_commands = self.pass_command_buffer(Pass::Ui, present_index)?;
+            command_buffer.execute_commands(&[ui_commands]);
+
+            self.ui_pass.record_exit(command_buffer.clone());
+
+            self.write_timestamp(command_buffer, Timestamp::UiEnd);
+            self.pop_debug_label(command_buffer.clone());
+        }
+
+        // Transition swapchain image to PresentImageKhr
+        self.swapchain_data.images[present_index].transition_layout(
+            command_buffer.clone(),
+            ImageLayout::ColorAttachmentOptimal, ImageLayout::PresentSrcKhr,
+            AccessFlags::COLOR_ATTACHMENT_WRITE, AccessFlags::HOST_READ,
+            PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, PipelineStageFlags::HOST,
+            ImageSubresourceRange {
+                aspect_mask: ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: OptionalMipLevels::MipLevels(1),
+                base_array_layer: 0,
+                layer_count: OptionalArrayLayers::ArrayLayers(1),
+            }
+        )?;
+
+        let written = self.plugin_timestamps.end_recording(command_buffer);
+        if self.plugin_timestamps_written.len() <= present_index {
+            self.plugin_timestamps_written.resize(present_index + 1, Vec::new());
+        }
+        self.plugin_timestamps_written[present_index] = written;
+
+        self.write_timestamp(command_buffer, Timestamp::FullEnd);
+
+        command_buffer.end()?;
+
+        self.commander.gfx_command_buffer_stale[present_index] = false;
+
+        Ok(())
+    }
+
+    // Record every pass that renders at the render extent, from the shadow
+    // pass through to the bloom merge into the shading image
+    fn record_scene(&mut self, command_buffer: &CommandBuffer, present_index: usize)
+                    -> Result<(), Error>
+    {
+        use dacite::core::SubpassContents;
+
+        // (for the shadow and probe passes; the plugin pass command buffers
+        // set their own)
+        command_buffer.set_blend_constants(&self.blend_constants);
+
+        // Shadow pass
+        if self.config.shadows {
+            command_buffer.set_viewport(0, &[Viewport {
+                x: 0.0,
+                y: 0.0,
+                width: self.shadow_pass.extent.width as f32,
+                height: self.shadow_pass.extent.height as f32,
+                min_depth: self.viewports[0].min_depth,
+                max_depth: self.viewports[0].max_depth,
+            }]);
+            command_buffer.set_scissor(0, &[Rect2D {
+                offset: Offset2D { x: 0, y: 0 },
+                extent: self.shadow_pass.extent,
+            }]);
+
+            self.push_debug_label(command_buffer.clone(), "Shadow", [0.2, 0.2, 0.2, 1.0]);
+            self.shadow_pass.record_entry(command_buffer.clone());
+
+            for slot in self.plugins_in_render_order() {
+                slot.plugin.record_shadow(command_buffer.clone());
+            }
+
+            self.shadow_pass.record_exit(command_buffer.clone());
+            self.pop_debug_label(command_buffer.clone());
+        }
+
+        // Reflection probe passes
+        for (index, probe) in self.probes.iter().enumerate() {
+            command_buffer.set_viewport(0, &[Viewport {
+                x: 0.0,
+                y: 0.0,
+                width: probe.extent.width as f32,
+                height: probe.extent.height as f32,
+                min_depth: self.viewports[0].min_depth,
+                max_depth: self.viewports[0].max_depth,
+            }]);
+
+            self.push_debug_label(command_buffer.clone(),
+                                  &*format!("Reflection Probe {}", index),
+                                  [0.0, 0.5, 0.5, 1.0]);
+
+            for face in 0..6 {
+                // (again for each face, in case a plugin changed it)
+                command_buffer.set_scissor(0, &[Rect2D {
+                    offset: Offset2D { x: 0, y: 0 },
+                    extent: probe.extent,
+                }]);
+
+                probe.record_entry(command_buffer.clone(), face);
+
+                for slot in self.plugins_in_render_order() {
+                    slot.plugin.record_probe(command_buffer.clone(), index, face as u32);
+                }
+
+                probe.record_exit(command_buffer.clone());
+            }
+
+            self.pop_debug_label(command_buffer.clone());
+        }
+
         // Bind viewports and scissors
         command_buffer.set_viewport(0, &self.viewports);
         command_buffer.set_scissor(0, &self.scissors);
 
-        self.target_data.transition_for_geometry(command_buffer.clone())?;
+        self.target_data.transition_for_geometry(command_buffer.clone(),
+                                                 self.geometry_pass.load_ops)?;
 
         // Geometry pass
         {
-            command_buffer.write_timestamp(
-                PipelineStageFlagBits::TopOfPipe,
-                &self.timestamp_query_pool,
-                Timestamp::GeometryStart as u32);
+            self.push_debug_label(command_buffer.clone(), "Geometry", [0.0, 0.8, 0.0, 1.0]);
+            self.write_timestamp(command_buffer, Timestamp::GeometryStart);
 
-            self.geometry_pass.record_entry(command_buffer.clone());
+            self.geometry_pass.record_entry(command_buffer.clone(),
+                                            SubpassContents::SecondaryCommandBuffers);
 
-            for plugin in &self.plugins {
-                // NOTE: Try to draw front to back
-                plugin.record_geometry(command_buffer.clone());
-            }
+            let geometry_commands = self.pass_command_buffer(Pass::Geometry,
+                                                             present_index)?;
+            command_buffer.execute_commands(&[geometry_commands]);
 
             self.geometry_pass.record_exit(command_buffer.clone());
 
-            command_buffer.write_timestamp(
-                PipelineStageFlagBits::TopOfPipe,
-                &self.timestamp_query_pool,
-                Timestamp::GeometryEnd as u32);
+            // Dynamic state is undefined after executing secondary command
+            // buffers
+            command_buffer.set_viewport(0, &self.viewports);
+            command_buffer.set_scissor(0, &self.scissors);
+
+            self.write_timestamp(command_buffer, Timestamp::GeometryEnd);
+            self.pop_debug_label(command_buffer.clone());
         }
 
         self.target_data.transition_for_shading(command_buffer.clone())?;
 
         // Shading pass
         {
-            command_buffer.write_timestamp(
-                PipelineStageFlagBits::TopOfPipe,
-                &self.timestamp_query_pool,
-                Timestamp::ShadingStart as u32);
+            self.push_debug_label(command_buffer.clone(), "Shading", [0.8, 0.8, 0.0, 1.0]);
+            self.write_timestamp(command_buffer, Timestamp::ShadingStart);
 
             self.shading_pass.record_entry(command_buffer.clone());
 
@@ -956,43 +3853,84 @@ impl Renderer {
 
             self.shading_pass.record_exit(command_buffer.clone());
 
-            command_buffer.write_timestamp(
-                PipelineStageFlagBits::TopOfPipe,
-                &self.timestamp_query_pool,
-                Timestamp::ShadingEnd as u32);
+            self.write_timestamp(command_buffer, Timestamp::ShadingEnd);
+            self.pop_debug_label(command_buffer.clone());
         }
 
+        // (this also returns the depth buffer to an attachment layout, which
+        // transition_for_post expects, so it is needed even without a
+        // transparent pass)
         self.target_data.transition_for_transparent(command_buffer.clone())?;
 
-        // Transparent pass
-        {
-            command_buffer.write_timestamp(
-                PipelineStageFlagBits::TopOfPipe,
-                &self.timestamp_query_pool,
-                Timestamp::TransparentStart as u32);
+        // Transparent pass (timestamps are written regardless, to keep
+        // Timings valid)
+        if !self.config.enable_transparent_pass {
+            self.write_timestamp(command_buffer, Timestamp::TransparentStart);
+            self.write_timestamp(command_buffer, Timestamp::TransparentEnd);
+        } else {
+            self.push_debug_label(command_buffer.clone(), "Transparent", [0.0, 0.5, 1.0, 1.0]);
+            self.write_timestamp(command_buffer, Timestamp::TransparentStart);
 
-            self.transparent_pass.record_entry(command_buffer.clone());
+            self.transparent_pass.record_entry(command_buffer.clone(),
+                                               SubpassContents::SecondaryCommandBuffers);
 
-            for plugin in &self.plugins {
-                plugin.record_transparent(command_buffer.clone());
-            }
+            let transparent_commands = self.pass_command_buffer(Pass::Transparent,
+                                                                present_index)?;
+            command_buffer.execute_commands(&[transparent_commands]);
 
             self.transparent_pass.record_exit(command_buffer.clone());
+            command_buffer.set_viewport(0, &self.viewports);
+            command_buffer.set_scissor(0, &self.scissors);
 
-            command_buffer.write_timestamp(
-                PipelineStageFlagBits::TopOfPipe,
-                &self.timestamp_query_pool,
-                Timestamp::TransparentEnd as u32);
+            self.write_timestamp(command_buffer, Timestamp::TransparentEnd);
+            self.pop_debug_label(command_buffer.clone());
+        }
+
+        // Temporal anti-aliasing resolve, before bloom
+        if let (Some(taa_pass), Some(taa_gfx)) = (self.taa_pass.as_ref(), self.taa_gfx.as_ref()) {
+            self.target_data.transition_for_taa(command_buffer.clone())?;
+
+            self.push_debug_label(command_buffer.clone(), "TAA", [0.5, 0.0, 1.0, 1.0]);
+
+            taa_pass.record_entry(command_buffer.clone());
+            taa_gfx.record(command_buffer.clone(), self.params_desc_set.clone());
+            taa_pass.record_exit(command_buffer.clone());
+
+            self.target_data.copy_taa_result(command_buffer.clone())?;
+
+            self.pop_debug_label(command_buffer.clone());
+        }
+
+        if let Some(ref compute_blur_gfx) = self.compute_blur_gfx {
+            self.target_data.transition_for_compute_blurh(command_buffer.clone())?;
+
+            // Blur/Bloom Filter/Horizontal dispatch
+            self.push_debug_label(command_buffer.clone(), "Blur H", [0.8, 0.0, 0.8, 1.0]);
+            self.write_timestamp(command_buffer, Timestamp::Blur1Start);
+            compute_blur_gfx.record_blurh(command_buffer.clone(),
+                                          self.params_desc_set.clone());
+            self.write_timestamp(command_buffer, Timestamp::Blur1End);
+            self.pop_debug_label(command_buffer.clone());
+
+            self.target_data.transition_for_compute_blurv(command_buffer.clone())?;
+
+            // Blur/Bloom Vertical/Merge dispatch
+            self.push_debug_label(command_buffer.clone(), "Blur V", [0.8, 0.0, 0.8, 1.0]);
+            self.write_timestamp(command_buffer, Timestamp::Blur2Start);
+            compute_blur_gfx.record_blurv(command_buffer.clone(),
+                                          self.params_desc_set.clone());
+            self.write_timestamp(command_buffer, Timestamp::Blur2End);
+            self.pop_debug_label(command_buffer.clone());
+
+            return Ok(());
         }
 
         self.target_data.transition_for_blurh(command_buffer.clone())?;
 
         // Blur/Bloom Filter/Horizontal pass
         {
-            command_buffer.write_timestamp(
-                PipelineStageFlagBits::TopOfPipe,
-                &self.timestamp_query_pool,
-                Timestamp::Blur1Start as u32);
+            self.push_debug_label(command_buffer.clone(), "Blur H", [0.8, 0.0, 0.8, 1.0]);
+            self.write_timestamp(command_buffer, Timestamp::Blur1Start);
 
             self.blur_h_pass.record_entry(command_buffer.clone());
 
@@ -1001,20 +3939,16 @@ impl Renderer {
 
             self.blur_h_pass.record_exit(command_buffer.clone());
 
-            command_buffer.write_timestamp(
-                PipelineStageFlagBits::TopOfPipe,
-                &self.timestamp_query_pool,
-                Timestamp::Blur1End as u32);
+            self.write_timestamp(command_buffer, Timestamp::Blur1End);
+            self.pop_debug_label(command_buffer.clone());
         }
 
         self.target_data.transition_for_blurv(command_buffer.clone())?;
 
         // Blur/Bloom Vertical/Merge pass
         {
-            command_buffer.write_timestamp(
-                PipelineStageFlagBits::TopOfPipe,
-                &self.timestamp_query_pool,
-                Timestamp::Blur2Start as u32);
+            self.push_debug_label(command_buffer.clone(), "Blur V", [0.8, 0.0, 0.8, 1.0]);
+            self.write_timestamp(command_buffer, Timestamp::Blur2Start);
 
             self.blur_v_pass.record_entry(command_buffer.clone());
 
@@ -1023,110 +3957,257 @@ impl Renderer {
 
             self.blur_v_pass.record_exit(command_buffer.clone());
 
-            command_buffer.write_timestamp(
-                PipelineStageFlagBits::TopOfPipe,
-                &self.timestamp_query_pool,
-                Timestamp::Blur2End as u32);
+            self.write_timestamp(command_buffer, Timestamp::Blur2End);
+            self.pop_debug_label(command_buffer.clone());
         }
 
-        self.target_data.transition_for_post(command_buffer.clone())?;
+        Ok(())
+    }
+
+    // The secondary command buffer of `pass` (Geometry, Transparent or Ui)
+    // for swapchain image `present_index`, re-recorded first if it is stale
+    fn pass_command_buffer(&mut self, pass: Pass, present_index: usize)
+                           -> Result<CommandBuffer, Error>
+    {
+        let (render_pass, framebuffer) = match pass {
+            Pass::Geometry => (self.geometry_pass.render_pass.clone(),
+                               self.geometry_pass.framebuffer.clone()),
+            Pass::Transparent => (self.transparent_pass.render_pass.clone(),
+                                  self.transparent_pass.framebuffer.clone()),
+            Pass::Ui => (self.ui_pass.render_pass.clone(),
+                         self.ui_pass.framebuffers[present_index].clone()),
+            _ => return Err(Error::General(
+                "Only the geometry, transparent and UI passes have their own command buffers"
+                    .to_owned())),
+        };
 
-        // Post pass
         {
-            command_buffer.write_timestamp(
-                PipelineStageFlagBits::TopOfPipe,
-                &self.timestamp_query_pool,
-                Timestamp::PostStart as u32);
+            let commands = self.pass_commands(&pass);
+            if !commands.is_stale(present_index) {
+                // (its timestamp queries are written again this frame)
+                let command_buffer = commands.command_buffer(present_index);
+                let timestamps = commands.timestamps(present_index).to_vec();
+                self.plugin_timestamps.mark_written(&timestamps);
+                return Ok(command_buffer);
+            }
+        }
 
-            self.post_pass.record_entry(command_buffer.clone(),
-                                        present_index);
+        let command_buffer = self.pass_commands(&pass)
+            .begin(present_index, render_pass, framebuffer)?;
+        let written_before = self.plugin_timestamps.written();
 
-            self.post_gfx.record(command_buffer.clone(),
-                                 self.params_desc_set.clone());
+        match pass {
+            Pass::Geometry => {
+                command_buffer.set_viewport(0, &self.viewports);
+                command_buffer.set_scissor(0, &self.scissors);
+                command_buffer.set_blend_constants(&self.blend_constants);
 
-            self.post_pass.record_exit(command_buffer.clone());
+                for slot in self.plugins_in_render_order() {
+                    // NOTE: Try to draw front to back
+                    slot.plugin.record_geometry(command_buffer.clone());
+                }
+            },
+            Pass::Transparent => {
+                command_buffer.set_viewport(0, &self.viewports);
+                command_buffer.set_scissor(0, &self.scissors);
+                command_buffer.set_blend_constants(&self.blend_constants);
 
-            command_buffer.write_timestamp(
-                PipelineStageFlagBits::TopOfPipe,
-                &self.timestamp_query_pool,
-                Timestamp::PostEnd as u32);
+                self.star_gfx.record(command_buffer.clone(),
+                                     self.params_desc_set.clone());
+
+                for slot in self.plugins_in_render_order() {
+                    slot.plugin.record_transparent(command_buffer.clone());
+                }
+                // Plugins may have narrowed the scissor (see ScissorRegion)
+                command_buffer.set_scissor(0, &self.scissors);
+
+                self.debug_lines_gfx.record(command_buffer.clone(),
+                                            self.params_desc_set.clone());
+
+                // UI that should be post-processed along with the scene
+                for slot in self.plugins_in_render_order() {
+                    if slot.plugin.ui_before_post() {
+                        slot.plugin.record_ui(command_buffer.clone());
+                    }
+                }
+            },
+            _ => {
+                command_buffer.set_viewport(0, &self.ui_viewports);
+                command_buffer.set_scissor(0, &self.ui_scissors);
+                command_buffer.set_blend_constants(&self.blend_constants);
+
+                // (unless drawn into the shading target, see record_scene)
+                let ui_before_post = self.config.enable_transparent_pass;
+                for slot in self.plugins_in_render_order() {
+                    if !(ui_before_post && slot.plugin.ui_before_post()) {
+                        slot.plugin.record_ui(command_buffer.clone());
+                    }
+                }
+            },
         }
 
-        self.target_data.transition_for_ui(command_buffer.clone())?;
+        let written = self.plugin_timestamps.written().into_iter()
+            .filter(|query| !written_before.contains(query))
+            .collect();
+        self.pass_commands(&pass).end(present_index, written)?;
 
-        // Ui pass
-        {
-            command_buffer.write_timestamp(
-                PipelineStageFlagBits::TopOfPipe,
-                &self.timestamp_query_pool,
-                Timestamp::UiStart as u32);
+        Ok(command_buffer)
+    }
 
-            self.ui_pass.record_entry(command_buffer.clone(),
-                                      present_index);
+    fn pass_commands(&mut self, pass: &Pass) -> &mut PassCommands
+    {
+        match *pass {
+            Pass::Geometry => &mut self.geometry_commands,
+            Pass::Transparent => &mut self.transparent_commands,
+            _ => &mut self.ui_commands,
+        }
+    }
 
-            for plugin in &self.plugins {
-                plugin.record_ui(command_buffer.clone());
-            }
+    // Open a labelled region of a command buffer, which GPU capture tools
+    // (e.g. RenderDoc) show as a group.  Regions may nest; close each with
+    // pop_debug_label().  These do nothing unless config.vulkan_debug_output
+    // is set and the device supports VK_EXT_debug_marker.
+    pub fn push_debug_label(&self, command_buffer: CommandBuffer, name: &str,
+                            color: [f32; 4])
+    {
+        if self.debug_markers {
+            use dacite::ext_debug_marker::DebugMarkerMarkerInfoExt;
 
-            self.ui_pass.record_exit(command_buffer.clone());
+            command_buffer.debug_marker_begin_ext(&DebugMarkerMarkerInfoExt {
+                marker_name: name.to_owned(),
+                color: color,
+                chain: None,
+            });
+        }
+    }
 
-            command_buffer.write_timestamp(
-                PipelineStageFlagBits::TopOfPipe,
-                &self.timestamp_query_pool,
-                Timestamp::UiEnd as u32);
+    pub fn pop_debug_label(&self, command_buffer: CommandBuffer)
+    {
+        if self.debug_markers {
+            command_buffer.debug_marker_end_ext();
         }
+    }
 
-        // Transition swapchain image to PresentImageKhr
-        self.swapchain_data.images[present_index].transition_layout(
-            command_buffer.clone(),
-            ImageLayout::ColorAttachmentOptimal, ImageLayout::PresentSrcKhr,
-            AccessFlags::COLOR_ATTACHMENT_WRITE, AccessFlags::HOST_READ,
-            PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, PipelineStageFlags::HOST,
-            ImageSubresourceRange {
-                aspect_mask: ImageAspectFlags::COLOR,
-                base_mip_level: 0,
-                level_count: OptionalMipLevels::MipLevels(1),
-                base_array_layer: 0,
-                layer_count: OptionalArrayLayers::ArrayLayers(1),
-            }
+    // Milliseconds from the start of the frame to each plugin timestamp
+    // written by the command buffer of swapchain image `present_index`
+    fn read_plugin_timestamps(&self, present_index: usize, full_start: u64)
+                              -> Result<HashMap<String, f32>, Error>
+    {
+        let mut timings = HashMap::new();
+
+        let query_pool = match self.plugin_timestamps.query_pool() {
+            Some(query_pool) => query_pool,
+            None => return Ok(timings),
+        };
+        let written = match self.plugin_timestamps_written.get(present_index) {
+            Some(written) if !written.is_empty() => written,
+            _ => return Ok(timings),
+        };
+
+        // Every query was written (end_recording() fills the gaps), so
+        // waiting cannot hang
+        let mut results = vec![QueryResult::U32(0); PLUGIN_TS_QUERY_COUNT as usize];
+        query_pool.get_results(
+            0, // first query
+            PLUGIN_TS_QUERY_COUNT, // query count
+            1, // stride (dacite multiplies by size of u32 or u64)
+            QueryResultFlags::WAIT,
+            &mut results
         )?;
 
-        command_buffer.write_timestamp(
-            PipelineStageFlagBits::TopOfPipe,
-            &self.timestamp_query_pool,
-            Timestamp::FullEnd as u32);
+        for &query in written {
+            let value = match results[query as usize] {
+                QueryResult::U32(u) => u as u64,
+                QueryResult::U64(u) => u,
+            };
+            let ms = (value.saturating_sub(full_start) as f32
+                      * self.ph_props.limits.timestamp_period) * 0.000_001;
+            timings.insert(self.plugin_timestamps.label(query), ms);
+        }
 
-        command_buffer.end()?;
+        Ok(timings)
+    }
 
-        self.commander.gfx_command_buffer_stale[present_index] = false;
+    // The timestamp query pool, unless timestamps are unsupported or
+    // Config::gpu_timing is off
+    fn active_timestamp_query_pool(&self) -> Option<&QueryPool>
+    {
+        if self.config.gpu_timing {
+            self.timestamp_query_pool.as_ref()
+        } else {
+            None
+        }
+    }
 
-        Ok(())
+    fn write_timestamp(&self, command_buffer: &CommandBuffer, timestamp: Timestamp)
+    {
+        if let Some(query_pool) = self.active_timestamp_query_pool() {
+            command_buffer.write_timestamp(
+                PipelineStageFlagBits::TopOfPipe,
+                query_pool,
+                timestamp as u32);
+        }
+    }
+
+    // A minimized window (on Windows at least) has a zero-sized surface
+    fn surface_is_minimized(&self) -> Result<bool, Error>
+    {
+        let capabilities = self.ph.get_surface_capabilities_khr(&self.surface)?;
+        Ok(match capabilities.current_extent {
+            Some(extent) => extent.width == 0 || extent.height == 0,
+            None => false,
+        })
     }
 
     fn rebuild(&mut self) -> Result<(), Error>
+    {
+        let extent = self.swapchain_data.extent;
+        self.resize(extent)
+    }
+
+    // Recreate the swapchain and everything sized to it. `new_extent` is
+    // used where the surface lets us choose the swapchain size; most
+    // surfaces dictate it (matching the window), in which case theirs is
+    // used. This is done automatically by run() when the window is resized,
+    // but may be called directly by integrations that handle resize events
+    // themselves.
+    pub fn resize(&mut self, new_extent: Extent2D) -> Result<(), Error>
     {
         // Wait until the device is idle
-        self.device.wait_idle()?;
+        self.commander.wait_idle(&self.device)?;
+
+        // We can't build a zero-sized swapchain. The run loop rebuilds once
+        // the window is restored.
+        if self.surface_is_minimized()? {
+            debug!("Window is minimized, pausing rendering");
+            self.minimized = true;
+            return Ok(());
+        }
 
         // Rebuild swapchain
-        self.swapchain_data.rebuild(&self.ph, &self.device, &self.surface)?;
+        self.swapchain_data.rebuild(&self.ph, &self.device, &self.surface,
+                                    new_extent)?;
 
         // Rebuild the targets
-        self.target_data.rebuild(&self.device, &mut self.memory, &self.commander,
-                                 self.swapchain_data.extent)?;
+        let render_extent = self.scaled_extent(self.swapchain_data.extent);
+        self.target_data.rebuild(&self.device, &mut self.memory.lock().unwrap(), &self.commander,
+                                 render_extent, self.swapchain_data.extent)?;
 
         // Rebuild the passes
         self.geometry_pass.rebuild(&self.device,
                                    &self.target_data.depth_image,
                                    &self.target_data.diffuse_image,
                                    &self.target_data.normals_image,
-                                   &self.target_data.material_image)?;
+                                   &self.target_data.material_image,
+                                   self.target_data.velocity_image.as_ref(),
+                                   self.target_data.entity_id_image.as_ref())?;
         self.shading_pass.rebuild(&self.device,
                                  &self.target_data.depth_image,
                                  &self.target_data.diffuse_image,
                                  &self.target_data.normals_image,
                                  &self.target_data.material_image,
-                                 &self.target_data.shading_image)?;
+                                 &self.target_data.shading_image,
+                                 self.target_data.linear_depth_image.as_ref())?;
         self.transparent_pass.rebuild(&self.device,
                                       &self.target_data.depth_image,
                                       &self.target_data.shading_image)?;
@@ -1137,25 +4218,41 @@ impl Renderer {
                                  &self.target_data.blur_image,
                                  &self.target_data.shading_image)?;
         self.post_pass.rebuild(&self.device,
-                               &self.target_data.shading_image,
                                &self.swapchain_data)?;
         self.ui_pass.rebuild(&self.device,
-                             &self.target_data.depth_image,
+                             &self.target_data.ui_depth_image,
                              &self.swapchain_data)?;
 
         // Rebuild post, blur
         self.shade_gfx.rebuild(&self.device, &self.target_data)?;
         self.post_gfx.rebuild(&self.device, &self.target_data)?;
         self.blur_gfx.rebuild(&self.device, &self.target_data)?;
+        if let Some(ref mut compute_blur_gfx) = self.compute_blur_gfx {
+            compute_blur_gfx.rebuild(&self.device, &self.target_data)?;
+        }
+        self.exposure_gfx.rebuild(&self.device, &self.target_data)?;
+        if let (Some(taa_pass), Some(taa_gfx)) = (self.taa_pass.as_mut(), self.taa_gfx.as_mut()) {
+            if let Some(ref taa_image) = self.target_data.taa_image {
+                taa_pass.rebuild(&self.device, taa_image)?;
+            }
+            taa_gfx.rebuild(&self.device, &self.target_data)?;
+        }
+        // The history images are new
+        self.taa_prev_matrices = None;
 
         // Update viewports and scissors
-        self.viewports[0].width = self.swapchain_data.extent.width as f32;
-        self.viewports[0].height = self.swapchain_data.extent.height as f32;
-        self.scissors[0].extent = self.swapchain_data.extent;
+        self.viewports[0].width = render_extent.width as f32;
+        self.viewports[0].height = render_extent.height as f32;
+        self.scissors[0].extent = render_extent;
+        self.ui_viewports[0].width = self.swapchain_data.extent.width as f32;
+        self.ui_viewports[0].height = self.swapchain_data.extent.height as f32;
+        self.ui_scissors[0].extent = self.swapchain_data.extent;
+        self.viewport_depth_range.update(self.viewports[0], self.ui_viewports[0]);
+        self.scissor_region.update(self.scissors[0].extent, self.ui_scissors[0].extent);
 
         // Rebuild plugins
-        for plugin in &mut self.plugins {
-            plugin.rebuild(self.swapchain_data.extent)?;
+        for slot in &mut self.plugins {
+            slot.plugin.rebuild(self.swapchain_data.extent)?;
         }
 
         // Re-record command buffers (the framebuffer image views are new, so we must)
@@ -1166,3 +4263,22 @@ impl Renderer {
         Ok(())
     }
 }
+
+// Coefficients for Params::depth_linearize_scale and depth_linearize_offset.
+// A standard projection maps view depth z to depth buffer value
+// d = B - A/z, with A = near*far/(far-near) and B = far/(far-near), so
+// z = A / (B - d).  A reversed depth buffer stores 1 - d instead.
+fn depth_linearization(near: f32, far: f32, reversed_depth_buffer: bool) -> (f32, f32)
+{
+    let (a, b) = if far.is_infinite() {
+        (near, 1.0)
+    } else {
+        (near * far / (far - near), far / (far - near))
+    };
+
+    if reversed_depth_buffer {
+        (a, b - 1.0)
+    } else {
+        (-a, -b)
+    }
+}
@@ -0,0 +1,109 @@
+
+use dacite::core::{CommandPool, CommandBuffer, RenderPass, Framebuffer};
+use crate::error::Error;
+
+// The secondary command buffers that plugins record one render pass into,
+// one per swapchain image, which the per-frame (primary) command buffers
+// execute.  Each is only re-recorded when marked stale, so a plugin whose
+// UI changes every frame doesn't cost a re-record of every other plugin's
+// geometry (see Plugin::changed_passes).  The primary command buffer is
+// still re-recorded, but that holds only the renderer's own few commands.
+pub struct PassCommands {
+    command_buffers: Vec<CommandBuffer>,
+    stale: Vec<bool>,
+    // The plugin timestamp queries each command buffer writes, which must
+    // be accounted for in every frame that executes it
+    timestamps: Vec<Vec<u32>>,
+}
+
+impl PassCommands {
+    pub fn new(command_pool: &CommandPool, count: u32) -> Result<PassCommands, Error>
+    {
+        use dacite::core::{CommandBufferAllocateInfo, CommandBufferLevel};
+
+        let allocate_info = CommandBufferAllocateInfo {
+            command_pool: command_pool.clone(),
+            level: CommandBufferLevel::Secondary,
+            command_buffer_count: count,
+            chain: None,
+        };
+        let command_buffers = CommandPool::allocate_command_buffers(&allocate_info)?;
+
+        Ok(PassCommands {
+            command_buffers: command_buffers,
+            stale: vec![true; count as usize],
+            timestamps: vec![Vec::new(); count as usize],
+        })
+    }
+
+    // Mark the command buffers of every swapchain image stale
+    pub fn mark_stale(&mut self)
+    {
+        for elem in self.stale.iter_mut() {
+            *elem = true;
+        }
+    }
+
+    // Mark the command buffer of swapchain image `index` stale
+    pub fn mark_image_stale(&mut self, index: usize)
+    {
+        self.stale[index] = true;
+    }
+
+    pub fn is_stale(&self, index: usize) -> bool
+    {
+        self.stale[index]
+    }
+
+    pub fn command_buffer(&self, index: usize) -> CommandBuffer
+    {
+        self.command_buffers[index].clone()
+    }
+
+    pub fn timestamps(&self, index: usize) -> &[u32]
+    {
+        &self.timestamps[index]
+    }
+
+    // Start re-recording the command buffer for swapchain image `index`,
+    // to be executed inside `render_pass` (subpass 0) with `framebuffer`.
+    // Dynamic state is not inherited from the primary command buffer, so
+    // the viewport and scissor must be set again.
+    pub fn begin(&mut self, index: usize, render_pass: RenderPass,
+                 framebuffer: Framebuffer)
+                 -> Result<CommandBuffer, Error>
+    {
+        use dacite::core::{CommandBufferBeginInfo, CommandBufferUsageFlags,
+                           CommandBufferResetFlags, CommandBufferInheritanceInfo,
+                           QueryControlFlags, QueryPipelineStatisticFlags};
+
+        let command_buffer = self.command_buffers[index].clone();
+        command_buffer.reset(CommandBufferResetFlags::empty())?;
+
+        let begin_info = CommandBufferBeginInfo {
+            flags: CommandBufferUsageFlags::RENDER_PASS_CONTINUE,
+            inheritance_info: Some(CommandBufferInheritanceInfo {
+                render_pass: Some(render_pass),
+                subpass: 0,
+                framebuffer: Some(framebuffer),
+                occlusion_query_enable: false,
+                query_flags: QueryControlFlags::empty(),
+                pipeline_statistics: QueryPipelineStatisticFlags::empty(),
+                chain: None,
+            }),
+            chain: None,
+        };
+        command_buffer.begin(&begin_info)?;
+
+        Ok(command_buffer)
+    }
+
+    // Finish re-recording, noting the plugin timestamp queries written
+    pub fn end(&mut self, index: usize, timestamps: Vec<u32>) -> Result<(), Error>
+    {
+        self.command_buffers[index].end()?;
+        self.timestamps[index] = timestamps;
+        self.stale[index] = false;
+        Ok(())
+    }
+}
@@ -11,8 +11,17 @@ pub use self::transparent::TransparentPass;
 pub mod blur;
 pub use self::blur::{BlurHPass, BlurVPass};
 
+pub mod taa;
+pub use self::taa::TaaPass;
+
 pub mod post;
 pub use self::post::PostPass;
 
 pub mod ui;
 pub use self::ui::UiPass;
+
+pub mod probe;
+pub use self::probe::ProbePass;
+
+pub mod shadow;
+pub use self::shadow::ShadowPass;
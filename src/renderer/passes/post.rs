@@ -2,13 +2,14 @@
 use dacite::core::{Device, RenderPass, Framebuffer, Extent2D, ImageView,
                    CommandBuffer};
 use crate::error::Error;
-use crate::renderer::image_wrap::ImageWrap;
 use crate::renderer::swapchain_data::SwapchainData;
 
+// The shading image is not an attachment of this pass; it is sampled through
+// a descriptor (see PostGfx).  This allows the shading image to be a different
+// size than the swapchain (see Renderer::set_resolution_scale).
 pub struct PostPass {
     pub framebuffers: Vec<Framebuffer>,
     pub swapchain_image_views: Vec<ImageView>,
-    pub shading_image_view: ImageView,
     pub extent: Extent2D,
     pub render_pass: RenderPass,
 }
@@ -16,7 +17,6 @@ pub struct PostPass {
 impl PostPass {
     pub fn new(
         device: &Device,
-        shading_image: &ImageWrap,
         swapchain_data: &SwapchainData)
         -> Result<PostPass, Error>
     {
@@ -28,18 +28,6 @@ impl PostPass {
                                RenderPassCreateFlags, RenderPassCreateInfo,
                                AttachmentReference, AttachmentIndex};
 
-            let shading_attachment_description = shading_image.get_attachment_description(
-                AttachmentLoadOp::Load,
-                AttachmentStoreOp::DontCare,
-                ImageLayout::ShaderReadOnlyOptimal,
-                ImageLayout::ShaderReadOnlyOptimal,
-            );
-
-            let shading_attachment_reference = AttachmentReference {
-                attachment: AttachmentIndex::Index(0),
-                layout: ImageLayout::ShaderReadOnlyOptimal
-            };
-
             let swapchain_attachment_description = swapchain_data.images[0].get_attachment_description(
                 AttachmentLoadOp::Clear,
                 AttachmentStoreOp::Store,
@@ -48,14 +36,14 @@ impl PostPass {
             );
 
             let swapchain_attachment_reference = AttachmentReference {
-                attachment: AttachmentIndex::Index(1),
+                attachment: AttachmentIndex::Index(0),
                 layout: ImageLayout::ColorAttachmentOptimal
             };
 
             let subpass = SubpassDescription {
                 flags: SubpassDescriptionFlags::empty(),
                 pipeline_bind_point: PipelineBindPoint::Graphics,
-                input_attachments: vec![shading_attachment_reference],
+                input_attachments: vec![],
                 color_attachments: vec![swapchain_attachment_reference],
                 resolve_attachments: vec![],
                 depth_stencil_attachment: None,
@@ -69,7 +57,7 @@ impl PostPass {
                 src_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
                 dst_stage_mask: PipelineStageFlags::FRAGMENT_SHADER,
                 src_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
-                dst_access_mask: AccessFlags::COLOR_ATTACHMENT_READ,
+                dst_access_mask: AccessFlags::SHADER_READ,
                 dependency_flags:  DependencyFlags::BY_REGION,
             };
 
@@ -87,8 +75,7 @@ impl PostPass {
             let create_info = RenderPassCreateInfo {
                 flags: RenderPassCreateFlags::empty(),
                 attachments: vec![
-                    shading_attachment_description, // 0
-                    swapchain_attachment_description // 1
+                    swapchain_attachment_description // 0
                 ],
                 subpasses: vec![subpass],
                 dependencies: vec![
@@ -101,28 +88,25 @@ impl PostPass {
             device.create_render_pass(&create_info, None)?
         };
 
-        let (shading_image_view, swapchain_image_views, framebuffers, extent) =
-            build(device, render_pass.clone(), shading_image, swapchain_data)?;
+        let (swapchain_image_views, framebuffers, extent) =
+            build(device, render_pass.clone(), swapchain_data)?;
 
         Ok(PostPass {
             framebuffers: framebuffers,
             swapchain_image_views: swapchain_image_views,
-            shading_image_view: shading_image_view,
             extent: extent,
             render_pass: render_pass,
         })
     }
 
     pub fn rebuild(&mut self, device: &Device,
-                   shading_image: &ImageWrap,
                    swapchain_data: &SwapchainData)
                    -> Result<(), Error>
     {
-        let (shading_image_view, swapchain_image_views, framebuffers, extent) =
-            build(device, self.render_pass.clone(), shading_image, swapchain_data)?;
+        let (swapchain_image_views, framebuffers, extent) =
+            build(device, self.render_pass.clone(), swapchain_data)?;
 
         self.framebuffers = framebuffers;
-        self.shading_image_view = shading_image_view;
         self.swapchain_image_views = swapchain_image_views;
         self.extent = extent;
 
@@ -131,6 +115,17 @@ impl PostPass {
 
     pub fn record_entry(&self, command_buffer: CommandBuffer,
                         present_index: usize)
+    {
+        self.record_entry_framebuffer(command_buffer,
+                                      &self.framebuffers[present_index],
+                                      self.extent);
+    }
+
+    // Begin the pass on some other framebuffer created against our
+    // render_pass, e.g. for Renderer::render_to_texture
+    pub fn record_entry_framebuffer(&self, command_buffer: CommandBuffer,
+                                    framebuffer: &Framebuffer,
+                                    extent: Extent2D)
     {
         use dacite::core::{Rect2D, Offset2D,
                            SubpassContents, RenderPassBeginInfo,
@@ -138,11 +133,9 @@ impl PostPass {
 
         let begin_info = RenderPassBeginInfo {
             render_pass: self.render_pass.clone(),
-            framebuffer: self.framebuffers[present_index].clone(),
-            render_area: Rect2D::new(Offset2D::zero(), self.extent),
+            framebuffer: framebuffer.clone(),
+            render_area: Rect2D::new(Offset2D::zero(), extent),
             clear_values:  vec![
-                ClearValue::Color(ClearColorValue::Float32([0.0, 0.0, 0.0, 1.0])), // unused
-                ClearValue::Color(ClearColorValue::Float32([0.0, 0.0, 0.0, 1.0])), // unused
                 ClearValue::Color(ClearColorValue::Float32([0.0, 0.0, 0.0, 1.0])),
             ],
             chain: None,
@@ -158,14 +151,12 @@ impl PostPass {
     }
 }
 
-fn build(device: &Device, render_pass: RenderPass, shading_image: &ImageWrap,
+fn build(device: &Device, render_pass: RenderPass,
          swapchain_data: &SwapchainData)
-    -> Result<(ImageView, Vec<ImageView>, Vec<Framebuffer>, Extent2D), Error>
+    -> Result<(Vec<ImageView>, Vec<Framebuffer>, Extent2D), Error>
 {
     let extent = swapchain_data.extent;
 
-    let shading_image_view = shading_image.get_image_view(device)?;
-
     let mut image_views = Vec::new();
     let mut framebuffers = Vec::new();
 
@@ -178,7 +169,6 @@ fn build(device: &Device, render_pass: RenderPass, shading_image: &ImageWrap,
             flags: FramebufferCreateFlags::empty(),
             render_pass: render_pass.clone(),
             attachments: vec![
-                shading_image_view.clone(),
                 image_view.clone(),
             ],
             width: extent.width,
@@ -192,5 +182,5 @@ fn build(device: &Device, render_pass: RenderPass, shading_image: &ImageWrap,
         framebuffers.push(framebuffer);
     };
 
-    Ok((shading_image_view, image_views, framebuffers, extent))
+    Ok((image_views, framebuffers, extent))
 }
@@ -0,0 +1,244 @@
+
+use dacite::core::{Device, RenderPass, Framebuffer, Extent2D, Extent3D,
+                   ImageView, CommandBuffer, ImageLayout, ImageUsageFlags,
+                   ImageTiling, ComponentMapping, AccessFlags,
+                   PipelineStageFlags, ImageSubresourceRange,
+                   ImageAspectFlags, OptionalMipLevels, OptionalArrayLayers};
+use crate::error::Error;
+use crate::renderer::image_wrap::{ImageWrap, ImageWrapType};
+use crate::renderer::memory::{Memory, Lifetime};
+use crate::renderer::commander::Commander;
+use crate::renderer::setup::requirements::{DEPTH_FORMAT, PROBE_FORMAT};
+
+// The orientation of each cubemap face, in Vulkan layer order
+// (+X, -X, +Y, -Y, +Z, -Z), as (forward, up, right) world-space directions.
+// `up` points towards the top row of the face image and `right` towards
+// its last column, so a view matrix built from these bases renders a face
+// that samples correctly.  Use a 90 degree field of view with an aspect of 1.
+pub const CUBEMAP_FACE_DIRECTIONS: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+    ([ 1.0,  0.0,  0.0], [0.0,  1.0,  0.0], [ 0.0, 0.0, -1.0]),
+    ([-1.0,  0.0,  0.0], [0.0,  1.0,  0.0], [ 0.0, 0.0,  1.0]),
+    ([ 0.0,  1.0,  0.0], [0.0,  0.0, -1.0], [ 1.0, 0.0,  0.0]),
+    ([ 0.0, -1.0,  0.0], [0.0,  0.0,  1.0], [ 1.0, 0.0,  0.0]),
+    ([ 0.0,  0.0,  1.0], [0.0,  1.0,  0.0], [ 1.0, 0.0,  0.0]),
+    ([ 0.0,  0.0, -1.0], [0.0,  1.0,  0.0], [-1.0, 0.0,  0.0]),
+];
+
+// A reflection probe: a cubemap color target with its own depth buffer,
+// rendered one face at a time.  After all six faces are rendered, the
+// cubemap is left in ShaderReadOnlyOptimal for sampling.
+pub struct ProbePass {
+    pub framebuffers: Vec<Framebuffer>, // one per face
+    #[allow(dead_code)]
+    pub face_image_views: Vec<ImageView>, // must survive for Framebuffer usage
+    #[allow(dead_code)]
+    pub depth_image_view: ImageView, // must survive for Framebuffer usage
+    pub depth_image: ImageWrap,
+    pub cubemap: ImageWrap,
+    pub reversed_depth_buffer: bool,
+    pub extent: Extent2D,
+    pub render_pass: RenderPass,
+}
+
+impl ProbePass {
+    pub fn new(
+        device: &Device,
+        memory: &mut Memory,
+        commander: &Commander,
+        size: u32,
+        reversed_depth_buffer: bool)
+        -> Result<ProbePass, Error>
+    {
+        let extent = Extent2D { width: size, height: size };
+
+        let cubemap = ImageWrap::new(
+            device, memory,
+            PROBE_FORMAT,
+            ComponentMapping::identity(),
+            1,
+            Extent3D { width: size, height: size, depth: 1 },
+            ImageWrapType::Cubemap,
+            ImageLayout::Undefined,
+            ImageTiling::Optimal,
+            ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::SAMPLED,
+            Lifetime::Permanent,
+            true, // solo
+            "Reflection Probe")?;
+
+        let mut depth_image = ImageWrap::new(
+            device, memory,
+            DEPTH_FORMAT,
+            ComponentMapping::identity(),
+            1,
+            Extent3D { width: size, height: size, depth: 1 },
+            ImageWrapType::Depth,
+            ImageLayout::Undefined,
+            ImageTiling::Optimal,
+            ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            Lifetime::Permanent,
+            true, // solo
+            "Reflection Probe Depth Buffer")?;
+
+        depth_image.transition_layout_now(
+            device,
+            ImageLayout::Undefined, ImageLayout::DepthStencilAttachmentOptimal,
+            Default::default(),
+            AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                | AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            PipelineStageFlags::TOP_OF_PIPE,
+            PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+            ImageSubresourceRange {
+                aspect_mask: ImageAspectFlags::DEPTH,
+                base_mip_level: 0,
+                level_count: OptionalMipLevels::MipLevels(1),
+                base_array_layer: 0,
+                layer_count: OptionalArrayLayers::ArrayLayers(1),
+            },
+            commander
+        )?;
+
+        let render_pass = {
+            use dacite::core::{AttachmentLoadOp, AttachmentStoreOp,
+                               SubpassDescription, SubpassDescriptionFlags,
+                               PipelineBindPoint, SubpassIndex, SubpassDependency,
+                               DependencyFlags,
+                               RenderPassCreateFlags, RenderPassCreateInfo,
+                               AttachmentReference, AttachmentIndex};
+
+            let depth_attachment_description = depth_image.get_attachment_description(
+                AttachmentLoadOp::Clear,
+                AttachmentStoreOp::DontCare,
+                ImageLayout::DepthStencilAttachmentOptimal,
+                ImageLayout::DepthStencilAttachmentOptimal
+            );
+
+            let depth_attachment_reference = AttachmentReference {
+                attachment: AttachmentIndex::Index(0),
+                layout: ImageLayout::DepthStencilAttachmentOptimal
+            };
+
+            // Each face is cleared, so we don't care what was there before
+            let face_attachment_description = cubemap.get_attachment_description(
+                AttachmentLoadOp::Clear,
+                AttachmentStoreOp::Store,
+                ImageLayout::Undefined,
+                ImageLayout::ShaderReadOnlyOptimal,
+            );
+
+            let face_attachment_reference = AttachmentReference {
+                attachment: AttachmentIndex::Index(1),
+                layout: ImageLayout::ColorAttachmentOptimal
+            };
+
+            let subpass = SubpassDescription {
+                flags: SubpassDescriptionFlags::empty(),
+                pipeline_bind_point: PipelineBindPoint::Graphics,
+                input_attachments: vec![],
+                color_attachments: vec![face_attachment_reference],
+                resolve_attachments: vec![],
+                depth_stencil_attachment: Some(depth_attachment_reference),
+                preserve_attachments: vec![],
+            };
+
+            // Prior reads of the cubemap (last frame) must finish before we
+            // write it again
+            let sampling_to_probe = SubpassDependency {
+                src_subpass: SubpassIndex::External,
+                dst_subpass: SubpassIndex::Index(0), // us
+                src_stage_mask: PipelineStageFlags::FRAGMENT_SHADER,
+                dst_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                src_access_mask: AccessFlags::SHADER_READ,
+                dst_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
+                dependency_flags:  DependencyFlags::empty(),
+            };
+
+            // We must write the face before any later pass samples it
+            let probe_to_sampling = SubpassDependency {
+                src_subpass: SubpassIndex::Index(0), // us
+                dst_subpass: SubpassIndex::External,
+                src_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                dst_stage_mask: PipelineStageFlags::FRAGMENT_SHADER,
+                src_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
+                dst_access_mask: AccessFlags::SHADER_READ,
+                dependency_flags:  DependencyFlags::empty(),
+            };
+
+            let create_info = RenderPassCreateInfo {
+                flags: RenderPassCreateFlags::empty(),
+                attachments: vec![
+                    depth_attachment_description,
+                    face_attachment_description,
+                ],
+                subpasses: vec![subpass],
+                dependencies: vec![
+                    sampling_to_probe,
+                    probe_to_sampling,
+                ],
+                chain: None,
+            };
+
+            device.create_render_pass(&create_info, None)?
+        };
+
+        let depth_image_view = depth_image.get_image_view(device)?;
+
+        let mut face_image_views: Vec<ImageView> = Vec::with_capacity(6);
+        let mut framebuffers: Vec<Framebuffer> = Vec::with_capacity(6);
+        for face in 0..6 {
+            use dacite::core::{FramebufferCreateInfo, FramebufferCreateFlags};
+
+            let face_image_view = cubemap.get_layer_image_view(device, face)?;
+
+            let create_info = FramebufferCreateInfo {
+                flags: FramebufferCreateFlags::empty(),
+                render_pass: render_pass.clone(),
+                attachments: vec![
+                    depth_image_view.clone(),
+                    face_image_view.clone(),
+                ],
+                width: extent.width,
+                height: extent.height,
+                layers: 1,
+                chain: None,
+            };
+            framebuffers.push(device.create_framebuffer(&create_info, None)?);
+            face_image_views.push(face_image_view);
+        }
+
+        Ok(ProbePass {
+            framebuffers: framebuffers,
+            face_image_views: face_image_views,
+            depth_image_view: depth_image_view,
+            depth_image: depth_image,
+            cubemap: cubemap,
+            reversed_depth_buffer: reversed_depth_buffer,
+            extent: extent,
+            render_pass: render_pass,
+        })
+    }
+
+    pub fn record_entry(&self, command_buffer: CommandBuffer, face: usize)
+    {
+        use dacite::core::{Rect2D, Offset2D,
+                           SubpassContents, RenderPassBeginInfo};
+
+        let begin_info = RenderPassBeginInfo {
+            render_pass: self.render_pass.clone(),
+            framebuffer: self.framebuffers[face].clone(),
+            render_area: Rect2D::new(Offset2D::zero(), self.extent),
+            clear_values:  vec![
+                self.depth_image.get_clear_value(self.reversed_depth_buffer),
+                self.cubemap.get_clear_value(self.reversed_depth_buffer),
+            ],
+            chain: None,
+        };
+
+        command_buffer.begin_render_pass(
+            &begin_info, SubpassContents::Inline);
+    }
+
+    pub fn record_exit(&self, command_buffer: CommandBuffer)
+    {
+        command_buffer.end_render_pass();
+    }
+}
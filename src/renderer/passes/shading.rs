@@ -7,6 +7,7 @@ use crate::renderer::image_wrap::ImageWrap;
 pub struct ShadingPass {
     pub framebuffer: Framebuffer,
     pub shading_image_view: ImageView,
+    pub linear_depth_image_view: Option<ImageView>, // must survive for Framebuffer usage
     pub material_image_view: ImageView,
     pub normals_image_view: ImageView,
     pub diffuse_image_view: ImageView,
@@ -23,7 +24,8 @@ impl ShadingPass {
         diffuse_image: &ImageWrap,
         normals_image: &ImageWrap,
         material_image: &ImageWrap,
-        shading_image: &ImageWrap)
+        shading_image: &ImageWrap,
+        linear_depth_image: Option<&ImageWrap>)
         -> Result<ShadingPass, Error>
     {
         let render_pass = {
@@ -89,6 +91,30 @@ impl ShadingPass {
                 layout: ImageLayout::ColorAttachmentOptimal
             };
 
+            let mut attachments = vec![
+                depth_attachment_description,
+                diffuse_attachment_description,
+                normals_attachment_description,
+                material_attachment_description,
+                shading_attachment_description,
+            ];
+            let mut color_attachments = vec![shading_attachment_reference];
+
+            // Linear depth is written fresh each frame, and left ready for
+            // later passes (and plugins) to sample
+            if let Some(linear_depth_image) = linear_depth_image {
+                attachments.push(linear_depth_image.get_attachment_description(
+                    AttachmentLoadOp::Clear,
+                    AttachmentStoreOp::Store,
+                    ImageLayout::Undefined,
+                    ImageLayout::ShaderReadOnlyOptimal,
+                ));
+                color_attachments.push(AttachmentReference {
+                    attachment: AttachmentIndex::Index(5),
+                    layout: ImageLayout::ColorAttachmentOptimal
+                });
+            }
+
             let subpass = SubpassDescription {
                 flags: SubpassDescriptionFlags::empty(),
                 pipeline_bind_point: PipelineBindPoint::Graphics,
@@ -96,7 +122,7 @@ impl ShadingPass {
                                         diffuse_attachment_reference,
                                         normals_attachment_reference,
                                         material_attachment_reference],
-                color_attachments: vec![shading_attachment_reference],
+                color_attachments: color_attachments,
                 resolve_attachments: vec![],
                 depth_stencil_attachment: None,
                 preserve_attachments: vec![],
@@ -129,19 +155,14 @@ impl ShadingPass {
                 src_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
                 dst_stage_mask: PipelineStageFlags::FRAGMENT_SHADER,
                 src_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
-                dst_access_mask: AccessFlags::COLOR_ATTACHMENT_READ,
+                dst_access_mask: AccessFlags::COLOR_ATTACHMENT_READ
+                    | AccessFlags::SHADER_READ, // linear depth is sampled
                 dependency_flags:  DependencyFlags::BY_REGION,
             };
 
             let create_info = RenderPassCreateInfo {
                 flags: RenderPassCreateFlags::empty(),
-                attachments: vec![
-                    depth_attachment_description,
-                    diffuse_attachment_description,
-                    normals_attachment_description,
-                    material_attachment_description,
-                    shading_attachment_description,
-                ],
+                attachments: attachments,
                 subpasses: vec![subpass],
                 dependencies: vec![
                     geometry_to_shading_1,
@@ -155,13 +176,16 @@ impl ShadingPass {
         };
 
         let (depth_image_view, diffuse_image_view, normals_image_view,
-             material_image_view, shading_image_view, framebuffer, extent) =
+             material_image_view, shading_image_view, linear_depth_image_view,
+             framebuffer, extent) =
             build(device, render_pass.clone(), depth_image, diffuse_image,
-                  normals_image, material_image, shading_image)?;
+                  normals_image, material_image, shading_image,
+                  linear_depth_image)?;
 
         Ok(ShadingPass {
             framebuffer: framebuffer,
             shading_image_view: shading_image_view,
+            linear_depth_image_view: linear_depth_image_view,
             material_image_view: material_image_view,
             normals_image_view: normals_image_view,
             diffuse_image_view: diffuse_image_view,
@@ -176,13 +200,16 @@ impl ShadingPass {
                    diffuse_image: &ImageWrap,
                    normals_image: &ImageWrap,
                    material_image: &ImageWrap,
-                   shading_image: &ImageWrap)
+                   shading_image: &ImageWrap,
+                   linear_depth_image: Option<&ImageWrap>)
                    -> Result<(), Error>
     {
         let (depth_image_view, diffuse_image_view, normals_image_view,
-             material_image_view, shading_image_view, framebuffer, extent) =
+             material_image_view, shading_image_view, linear_depth_image_view,
+             framebuffer, extent) =
             build(device, self.render_pass.clone(), depth_image,
-                  diffuse_image, normals_image, material_image, shading_image)?;
+                  diffuse_image, normals_image, material_image, shading_image,
+                  linear_depth_image)?;
 
         self.framebuffer = framebuffer;
         self.depth_image_view = depth_image_view;
@@ -190,6 +217,7 @@ impl ShadingPass {
         self.normals_image_view = normals_image_view;
         self.material_image_view = material_image_view;
         self.shading_image_view = shading_image_view;
+        self.linear_depth_image_view = linear_depth_image_view;
         self.extent = extent;
 
         Ok(())
@@ -201,20 +229,26 @@ impl ShadingPass {
                            SubpassContents, RenderPassBeginInfo,
                            ClearDepthStencilValue};
 
+        let mut clear_values = vec![
+            ClearValue::DepthStencil(ClearDepthStencilValue { // unused
+                depth: 0.0,
+                stencil: 0,
+            }),
+            ClearValue::Color(ClearColorValue::Float32([0.0, 0.0, 0.0, 1.0])), // ignored
+            ClearValue::Color(ClearColorValue::Float32([0.0, 0.0, 0.0, 1.0])), // ignored
+            ClearValue::Color(ClearColorValue::Float32([0.0, 0.0, 0.0, 1.0])), // ignored
+            ClearValue::Color(ClearColorValue::Float32([0.0, 0.0, 0.0, 1.0])),
+        ];
+        if self.linear_depth_image_view.is_some() {
+            clear_values.push(
+                ClearValue::Color(ClearColorValue::Float32([0.0, 0.0, 0.0, 0.0])));
+        }
+
         let begin_info = RenderPassBeginInfo {
             render_pass: self.render_pass.clone(),
             framebuffer: self.framebuffer.clone(),
             render_area: Rect2D::new(Offset2D::zero(), self.extent),
-            clear_values:  vec![
-                ClearValue::DepthStencil(ClearDepthStencilValue { // unused
-                    depth: 0.0,
-                    stencil: 0,
-                }),
-                ClearValue::Color(ClearColorValue::Float32([0.0, 0.0, 0.0, 1.0])), // ignored
-                ClearValue::Color(ClearColorValue::Float32([0.0, 0.0, 0.0, 1.0])), // ignored
-                ClearValue::Color(ClearColorValue::Float32([0.0, 0.0, 0.0, 1.0])), // ignored
-                ClearValue::Color(ClearColorValue::Float32([0.0, 0.0, 0.0, 1.0])),
-            ],
+            clear_values: clear_values,
             chain: None,
         };
 
@@ -230,14 +264,19 @@ impl ShadingPass {
 
 fn build(device: &Device, render_pass: RenderPass, depth_image: &ImageWrap,
          diffuse_image: &ImageWrap, normals_image: &ImageWrap, material_image: &ImageWrap,
-         shading_image: &ImageWrap)
-    -> Result<(ImageView, ImageView, ImageView, ImageView, ImageView, Framebuffer, Extent2D), Error>
+         shading_image: &ImageWrap, linear_depth_image: Option<&ImageWrap>)
+    -> Result<(ImageView, ImageView, ImageView, ImageView, ImageView, Option<ImageView>,
+               Framebuffer, Extent2D), Error>
 {
     let depth_image_view = depth_image.get_image_view(device)?;
     let diffuse_image_view = diffuse_image.get_image_view(device)?;
     let normals_image_view = normals_image.get_image_view(device)?;
     let material_image_view = material_image.get_image_view(device)?;
     let shading_image_view = shading_image.get_image_view(device)?;
+    let linear_depth_image_view = match linear_depth_image {
+        Some(image) => Some(image.get_image_view(device)?),
+        None => None
+    };
 
     let extent = Extent2D {
         width: depth_image.extent.width,
@@ -247,16 +286,21 @@ fn build(device: &Device, render_pass: RenderPass, depth_image: &ImageWrap,
     let framebuffer = {
         use dacite::core::{FramebufferCreateInfo, FramebufferCreateFlags};
 
+        let mut attachments = vec![
+            depth_image_view.clone(),
+            diffuse_image_view.clone(),
+            normals_image_view.clone(),
+            material_image_view.clone(),
+            shading_image_view.clone(),
+        ];
+        if let Some(ref view) = linear_depth_image_view {
+            attachments.push(view.clone());
+        }
+
         let create_info = FramebufferCreateInfo {
             flags: FramebufferCreateFlags::empty(),
             render_pass: render_pass,
-            attachments: vec![
-                depth_image_view.clone(),
-                diffuse_image_view.clone(),
-                normals_image_view.clone(),
-                material_image_view.clone(),
-                shading_image_view.clone(),
-            ],
+            attachments: attachments,
             width: extent.width,
             height: extent.height,
             layers: 1,
@@ -266,5 +310,5 @@ fn build(device: &Device, render_pass: RenderPass, depth_image: &ImageWrap,
     };
 
     Ok((depth_image_view, diffuse_image_view, normals_image_view, material_image_view,
-        shading_image_view, framebuffer, extent))
+        shading_image_view, linear_depth_image_view, framebuffer, extent))
 }
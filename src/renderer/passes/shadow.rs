@@ -0,0 +1,187 @@
+
+use dacite::core::{Device, RenderPass, Framebuffer, Extent2D, Extent3D,
+                   ImageView, CommandBuffer, ImageLayout, ImageUsageFlags,
+                   ImageTiling, ComponentMapping, AccessFlags,
+                   PipelineStageFlags, ImageSubresourceRange,
+                   ImageAspectFlags, OptionalMipLevels, OptionalArrayLayers};
+use crate::error::Error;
+use crate::renderer::image_wrap::{ImageWrap, ImageWrapType};
+use crate::renderer::memory::{Memory, Lifetime};
+use crate::renderer::commander::Commander;
+use crate::renderer::setup::requirements::DEPTH_FORMAT;
+
+// A depth-only pass rendering the scene from the primary directional light.
+// The shadow map is left in ShaderReadOnlyOptimal for the shading pass.
+pub struct ShadowPass {
+    pub framebuffer: Framebuffer,
+    pub depth_image_view: ImageView,
+    pub depth_image: ImageWrap,
+    pub reversed_depth_buffer: bool,
+    pub extent: Extent2D,
+    pub render_pass: RenderPass,
+}
+
+impl ShadowPass {
+    pub fn new(
+        device: &Device,
+        memory: &mut Memory,
+        commander: &Commander,
+        size: u32,
+        reversed_depth_buffer: bool)
+        -> Result<ShadowPass, Error>
+    {
+        let extent = Extent2D { width: size, height: size };
+
+        let mut depth_image = ImageWrap::new(
+            device, memory,
+            DEPTH_FORMAT,
+            ComponentMapping::identity(),
+            1,
+            Extent3D { width: size, height: size, depth: 1 },
+            ImageWrapType::Depth,
+            ImageLayout::Undefined,
+            ImageTiling::Optimal,
+            ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | ImageUsageFlags::SAMPLED,
+            Lifetime::Permanent,
+            true, // solo
+            "Shadow Map")?;
+
+        // The shading pass samples the shadow map even on frames (or
+        // configurations) where the shadow pass is not recorded
+        depth_image.transition_layout_now(
+            device,
+            ImageLayout::Undefined, ImageLayout::ShaderReadOnlyOptimal,
+            Default::default(),
+            AccessFlags::SHADER_READ,
+            PipelineStageFlags::TOP_OF_PIPE,
+            PipelineStageFlags::FRAGMENT_SHADER,
+            ImageSubresourceRange {
+                aspect_mask: ImageAspectFlags::DEPTH,
+                base_mip_level: 0,
+                level_count: OptionalMipLevels::MipLevels(1),
+                base_array_layer: 0,
+                layer_count: OptionalArrayLayers::ArrayLayers(1),
+            },
+            commander
+        )?;
+
+        let render_pass = {
+            use dacite::core::{AttachmentLoadOp, AttachmentStoreOp,
+                               SubpassDescription, SubpassDescriptionFlags,
+                               PipelineBindPoint, SubpassIndex, SubpassDependency,
+                               DependencyFlags,
+                               RenderPassCreateFlags, RenderPassCreateInfo,
+                               AttachmentReference, AttachmentIndex};
+
+            // The shadow map is cleared, so we don't care what was there before
+            let depth_attachment_description = depth_image.get_attachment_description(
+                AttachmentLoadOp::Clear,
+                AttachmentStoreOp::Store,
+                ImageLayout::Undefined,
+                ImageLayout::ShaderReadOnlyOptimal
+            );
+
+            let depth_attachment_reference = AttachmentReference {
+                attachment: AttachmentIndex::Index(0),
+                layout: ImageLayout::DepthStencilAttachmentOptimal
+            };
+
+            let subpass = SubpassDescription {
+                flags: SubpassDescriptionFlags::empty(),
+                pipeline_bind_point: PipelineBindPoint::Graphics,
+                input_attachments: vec![],
+                color_attachments: vec![],
+                resolve_attachments: vec![],
+                depth_stencil_attachment: Some(depth_attachment_reference),
+                preserve_attachments: vec![],
+            };
+
+            // Prior reads of the shadow map (last frame) must finish before
+            // we write it again
+            let sampling_to_shadow = SubpassDependency {
+                src_subpass: SubpassIndex::External,
+                dst_subpass: SubpassIndex::Index(0), // us
+                src_stage_mask: PipelineStageFlags::FRAGMENT_SHADER,
+                dst_stage_mask: PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+                src_access_mask: AccessFlags::SHADER_READ,
+                dst_access_mask: AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                    | AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                dependency_flags:  DependencyFlags::empty(),
+            };
+
+            // We must write the shadow map before the shading pass samples it
+            let shadow_to_sampling = SubpassDependency {
+                src_subpass: SubpassIndex::Index(0), // us
+                dst_subpass: SubpassIndex::External,
+                src_stage_mask: PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                dst_stage_mask: PipelineStageFlags::FRAGMENT_SHADER,
+                src_access_mask: AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                dst_access_mask: AccessFlags::SHADER_READ,
+                dependency_flags:  DependencyFlags::empty(),
+            };
+
+            let create_info = RenderPassCreateInfo {
+                flags: RenderPassCreateFlags::empty(),
+                attachments: vec![depth_attachment_description],
+                subpasses: vec![subpass],
+                dependencies: vec![
+                    sampling_to_shadow,
+                    shadow_to_sampling,
+                ],
+                chain: None,
+            };
+
+            device.create_render_pass(&create_info, None)?
+        };
+
+        let depth_image_view = depth_image.get_image_view(device)?;
+
+        let framebuffer = {
+            use dacite::core::{FramebufferCreateInfo, FramebufferCreateFlags};
+
+            let create_info = FramebufferCreateInfo {
+                flags: FramebufferCreateFlags::empty(),
+                render_pass: render_pass.clone(),
+                attachments: vec![depth_image_view.clone()],
+                width: extent.width,
+                height: extent.height,
+                layers: 1,
+                chain: None,
+            };
+            device.create_framebuffer(&create_info, None)?
+        };
+
+        Ok(ShadowPass {
+            framebuffer: framebuffer,
+            depth_image_view: depth_image_view,
+            depth_image: depth_image,
+            reversed_depth_buffer: reversed_depth_buffer,
+            extent: extent,
+            render_pass: render_pass,
+        })
+    }
+
+    pub fn record_entry(&self, command_buffer: CommandBuffer)
+    {
+        use dacite::core::{Rect2D, Offset2D,
+                           SubpassContents, RenderPassBeginInfo};
+
+        let begin_info = RenderPassBeginInfo {
+            render_pass: self.render_pass.clone(),
+            framebuffer: self.framebuffer.clone(),
+            render_area: Rect2D::new(Offset2D::zero(), self.extent),
+            clear_values:  vec![
+                self.depth_image.get_clear_value(self.reversed_depth_buffer),
+            ],
+            chain: None,
+        };
+
+        command_buffer.begin_render_pass(
+            &begin_info, SubpassContents::Inline);
+    }
+
+    pub fn record_exit(&self, command_buffer: CommandBuffer)
+    {
+        command_buffer.end_render_pass();
+    }
+}
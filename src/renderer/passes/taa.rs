@@ -0,0 +1,166 @@
+
+use dacite::core::{Device, RenderPass, Framebuffer, Extent2D, ImageView,
+                   CommandBuffer};
+use crate::error::Error;
+use crate::renderer::image_wrap::ImageWrap;
+
+// Resolves the shading image and the TAA history into the TAA image (see
+// Config::taa_enabled).  The shading, history and depth images are sampled,
+// not attached.
+pub struct TaaPass {
+    pub framebuffer: Framebuffer,
+    pub taa_image_view: ImageView,
+    pub extent: Extent2D,
+    pub render_pass: RenderPass,
+}
+
+impl TaaPass {
+    pub fn new(
+        device: &Device,
+        taa_image: &ImageWrap)
+        -> Result<TaaPass, Error>
+    {
+        let render_pass = {
+            use dacite::core::{AttachmentLoadOp, AttachmentStoreOp, ImageLayout,
+                               SubpassDescription, SubpassDescriptionFlags,
+                               PipelineBindPoint, SubpassIndex, SubpassDependency,
+                               PipelineStageFlags, AccessFlags, DependencyFlags,
+                               RenderPassCreateFlags, RenderPassCreateInfo,
+                               AttachmentReference, AttachmentIndex};
+
+            // Every pixel is written, so the old contents don't matter
+            let taa_attachment_description = taa_image.get_attachment_description(
+                AttachmentLoadOp::DontCare,
+                AttachmentStoreOp::Store,
+                ImageLayout::ColorAttachmentOptimal,
+                ImageLayout::ColorAttachmentOptimal,
+            );
+
+            let taa_attachment_reference = AttachmentReference {
+                attachment: AttachmentIndex::Index(0),
+                layout: ImageLayout::ColorAttachmentOptimal
+            };
+
+            let subpass = SubpassDescription {
+                flags: SubpassDescriptionFlags::empty(),
+                pipeline_bind_point: PipelineBindPoint::Graphics,
+                input_attachments: vec![],
+                color_attachments: vec![taa_attachment_reference],
+                resolve_attachments: vec![],
+                depth_stencil_attachment: None,
+                preserve_attachments: vec![],
+            };
+
+            // We must have written the shading image before we sample it
+            let transparent_to_taa = SubpassDependency {
+                src_subpass: SubpassIndex::External, // transparent (prior pass)
+                dst_subpass: SubpassIndex::Index(0), // us
+                src_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                dst_stage_mask: PipelineStageFlags::FRAGMENT_SHADER,
+                src_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
+                dst_access_mask: AccessFlags::SHADER_READ,
+                dependency_flags: DependencyFlags::empty(),
+            };
+
+            // We must write the TAA image before it is copied out
+            let taa_to_copy = SubpassDependency {
+                src_subpass: SubpassIndex::Index(0), // us
+                dst_subpass: SubpassIndex::External, // history and shading copies
+                src_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                dst_stage_mask: PipelineStageFlags::TRANSFER,
+                src_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
+                dst_access_mask: AccessFlags::TRANSFER_READ,
+                dependency_flags: DependencyFlags::empty(),
+            };
+
+            let create_info = RenderPassCreateInfo {
+                flags: RenderPassCreateFlags::empty(),
+                attachments: vec![taa_attachment_description],
+                subpasses: vec![subpass],
+                dependencies: vec![
+                    transparent_to_taa,
+                    taa_to_copy,
+                ],
+                chain: None,
+            };
+
+            device.create_render_pass(&create_info, None)?
+        };
+
+        let (taa_image_view, framebuffer, extent) =
+            build(device, render_pass.clone(), taa_image)?;
+
+        Ok(TaaPass {
+            framebuffer: framebuffer,
+            taa_image_view: taa_image_view,
+            extent: extent,
+            render_pass: render_pass,
+        })
+    }
+
+    pub fn rebuild(&mut self, device: &Device,
+                   taa_image: &ImageWrap)
+                   -> Result<(), Error>
+    {
+        let (taa_image_view, framebuffer, extent) =
+            build(device, self.render_pass.clone(), taa_image)?;
+
+        self.framebuffer = framebuffer;
+        self.taa_image_view = taa_image_view;
+        self.extent = extent;
+
+        Ok(())
+    }
+
+    pub fn record_entry(&self, command_buffer: CommandBuffer)
+    {
+        use dacite::core::{Rect2D, Offset2D,
+                           SubpassContents, RenderPassBeginInfo};
+
+        let begin_info = RenderPassBeginInfo {
+            render_pass: self.render_pass.clone(),
+            framebuffer: self.framebuffer.clone(),
+            render_area: Rect2D::new(Offset2D::zero(), self.extent),
+            clear_values: vec![],
+            chain: None,
+        };
+
+        command_buffer.begin_render_pass(
+            &begin_info, SubpassContents::Inline);
+    }
+
+    pub fn record_exit(&self, command_buffer: CommandBuffer)
+    {
+        command_buffer.end_render_pass();
+    }
+}
+
+fn build(device: &Device, render_pass: RenderPass, taa_image: &ImageWrap)
+    -> Result<(ImageView, Framebuffer, Extent2D), Error>
+{
+    let taa_image_view = taa_image.get_image_view(device)?;
+
+    let extent = Extent2D {
+        width: taa_image.extent.width,
+        height: taa_image.extent.height
+    };
+
+    let framebuffer = {
+        use dacite::core::{FramebufferCreateInfo, FramebufferCreateFlags};
+
+        let create_info = FramebufferCreateInfo {
+            flags: FramebufferCreateFlags::empty(),
+            render_pass: render_pass,
+            attachments: vec![
+                taa_image_view.clone(),
+            ],
+            width: extent.width,
+            height: extent.height,
+            layers: 1,
+            chain: None,
+        };
+        device.create_framebuffer(&create_info, None)?
+    };
+
+    Ok((taa_image_view, framebuffer, extent))
+}
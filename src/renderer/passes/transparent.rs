@@ -1,6 +1,6 @@
 
 use dacite::core::{Device, RenderPass, Framebuffer, Extent2D, ImageView,
-                   CommandBuffer};
+                   CommandBuffer, SubpassContents};
 use crate::error::Error;
 use crate::renderer::image_wrap::ImageWrap;
 
@@ -11,95 +11,19 @@ pub struct TransparentPass {
     pub depth_image_view: ImageView, // must survive for Framebuffer usage
     pub extent: Extent2D,
     pub render_pass: RenderPass,
+    pub clear: bool,
 }
 
 impl TransparentPass {
     pub fn new(
         device: &Device,
         depth_image: &ImageWrap,
-        shading_image: &ImageWrap)
+        shading_image: &ImageWrap,
+        clear: bool)
         -> Result<TransparentPass, Error>
     {
-        let render_pass = {
-            use dacite::core::{AttachmentLoadOp, AttachmentStoreOp, ImageLayout,
-                               SubpassDescription, SubpassDescriptionFlags,
-                               PipelineBindPoint, SubpassIndex, SubpassDependency,
-                               PipelineStageFlags, AccessFlags, DependencyFlags,
-                               RenderPassCreateFlags, RenderPassCreateInfo,
-                               AttachmentReference, AttachmentIndex};
-
-            let depth_attachment_description = depth_image.get_attachment_description(
-                AttachmentLoadOp::Load,
-                AttachmentStoreOp::DontCare,
-                ImageLayout::DepthStencilAttachmentOptimal,
-                ImageLayout::DepthStencilAttachmentOptimal
-            );
-
-            let depth_attachment_reference = AttachmentReference {
-                attachment: AttachmentIndex::Index(0),
-                layout: ImageLayout::DepthStencilAttachmentOptimal
-            };
-
-            let shading_attachment_description = shading_image.get_attachment_description(
-                AttachmentLoadOp::Load,
-                AttachmentStoreOp::Store,
-                ImageLayout::ColorAttachmentOptimal,
-                ImageLayout::ColorAttachmentOptimal,
-            );
-
-            let shading_attachment_reference = AttachmentReference {
-                attachment: AttachmentIndex::Index(1),
-                layout: ImageLayout::ColorAttachmentOptimal
-            };
-
-            let subpass = SubpassDescription {
-                flags: SubpassDescriptionFlags::empty(),
-                pipeline_bind_point: PipelineBindPoint::Graphics,
-                input_attachments: vec![],
-                color_attachments: vec![shading_attachment_reference],
-                resolve_attachments: vec![],
-                depth_stencil_attachment: Some(depth_attachment_reference),
-                preserve_attachments: vec![],
-            };
-
-            // We must have written the depth buffer before this RenderPass reads it
-            let shading_to_transparent = SubpassDependency {
-                src_subpass: SubpassIndex::External, // shading (prior pass)
-                dst_subpass: SubpassIndex::Index(0), // us
-                src_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-                dst_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-                src_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
-                dst_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
-                dependency_flags:  DependencyFlags::BY_REGION,
-            };
-
-            // We must write the shading buffer before the next RenderPass reads it
-            let transparent_to_post = SubpassDependency {
-                src_subpass: SubpassIndex::Index(0), // us
-                dst_subpass: SubpassIndex::External, // post processing
-                src_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-                dst_stage_mask: PipelineStageFlags::FRAGMENT_SHADER,
-                src_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
-                dst_access_mask: AccessFlags::COLOR_ATTACHMENT_READ,
-                dependency_flags:  DependencyFlags::BY_REGION,
-            };
-
-            let create_info = RenderPassCreateInfo {
-                flags: RenderPassCreateFlags::empty(),
-                attachments: vec![
-                    depth_attachment_description,
-                    shading_attachment_description,
-                ],
-                subpasses: vec![subpass],
-                dependencies: vec![
-                    shading_to_transparent,
-                    transparent_to_post,
-                ],
-                chain: None,
-            };
-
-            device.create_render_pass(&create_info, None)?
-        };
+        let render_pass = build_render_pass(device, depth_image, shading_image,
+                                            clear)?;
 
         let (depth_image_view, shading_image_view, framebuffer, extent) =
             build(device, render_pass.clone(), depth_image, shading_image)?;
@@ -110,9 +34,23 @@ impl TransparentPass {
             depth_image_view: depth_image_view,
             extent: extent,
             render_pass: render_pass,
+            clear: clear,
         })
     }
 
+    // Rebuild the render pass (and framebuffer) to clear the shading
+    // target first, or not
+    pub fn set_clear(&mut self, device: &Device, depth_image: &ImageWrap,
+                     shading_image: &ImageWrap, clear: bool)
+                     -> Result<(), Error>
+    {
+        self.render_pass = build_render_pass(device, depth_image, shading_image,
+                                             clear)?;
+        self.clear = clear;
+
+        self.rebuild(device, depth_image, shading_image)
+    }
+
     pub fn rebuild(&mut self, device: &Device,
                    depth_image: &ImageWrap,
                    shading_image: &ImageWrap)
@@ -129,10 +67,11 @@ impl TransparentPass {
         Ok(())
     }
 
-    pub fn record_entry(&self, command_buffer: CommandBuffer)
+    // (see GeometryPass::record_entry for `contents`)
+    pub fn record_entry(&self, command_buffer: CommandBuffer,
+                        contents: SubpassContents)
     {
-        use dacite::core::{Rect2D, Offset2D,
-                           SubpassContents, RenderPassBeginInfo,
+        use dacite::core::{Rect2D, Offset2D, RenderPassBeginInfo,
                            ClearValue, ClearDepthStencilValue, ClearColorValue};
 
         let begin_info = RenderPassBeginInfo {
@@ -144,14 +83,13 @@ impl TransparentPass {
                     depth: 0.0,
                     stencil: 0,
                 }),
-                ClearValue::Color( // unused
-                    ClearColorValue::Float32([0.0, 0.0, 0.0, 1.0])),
+                ClearValue::Color( // (only if clearing)
+                    ClearColorValue::Float32([0.0, 0.0, 0.0, 0.0])),
             ],
             chain: None,
         };
 
-        command_buffer.begin_render_pass(
-            &begin_info, SubpassContents::Inline);
+        command_buffer.begin_render_pass(&begin_info, contents);
     }
 
     pub fn record_exit(&self, command_buffer: CommandBuffer)
@@ -160,6 +98,104 @@ impl TransparentPass {
     }
 }
 
+fn build_render_pass(device: &Device, depth_image: &ImageWrap,
+                     shading_image: &ImageWrap, clear: bool)
+    -> Result<RenderPass, Error>
+{
+    use dacite::core::{AttachmentLoadOp, AttachmentStoreOp, ImageLayout,
+                       SubpassDescription, SubpassDescriptionFlags,
+                       PipelineBindPoint, SubpassIndex, SubpassDependency,
+                       PipelineStageFlags, AccessFlags, DependencyFlags,
+                       RenderPassCreateFlags, RenderPassCreateInfo,
+                       AttachmentReference, AttachmentIndex};
+
+    // The depth buffer is attached (not sampled) for the whole pass,
+    // having been moved back to DepthStencilAttachmentOptimal by
+    // TargetData::transition_for_transparent.  Whether a draw writes
+    // depth is up to its pipeline (PipelineSetup::write_depth), e.g.
+    // particles only test it.  Depth is stored because later passes
+    // (depth of field, TAA) and readbacks (read_depth, capture_gbuffer)
+    // use it after this pass.
+    let depth_attachment_description = depth_image.get_attachment_description(
+        AttachmentLoadOp::Load,
+        AttachmentStoreOp::Store,
+        ImageLayout::DepthStencilAttachmentOptimal,
+        ImageLayout::DepthStencilAttachmentOptimal
+    );
+
+    let depth_attachment_reference = AttachmentReference {
+        attachment: AttachmentIndex::Index(0),
+        layout: ImageLayout::DepthStencilAttachmentOptimal
+    };
+
+    // Normally the transparent objects blend over the lit scene, but they
+    // can be drawn on their own (see Config::transparent_clear)
+    let shading_load_op = if clear {
+        AttachmentLoadOp::Clear
+    } else {
+        AttachmentLoadOp::Load
+    };
+    let shading_attachment_description = shading_image.get_attachment_description(
+        shading_load_op,
+        AttachmentStoreOp::Store,
+        ImageLayout::ColorAttachmentOptimal,
+        ImageLayout::ColorAttachmentOptimal,
+    );
+
+    let shading_attachment_reference = AttachmentReference {
+        attachment: AttachmentIndex::Index(1),
+        layout: ImageLayout::ColorAttachmentOptimal
+    };
+
+    let subpass = SubpassDescription {
+        flags: SubpassDescriptionFlags::empty(),
+        pipeline_bind_point: PipelineBindPoint::Graphics,
+        input_attachments: vec![],
+        color_attachments: vec![shading_attachment_reference],
+        resolve_attachments: vec![],
+        depth_stencil_attachment: Some(depth_attachment_reference),
+        preserve_attachments: vec![],
+    };
+
+    // We must have written the depth buffer before this RenderPass reads it
+    let shading_to_transparent = SubpassDependency {
+        src_subpass: SubpassIndex::External, // shading (prior pass)
+        dst_subpass: SubpassIndex::Index(0), // us
+        src_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        dst_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        src_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
+        dst_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
+        dependency_flags:  DependencyFlags::BY_REGION,
+    };
+
+    // We must write the shading buffer before the next RenderPass reads it
+    let transparent_to_post = SubpassDependency {
+        src_subpass: SubpassIndex::Index(0), // us
+        dst_subpass: SubpassIndex::External, // post processing
+        src_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        dst_stage_mask: PipelineStageFlags::FRAGMENT_SHADER,
+        src_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
+        dst_access_mask: AccessFlags::COLOR_ATTACHMENT_READ,
+        dependency_flags:  DependencyFlags::BY_REGION,
+    };
+
+    let create_info = RenderPassCreateInfo {
+        flags: RenderPassCreateFlags::empty(),
+        attachments: vec![
+            depth_attachment_description,
+            shading_attachment_description,
+        ],
+        subpasses: vec![subpass],
+        dependencies: vec![
+            shading_to_transparent,
+            transparent_to_post,
+        ],
+        chain: None,
+    };
+
+    Ok(device.create_render_pass(&create_info, None)?)
+}
+
 fn build(device: &Device, render_pass: RenderPass, depth_image: &ImageWrap,
          shading_image: &ImageWrap)
     -> Result<(ImageView, ImageView, Framebuffer, Extent2D), Error>
@@ -1,6 +1,6 @@
 
 use dacite::core::{Device, RenderPass, Framebuffer, Extent2D, ImageView,
-                   CommandBuffer};
+                   CommandBuffer, SubpassContents};
 use crate::error::Error;
 use crate::renderer::swapchain_data::SwapchainData;
 use crate::renderer::image_wrap::ImageWrap;
@@ -118,12 +118,13 @@ impl UiPass {
         Ok(())
     }
 
+    // (see GeometryPass::record_entry for `contents`)
     pub fn record_entry(&self,
                         command_buffer: CommandBuffer,
-                        present_index: usize)
+                        present_index: usize,
+                        contents: SubpassContents)
     {
-        use dacite::core::{Rect2D, Offset2D,
-                           SubpassContents, RenderPassBeginInfo,
+        use dacite::core::{Rect2D, Offset2D, RenderPassBeginInfo,
                            ClearValue, ClearColorValue,
                            ClearDepthStencilValue};
 
@@ -141,8 +142,7 @@ impl UiPass {
             chain: None,
         };
 
-        command_buffer.begin_render_pass(
-            &begin_info, SubpassContents::Inline);
+        command_buffer.begin_render_pass(&begin_info, contents);
     }
 
     pub fn record_exit(
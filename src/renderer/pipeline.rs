@@ -1,5 +1,5 @@
 
-use dacite::core::{Device, Viewport, Rect2D, RenderPass, ShaderModule,
+use dacite::core::{Device, PipelineCache, Viewport, Rect2D, RenderPass, ShaderModule,
                    Pipeline, PipelineLayout, PipelineLayoutCreateInfo,
                    DescriptorSetLayout, PrimitiveTopology, CullModeFlags, FrontFace,
                    GraphicsPipelineCreateInfo, PipelineCreateFlags,
@@ -28,10 +28,13 @@ use dacite::core::{Device, Viewport, Rect2D, RenderPass, ShaderModule,
                    PipelineLayoutCreateFlags,
                    SpecializationInfo, PushConstantRange};
 use crate::error::Error;
-use super::{DepthHandling, BlendMode};
+use super::{DepthHandling, DepthBias, BlendMode};
 
+// `pipeline_cache` is the Renderer's (see Config::pipeline_cache_path),
+// shared by plugin pipelines and the renderer's own.
 pub fn create(
     device: &Device,
+    pipeline_cache: Option<&PipelineCache>,
     viewport: Viewport,
     scissors: Rect2D,
     reversed_depth_buffer: bool,
@@ -43,10 +46,16 @@ pub fn create(
     fragment_spec_info: Option<SpecializationInfo>,
     vertex_type: Option<PipelineVertexInputStateCreateInfo>,
     topology: PrimitiveTopology,
+    primitive_restart: bool,
+    line_width: f32,
     cull_mode: CullModeFlags,
     front_face: FrontFace,
+    depth_clamp: bool,
     depth_handling: DepthHandling,
+    depth_bias: Option<DepthBias>,
+    sample_shading: Option<f32>,
     blend: Vec<BlendMode>,
+    color_write_mask: ColorComponentFlags,
     push_constant_ranges: Vec<PushConstantRange>)
     -> Result<(PipelineLayout, Pipeline), Error>
 {
@@ -75,18 +84,41 @@ pub fn create(
                     src_color_blend_factor: match bm {
                         &BlendMode::Add => BlendFactor::One,
                         &BlendMode::PreMultiplied => BlendFactor::One,
+                        &BlendMode::Multiply => BlendFactor::DstColor,
+                        &BlendMode::Screen => BlendFactor::One,
+                        &BlendMode::ConstantAlpha => BlendFactor::ConstantAlpha,
+                        &BlendMode::ConstantColor => BlendFactor::ConstantColor,
+                        &BlendMode::Custom { src_color, .. } => src_color,
                         _ => BlendFactor::SrcAlpha,
                     },
                     dst_color_blend_factor: match bm {
                         &BlendMode::Add => BlendFactor::One,
+                        &BlendMode::Multiply => BlendFactor::Zero,
+                        &BlendMode::Screen => BlendFactor::OneMinusSrcColor,
+                        &BlendMode::ConstantAlpha => BlendFactor::OneMinusConstantAlpha,
+                        &BlendMode::ConstantColor => BlendFactor::OneMinusConstantColor,
+                        &BlendMode::Custom { dst_color, .. } => dst_color,
                         _ => BlendFactor::OneMinusSrcAlpha,
                     },
-                    color_blend_op: BlendOp::Add,
-                    src_alpha_blend_factor: BlendFactor::One,
-                    dst_alpha_blend_factor: BlendFactor::Zero,
-                    alpha_blend_op: BlendOp::Add,
-                    color_write_mask: ColorComponentFlags::R | ColorComponentFlags::G | ColorComponentFlags::B
+                    color_blend_op: match bm {
+                        &BlendMode::Custom { color_op, .. } => color_op,
+                        _ => BlendOp::Add,
+                    },
+                    src_alpha_blend_factor: match bm {
+                        &BlendMode::Custom { src_alpha, .. } => src_alpha,
+                        _ => BlendFactor::One,
+                    },
+                    dst_alpha_blend_factor: match bm {
+                        &BlendMode::Custom { dst_alpha, .. } => dst_alpha,
+                        _ => BlendFactor::Zero,
+                    },
+                    alpha_blend_op: match bm {
+                        &BlendMode::Custom { alpha_op, .. } => alpha_op,
+                        _ => BlendOp::Add,
+                    },
+                    color_write_mask: color_write_mask,
                 }).collect(),
+            // (dynamic, see Renderer::set_blend_constants)
             blend_constants: [0.0, 0.0, 0.0, 0.0],
             chain: None,
         })
@@ -107,7 +139,7 @@ pub fn create(
         input_assembly_state: PipelineInputAssemblyStateCreateInfo {
             flags: PipelineInputAssemblyStateCreateFlags::empty(),
             topology: topology,
-            primitive_restart_enable: false,
+            primitive_restart_enable: primitive_restart,
             chain: None,
         },
         tessellation_state: None,
@@ -119,23 +151,23 @@ pub fn create(
         }),
         rasterization_state: PipelineRasterizationStateCreateInfo {
             flags: PipelineRasterizationStateCreateFlags::empty(),
-            depth_clamp_enable: false,
+            depth_clamp_enable: depth_clamp,
             rasterizer_discard_enable: false,
             polygon_mode: PolygonMode::Fill,
             cull_mode: cull_mode,
             front_face: front_face,
-            depth_bias_enable: false,
-            depth_bias_constant_factor: 0.0,
+            depth_bias_enable: depth_bias.is_some(),
+            depth_bias_constant_factor: depth_bias.map_or(0.0, |b| b.constant_factor),
             depth_bias_clamp: 0.0,
-            depth_bias_slope_factor: 0.0,
-            line_width: 1.0,
+            depth_bias_slope_factor: depth_bias.map_or(0.0, |b| b.slope_factor),
+            line_width: line_width,
             chain: None,
         },
         multisample_state: Some(PipelineMultisampleStateCreateInfo {
             flags: PipelineMultisampleStateCreateFlags::empty(),
             rasterization_samples: SampleCountFlagBits::SampleCount1,
-            sample_shading_enable: false,
-            min_sample_shading: 0.0,
+            sample_shading_enable: sample_shading.is_some(),
+            min_sample_shading: sample_shading.unwrap_or(0.0),
             sample_mask: vec![],
             alpha_to_coverage_enable: false,
             alpha_to_one_enable: false,
@@ -143,14 +175,17 @@ pub fn create(
         }),
         depth_stencil_state: match depth_handling {
             DepthHandling::None => None,
-            DepthHandling::Some(test,write) => Some(PipelineDepthStencilStateCreateInfo {
+            DepthHandling::Some(test,write,compare) => Some(PipelineDepthStencilStateCreateInfo {
                 flags: Default::default(),
                 depth_test_enable: test,
                 depth_write_enable: write,
-                depth_compare_op: if reversed_depth_buffer {
-                    CompareOp::GreaterOrEqual
-                } else {
-                    CompareOp::LessOrEqual
+                depth_compare_op: match compare {
+                    Some(op) => op,
+                    None => if reversed_depth_buffer {
+                        CompareOp::GreaterOrEqual
+                    } else {
+                        CompareOp::LessOrEqual
+                    }
                 },
                 depth_bounds_test_enable: false,
                 stencil_test_enable: false,
@@ -180,7 +215,8 @@ pub fn create(
         color_blend_state: blend_create,
         dynamic_state: Some(PipelineDynamicStateCreateInfo {
             flags: Default::default(),
-            dynamic_states: vec![DynamicState::Viewport, DynamicState::Scissor],
+            dynamic_states: vec![DynamicState::Viewport, DynamicState::Scissor,
+                                 DynamicState::BlendConstants],
             chain: None,
         }),
         layout: layout.clone(),
@@ -218,7 +254,7 @@ pub fn create(
     }
 
     let create_infos = vec![create_info];
-    let pipelines = device.create_graphics_pipelines(None, &create_infos, None)
+    let pipelines = device.create_graphics_pipelines(pipeline_cache, &create_infos, None)
         .map_err(|(e, _)| e)?;
     Ok((layout, pipelines[0].clone()))
 }
@@ -0,0 +1,177 @@
+
+use std::sync::{Arc, Mutex};
+use dacite::core::{CommandBuffer, QueryPool, PipelineStageFlagBits};
+
+// The most distinct labels that can be timed (across all plugins)
+pub const PLUGIN_TS_QUERY_COUNT: u32 = 32;
+
+struct Labels {
+    // A label's query is its index here.  Labels are never removed.
+    names: Vec<String>,
+    // Queries written during the current recording
+    written: Vec<bool>,
+    // Writes are only valid inside the per-frame command buffers, which
+    // reset the query pool
+    recording: bool,
+    warned_full: bool,
+    // (see Config::gpu_timing)
+    enabled: bool,
+}
+
+// Lets plugins time their own sub-stages on the GPU.  Get one from
+// Renderer::plugin_timestamps(), keep it in the plugin, and call write()
+// from the record_* callbacks.  Each write stores the GPU time at that point
+// under its label; the results show up in Stats::plugin_timestamps as
+// milliseconds since the start of the frame, so a stage's duration is the
+// difference between two labels.
+//
+// The queries are reset at the start of every frame's command buffer, and a
+// query may only be written once between resets, so write each label at
+// most once per frame (e.g. not from record_probe, which runs per face).
+// Writes outside of the per-frame command buffers (such as in
+// Renderer::render_to_texture) are ignored, as are labels beyond the first
+// PLUGIN_TS_QUERY_COUNT.  With Config::gpu_timing off, every write is
+// ignored.
+#[derive(Clone)]
+pub struct PluginTimestamps {
+    query_pool: Option<QueryPool>, // None if timestamps are unsupported
+    labels: Arc<Mutex<Labels>>,
+}
+
+impl PluginTimestamps {
+    pub(crate) fn new(query_pool: Option<QueryPool>, enabled: bool) -> PluginTimestamps
+    {
+        PluginTimestamps {
+            query_pool: query_pool,
+            labels: Arc::new(Mutex::new(Labels {
+                names: Vec::new(),
+                written: vec![false; PLUGIN_TS_QUERY_COUNT as usize],
+                recording: false,
+                warned_full: false,
+                enabled: enabled,
+            })),
+        }
+    }
+
+    pub fn write(&self, command_buffer: &CommandBuffer, label: &str)
+    {
+        let query_pool = match self.query_pool {
+            Some(ref query_pool) => query_pool,
+            None => return,
+        };
+
+        let mut labels = self.labels.lock().unwrap();
+        if !labels.recording {
+            return;
+        }
+
+        let query = match labels.names.iter().position(|name| name == label) {
+            Some(query) => query,
+            None => {
+                if labels.names.len() >= PLUGIN_TS_QUERY_COUNT as usize {
+                    if !labels.warned_full {
+                        warn!("Too many plugin timestamp labels, ignoring {}", label);
+                        labels.warned_full = true;
+                    }
+                    return;
+                }
+                labels.names.push(label.to_owned());
+                labels.names.len() - 1
+            }
+        };
+
+        if labels.written[query] {
+            warn!("Plugin timestamp {} written twice in one frame", label);
+            return;
+        }
+        labels.written[query] = true;
+
+        command_buffer.write_timestamp(
+            PipelineStageFlagBits::BottomOfPipe,
+            query_pool,
+            query as u32);
+    }
+
+    // Whether writes are recorded (see Renderer::set_gpu_timing_enabled)
+    pub(crate) fn set_enabled(&self, enabled: bool)
+    {
+        self.labels.lock().unwrap().enabled = enabled;
+    }
+
+    // Reset the queries at the start of a per-frame command buffer, and
+    // accept writes until end_recording()
+    pub(crate) fn begin_recording(&self, command_buffer: &CommandBuffer)
+    {
+        if let Some(ref query_pool) = self.query_pool {
+            let mut labels = self.labels.lock().unwrap();
+            if !labels.enabled {
+                return;
+            }
+            command_buffer.reset_query_pool(query_pool, 0, PLUGIN_TS_QUERY_COUNT);
+            labels.recording = true;
+            for written in labels.written.iter_mut() {
+                *written = false;
+            }
+        }
+    }
+
+    // Write every query that no plugin wrote, so that reading the results
+    // never waits on a query that will not become available.  Returns the
+    // queries that plugins did write.
+    pub(crate) fn end_recording(&self, command_buffer: &CommandBuffer) -> Vec<u32>
+    {
+        let query_pool = match self.query_pool {
+            Some(ref query_pool) => query_pool,
+            None => return vec![],
+        };
+
+        let mut labels = self.labels.lock().unwrap();
+        if !labels.recording {
+            return vec![]; // disabled
+        }
+        labels.recording = false;
+
+        let mut written = Vec::new();
+        for query in 0..PLUGIN_TS_QUERY_COUNT {
+            if labels.written[query as usize] {
+                written.push(query);
+            } else {
+                command_buffer.write_timestamp(
+                    PipelineStageFlagBits::BottomOfPipe,
+                    query_pool,
+                    query);
+            }
+        }
+        written
+    }
+
+    // The queries written so far in the current recording
+    pub(crate) fn written(&self) -> Vec<u32>
+    {
+        let labels = self.labels.lock().unwrap();
+        (0..PLUGIN_TS_QUERY_COUNT)
+            .filter(|&query| labels.written[query as usize])
+            .collect()
+    }
+
+    // Count `queries` as written in the current recording, for a secondary
+    // command buffer that wrote them when it was recorded and is executed
+    // again without being re-recorded
+    pub(crate) fn mark_written(&self, queries: &[u32])
+    {
+        let mut labels = self.labels.lock().unwrap();
+        for &query in queries {
+            labels.written[query as usize] = true;
+        }
+    }
+
+    pub(crate) fn query_pool(&self) -> Option<&QueryPool>
+    {
+        self.query_pool.as_ref()
+    }
+
+    pub(crate) fn label(&self, query: u32) -> String
+    {
+        self.labels.lock().unwrap().names[query as usize].clone()
+    }
+}
@@ -4,11 +4,12 @@ use dacite::core::{Device, DescriptorPool, DescriptorSet, DescriptorSetLayout,
                    DescriptorType, CommandBuffer, RenderPass, Viewport, Rect2D,
                    PipelineBindPoint, Pipeline, PipelineLayout, PrimitiveTopology,
                    CullModeFlags, FrontFace, ShaderModuleCreateFlags,
-                   ShaderModuleCreateInfo, ShaderModule,
-                   SpecializationInfo, SpecializationMapEntry};
+                   ShaderModuleCreateInfo, ShaderModule, ColorComponentFlags,
+                   SpecializationInfo, SpecializationMapEntry, PipelineCache};
 use crate::error::Error;
 use serde::Deserialize;
 use super::target_data::TargetData;
+use super::descriptor_set_writer::DescriptorSetWriter;
 use super::{DepthHandling, BlendMode};
 
 #[repr(u32)]
@@ -19,20 +20,48 @@ pub enum Tonemapper {
     Exposure = 2,
     HybridLogGamma = 3,
     Falsecolor = 4,
+    // Reinhard applied to luminance only, preserving the hue and saturation
+    // of bright colors (which per-channel Reinhard washes out)
+    ReinhardLuminance = 5,
+}
+
+// The transfer function used to encode the tonemapped output for the
+// display.  Srgb is the default.  On sRGB swapchain formats, where the
+// hardware applies sRGB encoding, the others are pre-compensated for it.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum OutputTransfer {
+    Srgb = 0,
+    Gamma22 = 1,
+    Linear = 2,
+    // SMPTE ST 2084, with 1.0 mapped to 203 nits (BT.2408 reference white)
+    Pq = 3,
 }
 
 pub struct PostGfx {
     pipeline: Pipeline,
     pipeline_layout: PipelineLayout,
+    pipeline_cache: PipelineCache,
     descriptor_set: DescriptorSet,
     #[allow(dead_code)] // this must remain alive
     desc_layout: DescriptorSetLayout,
+    params_layout: DescriptorSetLayout,
+    render_pass: RenderPass,
+    viewport: Viewport,
+    scissors: Rect2D,
+    display_luminance: u32,
+    surface_needs_gamma: bool,
+    output_transfer: OutputTransfer,
+    reversed_depth_buffer: bool,
+    depth_of_field: bool,
     shading_image_view: ImageView,
+    depth_image_view: ImageView,
     sampler: Sampler,
 }
 
 impl PostGfx {
     pub fn new(device: &Device,
+               pipeline_cache: PipelineCache,
                descriptor_pool: DescriptorPool,
                target_data: &TargetData,
                render_pass: RenderPass,
@@ -40,7 +69,9 @@ impl PostGfx {
                scissors: Rect2D,
                display_luminance: u32,
                params_layout: DescriptorSetLayout,
-               surface_needs_gamma: bool)
+               surface_needs_gamma: bool,
+               reversed_depth_buffer: bool,
+               depth_of_field: bool)
               -> Result<PostGfx, Error>
     {
         let sampler = {
@@ -71,6 +102,9 @@ impl PostGfx {
         let shading_image_view = target_data.shading_image.
             get_image_view(device)?;
 
+        let depth_image_view = target_data.depth_image.
+            get_image_view(device)?;
+
         let desc_bindings = {
             use dacite::core::ShaderStageFlags;
             vec![
@@ -81,6 +115,13 @@ impl PostGfx {
                     stage_flags: ShaderStageFlags::FRAGMENT,
                     immutable_samplers: vec![],
                 },
+                DescriptorSetLayoutBinding {
+                    binding: 1,
+                    descriptor_type: DescriptorType::CombinedImageSampler,
+                    descriptor_count: 1,
+                    stage_flags: ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: vec![],
+                },
             ]
         };
 
@@ -108,48 +149,31 @@ impl PostGfx {
             descriptor_sets.pop().unwrap()
         };
 
-        let vertex_shader = vertex_shader(device)?;
+        let output_transfer = OutputTransfer::Srgb;
 
-        let fragment_shader = fragment_shader(device, display_luminance)?;
-
-        let fragment_spec = SpecializationInfo {
-            map_entries: vec![
-                SpecializationMapEntry { // near depth
-                    constant_id: 0,
-                    offset: 0,
-                    size: ::std::mem::size_of::<i32>(),
-                },
-            ],
-            data: {
-                let i: [i32; 1] = if surface_needs_gamma { [1] } else { [0] };
-                unsafe {
-                    ::std::slice::from_raw_parts(
-                        i.as_ptr() as *const u8,
-                        1 * ::std::mem::size_of::<i32>()).to_vec()
-                }
-            }
-        };
-
-        let (pipeline_layout, pipeline) =
-            super::pipeline::create(
-                device, viewport, scissors,
-                true, // reversed depth buffer irrelevant for post
-                render_pass, vec![desc_layout.clone(),
-                                  params_layout],
-                Some(vertex_shader), None, Some(fragment_shader), Some(fragment_spec),
-                None,
-                PrimitiveTopology::TriangleList,
-                CullModeFlags::NONE, FrontFace::Clockwise,
-                DepthHandling::None,
-                vec![BlendMode::Off],
-                vec![])?;
+        let (pipeline_layout, pipeline) = build_pipeline(
+            device, &pipeline_cache, render_pass.clone(), viewport, scissors.clone(),
+            desc_layout.clone(), params_layout.clone(),
+            display_luminance, surface_needs_gamma, output_transfer,
+            reversed_depth_buffer, depth_of_field)?;
 
         let mut post_gfx = PostGfx {
             pipeline: pipeline,
             pipeline_layout: pipeline_layout,
+            pipeline_cache: pipeline_cache,
             descriptor_set: descriptor_set,
             desc_layout: desc_layout,
+            params_layout: params_layout,
+            render_pass: render_pass,
+            viewport: viewport,
+            scissors: scissors,
+            display_luminance: display_luminance,
+            surface_needs_gamma: surface_needs_gamma,
+            output_transfer: output_transfer,
+            reversed_depth_buffer: reversed_depth_buffer,
+            depth_of_field: depth_of_field,
             shading_image_view: shading_image_view,
+            depth_image_view: depth_image_view,
             sampler: sampler,
         };
 
@@ -163,38 +187,65 @@ impl PostGfx {
     {
         self.shading_image_view = target_data.shading_image.
             get_image_view(device)?;
+        self.depth_image_view = target_data.depth_image.
+            get_image_view(device)?;
 
         self.write();
 
         Ok(())
     }
 
+    pub fn output_transfer(&self) -> OutputTransfer {
+        self.output_transfer
+    }
+
+    // Change the output transfer function. This rebuilds the pipeline, so
+    // the caller must ensure it is not in use and re-record command buffers.
+    pub fn set_output_transfer(&mut self, device: &Device,
+                               output_transfer: OutputTransfer)
+        -> Result<(), Error>
+    {
+        let (pipeline_layout, pipeline) = build_pipeline(
+            device, &self.pipeline_cache, self.render_pass.clone(), self.viewport,
+            self.scissors.clone(), self.desc_layout.clone(), self.params_layout.clone(),
+            self.display_luminance, self.surface_needs_gamma, output_transfer,
+            self.reversed_depth_buffer, self.depth_of_field)?;
+
+        self.pipeline_layout = pipeline_layout;
+        self.pipeline = pipeline;
+        self.output_transfer = output_transfer;
+
+        Ok(())
+    }
+
+    // Switch the depth convention (see Config::reversed_depth_buffer). This
+    // rebuilds the pipeline, so the caller must ensure it is not in use and
+    // re-record command buffers.
+    pub fn set_reversed_depth_buffer(&mut self, device: &Device,
+                                     reversed_depth_buffer: bool)
+        -> Result<(), Error>
+    {
+        let (pipeline_layout, pipeline) = build_pipeline(
+            device, &self.pipeline_cache, self.render_pass.clone(), self.viewport,
+            self.scissors.clone(), self.desc_layout.clone(), self.params_layout.clone(),
+            self.display_luminance, self.surface_needs_gamma, self.output_transfer,
+            reversed_depth_buffer, self.depth_of_field)?;
+
+        self.pipeline_layout = pipeline_layout;
+        self.pipeline = pipeline;
+        self.reversed_depth_buffer = reversed_depth_buffer;
+
+        Ok(())
+    }
+
     fn write(&mut self)
     {
-        use dacite::core::{WriteDescriptorSet, WriteDescriptorSetElements,
-                           DescriptorImageInfo};
-
-        DescriptorSet::update(
-            Some(&[
-                WriteDescriptorSet {
-                    dst_set: self.descriptor_set.clone(),
-                    dst_binding: 0,
-                    dst_array_element: 0, // only have 1 element
-                    descriptor_type: DescriptorType::CombinedImageSampler,
-                    elements: WriteDescriptorSetElements::ImageInfo(
-                        vec![
-                            DescriptorImageInfo {
-                                sampler: Some(self.sampler.clone()),
-                                image_view: Some(self.shading_image_view.clone()),
-                                image_layout: ImageLayout::ShaderReadOnlyOptimal,
-                            }
-                        ]
-                    ),
-                    chain: None,
-                },
-            ]),
-            None
-        );
+        DescriptorSetWriter::new(self.descriptor_set.clone())
+            .image(0, self.sampler.clone(), self.shading_image_view.clone(),
+                   ImageLayout::ShaderReadOnlyOptimal)
+            .image(1, self.sampler.clone(), self.depth_image_view.clone(),
+                   ImageLayout::ShaderReadOnlyOptimal)
+            .flush();
     }
 
     pub fn record(&self, command_buffer: CommandBuffer,
@@ -217,6 +268,85 @@ impl PostGfx {
     }
 }
 
+fn build_pipeline(device: &Device,
+                  pipeline_cache: &PipelineCache,
+                  render_pass: RenderPass,
+                  viewport: Viewport,
+                  scissors: Rect2D,
+                  desc_layout: DescriptorSetLayout,
+                  params_layout: DescriptorSetLayout,
+                  display_luminance: u32,
+                  surface_needs_gamma: bool,
+                  output_transfer: OutputTransfer,
+                  reversed_depth_buffer: bool,
+                  depth_of_field: bool)
+                  -> Result<(PipelineLayout, Pipeline), Error>
+{
+    let vertex_shader = vertex_shader(device)?;
+
+    let fragment_shader = fragment_shader(device, display_luminance)?;
+
+    let fragment_spec = SpecializationInfo {
+        map_entries: vec![
+            SpecializationMapEntry { // surface needs gamma
+                constant_id: 0,
+                offset: 0,
+                size: ::std::mem::size_of::<i32>(),
+            },
+            SpecializationMapEntry { // output transfer function
+                constant_id: 1,
+                offset: 1 * ::std::mem::size_of::<i32>() as u32,
+                size: ::std::mem::size_of::<i32>(),
+            },
+            SpecializationMapEntry { // depth of field enabled
+                constant_id: 2,
+                offset: 2 * ::std::mem::size_of::<i32>() as u32,
+                size: ::std::mem::size_of::<i32>(),
+            },
+            SpecializationMapEntry { // near depth
+                constant_id: 3,
+                offset: 3 * ::std::mem::size_of::<i32>() as u32,
+                size: ::std::mem::size_of::<f32>(),
+            },
+            SpecializationMapEntry { // far depth
+                constant_id: 4,
+                offset: 4 * ::std::mem::size_of::<i32>() as u32,
+                size: ::std::mem::size_of::<f32>(),
+            },
+        ],
+        // surface_needs_gamma, output_transfer, dof_enabled, near, then far
+        data: {
+            let (near, far) = if reversed_depth_buffer { (1.0_f32, 0.0_f32) } else { (0.0, 1.0) };
+            let d: [u32; 5] = [surface_needs_gamma as u32, output_transfer as u32,
+                               depth_of_field as u32, near.to_bits(), far.to_bits()];
+            unsafe {
+                ::std::slice::from_raw_parts(
+                    d.as_ptr() as *const u8,
+                    5 * ::std::mem::size_of::<u32>()).to_vec()
+            }
+        }
+    };
+
+    super::pipeline::create(
+        device, Some(pipeline_cache), viewport, scissors,
+        true, // reversed depth buffer irrelevant for post
+        render_pass, vec![desc_layout,
+                          params_layout],
+        Some(vertex_shader), None, Some(fragment_shader), Some(fragment_spec),
+        None,
+        PrimitiveTopology::TriangleList,
+        false, // no primitive restart
+        1.0, // line width
+        CullModeFlags::NONE, FrontFace::Clockwise,
+        false, // no depth clamp
+        DepthHandling::None,
+        None, // no depth bias
+        None, // no sample shading
+        vec![BlendMode::Off],
+        ColorComponentFlags::R | ColorComponentFlags::G | ColorComponentFlags::B,
+        vec![])
+}
+
 fn vertex_shader(device: &Device) -> Result<ShaderModule, Error>
 {
     let bytes: &[u8] = glsl_vs!(r#"
@@ -273,12 +403,20 @@ fn fragment_shader(device: &Device, _display_luminance: u32)
 #extension GL_ARB_shading_language_420pack : enable
 
 layout(constant_id = 0) const int surface_needs_gamma = 0;
+// 0 = sRGB, 1 = gamma 2.2, 2 = linear, 3 = PQ
+layout(constant_id = 1) const int output_transfer = 0;
+layout(constant_id = 2) const int dof_enabled = 0;
+layout(constant_id = 3) const float depth_near = 0.0;
+layout(constant_id = 4) const float depth_far = 1.0;
 
 layout (binding = 0) uniform sampler2D shadingTex;
+layout (binding = 1) uniform sampler2D depthTex;
 
 layout (set = 1, binding = 0) uniform UBO
 {
   mat4 inv_projection;
+  mat4 inv_view;
+  mat4 shadow_view_projection;
   vec4 dlight_directions[2];
   vec4 dlight_irradiances[2];
   float bloom_strength;
@@ -287,6 +425,16 @@ layout (set = 1, binding = 0) uniform UBO
   float ambient;
   float white_level;
   int tonemapper;
+  float shadow_bias;
+  float focus_distance;
+  float aperture;
+  float star_brightness;
+  float star_size;
+  float near_plane;
+  float far_plane;
+  float depth_linearize_scale;
+  float depth_linearize_offset;
+  float exposure;
 } ubo;
 
 layout (location = 0) in vec2 inUV;
@@ -338,13 +486,67 @@ vec3 reinhard_tonemap(vec3 scene_referred) {
   return scene_referred / (scene_referred + vec3(1.0));
 }
 
+vec3 reinhard_luminance_tonemap(vec3 scene_referred) {
+  float lum = dot(vec3(0.2126729, 0.7151522, 0.0721750), scene_referred);
+  if (lum <= 0.0) {
+    return vec3(0.0);
+  }
+  float tonemapped_lum = lum / (lum + 1.0);
+  // Rescaling can push a saturated channel above 1.0; clamp what remains
+  return clamp(scene_referred * (tonemapped_lum / lum), 0.0, 1.0);
+}
+
 vec3 clamp_tonemap(vec3 scene_referred) {
   return clamp(scene_referred, 0.0, 1.0);
 }
 
+// (ubo.exposure has already been applied to every tonemapper's input)
 vec3 exposure_tonemap(vec3 scene_referred) {
-  const float exposure = 1.0;
-  return vec3(1.0) - exp(-scene_referred * exposure);
+  return vec3(1.0) - exp(-scene_referred);
+}
+
+// Distance from the camera to the surface seen at uv (view space)
+float view_distance(vec2 uv) {
+  float fragdepth = texture(depthTex, uv).r;
+  vec4 clipPos;
+  clipPos.xy = (2.0 * uv) - 1;
+  clipPos.z = (fragdepth - depth_near) / (depth_far - depth_near);
+  clipPos.w = 1.0;
+  vec4 position = ubo.inv_projection * clipPos;
+  return length(position.xyz / position.w);
+}
+
+// Circle of confusion radius, in pixels
+float circle_of_confusion(vec2 uv) {
+  float dist = max(view_distance(uv), 0.0001);
+  return min(ubo.aperture * abs(1.0 - ubo.focus_distance / dist), ubo.aperture);
+}
+
+// Gather over a disc of the pixel's circle of confusion.  Samples only
+// contribute if their own circle of confusion reaches this pixel, so that
+// sharp foreground edges don't bleed into blurred backgrounds.
+vec3 depth_of_field(vec2 uv) {
+  const int taps = 24;
+  const float golden_angle = 2.39996323;
+
+  vec3 center = texture(shadingTex, uv).rgb;
+  float coc = circle_of_confusion(uv);
+  if (coc < 0.5) {
+    return center;
+  }
+
+  vec2 texel = 1.0 / vec2(textureSize(shadingTex, 0));
+  vec3 sum = center;
+  float total = 1.0;
+  for (int i = 1; i <= taps; i++) {
+    float r = coc * sqrt(float(i) / float(taps));
+    float theta = float(i) * golden_angle;
+    vec2 suv = uv + vec2(cos(theta), sin(theta)) * r * texel;
+    float weight = clamp(circle_of_confusion(suv) - r + 1.0, 0.0, 1.0);
+    sum += texture(shadingTex, suv).rgb * weight;
+    total += weight;
+  }
+  return sum / total;
 }
 
 float srgb_gamma(float linear) {
@@ -355,10 +557,48 @@ float srgb_gamma(float linear) {
   }
 }
 
+// Undoes the sRGB encoding that the hardware applies to sRGB surfaces
+float srgb_linearize(float encoded) {
+  if (encoded <= 0.04045) {
+    return encoded / 12.92;
+  } else {
+    return pow((encoded + 0.055) / (1 + 0.055), 2.4);
+  }
+}
+
+float pq(float linear) {
+  const float m1 = 0.1593017578125;
+  const float m2 = 78.84375;
+  const float c1 = 0.8359375;
+  const float c2 = 18.8515625;
+  const float c3 = 18.6875;
+  const float reference_white = 203.0; // nits
+
+  float y = clamp(linear * reference_white / 10000.0, 0.0, 1.0);
+  float ym = pow(y, m1);
+  return pow((c1 + c2 * ym) / (1 + c3 * ym), m2);
+}
+
+float encode(float linear) {
+  if (output_transfer == 1) {
+    return pow(max(linear, 0.0), 1/2.2);
+  } else if (output_transfer == 2) {
+    return linear;
+  } else {
+    return pq(linear);
+  }
+}
+
 void main()
 {
   // Load scene referred color from shadingTex
-  vec3 scene_referred = texture(shadingTex, inUV).rgb;
+  vec3 scene_referred;
+  if (dof_enabled != 0 && ubo.aperture > 0.0) {
+    scene_referred = depth_of_field(inUV);
+  } else {
+    scene_referred = texture(shadingTex, inUV).rgb;
+  }
+  scene_referred *= ubo.exposure;
 
   vec3 tonemapped;
   if (ubo.tonemapper == 0) {
@@ -376,17 +616,34 @@ void main()
   else if (ubo.tonemapper == 4) {
     tonemapped = falsecolor_tonemap(scene_referred);
   }
+  else if (ubo.tonemapper == 5) {
+    tonemapped = reinhard_luminance_tonemap(scene_referred);
+  }
   else {
     tonemapped = reinhard_tonemap(scene_referred);
   }
 
-  if (surface_needs_gamma != 0) {
-    outFragColor = vec4(srgb_gamma(tonemapped.r),
-                        srgb_gamma(tonemapped.g),
-                        srgb_gamma(tonemapped.b),
-                        1.0);
+  if (output_transfer == 0) {
+    if (surface_needs_gamma != 0) {
+      outFragColor = vec4(srgb_gamma(tonemapped.r),
+                          srgb_gamma(tonemapped.g),
+                          srgb_gamma(tonemapped.b),
+                          1.0);
+    } else {
+      outFragColor = vec4(tonemapped, 1.0);
+    }
   } else {
-    outFragColor = vec4(tonemapped, 1.0);
+    vec3 encoded = vec3(encode(tonemapped.r),
+                        encode(tonemapped.g),
+                        encode(tonemapped.b));
+    if (surface_needs_gamma != 0) {
+      outFragColor = vec4(encoded, 1.0);
+    } else {
+      outFragColor = vec4(srgb_linearize(encoded.r),
+                          srgb_linearize(encoded.g),
+                          srgb_linearize(encoded.b),
+                          1.0);
+    }
   }
 }
 "#);
@@ -0,0 +1,91 @@
+
+use std::sync::{Arc, Mutex};
+use dacite::core::{Device, BufferUsageFlags};
+use crate::error::Error;
+use super::buffer::{HostVisibleBuffer, DeviceLocalBuffer};
+use super::image_wrap::ImageWrap;
+use super::memory::{Memory, Lifetime};
+use super::commander::Commander;
+use super::mesh::VulkanMesh;
+use super::resource_manager::ResourceManager;
+use super::setup::QueueIndices;
+
+// Loads textures, meshes and buffers on a thread other than the render
+// thread, so that reading, decompressing and uploading assets doesn't stall
+// rendering.  Get one from Renderer::resource_loader() and move it to a
+// worker thread; each worker needs its own.
+//
+// A loader has its own staging buffer, command pools and resource cache
+// (so the same asset loaded by the renderer and by a loader is uploaded
+// twice), and shares the renderer's device memory and queues, which it locks
+// only while allocating and submitting.  Vertex deserializers registered
+// before the loader was made apply to it as well.
+//
+// Loaded resources are ready to use as soon as the call returns.  Hand them
+// to the plugin that draws them with the Sender from Renderer::plugin_sender()
+// (they arrive in Plugin::receive() on the render thread), then have the
+// plugin's next update() return true so that command buffers are re-recorded
+// with them.
+pub struct ResourceLoader {
+    device: Device,
+    memory: Arc<Mutex<Memory>>,
+    commander: Commander,
+    staging_buffer: HostVisibleBuffer,
+    resource_manager: ResourceManager,
+}
+
+impl ResourceLoader {
+    pub(crate) fn new(device: &Device,
+                      memory: Arc<Mutex<Memory>>,
+                      commander: &Commander,
+                      queue_indices: &QueueIndices,
+                      resource_manager: &ResourceManager)
+                      -> Result<ResourceLoader, Error>
+    {
+        let commander = commander.new_for_thread(device, queue_indices)?;
+
+        let staging_buffer = HostVisibleBuffer::new::<u8>(
+            device, &mut memory.lock().unwrap(),
+            crate::renderer::setup::requirements::MAX_GPU_UPLOAD as usize,
+            BufferUsageFlags::TRANSFER_SRC,
+            Lifetime::Temporary, "Loader Staging Buffer")?;
+
+        Ok(ResourceLoader {
+            device: device.clone(),
+            memory: memory,
+            commander: commander,
+            staging_buffer: staging_buffer,
+            resource_manager: resource_manager.new_for_thread(),
+        })
+    }
+
+    pub fn load_mesh(&mut self, dir: &str, name: &str) -> Result<VulkanMesh, Error>
+    {
+        self.resource_manager.load_mesh(
+            &self.device, &self.memory, &self.commander,
+            &mut self.staging_buffer, dir, name)
+    }
+
+    pub fn load_texture(&mut self, name: &str) -> Result<ImageWrap, Error>
+    {
+        self.resource_manager.load_texture(
+            &self.device, &self.memory, &self.commander,
+            &mut self.staging_buffer, name)
+    }
+
+    pub fn load_cubemap(&mut self, names: [&str; 6]) -> Result<ImageWrap, Error>
+    {
+        self.resource_manager.load_cubemap(
+            &self.device, &self.memory, &self.commander,
+            &mut self.staging_buffer, names)
+    }
+
+    pub fn load_buffer(&mut self,
+                       usage: BufferUsageFlags,
+                       name: &str) -> Result<DeviceLocalBuffer, Error>
+    {
+        self.resource_manager.load_buffer(
+            &self.device, &mut self.memory.lock().unwrap(), &self.commander,
+            &mut self.staging_buffer, usage, name)
+    }
+}
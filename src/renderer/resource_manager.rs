@@ -3,36 +3,94 @@ use crate::error::Error;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
-use std::path::PathBuf;
-use dacite::core::{Device, ShaderModule, BufferUsageFlags};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use dacite::core::{Device, ShaderModule, BufferUsageFlags, Format, Extent2D,
+                   Extent3D, Offset3D, PhysicalDeviceLimits, ComponentMapping};
 
-use siege_mesh::VertexType;
+use siege_mesh::{VertexType, Vertex, Mesh};
 use super::buffer::{HostVisibleBuffer, DeviceLocalBuffer};
 use super::image_wrap::{ImageWrap, ImageWrapType};
 use super::memory::{Memory, Lifetime};
 use super::commander::Commander;
-use super::mesh::VulkanMesh;
+use super::mesh::{VulkanMesh, MeshMaterial};
+use super::spirv::{self, ShaderInterface};
+
+// The siege-mesh file header: a u32 magic number, then the u32 vertex type
+// id (both little-endian), with the mesh data after it
+const MESH_HEADER_LEN: usize = 8;
+const MESH_VERTEX_TYPE_OFFSET: usize = 4;
+
+// Uploads a mesh file's contents (after the header) for a vertex type
+// registered with register_vertex_deserializer.  These are shared with the
+// resource managers of ResourceLoaders on other threads.
+type MeshLoader = Arc<dyn Fn(&Device, &mut Memory, &Commander,
+                             &mut HostVisibleBuffer, &[u8], &str)
+                             -> Result<VulkanMesh, Error> + Send + Sync>;
 
 pub struct ResourceManager {
     asset_path: PathBuf,
     shaders: HashMap<String, ShaderModule>,
+    shader_interfaces: HashMap<String, ShaderInterface>,
     meshes: HashMap<String, VulkanMesh>,
+    mesh_loaders: HashMap<u32, MeshLoader>,
     textures: HashMap<String, ImageWrap>,
     buffers: HashMap<String, DeviceLocalBuffer>,
+    texture_compression_etc2: bool,
+    max_image_dimension_2d: u32,
+    max_image_dimension_cube: u32,
 }
 
 impl ResourceManager {
-    pub fn new(asset_path: PathBuf) -> ResourceManager
+    pub fn new(asset_path: PathBuf,
+               texture_compression_etc2: bool,
+               limits: &PhysicalDeviceLimits) -> ResourceManager
     {
         ResourceManager {
             asset_path: asset_path,
             shaders: HashMap::new(),
+            shader_interfaces: HashMap::new(),
             meshes: HashMap::new(),
+            mesh_loaders: HashMap::new(),
             textures: HashMap::new(),
             buffers: HashMap::new(),
+            texture_compression_etc2: texture_compression_etc2,
+            max_image_dimension_2d: limits.max_image_dimension_2d,
+            max_image_dimension_cube: limits.max_image_dimension_cube,
         }
     }
 
+    // A resource manager for a ResourceLoader's thread, with the same
+    // settings and vertex deserializers but its own (empty) caches
+    pub fn new_for_thread(&self) -> ResourceManager
+    {
+        ResourceManager {
+            asset_path: self.asset_path.clone(),
+            shaders: HashMap::new(),
+            shader_interfaces: HashMap::new(),
+            meshes: HashMap::new(),
+            mesh_loaders: self.mesh_loaders.clone(),
+            textures: HashMap::new(),
+            buffers: HashMap::new(),
+            texture_compression_etc2: self.texture_compression_etc2,
+            max_image_dimension_2d: self.max_image_dimension_2d,
+            max_image_dimension_cube: self.max_image_dimension_cube,
+        }
+    }
+
+    // Forget every cached shader, mesh, texture and buffer, so they are
+    // loaded afresh next time.  Their memory returns to its chunk once every
+    // clone handed out has been dropped too.  Registered vertex
+    // deserializers are kept.
+    pub fn clear_all(&mut self)
+    {
+        self.shaders.clear();
+        self.shader_interfaces.clear();
+        self.meshes.clear();
+        self.textures.clear();
+        self.buffers.clear();
+    }
+
     pub fn load_shader(&mut self, device: &Device, name: &str) -> Result<ShaderModule, Error>
     {
         use dacite::core::{ShaderModuleCreateInfo, ShaderModuleCreateFlags};
@@ -41,15 +99,7 @@ impl ResourceManager {
             return Ok(s.clone());
         }
 
-        let mut path = self.asset_path.clone();
-        path.push("shaders");
-        path.push(format!("{}.spv", name));
-
-        let shader_spv_file = File::open(&path)?;
-        // FIXME: this just skips bad bytes, rather than erroring
-        let bytes: Vec<u8> = shader_spv_file.bytes()
-            .filter_map(|byte| byte.ok())
-            .collect();
+        let bytes = self.read_shader(name)?;
 
         let create_info = ShaderModuleCreateInfo {
             flags: ShaderModuleCreateFlags::empty(),
@@ -64,9 +114,55 @@ impl ResourceManager {
         Ok(shader_module)
     }
 
+    // The descriptor bindings and push constants a shader declares
+    pub fn shader_interface(&mut self, name: &str) -> Result<ShaderInterface, Error>
+    {
+        if let Some(i) = self.shader_interfaces.get(name) {
+            return Ok(i.clone());
+        }
+
+        let interface = spirv::reflect(&self.read_shader(name)?)?;
+
+        self.shader_interfaces.insert(name.to_owned(), interface.clone());
+
+        Ok(interface)
+    }
+
+    fn read_shader(&self, name: &str) -> Result<Vec<u8>, Error>
+    {
+        let mut path = self.asset_path.clone();
+        path.push("shaders");
+        path.push(format!("{}.spv", name));
+
+        let shader_spv_file = File::open(&path)?;
+        // FIXME: this just skips bad bytes, rather than erroring
+        Ok(shader_spv_file.bytes()
+           .filter_map(|byte| byte.ok())
+           .collect())
+    }
+
+    // Load meshes whose header has the given vertex type id with
+    // `deserialize`, which is handed the file contents after the header.
+    // This takes precedence over the built-in vertex types.
+    pub fn register_vertex_deserializer<V, F>(&mut self, vertex_type_id: u32,
+                                              deserialize: F)
+        where V: Vertex + 'static,
+              F: Fn(&[u8]) -> Result<Mesh<V>, Error> + Send + Sync + 'static
+    {
+        self.mesh_loaders.insert(vertex_type_id, Arc::new(
+            move |device, memory, commander, staging_buffer, bytes, name| {
+                let mesh = deserialize(bytes)?;
+                VulkanMesh::new(device, memory, commander,
+                                staging_buffer, mesh, name)
+            }));
+    }
+
+    // (memory is locked only once the file has been read, so that loading
+    // on a ResourceLoader's thread holds up the render thread no more than
+    // it must)
     pub fn load_mesh(&mut self,
                      device: &Device,
-                     memory: &mut Memory,
+                     memory: &Mutex<Memory>,
                      commander: &Commander,
                      staging_buffer: &mut HostVisibleBuffer,
                      dir: &str, // by type, e.g. 'graybox'
@@ -83,8 +179,27 @@ impl ResourceManager {
         path.push(dir);
         path.push(format!("{}.mesh", name));
 
-        let (vertex_type, bytes) = ::siege_mesh::load_header(&path)?;
-        let vulkan_mesh = {
+        // siege-mesh files carry no material, so it comes from a file
+        // beside the mesh, if there is one
+        let material = match ::std::fs::read_to_string(path.with_extension("material")) {
+            Ok(text) => Some(MeshMaterial::parse(&text)?),
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        // Registered vertex types come first.  siege_mesh::load_header
+        // rejects ids it doesn't know, so we read the id ourselves and skip
+        // the header for those.
+        let vertex_type_id = read_vertex_type_id(&path)?;
+        let mut vulkan_mesh = if let Some(loader) = self.mesh_loaders.get(&vertex_type_id) {
+            let contents = ::std::fs::read(&path)?;
+            let bytes = contents.get(MESH_HEADER_LEN..).unwrap_or(&[]);
+            let mut memory = memory.lock().unwrap();
+            loader(device, &mut *memory, commander, staging_buffer, bytes, name)?
+        } else {
+            let (vertex_type, bytes) = ::siege_mesh::load_header(&path)?;
+            let mut memory = memory.lock().unwrap();
+            let memory = &mut *memory;
             // FIXME: this per-vertex-type code is probably not required
             // anymore; will need to bubble up changes into siege-mesh.
             match vertex_type {
@@ -131,15 +246,19 @@ impl ResourceManager {
             }
         };
 
+        vulkan_mesh.material = material;
+
         self.meshes.insert(name.to_owned(), vulkan_mesh.clone());
 
         Ok(vulkan_mesh)
     }
 
+    // (memory is locked only to allocate the image, not while reading,
+    // decompressing or uploading)
     pub fn load_texture(
         &mut self,
         device: &Device,
-        memory: &mut Memory,
+        memory: &Mutex<Memory>,
         commander: &Commander,
         staging_buffer: &mut HostVisibleBuffer,
         name: &str)
@@ -150,6 +269,217 @@ impl ResourceManager {
             return Ok(texref.clone());
         }
 
+        let (dds, format, component_mapping) = self.read_dds(name)?;
+        let etc2_block_size = crate::format::etc2_block_size(format);
+        //println!("{} format is {:?}", name, format);
+
+        use ddsfile::Caps2;
+        let image_wrap_type = if dds.header.caps2.contains(Caps2::CUBEMAP) {
+            ImageWrapType::Cubemap
+        } else {
+            ImageWrapType::Standard
+        };
+        let num_layers = dds.get_num_array_layers();
+
+        use dacite::core::Extent3D;
+        let extent = Extent3D {
+            width: dds.get_width(),
+            height: dds.get_height(),
+            depth: dds.get_depth(),
+        };
+        self.check_texture_size(extent.width, extent.height,
+                                image_wrap_type == ImageWrapType::Cubemap)?;
+
+        // Copy texture to staging buffer
+        let main_texture_size = match etc2_block_size {
+            Some(block_size) => {
+                // ddsfile doesn't know ETC2 layouts, but the layers are
+                // contiguous so we can copy the data whole. Blocks are 4x4.
+                staging_buffer.write_array(&*dds.data, Some(0))?;
+                let blocks_wide = (extent.width + 3) / 4;
+                let blocks_high = (extent.height + 3) / 4;
+                blocks_wide * blocks_high * block_size
+            },
+            None => {
+                let mut offset: usize = 0;
+                for layer in 0..num_layers {
+                    let data = dds.get_data(layer)?;
+                    staging_buffer.write_array(data, Some(offset))?;
+                    offset += data.len();
+                }
+                dds.get_main_texture_size().unwrap()
+            }
+        };
+
+        // create image wrap
+        use dacite::core::{ImageLayout, ImageTiling, ImageUsageFlags};
+        let mut image_wrap = ImageWrap::new(
+            device, &mut *memory.lock().unwrap(), format, component_mapping,
+            dds.get_num_mipmap_levels(),
+            extent,
+            image_wrap_type,
+            ImageLayout::Undefined,
+            ImageTiling::Optimal,
+            ImageUsageFlags::TRANSFER_DST | ImageUsageFlags::SAMPLED,
+            Lifetime::Temporary,
+            false, // not solo
+            &*format!("texture {}", name))?;
+
+        // copy_in_from_buffer
+        // (this will transition to ImageLayout::TransferDstOptimal first)
+        image_wrap.copy_in_from_buffer(
+            device,
+            &commander,
+            &staging_buffer.inner(),
+            main_texture_size
+        )?;
+
+        // transfer layout to ImageLayout::ShaderReadOnlyOptimal
+        use dacite::core::{AccessFlags, ImageAspectFlags, OptionalMipLevels,
+                           OptionalArrayLayers, ImageSubresourceRange,
+                           PipelineStageFlags};
+        image_wrap.transition_layout_now(
+            device,
+            ImageLayout::Undefined, ImageLayout::ShaderReadOnlyOptimal,
+            AccessFlags::TRANSFER_WRITE, AccessFlags::SHADER_READ,
+            PipelineStageFlags::TRANSFER, PipelineStageFlags::VERTEX_SHADER,
+            ImageSubresourceRange {
+                aspect_mask: ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: OptionalMipLevels::MipLevels(1),
+                base_array_layer: 0,
+                layer_count: OptionalArrayLayers::ArrayLayers(num_layers),
+            },
+            &commander)?;
+        // And wait until that completes
+        // FIXME - this is a CPU side stall.
+
+        // insert to hashmap
+        self.textures.insert(name.to_owned(), image_wrap.clone());
+
+        Ok(image_wrap)
+    }
+
+    // Load six 2D textures (each a DDS file, as for load_texture) as the
+    // faces of a cubemap, in the order +X, -X, +Y, -Y, +Z, -Z.  The faces
+    // must share a format, size and mip count.  The cubemap is cached under
+    // the face names joined with '|'.
+    pub fn load_cubemap(
+        &mut self,
+        device: &Device,
+        memory: &Mutex<Memory>,
+        commander: &Commander,
+        staging_buffer: &mut HostVisibleBuffer,
+        names: [&str; 6])
+        -> Result<ImageWrap, Error>
+    {
+        let key = names.join("|");
+
+        // Check if we already have it
+        if let Some(texref) = self.textures.get(&key) {
+            return Ok(texref.clone());
+        }
+
+        let mut faces = Vec::with_capacity(6);
+        for name in names.iter() {
+            faces.push(self.read_dds(name)?);
+        }
+
+        let (format, component_mapping) = (faces[0].1, faces[0].2.clone());
+        let (width, height) = (faces[0].0.get_width(), faces[0].0.get_height());
+        let mip_levels = faces[0].0.get_num_mipmap_levels();
+        for (name, &(ref dds, face_format, _)) in names.iter().zip(faces.iter()) {
+            if face_format != format
+                || dds.get_width() != width || dds.get_height() != height
+                || dds.get_num_mipmap_levels() != mip_levels
+            {
+                return Err(Error::General(format!(
+                    "Cubemap face {} ({:?}, {}x{}, {} mips) doesn't match face {} ({:?}, {}x{}, {} mips)",
+                    name, face_format, dds.get_width(), dds.get_height(),
+                    dds.get_num_mipmap_levels(),
+                    names[0], format, width, height, mip_levels)));
+            }
+            if dds.get_depth() > 1 || dds.get_num_array_layers() > 1 {
+                return Err(Error::General(format!(
+                    "Cubemap face {} must be a single 2D texture", name)));
+            }
+        }
+        if width != height {
+            return Err(Error::General(format!(
+                "Cubemap faces must be square, not {}x{}", width, height)));
+        }
+        self.check_texture_size(width, height, true)?;
+
+        // Copy the faces, each with its mips, to the staging buffer
+        let etc2_block_size = crate::format::etc2_block_size(format);
+        let face_size = faces[0].0.data.len();
+        if (face_size * 6) as u64 > staging_buffer.size() {
+            return Err(Error::General(format!(
+                "Cubemap data of {} bytes exceeds the staging buffer", face_size * 6)));
+        }
+        for (i, &(ref dds, _, _)) in faces.iter().enumerate() {
+            staging_buffer.write_array(&*dds.data, Some(i * face_size))?;
+        }
+        let main_texture_size = match etc2_block_size {
+            Some(block_size) => {
+                let blocks_wide = (width + 3) / 4;
+                let blocks_high = (height + 3) / 4;
+                blocks_wide * blocks_high * block_size
+            },
+            None => faces[0].0.get_main_texture_size().unwrap(),
+        };
+
+        // create image wrap
+        use dacite::core::{ImageLayout, ImageTiling, ImageUsageFlags};
+        let mut image_wrap = ImageWrap::new(
+            device, &mut *memory.lock().unwrap(), format, component_mapping,
+            mip_levels,
+            Extent3D { width: width, height: height, depth: 1 },
+            ImageWrapType::Cubemap,
+            ImageLayout::Undefined,
+            ImageTiling::Optimal,
+            ImageUsageFlags::TRANSFER_DST | ImageUsageFlags::SAMPLED,
+            Lifetime::Temporary,
+            false, // not solo
+            &*format!("cubemap {}", names[0]))?;
+
+        // (this will transition to ImageLayout::TransferDstOptimal first)
+        image_wrap.copy_in_faces_from_buffer(
+            device,
+            &commander,
+            &staging_buffer.inner(),
+            face_size as u32,
+            main_texture_size
+        )?;
+
+        // transfer layout to ImageLayout::ShaderReadOnlyOptimal
+        use dacite::core::{AccessFlags, ImageAspectFlags, OptionalMipLevels,
+                           OptionalArrayLayers, ImageSubresourceRange,
+                           PipelineStageFlags};
+        image_wrap.transition_layout_now(
+            device,
+            ImageLayout::TransferDstOptimal, ImageLayout::ShaderReadOnlyOptimal,
+            AccessFlags::TRANSFER_WRITE, AccessFlags::SHADER_READ,
+            PipelineStageFlags::TRANSFER, PipelineStageFlags::FRAGMENT_SHADER,
+            ImageSubresourceRange {
+                aspect_mask: ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: OptionalMipLevels::MipLevels(mip_levels),
+                base_array_layer: 0,
+                layer_count: OptionalArrayLayers::ArrayLayers(6),
+            },
+            &commander)?;
+
+        self.textures.insert(key, image_wrap.clone());
+
+        Ok(image_wrap)
+    }
+
+    // Read and decompress the DDS file of texture `name`, and determine its
+    // format
+    fn read_dds(&self, name: &str)
+                -> Result<(ddsfile::Dds, Format, ComponentMapping), Error>
+    {
         let mut path = self.asset_path.clone();
 
         // All textures under the siege engine are stored in DDS files
@@ -182,49 +512,74 @@ impl ResourceManager {
                             None => return Err(Error::UnsupportedFormat),
                         }
                     },
-                    None => return Err(Error::UnsupportedFormat),
+                    None => match dds.header.spf.fourcc.as_ref()
+                        .and_then(|fourcc| crate::format::from_fourcc(fourcc.0))
+                    {
+                        Some(f) => (f, ComponentMapping::identity()),
+                        None => return Err(Error::UnsupportedFormat),
+                    }
                 }
             }
         };
-        //println!("{} format is {:?}", name, format);
+        if crate::format::etc2_block_size(format).is_some() && !self.texture_compression_etc2 {
+            return Err(Error::UnsupportedFormat);
+        }
 
-        use ddsfile::Caps2;
-        let image_wrap_type = if dds.header.caps2.contains(Caps2::CUBEMAP) {
-            ImageWrapType::Cubemap
-        } else {
-            ImageWrapType::Standard
-        };
-        let num_layers = dds.get_num_array_layers();
+        Ok((dds, format, component_mapping))
+    }
 
-        use dacite::core::Extent3D;
-        let extent = Extent3D {
-            width: dds.get_width(),
-            height: dds.get_height(),
-            depth: dds.get_depth(),
+    // Create a texture from raw pixel bytes (e.g. procedurally generated).
+    // The data must already be in the given format, tightly packed, single
+    // mip level. These are not cached.
+    pub fn create_texture(
+        &mut self,
+        device: &Device,
+        memory: &mut Memory,
+        commander: &Commander,
+        staging_buffer: &mut HostVisibleBuffer,
+        format: Format,
+        extent: Extent2D,
+        data: &[u8])
+        -> Result<ImageWrap, Error>
+    {
+        self.check_texture_size(extent.width, extent.height, false)?;
+
+        // A short slice would upload whatever the staging buffer held
+        let size = match crate::format::image_size(format, extent.width, extent.height) {
+            Some(size) => size as usize,
+            None => return Err(Error::UnsupportedFormat),
         };
+        if data.len() != size {
+            return Err(Error::General(format!(
+                "Texture data of {} bytes doesn't match the {} bytes of a {}x{} {:?} texture",
+                data.len(), size, extent.width, extent.height, format)));
+        }
 
-        // Copy texture to staging buffer
-        let mut offset: usize = 0;
-        for layer in 0..num_layers {
-            let data = dds.get_data(layer)?;
-            staging_buffer.write_array(data, Some(offset))?;
-            offset += data.len();
+        if data.len() as u64 > staging_buffer.size() {
+            return Err(Error::General(format!(
+                "Texture data of {} bytes exceeds the staging buffer", data.len())));
         }
 
+        // Copy texture to staging buffer
+        staging_buffer.write_array(data, None)?;
+
         // create image wrap
-        use dacite::core::{ImageLayout, ImageTiling, ImageUsageFlags,
-                           ComponentMapping};
+        use dacite::core::{ImageLayout, ImageTiling, ImageUsageFlags};
         let mut image_wrap = ImageWrap::new(
-            device, memory, format, component_mapping,
-            dds.get_num_mipmap_levels(),
-            extent,
-            image_wrap_type,
+            device, memory, format, ComponentMapping::identity(),
+            1, // just one mip
+            Extent3D { width: extent.width, height: extent.height, depth: 1 },
+            ImageWrapType::Standard,
             ImageLayout::Undefined,
             ImageTiling::Optimal,
             ImageUsageFlags::TRANSFER_DST | ImageUsageFlags::SAMPLED,
             Lifetime::Temporary,
             false, // not solo
-            &*format!("texture {}", name))?;
+            "procedural texture")?;
+
+        // Force a flush (FIXME if block held arc to mapped memory we would not have
+        // to flush every chunk)
+        memory.flush()?;
 
         // copy_in_from_buffer
         // (this will transition to ImageLayout::TransferDstOptimal first)
@@ -232,8 +587,7 @@ impl ResourceManager {
             device,
             &commander,
             &staging_buffer.inner(),
-            dds.get_main_texture_size().unwrap(),
-            dds.get_min_mipmap_size_in_bytes()
+            data.len() as u32
         )?;
 
         // transfer layout to ImageLayout::ShaderReadOnlyOptimal
@@ -242,7 +596,7 @@ impl ResourceManager {
                            PipelineStageFlags};
         image_wrap.transition_layout_now(
             device,
-            ImageLayout::Undefined, ImageLayout::ShaderReadOnlyOptimal,
+            ImageLayout::TransferDstOptimal, ImageLayout::ShaderReadOnlyOptimal,
             AccessFlags::TRANSFER_WRITE, AccessFlags::SHADER_READ,
             PipelineStageFlags::TRANSFER, PipelineStageFlags::VERTEX_SHADER,
             ImageSubresourceRange {
@@ -250,18 +604,80 @@ impl ResourceManager {
                 base_mip_level: 0,
                 level_count: OptionalMipLevels::MipLevels(1),
                 base_array_layer: 0,
-                layer_count: OptionalArrayLayers::ArrayLayers(num_layers),
+                layer_count: OptionalArrayLayers::ArrayLayers(1),
             },
             &commander)?;
-        // And wait until that completes
-        // FIXME - this is a CPU side stall.
-
-        // insert to hashmap
-        self.textures.insert(name.to_owned(), image_wrap.clone());
 
         Ok(image_wrap)
     }
 
+    // Fail clearly up front, rather than inside the driver
+    fn check_texture_size(&self, width: u32, height: u32, cubemap: bool)
+                          -> Result<(), Error>
+    {
+        let max = if cubemap {
+            self.max_image_dimension_cube
+        } else {
+            self.max_image_dimension_2d
+        };
+        let requested = ::std::cmp::max(width, height);
+        if requested > max {
+            return Err(Error::TextureTooLarge { requested: requested, max: max });
+        }
+        Ok(())
+    }
+
+    // Overwrite a region of an existing (ShaderReadOnlyOptimal) texture with
+    // raw pixel bytes, which must be tightly packed in the texture's format.
+    pub fn update_texture_region(
+        &mut self,
+        device: &Device,
+        memory: &mut Memory,
+        commander: &Commander,
+        staging_buffer: &mut HostVisibleBuffer,
+        image_wrap: &mut ImageWrap,
+        image_offset: Offset3D,
+        extent: Extent3D,
+        mip_level: u32,
+        array_layer: u32,
+        data: &[u8])
+        -> Result<(), Error>
+    {
+        // A short slice would copy whatever the staging buffer held
+        let format = image_wrap.format;
+        let size = match crate::format::image_size(format, extent.width, extent.height) {
+            Some(size) => size as usize,
+            None => return Err(Error::UnsupportedFormat),
+        };
+        if data.len() != size {
+            return Err(Error::General(format!(
+                "Texture data of {} bytes doesn't match the {} bytes of a {}x{} {:?} region",
+                data.len(), size, extent.width, extent.height, format)));
+        }
+
+        if data.len() as u64 > staging_buffer.size() {
+            return Err(Error::General(format!(
+                "Texture data of {} bytes exceeds the staging buffer", data.len())));
+        }
+
+        // Copy texture data to staging buffer
+        staging_buffer.write_array(data, None)?;
+
+        // Force a flush (FIXME if block held arc to mapped memory we would not have
+        // to flush every chunk)
+        memory.flush()?;
+
+        image_wrap.copy_region_from_buffer(
+            device,
+            &commander,
+            &staging_buffer.inner(),
+            0,
+            image_offset,
+            extent,
+            mip_level,
+            array_layer)
+    }
+
     pub fn load_buffer(
         &mut self,
         device: &Device,
@@ -329,3 +745,14 @@ impl ResourceManager {
         Ok(dlb)
     }
 }
+
+// The vertex type id in a mesh file's header, whether or not siege-mesh
+// knows it
+fn read_vertex_type_id(path: &Path) -> Result<u32, Error>
+{
+    let mut header = [0; MESH_HEADER_LEN];
+    File::open(path)?.read_exact(&mut header)?;
+    let mut id = [0; 4];
+    id.copy_from_slice(&header[MESH_VERTEX_TYPE_OFFSET..MESH_VERTEX_TYPE_OFFSET + 4]);
+    Ok(u32::from_le_bytes(id))
+}
@@ -0,0 +1,100 @@
+
+use std::sync::{Arc, Mutex};
+use dacite::core::{CommandBuffer, Rect2D, Offset2D, Extent2D};
+use super::Pass;
+
+struct Extents {
+    render: Extent2D, // geometry and transparent passes
+    ui: Extent2D,
+    shadow: Extent2D,
+}
+
+// Lets plugins clip their draws to a rectangle, e.g. for scrolling UI panels
+// or split-screen effects.  Get one from Renderer::scissor_region(), keep it
+// in the plugin, and call set() from the record_* callbacks, then reset()
+// when done.  The scissor is dynamic state that persists through the rest
+// of the command buffer, so plugins should use this rather than calling
+// CommandBuffer::set_scissor() themselves (the renderer restores its own
+// scissor before drawing, but not between plugins).
+//
+// Rectangles are in pixels of the pass's target: the render extent for the
+// geometry and transparent passes, the swapchain extent for the UI pass, and
+// the shadow map size for the shadow pass.  They are clipped to that target,
+// and the extents always match the current targets, so the handle stays
+// valid across resizes.
+//
+// Reflection probe passes (Pass::Probe) are not supported, since each
+// probe has its own extent.
+#[derive(Clone)]
+pub struct ScissorRegion {
+    extents: Arc<Mutex<Extents>>,
+}
+
+impl ScissorRegion {
+    pub(crate) fn new(render: Extent2D, ui: Extent2D, shadow: Extent2D)
+                      -> ScissorRegion
+    {
+        ScissorRegion {
+            extents: Arc::new(Mutex::new(Extents {
+                render: render,
+                ui: ui,
+                shadow: shadow,
+            })),
+        }
+    }
+
+    // Clip subsequent draws in `pass` to `rect`
+    pub fn set(&self, command_buffer: &CommandBuffer, pass: Pass, rect: Rect2D)
+    {
+        let extents = self.extents.lock().unwrap();
+        let extent = match Self::extent(&extents, pass) {
+            Some(extent) => extent,
+            None => return,
+        };
+
+        let x0 = rect.offset.x.max(0).min(extent.width as i32);
+        let y0 = rect.offset.y.max(0).min(extent.height as i32);
+        let x1 = (rect.offset.x as i64 + rect.extent.width as i64)
+            .max(x0 as i64).min(extent.width as i64) as i32;
+        let y1 = (rect.offset.y as i64 + rect.extent.height as i64)
+            .max(y0 as i64).min(extent.height as i64) as i32;
+
+        command_buffer.set_scissor(0, &[Rect2D {
+            offset: Offset2D { x: x0, y: y0 },
+            extent: Extent2D { width: (x1 - x0) as u32, height: (y1 - y0) as u32 },
+        }]);
+    }
+
+    // Restore the renderer's full-target scissor for `pass`
+    pub fn reset(&self, command_buffer: &CommandBuffer, pass: Pass)
+    {
+        let extents = self.extents.lock().unwrap();
+        if let Some(extent) = Self::extent(&extents, pass) {
+            command_buffer.set_scissor(0, &[Rect2D {
+                offset: Offset2D { x: 0, y: 0 },
+                extent: extent,
+            }]);
+        }
+    }
+
+    // Called by the renderer whenever its extents change
+    pub(crate) fn update(&self, render: Extent2D, ui: Extent2D)
+    {
+        let mut extents = self.extents.lock().unwrap();
+        extents.render = render;
+        extents.ui = ui;
+    }
+
+    fn extent(extents: &Extents, pass: Pass) -> Option<Extent2D>
+    {
+        match pass {
+            Pass::Geometry | Pass::Transparent => Some(extents.render),
+            Pass::Ui => Some(extents.ui),
+            Pass::Shadow => Some(extents.shadow),
+            Pass::Probe => {
+                warn!("Scissor regions are not supported in probe passes");
+                None
+            },
+        }
+    }
+}
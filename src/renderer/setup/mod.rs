@@ -7,10 +7,12 @@ pub use self::queue_indices::QueueIndices;
 mod physical;
 pub use self::physical::{Physical, find_suitable_device};
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::collections::HashMap;
 use dacite::core::{InstanceExtensions, Instance, PhysicalDevice, DeviceExtensions,
-                   Device, Semaphore, Fence, DescriptorPool};
+                   Device, Semaphore, Fence, DescriptorPool, InstanceCreateInfo,
+                   PhysicalDeviceProperties, PhysicalDeviceFeatures, PipelineCache};
 use dacite::ext_debug_report::{DebugReportFlagsExt, DebugReportObjectTypeExt,
                                DebugReportCallbackExt, DebugReportCallbacksExt};
 use dacite::khr_surface::SurfaceKhr;
@@ -20,49 +22,110 @@ use winit::Window;
 use self::requirements::FEATURES_NEEDED;
 use crate::config::Config;
 use crate::error::Error;
-use crate::renderer::VulkanLogLevel;
+use crate::renderer::{VulkanLogLevel, ValidationCallback};
 
-pub fn setup_instance(config: &Config, window: &Window) -> Result<Instance, Error>
+// Returns the instance along with the layers that were actually enabled
+pub fn setup_instance(config: &Config, window: &Window)
+                      -> Result<(Instance, Vec<String>), Error>
 {
-    let create_info = {
-        use dacite::core::{InstanceCreateFlags, InstanceCreateInfo,
-                           ApplicationInfo, Version};
+    let layers = available_layers(&config.vulkan_layers)?;
 
-        let mut extensions = compute_instance_extensions(window)?;
+    // Request the highest version allowed by the config. Vulkan 1.0
+    // implementations reject any other api_version with IncompatibleDriver,
+    // in which case we fall back to 1.0.
+    // FIXME: dacite does not expose vkEnumerateInstanceVersion, which would
+    // let us determine the supported version up front.
+    let (major, minor, patch) = api_version(config.max_api_version);
 
-        if config.vulkan_debug_output {
-            extensions.add_ext_debug_report();
-        }
+    let instance = match Instance::create(
+        &instance_create_info(config, window, &layers, major, minor, patch)?, None)
+    {
+        Ok(instance) => {
+            info!("Using Vulkan API version {}.{}", major, minor);
+            instance
+        },
+        Err(dacite::core::Error::IncompatibleDriver) if (major, minor) != (1, 0) => {
+            warn!("Vulkan API version {}.{} is not supported, falling back to 1.0",
+                  major, minor);
+            Instance::create(&instance_create_info(config, window, &layers, 1, 0, 3)?,
+                             None)?
+        },
+        Err(e) => return Err(From::from(e))
+    };
+
+    Ok((instance, layers))
+}
+
+// Instance creation fails if any requested layer is missing, so we drop
+// (and warn about) the ones that are not installed.
+fn available_layers(requested: &[String]) -> Result<Vec<String>, Error>
+{
+    let available: Vec<String> = Instance::enumerate_instance_layer_properties()?
+        .into_iter()
+        .map(|properties| properties.layer_name)
+        .collect();
+
+    Ok(requested.iter()
+       .filter(|layer| {
+           if available.contains(layer) {
+               true
+           } else {
+               warn!("Vulkan layer {} is not available", layer);
+               false
+           }
+       })
+       .cloned()
+       .collect())
+}
 
-        InstanceCreateInfo {
-            flags: InstanceCreateFlags::empty(),
-            application_info: Some(ApplicationInfo {
-                application_name: Some("Eye of Baal".to_owned()),
-                application_version: Version {
-                    major: config.major_version,
-                    minor: config.minor_version,
-                    patch: config.patch_version,
-                }.as_api_version(),
-                engine_name: Some("Siege Engine".to_owned()),
-                engine_version: Version {
-                    major: env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap(),
-                    minor: env!("CARGO_PKG_VERSION_MINOR").parse().unwrap(),
-                    patch: env!("CARGO_PKG_VERSION_PATCH").parse().unwrap()
-                }.as_api_version(),
-                api_version: Some(Version {
-                    major: 1,
-                    minor: 0,
-                    patch: 3,
-                }),
-                chain: None,
+// Returns the (major, minor, patch) api version to request
+fn api_version(max_api_version: [u32; 2]) -> (u32, u32, u32)
+{
+    match max_api_version {
+        // Versions before 1.1 did not support negotiation
+        [1, 0] | [0, _] => (1, 0, 3),
+        [major, minor] => (major, minor, 0),
+    }
+}
+
+fn instance_create_info(config: &Config, window: &Window, layers: &[String],
+                        api_major: u32, api_minor: u32, api_patch: u32)
+                        -> Result<InstanceCreateInfo, Error>
+{
+    use dacite::core::{InstanceCreateFlags, ApplicationInfo, Version};
+
+    let mut extensions = compute_instance_extensions(window)?;
+
+    if config.vulkan_debug_output {
+        extensions.add_ext_debug_report();
+    }
+
+    Ok(InstanceCreateInfo {
+        flags: InstanceCreateFlags::empty(),
+        application_info: Some(ApplicationInfo {
+            application_name: Some("Eye of Baal".to_owned()),
+            application_version: Version {
+                major: config.major_version,
+                minor: config.minor_version,
+                patch: config.patch_version,
+            }.as_api_version(),
+            engine_name: Some("Siege Engine".to_owned()),
+            engine_version: Version {
+                major: env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap(),
+                minor: env!("CARGO_PKG_VERSION_MINOR").parse().unwrap(),
+                patch: env!("CARGO_PKG_VERSION_PATCH").parse().unwrap()
+            }.as_api_version(),
+            api_version: Some(Version {
+                major: api_major,
+                minor: api_minor,
+                patch: api_patch,
             }),
-            enabled_layers: config.vulkan_layers.clone(),
-            enabled_extensions: extensions,
             chain: None,
-        }
-    };
-
-    Ok(Instance::create(&create_info, None)?)
+        }),
+        enabled_layers: layers.to_vec(),
+        enabled_extensions: extensions,
+        chain: None,
+    })
 }
 
 
@@ -86,7 +149,24 @@ fn compute_instance_extensions(window: &Window) -> Result<InstanceExtensions, Er
     }
 }
 
-pub fn setup_debug_callback(config: &Config, instance: &Instance)
+// Validation messages go to the log, and also to the client callback if one
+// is set.  Reporting can be switched off at runtime (the layers remain).
+pub struct ValidationOutput {
+    pub enabled: AtomicBool,
+    pub callback: Mutex<Option<ValidationCallback>>,
+}
+
+impl ValidationOutput {
+    pub fn new() -> ValidationOutput {
+        ValidationOutput {
+            enabled: AtomicBool::new(true),
+            callback: Mutex::new(None),
+        }
+    }
+}
+
+pub fn setup_debug_callback(config: &Config, instance: &Instance,
+                            output: Arc<ValidationOutput>)
                             -> Result<Option<DebugReportCallbackExt>, Error>
 {
     if config.vulkan_debug_output {
@@ -112,7 +192,7 @@ pub fn setup_debug_callback(config: &Config, instance: &Instance)
 
         let create_info = DebugReportCallbackCreateInfoExt {
             flags: flags,
-            callback: Arc::new(DebugCallback),
+            callback: Arc::new(DebugCallback { output: output }),
             chain: None,
         };
 
@@ -123,8 +203,15 @@ pub fn setup_debug_callback(config: &Config, instance: &Instance)
     }
 }
 
-#[derive(Debug)]
-struct DebugCallback;
+struct DebugCallback {
+    output: Arc<ValidationOutput>,
+}
+
+impl ::std::fmt::Debug for DebugCallback {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "DebugCallback")
+    }
+}
 
 impl DebugReportCallbacksExt for DebugCallback {
     fn callback(
@@ -137,21 +224,36 @@ impl DebugReportCallbacksExt for DebugCallback {
         _layer_prefix: Option<&str>,
         message: Option<&str>) -> bool
     {
+        if !self.output.enabled.load(Ordering::Relaxed) {
+            return false;
+        }
+
         if let Some(m) = message {
-            if flags.intersects(DebugReportFlagsExt::ERROR) {
+            let level = if flags.intersects(DebugReportFlagsExt::ERROR) {
                 error!("\r\n  vk: {}", m);
+                VulkanLogLevel::Error
             }
             else if flags.intersects(DebugReportFlagsExt::WARNING) {
                 warn!("\r\n  vk: {}", m);
+                VulkanLogLevel::Warning
             }
             else if flags.intersects(DebugReportFlagsExt::PERFORMANCE_WARNING) {
                 warn!("\r\n  vk: {}", m);
+                VulkanLogLevel::PerformanceWarning
             }
             else if flags.intersects(DebugReportFlagsExt::INFORMATION) {
                 info!("\r\n  vk: {}", m);
+                VulkanLogLevel::Information
             }
-            else if flags.intersects(DebugReportFlagsExt::DEBUG) {
+            else {
                 debug!("\r\n  vk: {}", m);
+                VulkanLogLevel::Debug
+            };
+
+            if let Ok(callback) = self.output.callback.lock() {
+                if let Some(ref callback) = *callback {
+                    callback(level, m);
+                }
             }
         }
 
@@ -174,7 +276,8 @@ pub fn setup_surface(window: &Window, instance: &Instance) -> Result<SurfaceKhr,
 
 pub fn create_device(physical_device: &PhysicalDevice,
                      device_extensions: DeviceExtensions,
-                     queue_indices: &QueueIndices)
+                     queue_indices: &QueueIndices,
+                     features_available: &PhysicalDeviceFeatures)
                      -> Result<Device, Error>
 {
     use dacite::core::{DeviceQueueCreateInfo, DeviceQueueCreateFlags,
@@ -208,13 +311,49 @@ pub fn create_device(physical_device: &PhysicalDevice,
         queue_create_infos: device_queue_create_infos,
         enabled_layers: vec![],
         enabled_extensions: device_extensions,
-        enabled_features: Some(FEATURES_NEEDED),
+        enabled_features: Some(enabled_features(features_available)),
         chain: None,
     };
 
     Ok(physical_device.create_device(&device_create_info, None)?)
 }
 
+// The features we need, plus preferred ones that are used if available.  A
+// device lacking a preferred feature is still used, and the renderer makes
+// do without it (see Renderer::has_anisotrophy() and its neighbours).
+pub fn enabled_features(features_available: &PhysicalDeviceFeatures)
+                        -> PhysicalDeviceFeatures
+{
+    let mut features = FEATURES_NEEDED;
+    features.sampler_anisotropy = features_available.sampler_anisotropy;
+    features.large_points = features_available.large_points;
+    features.multi_draw_indirect = features_available.multi_draw_indirect;
+    features.depth_clamp = features_available.depth_clamp;
+    features.wide_lines = features_available.wide_lines;
+    features.sample_rate_shading = features_available.sample_rate_shading;
+    features.texture_compression_etc2 = features_available.texture_compression_etc2;
+    features
+}
+
+// The preferred features (see enabled_features) that a device lacks
+pub fn missing_preferred_features(features_available: &PhysicalDeviceFeatures)
+                                  -> Vec<&'static str>
+{
+    let preferred = [
+        ("sampler_anisotropy", features_available.sampler_anisotropy),
+        ("large_points", features_available.large_points),
+        ("multi_draw_indirect", features_available.multi_draw_indirect),
+        ("depth_clamp", features_available.depth_clamp),
+        ("wide_lines", features_available.wide_lines),
+        ("sample_rate_shading", features_available.sample_rate_shading),
+        ("texture_compression_etc2", features_available.texture_compression_etc2),
+    ];
+    preferred.iter()
+        .filter(|&&(_, available)| !available)
+        .map(|&(name, _)| name)
+        .collect()
+}
+
 pub fn get_descriptor_pool(device: &Device, config: &Config) -> Result<DescriptorPool, Error>
 {
     use dacite::core::{DescriptorPoolCreateInfo, DescriptorPoolSize,
@@ -248,6 +387,14 @@ pub fn get_descriptor_pool(device: &Device, config: &Config) -> Result<Descripto
                 descriptor_type: DescriptorType::CombinedImageSampler,
                 descriptor_count: config.max_combined_image_samplers,
             },
+            DescriptorPoolSize {
+                descriptor_type: DescriptorType::StorageBuffer,
+                descriptor_count: config.max_storage_buffers,
+            },
+            DescriptorPoolSize {
+                descriptor_type: DescriptorType::StorageImage,
+                descriptor_count: config.max_storage_images,
+            },
         ],
         chain: None,
     };
@@ -256,6 +403,83 @@ pub fn get_descriptor_pool(device: &Device, config: &Config) -> Result<Descripto
 
 }
 
+// The pipeline cache, primed from Config::pipeline_cache_path if that holds
+// a cache this device and driver can use.  Anything wrong with the file is
+// logged and we start with an empty cache, as it only saves time.
+pub fn get_pipeline_cache(device: &Device, config: &Config,
+                          ph_props: &PhysicalDeviceProperties)
+                          -> Result<PipelineCache, Error>
+{
+    use dacite::core::{PipelineCacheCreateInfo, PipelineCacheCreateFlags};
+
+    let initial_data = match config.pipeline_cache_path {
+        Some(ref path) => match ::std::fs::read(path) {
+            Ok(data) => {
+                if pipeline_cache_matches(&data, ph_props.vendor_id, ph_props.device_id,
+                                          &ph_props.pipeline_cache_uuid) {
+                    info!("Loaded {} byte pipeline cache from {}",
+                          data.len(), path.display());
+                    Some(data)
+                } else {
+                    info!("Ignoring pipeline cache {} from another device or driver",
+                          path.display());
+                    None
+                }
+            },
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => {
+                info!("No pipeline cache at {} yet", path.display());
+                None
+            },
+            Err(e) => {
+                warn!("Unable to read pipeline cache {}: {}", path.display(), e);
+                None
+            },
+        },
+        None => None,
+    };
+
+    if initial_data.is_some() {
+        let create_info = PipelineCacheCreateInfo {
+            flags: PipelineCacheCreateFlags::empty(),
+            initial_data: initial_data,
+            chain: None,
+        };
+        match device.create_pipeline_cache(&create_info, None) {
+            Ok(pipeline_cache) => return Ok(pipeline_cache),
+            Err(e) => warn!("Unable to use the saved pipeline cache ({}), starting empty", e),
+        }
+    }
+
+    let create_info = PipelineCacheCreateInfo {
+        flags: PipelineCacheCreateFlags::empty(),
+        initial_data: None,
+        chain: None,
+    };
+    Ok(device.create_pipeline_cache(&create_info, None)?)
+}
+
+// Check the header Vulkan puts at the start of pipeline cache data: its
+// length, version (1), vendor and device IDs, and the driver's cache UUID
+fn pipeline_cache_matches(data: &[u8], vendor_id: u32, device_id: u32, uuid: &[u8])
+                          -> bool
+{
+    const HEADER_LEN: usize = 16 + 16;
+
+    if data.len() < HEADER_LEN {
+        return false;
+    }
+    let word = |i: usize| {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&data[i * 4..i * 4 + 4]);
+        u32::from_le_bytes(bytes)
+    };
+    word(0) as usize >= HEADER_LEN
+        && word(1) == 1 // VK_PIPELINE_CACHE_HEADER_VERSION_ONE
+        && word(2) == vendor_id
+        && word(3) == device_id
+        && data[16..32] == uuid[..]
+}
+
 pub fn get_semaphores(device: &Device) -> Result<(Semaphore, Semaphore), Error>
 {
     use dacite::core::{SemaphoreCreateInfo, SemaphoreCreateFlags};
@@ -272,6 +496,24 @@ pub fn get_semaphores(device: &Device) -> Result<(Semaphore, Semaphore), Error>
 }
 
 
+// Timestamp queries are only meaningful if the graphics queue writes them
+// and the device tells us how long a tick is.  Some mobile and virtualized
+// devices do neither.
+pub fn timestamps_supported(physical_device_properties: &PhysicalDeviceProperties,
+                            queue_indices: &QueueIndices)
+                            -> bool
+{
+    if queue_indices.graphics_timestamp_valid_bits == 0 {
+        warn!("Graphics queue does not support timestamps; GPU timings are unavailable");
+        false
+    } else if physical_device_properties.limits.timestamp_period <= 0.0 {
+        warn!("Device reports no timestamp period; GPU timings are unavailable");
+        false
+    } else {
+        true
+    }
+}
+
 pub fn get_graphics_fence(device: &Device, signalled: bool) -> Result<Fence, Error>
 {
     use dacite::core::{FenceCreateInfo, FenceCreateFlags};
@@ -285,3 +527,63 @@ pub fn get_graphics_fence(device: &Device, signalled: bool) -> Result<Fence, Err
     };
     Ok(device.create_fence(&create_info, None)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::pipeline_cache_matches;
+
+    const VENDOR: u32 = 0x10de;
+    const DEVICE: u32 = 0x1b80;
+    const UUID: [u8; 16] = [7; 16];
+
+    // A cache header as a driver writes it, followed by some cache data
+    fn header(length: u32, version: u32, vendor_id: u32, device_id: u32,
+              uuid: &[u8; 16]) -> Vec<u8>
+    {
+        let mut data = Vec::new();
+        data.extend_from_slice(&length.to_le_bytes());
+        data.extend_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(&vendor_id.to_le_bytes());
+        data.extend_from_slice(&device_id.to_le_bytes());
+        data.extend_from_slice(uuid);
+        data.extend_from_slice(&[0xAB; 64]);
+        data
+    }
+
+    #[test]
+    fn matching_header_is_accepted() {
+        let data = header(32, 1, VENDOR, DEVICE, &UUID);
+        assert!(pipeline_cache_matches(&data, VENDOR, DEVICE, &UUID));
+    }
+
+    #[test]
+    fn short_data_is_rejected() {
+        let data = header(32, 1, VENDOR, DEVICE, &UUID);
+        assert!(!pipeline_cache_matches(&[], VENDOR, DEVICE, &UUID));
+        assert!(!pipeline_cache_matches(&data[..31], VENDOR, DEVICE, &UUID));
+        // The header's own length field must cover the header too
+        let data = header(16, 1, VENDOR, DEVICE, &UUID);
+        assert!(!pipeline_cache_matches(&data, VENDOR, DEVICE, &UUID));
+    }
+
+    #[test]
+    fn wrong_version_is_rejected() {
+        let data = header(32, 2, VENDOR, DEVICE, &UUID);
+        assert!(!pipeline_cache_matches(&data, VENDOR, DEVICE, &UUID));
+    }
+
+    #[test]
+    fn other_vendor_or_device_is_rejected() {
+        let data = header(32, 1, VENDOR, DEVICE, &UUID);
+        assert!(!pipeline_cache_matches(&data, 0x1002, DEVICE, &UUID));
+        assert!(!pipeline_cache_matches(&data, VENDOR, DEVICE + 1, &UUID));
+    }
+
+    #[test]
+    fn other_driver_uuid_is_rejected() {
+        let data = header(32, 1, VENDOR, DEVICE, &UUID);
+        let mut uuid = UUID;
+        uuid[15] = 8;
+        assert!(!pipeline_cache_matches(&data, VENDOR, DEVICE, &uuid));
+    }
+}
@@ -17,6 +17,8 @@ pub struct Physical {
     pub physical_device_memory_properties: PhysicalDeviceMemoryProperties,
     pub queue_indices: QueueIndices,
     pub device_extensions: DeviceExtensions,
+    // Whether VK_EXT_debug_marker is enabled (for command buffer labels)
+    pub debug_markers: bool,
 }
 
 pub fn find_suitable_device(
@@ -25,7 +27,7 @@ pub fn find_suitable_device(
     surface: &SurfaceKhr)
     -> Result<Physical, Error>
 {
-    let formats_needed = get_formats_needed();
+    let formats_needed = get_formats_needed(config);
 
     let devices: Vec<Physical> = instance.enumerate_physical_devices()?.into_iter()
         .filter_map(|physical_device| {
@@ -77,7 +79,8 @@ fn check_device_suitability(
 
     let queue_indices = QueueIndices::new(&physical_device, surface)?;
 
-    let device_extensions = check_device_extensions(&physical_device)?;
+    let (device_extensions, debug_markers) = check_device_extensions(
+        config, &physical_device)?;
 
     check_limits(&physical_device_properties.limits)?;
 
@@ -113,6 +116,7 @@ fn check_device_suitability(
         physical_device_memory_properties: physical_device_memory_properties,
         queue_indices: queue_indices,
         device_extensions: device_extensions,
+        debug_markers: debug_markers,
     })
 }
 
@@ -126,8 +130,9 @@ fn check_physical_device_features(
 
     features_needed.difference(&features_available);// subtract out available features
     if !features_needed.is_empty() {
-        // Some feature that we need is not available. Unfortunately it's hard to tell
-        // which without a very long set of if/then statements.
+        // Some feature that we need is not available (features we merely prefer
+        // aren't checked here). Unfortunately it's hard to tell which without a
+        // very long set of if/then statements.
         Err(Error::DeviceNotSuitable(
             "Device is missing a required feature".to_owned()))
     } else {
@@ -156,15 +161,26 @@ fn check_physical_device_memory_properties(
     Ok(memory_properties)
 }
 
-fn check_device_extensions(physical_device: &PhysicalDevice) -> Result<DeviceExtensions, Error>
+fn check_device_extensions(config: &Config, physical_device: &PhysicalDevice)
+                           -> Result<(DeviceExtensions, bool), Error>
 {
     let available_extensions = physical_device.get_device_extension_properties(None)?;
     let mut required_extensions = DeviceExtensionsProperties::new();
     required_extensions.add_khr_swapchain(67); // spec version 67
 
+    // Debug markers are optional; we only want them with debug output on
+    let debug_markers = config.vulkan_debug_output && {
+        let mut marker_extension = DeviceExtensionsProperties::new();
+        marker_extension.add_ext_debug_marker(4); // spec version 4
+        marker_extension.difference(&available_extensions).is_empty()
+    };
+
     let missing_extensions = required_extensions.difference(&available_extensions);
     if missing_extensions.is_empty() {
-        Ok(required_extensions.to_extensions())
+        if debug_markers {
+            required_extensions.add_ext_debug_marker(4);
+        }
+        Ok((required_extensions.to_extensions(), debug_markers))
     }
     else {
         let mut s = String::new();
@@ -207,6 +223,12 @@ pub fn log_device_details(phys: &Physical)
           phys.queue_indices.present_family, phys.queue_indices.present_index,
           phys.queue_indices.transfer_family, phys.queue_indices.transfer_index);
 
+    let missing = super::missing_preferred_features(&phys.physical_device_features);
+    if !missing.is_empty() {
+        info!("Optional features not available (rendering is degraded): {}",
+              missing.join(", "));
+    }
+
     // display some memory properties
     for (i,heap) in phys.physical_device_memory_properties.memory_heaps.iter().enumerate() {
         let mut output = String::new();
@@ -1,6 +1,6 @@
 
 use crate::error::Error;
-use dacite::core::PhysicalDevice;
+use dacite::core::{PhysicalDevice, QueueFamilyProperties};
 use dacite::khr_surface::SurfaceKhr;
 
 #[derive(Debug)]
@@ -11,6 +11,8 @@ pub struct QueueIndices {
     pub graphics_index: u32,
     pub present_family: u32,
     pub present_index: u32,
+    // 0 if the graphics queue does not support timestamp queries
+    pub graphics_timestamp_valid_bits: u32,
 }
 
 impl QueueIndices {
@@ -18,12 +20,24 @@ impl QueueIndices {
     pub fn new(physical_device: &PhysicalDevice,
                surface: &SurfaceKhr)
                -> Result<QueueIndices, Error>
+    {
+        let queue_family_properties: Vec<_> = physical_device.get_queue_family_properties();
+        let supports_present: Vec<bool> = (0..queue_family_properties.len())
+            .map(|i| physical_device.get_surface_support_khr(i as u32, surface) == Ok(true))
+            .collect();
+
+        QueueIndices::from_families(&queue_family_properties, &supports_present)
+    }
+
+    // Choose the queues from the device's queue families, given whether
+    // each family can present to the surface
+    pub fn from_families(queue_family_properties: &[QueueFamilyProperties],
+                         supports_present: &[bool])
+                         -> Result<QueueIndices, Error>
     {
         use std::collections::HashSet;
         use dacite::core::QueueFlags;
 
-        let queue_family_properties: Vec<_> = physical_device.get_queue_family_properties();
-
         // The family used must support the feature in question.  For each feature,
         // we build a set of all families that would work.
         let mut p_set: HashSet<usize> = HashSet::new();
@@ -31,13 +45,16 @@ impl QueueIndices {
         let mut t_set: HashSet<usize> = HashSet::new();
         for (i,qfp) in queue_family_properties.iter().enumerate() {
             if qfp.queue_count < 1 { continue; }
-            if physical_device.get_surface_support_khr(i as u32, surface) == Ok(true) {
+            if supports_present[i] {
                 p_set.insert(i);
             }
             if qfp.queue_flags.contains(QueueFlags::GRAPHICS) {
                 g_set.insert(i);
             }
-            if qfp.queue_flags.contains(QueueFlags::TRANSFER) {
+            // Graphics and compute queues support transfers whether or not
+            // they advertise it
+            if qfp.queue_flags.intersects(QueueFlags::TRANSFER | QueueFlags::GRAPHICS
+                                          | QueueFlags::COMPUTE) {
                 t_set.insert(i);
             }
         }
@@ -89,14 +106,19 @@ impl QueueIndices {
             } else {
                 // At this point, we already know we are stuck with the transfer family.
                 // No sense trying to prefer a presentation set queue, we only have one left.
+                // (the transfer queue is index 0, so take the next if there is one)
                 let qc = queue_family_properties[transfer_family].queue_count;
-                if qc >=3 {
+                if qc >= 2 {
                     (transfer_family, 1)
-                } else if qc >= 2 {
-                    (transfer_family, 0)
                 } else {
-                    return Err(Error::DeviceNotSuitable(
-                        "Not enough queues for two graphics queues".to_owned()))
+                    // A single queue must do everything. Submissions may come
+                    // from other threads (e.g. resource uploads), so every
+                    // submit holds Commander::lock_queues(); with that held,
+                    // queue submissions execute in order and a transfer
+                    // (which waits on its own fence) simply runs after any
+                    // frame already in flight.
+                    info!("Only one queue is available; transfers will share the graphics queue");
+                    (transfer_family, 0)
                 }
             };
             (graphics_family, graphics_index)
@@ -129,6 +151,61 @@ impl QueueIndices {
             graphics_index: graphics_index as u32,
             present_family: present_family as u32,
             present_index: present_index as u32,
+            graphics_timestamp_valid_bits:
+                queue_family_properties[graphics_family].timestamp_valid_bits,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use dacite::core::{QueueFamilyProperties, QueueFlags, Extent3D};
+    use super::QueueIndices;
+
+    fn family(queue_flags: QueueFlags, queue_count: u32) -> QueueFamilyProperties {
+        QueueFamilyProperties {
+            queue_flags: queue_flags,
+            queue_count: queue_count,
+            timestamp_valid_bits: 64,
+            min_image_transfer_granularity: Extent3D { width: 1, height: 1, depth: 1 },
+        }
+    }
+
+    #[test]
+    fn single_queue_is_shared() {
+        let families = [family(QueueFlags::GRAPHICS | QueueFlags::COMPUTE
+                               | QueueFlags::TRANSFER, 1)];
+        let qi = QueueIndices::from_families(&families, &[true]).unwrap();
+        assert_eq!((qi.graphics_family, qi.graphics_index), (0, 0));
+        assert_eq!((qi.transfer_family, qi.transfer_index), (0, 0));
+        assert_eq!((qi.present_family, qi.present_index), (0, 0));
+    }
+
+    #[test]
+    fn two_queues_in_one_family_are_separate() {
+        let families = [family(QueueFlags::GRAPHICS | QueueFlags::TRANSFER, 2)];
+        let qi = QueueIndices::from_families(&families, &[true]).unwrap();
+        assert_eq!((qi.transfer_family, qi.transfer_index), (0, 0));
+        assert_eq!((qi.graphics_family, qi.graphics_index), (0, 1));
+        assert_eq!((qi.present_family, qi.present_index), (0, 1));
+    }
+
+    #[test]
+    fn separate_transfer_family_is_preferred() {
+        let families = [family(QueueFlags::GRAPHICS | QueueFlags::TRANSFER, 2),
+                        family(QueueFlags::TRANSFER, 2)];
+        let qi = QueueIndices::from_families(&families, &[true, false]).unwrap();
+        assert_eq!((qi.transfer_family, qi.transfer_index), (1, 0));
+        assert_eq!((qi.graphics_family, qi.graphics_index), (0, 0));
+        assert_eq!((qi.present_family, qi.present_index), (0, 0));
+    }
+
+    #[test]
+    fn compute_family_without_transfer_flag_does_transfers() {
+        let families = [family(QueueFlags::GRAPHICS, 1),
+                        family(QueueFlags::COMPUTE, 1)];
+        let qi = QueueIndices::from_families(&families, &[true, false]).unwrap();
+        assert_eq!((qi.transfer_family, qi.transfer_index), (1, 0));
+        assert_eq!((qi.graphics_family, qi.graphics_index), (0, 0));
+    }
+}
@@ -4,6 +4,7 @@
  */
 
 use dacite::core::{PhysicalDeviceFeatures, Format, FormatProperties};
+use crate::config::Config;
 
 // This is used to determine the size of the staging buffer
 pub const MAX_GPU_UPLOAD: u64 = crate::renderer::memory::CHUNK_SIZE;
@@ -12,20 +13,28 @@ pub const MAX_GPU_UPLOAD: u64 = crate::renderer::memory::CHUNK_SIZE;
 // (see http://vulkan.gpuinfo.org) and it is a good resolution, and it is
 // floating-point (so reverse z-buffering works).
 pub const DEPTH_FORMAT: Format = Format::D32_SFloat;
-pub const DIFFUSE_FORMAT: Format = Format::A2B10G10R10_UNorm_Pack32;
-pub const NORMALS_FORMAT: Format = Format::A2B10G10R10_UNorm_Pack32;
-pub const MATERIAL_FORMAT: Format = Format::R8G8B8A8_UNorm;
-pub const SHADING_FORMAT: Format = Format::R16G16B16A16_SFloat;
-pub const BLUR_FORMAT: Format = Format::R16G16B16A16_SFloat;
+// Reflection probe cubemaps hold linear luminance like the shading target
+pub const PROBE_FORMAT: Format = Format::R16G16B16A16_SFloat;
+// Optional linear view-space depth (Config::linear_depth_target). Color
+// attachment support for this format is mandatory, so it is not checked.
+pub const LINEAR_DEPTH_FORMAT: Format = Format::R32_SFloat;
+// Optional motion vectors (Config::motion_vectors), also mandatory as a
+// color attachment
+pub const VELOCITY_FORMAT: Format = Format::R16G16_SFloat;
+// Optional entity IDs (Config::entity_id_target), also mandatory as a color
+// attachment
+pub const ENTITY_ID_FORMAT: Format = Format::R32_UInt;
 
+// Devices without all of these are rejected.  Features we can do without
+// are enabled if available (see setup::enabled_features()).
 pub const FEATURES_NEEDED: PhysicalDeviceFeatures = PhysicalDeviceFeatures {
-    large_points: true,
-    sampler_anisotropy: true, // FIXME - we want this, we dont need it.
     texture_compression_bc: true,
     robust_buffer_access: cfg!(debug_assertions), // finds bugs; too expensive for live.
     //
     // the rest are false
     //
+    large_points: false, // preferred
+    sampler_anisotropy: false, // preferred
     full_draw_index_uint32: false,
     image_cube_array: false,
     independent_blend: false,
@@ -80,7 +89,7 @@ pub const FEATURES_NEEDED: PhysicalDeviceFeatures = PhysicalDeviceFeatures {
 };
 
 // FIXME: make a const fn once that is stable
-pub fn get_formats_needed() ->  [(Format, FormatProperties); 12] {
+pub fn get_formats_needed(config: &Config) ->  [(Format, FormatProperties); 12] {
     use dacite::core::FormatFeatureFlags;
 
     [
@@ -97,27 +106,32 @@ pub fn get_formats_needed() ->  [(Format, FormatProperties); 12] {
             buffer_features: FormatFeatureFlags::empty(),
         }),
         // Diffuse buffer uses this
-        (DIFFUSE_FORMAT, FormatProperties {
+        (config.diffuse_format.format(), FormatProperties {
             linear_tiling_features: FormatFeatureFlags::empty(),
             optimal_tiling_features: FormatFeatureFlags::COLOR_ATTACHMENT,
             buffer_features: FormatFeatureFlags::empty(),
         }),
         // Normals buffer uses this
-        (NORMALS_FORMAT, FormatProperties {
+        (config.normals_format.format(), FormatProperties {
             linear_tiling_features: FormatFeatureFlags::empty(),
             optimal_tiling_features: FormatFeatureFlags::COLOR_ATTACHMENT,
             buffer_features: FormatFeatureFlags::empty(),
         }),
         // Material buffer uses this
-        (MATERIAL_FORMAT, FormatProperties {
+        (config.material_format.format(), FormatProperties {
             linear_tiling_features: FormatFeatureFlags::empty(),
             optimal_tiling_features: FormatFeatureFlags::COLOR_ATTACHMENT,
             buffer_features: FormatFeatureFlags::empty(),
         }),
-        // Shading attachment uses this
-        (SHADING_FORMAT, FormatProperties {
+        // Shading and blur attachments use this.  They are blended into
+        // (transparent pass, blur) and sampled with a linear filter (blur,
+        // post).
+        (config.shading_format.format(), FormatProperties {
             linear_tiling_features: FormatFeatureFlags::empty(),
-            optimal_tiling_features: FormatFeatureFlags::COLOR_ATTACHMENT,
+            optimal_tiling_features: FormatFeatureFlags::COLOR_ATTACHMENT
+                | FormatFeatureFlags::COLOR_ATTACHMENT_BLEND
+                | FormatFeatureFlags::SAMPLED_IMAGE
+                | FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR,
             buffer_features: FormatFeatureFlags::empty(),
         }),
         // We will use these formats for assets
@@ -4,18 +4,68 @@ use dacite::core::{Device, DescriptorPool, DescriptorSet, DescriptorSetLayout,
                    DescriptorType, CommandBuffer, RenderPass, Viewport, Rect2D,
                    PipelineBindPoint, Pipeline, PipelineLayout, PrimitiveTopology,
                    CullModeFlags, FrontFace, ShaderModuleCreateFlags,
-                   ShaderModuleCreateInfo, ShaderModule,
-                   SpecializationInfo, SpecializationMapEntry};
+                   ShaderModuleCreateInfo, ShaderModule, ColorComponentFlags,
+                   SpecializationInfo, SpecializationMapEntry, PipelineCache};
 use crate::error::Error;
+use serde::Deserialize;
 use super::target_data::TargetData;
+use super::image_wrap::{ImageWrap, ImageWrapType};
+use super::memory::{Memory, Lifetime};
+use super::commander::Commander;
+use super::descriptor_set_writer::DescriptorSetWriter;
 use super::{DepthHandling, BlendMode};
 
+// Which channels of the material g-buffer hold roughness, metallicity,
+// ambient occlusion and cavity (see Config::material_packing), so that
+// plugins can write material textures packed in their own convention.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MaterialPacking {
+    // r = roughness, g = metallicity, b = ambient occlusion, a = cavity
+    Rmac,
+    // r = ambient occlusion, g = roughness, b = metallicity, a = cavity
+    Orm,
+    // The channel (0 = r through 3 = a) of roughness, metallicity, ambient
+    // occlusion and cavity, in that order
+    Custom([u32; 4]),
+}
+
+impl MaterialPacking {
+    // The channel of roughness, metallicity, ambient occlusion and cavity
+    pub fn channels(&self) -> [u32; 4] {
+        match *self {
+            MaterialPacking::Rmac => [0, 1, 2, 3],
+            MaterialPacking::Orm => [1, 2, 0, 3],
+            MaterialPacking::Custom(channels) => channels,
+        }
+    }
+}
+
 pub struct ShadeGfx {
     pipeline: Pipeline,
     pipeline_layout: PipelineLayout,
+    pipeline_cache: PipelineCache,
     descriptor_set: DescriptorSet,
     #[allow(dead_code)] // this must remain alive
     desc_layout: DescriptorSetLayout,
+    params_layout: DescriptorSetLayout,
+    render_pass: RenderPass,
+    viewport: Viewport,
+    scissors: Rect2D,
+    reversed_depth_buffer: bool,
+    ao_image_view: Option<ImageView>,
+    // prefiltered environment cubemap and BRDF lookup table
+    environment_image_views: Option<(ImageView, ImageView)>,
+    #[allow(dead_code)] // this must remain alive
+    dummy_cubemap: ImageWrap,
+    dummy_cube_view: ImageView,
+    env_sampler: Sampler,
+    shadow_image_view: ImageView,
+    shadow_sampler: Sampler,
+    shadows_enabled: bool,
+    linear_depth: bool,
+    hdr_ceiling: f32,
+    material_packing: MaterialPacking,
     material_image_view: ImageView,
     normals_image_view: ImageView,
     diffuse_image_view: ImageView,
@@ -25,13 +75,21 @@ pub struct ShadeGfx {
 
 impl ShadeGfx {
     pub fn new(device: &Device,
+               pipeline_cache: PipelineCache,
+               memory: &mut Memory,
+               commander: &Commander,
                descriptor_pool: DescriptorPool,
                target_data: &TargetData,
                render_pass: RenderPass,
                viewport: Viewport,
                scissors: Rect2D,
                params_layout: DescriptorSetLayout,
-               reversed_depth_buffer: bool)
+               reversed_depth_buffer: bool,
+               shadow_image_view: ImageView,
+               shadows_enabled: bool,
+               linear_depth: bool,
+               hdr_ceiling: f32,
+               material_packing: MaterialPacking)
                -> Result<ShadeGfx, Error>
     {
         let sampler = {
@@ -59,6 +117,102 @@ impl ShadeGfx {
             }, None)?
         };
 
+        // The environment cubemap is sampled at a mip level chosen by
+        // roughness, so this sampler allows the full mip chain.
+        let env_sampler = {
+            use dacite::core::{SamplerCreateInfo, SamplerMipmapMode, SamplerAddressMode,
+                               BorderColor, Filter, CompareOp};
+
+            device.create_sampler(&SamplerCreateInfo {
+                flags: Default::default(),
+                mag_filter: Filter::Linear,
+                min_filter: Filter::Linear,
+                mipmap_mode: SamplerMipmapMode::Linear,
+                address_mode_u: SamplerAddressMode::ClampToEdge,
+                address_mode_v: SamplerAddressMode::ClampToEdge,
+                address_mode_w: SamplerAddressMode::ClampToEdge,
+                mip_lod_bias: 0.0,
+                anisotropy_enable: false,
+                max_anisotropy: 1.0,
+                compare_enable: false,
+                compare_op: CompareOp::Never,
+                min_lod: 0.0,
+                max_lod: 16.0,
+                border_color: BorderColor::FloatOpaqueWhite,
+                unnormalized_coordinates: false,
+                chain: None
+            }, None)?
+        };
+
+        // The shadow map is compared texel by texel (PCF) in the shader, so it
+        // must not be filtered.
+        let shadow_sampler = {
+            use dacite::core::{SamplerCreateInfo, SamplerMipmapMode, SamplerAddressMode,
+                               BorderColor, Filter, CompareOp};
+
+            device.create_sampler(&SamplerCreateInfo {
+                flags: Default::default(),
+                mag_filter: Filter::Nearest,
+                min_filter: Filter::Nearest,
+                mipmap_mode: SamplerMipmapMode::Nearest,
+                address_mode_u: SamplerAddressMode::ClampToEdge,
+                address_mode_v: SamplerAddressMode::ClampToEdge,
+                address_mode_w: SamplerAddressMode::ClampToEdge,
+                mip_lod_bias: 0.0,
+                anisotropy_enable: false,
+                max_anisotropy: 1.0,
+                compare_enable: false,
+                compare_op: CompareOp::Never,
+                min_lod: 0.0,
+                max_lod: 1.0,
+                border_color: BorderColor::FloatOpaqueWhite,
+                unnormalized_coordinates: false,
+                chain: None
+            }, None)?
+        };
+
+        // The environment binding is a samplerCube, so when no environment
+        // is set we need some cubemap to bind there. Its contents are never
+        // read.
+        let dummy_cubemap = {
+            use dacite::core::{Format, ComponentMapping, Extent3D, ImageLayout,
+                               ImageTiling, ImageUsageFlags, AccessFlags,
+                               PipelineStageFlags, ImageSubresourceRange,
+                               ImageAspectFlags, OptionalMipLevels,
+                               OptionalArrayLayers};
+
+            let mut dummy_cubemap = ImageWrap::new(
+                device, memory,
+                Format::R8G8B8A8_UNorm,
+                ComponentMapping::identity(),
+                1,
+                Extent3D { width: 1, height: 1, depth: 1 },
+                ImageWrapType::Cubemap,
+                ImageLayout::Undefined,
+                ImageTiling::Optimal,
+                ImageUsageFlags::SAMPLED,
+                Lifetime::Permanent,
+                false,
+                "Dummy Environment Cubemap")?;
+
+            dummy_cubemap.transition_layout_now(
+                device,
+                ImageLayout::Undefined, ImageLayout::ShaderReadOnlyOptimal,
+                AccessFlags::empty(), AccessFlags::SHADER_READ,
+                PipelineStageFlags::TOP_OF_PIPE, PipelineStageFlags::FRAGMENT_SHADER,
+                ImageSubresourceRange {
+                    aspect_mask: ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: OptionalMipLevels::MipLevels(1),
+                    base_array_layer: 0,
+                    layer_count: OptionalArrayLayers::ArrayLayers(6),
+                },
+                commander)?;
+
+            dummy_cubemap
+        };
+        let dummy_cube_view = dummy_cubemap.get_image_view(device)?;
+
         let depth_image_view = target_data.depth_image.get_image_view(device)?;
         let diffuse_image_view = target_data.diffuse_image.get_image_view(device)?;
         let normals_image_view = target_data.normals_image.get_image_view(device)?;
@@ -67,34 +221,69 @@ impl ShadeGfx {
         let desc_bindings = {
             use dacite::core::ShaderStageFlags;
             vec![
-                DescriptorSetLayoutBinding {
+                DescriptorSetLayoutBinding { // g-buffer, sampled with binding 8
                     binding: 0,
-                    descriptor_type: DescriptorType::CombinedImageSampler,
+                    descriptor_type: DescriptorType::SampledImage,
                     descriptor_count: 1,
                     stage_flags: ShaderStageFlags::FRAGMENT,
                     immutable_samplers: vec![],
                 },
                 DescriptorSetLayoutBinding {
                     binding: 1,
-                    descriptor_type: DescriptorType::CombinedImageSampler,
+                    descriptor_type: DescriptorType::SampledImage,
                     descriptor_count: 1,
                     stage_flags: ShaderStageFlags::FRAGMENT,
                     immutable_samplers: vec![],
                 },
                 DescriptorSetLayoutBinding {
                     binding: 2,
-                    descriptor_type: DescriptorType::CombinedImageSampler,
+                    descriptor_type: DescriptorType::SampledImage,
                     descriptor_count: 1,
                     stage_flags: ShaderStageFlags::FRAGMENT,
                     immutable_samplers: vec![],
                 },
                 DescriptorSetLayoutBinding {
                     binding: 3,
+                    descriptor_type: DescriptorType::SampledImage,
+                    descriptor_count: 1,
+                    stage_flags: ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: vec![],
+                },
+                DescriptorSetLayoutBinding { // optional ambient occlusion
+                    binding: 4,
+                    descriptor_type: DescriptorType::CombinedImageSampler,
+                    descriptor_count: 1,
+                    stage_flags: ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: vec![],
+                },
+                DescriptorSetLayoutBinding { // optional environment cubemap
+                    binding: 5,
                     descriptor_type: DescriptorType::CombinedImageSampler,
                     descriptor_count: 1,
                     stage_flags: ShaderStageFlags::FRAGMENT,
                     immutable_samplers: vec![],
                 },
+                DescriptorSetLayoutBinding { // optional BRDF lookup table
+                    binding: 6,
+                    descriptor_type: DescriptorType::CombinedImageSampler,
+                    descriptor_count: 1,
+                    stage_flags: ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: vec![],
+                },
+                DescriptorSetLayoutBinding { // shadow map
+                    binding: 7,
+                    descriptor_type: DescriptorType::CombinedImageSampler,
+                    descriptor_count: 1,
+                    stage_flags: ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: vec![],
+                },
+                DescriptorSetLayoutBinding { // shared g-buffer sampler
+                    binding: 8,
+                    descriptor_type: DescriptorType::Sampler,
+                    descriptor_count: 1,
+                    stage_flags: ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: vec![],
+                },
             ]
         };
 
@@ -122,53 +311,34 @@ impl ShadeGfx {
             descriptor_sets.pop().unwrap()
         };
 
-        let vertex_shader = vertex_shader(device)?;
-
-        let fragment_shader = fragment_shader(device)?;
-
-        let fragment_spec = SpecializationInfo {
-            map_entries: vec![
-                SpecializationMapEntry { // near depth
-                    constant_id: 0,
-                    offset: 0,
-                    size: ::std::mem::size_of::<f32>(),
-                },
-                SpecializationMapEntry { // far depth
-                    constant_id: 0,
-                    offset: 1 * ::std::mem::size_of::<f32>() as u32,
-                    size: ::std::mem::size_of::<f32>(),
-                },
-            ],
-            // near than far
-            data: {
-                let f: [f32; 2] = if reversed_depth_buffer { [1.0, 0.0] } else { [0.0, 1.0] };
-                unsafe {
-                    ::std::slice::from_raw_parts(
-                        f.as_ptr() as *const u8,
-                        2 * ::std::mem::size_of::<f32>()).to_vec()
-                }
-            }
-        };
-
-        let (pipeline_layout, pipeline) =
-            super::pipeline::create(
-                device, viewport, scissors,
-                reversed_depth_buffer,
-                render_pass, vec![desc_layout.clone(),
-                                  params_layout],
-                Some(vertex_shader), None, Some(fragment_shader), Some(fragment_spec),
-                None,
-                PrimitiveTopology::TriangleList,
-                CullModeFlags::NONE, FrontFace::Clockwise,
-                DepthHandling::None, // no depth attachment (we use as input herein)
-                vec![BlendMode::Off],
-                vec![])?;
+        let (pipeline_layout, pipeline) = build_pipeline(
+            device, &pipeline_cache, render_pass.clone(), viewport, scissors.clone(),
+            desc_layout.clone(), params_layout.clone(),
+            reversed_depth_buffer, false, false, shadows_enabled, linear_depth,
+            hdr_ceiling, material_packing)?;
 
         let mut shade_gfx = ShadeGfx {
             pipeline: pipeline,
             pipeline_layout: pipeline_layout,
+            pipeline_cache: pipeline_cache,
             descriptor_set: descriptor_set,
             desc_layout: desc_layout,
+            params_layout: params_layout,
+            render_pass: render_pass,
+            viewport: viewport,
+            scissors: scissors,
+            reversed_depth_buffer: reversed_depth_buffer,
+            ao_image_view: None,
+            environment_image_views: None,
+            dummy_cubemap: dummy_cubemap,
+            dummy_cube_view: dummy_cube_view,
+            env_sampler: env_sampler,
+            shadow_image_view: shadow_image_view,
+            shadow_sampler: shadow_sampler,
+            shadows_enabled: shadows_enabled,
+            linear_depth: linear_depth,
+            hdr_ceiling: hdr_ceiling,
+            material_packing: material_packing,
             material_image_view: material_image_view,
             normals_image_view: normals_image_view,
             diffuse_image_view: diffuse_image_view,
@@ -199,80 +369,99 @@ impl ShadeGfx {
         Ok(())
     }
 
+    // Set (or unset) a screen-space ambient occlusion texture which is
+    // multiplied into the ambient term. This rebuilds the pipeline, so the
+    // caller must ensure it is not in use and re-record command buffers.
+    pub fn set_ao_image_view(&mut self, device: &Device, ao_image_view: Option<ImageView>)
+        -> Result<(), Error>
+    {
+        self.ao_image_view = ao_image_view;
+        self.rebuild_pipeline(device)
+    }
+
+    // Set (or unset) a prefiltered environment cubemap (with roughness
+    // mapped across its mip levels) and a BRDF lookup table, for image-based
+    // specular lighting. This rebuilds the pipeline, so the caller must
+    // ensure it is not in use and re-record command buffers.
+    pub fn set_environment_image_views(&mut self, device: &Device,
+                                       environment_image_views: Option<(ImageView, ImageView)>)
+        -> Result<(), Error>
+    {
+        self.environment_image_views = environment_image_views;
+        self.rebuild_pipeline(device)
+    }
+
+    pub fn material_packing(&self) -> MaterialPacking {
+        self.material_packing
+    }
+
+    // Change which material channels hold which properties. This rebuilds
+    // the pipeline, so the caller must ensure it is not in use and
+    // re-record command buffers.
+    pub fn set_material_packing(&mut self, device: &Device,
+                                material_packing: MaterialPacking)
+        -> Result<(), Error>
+    {
+        self.material_packing = material_packing;
+        self.rebuild_pipeline(device)
+    }
+
+    // Switch the depth convention (see Config::reversed_depth_buffer). This
+    // rebuilds the pipeline, so the caller must ensure it is not in use and
+    // re-record command buffers.
+    pub fn set_reversed_depth_buffer(&mut self, device: &Device,
+                                     reversed_depth_buffer: bool)
+        -> Result<(), Error>
+    {
+        self.reversed_depth_buffer = reversed_depth_buffer;
+        self.rebuild_pipeline(device)
+    }
+
+    fn rebuild_pipeline(&mut self, device: &Device) -> Result<(), Error>
+    {
+        let (pipeline_layout, pipeline) = build_pipeline(
+            device, &self.pipeline_cache, self.render_pass.clone(), self.viewport,
+            self.scissors.clone(), self.desc_layout.clone(), self.params_layout.clone(),
+            self.reversed_depth_buffer, self.ao_image_view.is_some(),
+            self.environment_image_views.is_some(),
+            self.shadows_enabled, self.linear_depth, self.hdr_ceiling,
+            self.material_packing)?;
+
+        self.pipeline_layout = pipeline_layout;
+        self.pipeline = pipeline;
+
+        self.write();
+
+        Ok(())
+    }
+
     fn write(&mut self)
     {
-        use dacite::core::{WriteDescriptorSet, WriteDescriptorSetElements,
-                           DescriptorImageInfo};
-
-        DescriptorSet::update(
-            Some(&[
-                WriteDescriptorSet {
-                    dst_set: self.descriptor_set.clone(),
-                    dst_binding: 0,
-                    dst_array_element: 0, // only have 1 element
-                    descriptor_type: DescriptorType::CombinedImageSampler,
-                    elements: WriteDescriptorSetElements::ImageInfo(
-                        vec![
-                            DescriptorImageInfo {
-                                sampler: Some(self.sampler.clone()),
-                                image_view: Some(self.depth_image_view.clone()),
-                                image_layout: ImageLayout::ShaderReadOnlyOptimal,
-                            }
-                        ]
-                    ),
-                    chain: None,
-                },
-                WriteDescriptorSet {
-                    dst_set: self.descriptor_set.clone(),
-                    dst_binding: 1,
-                    dst_array_element: 0, // only have 1 element
-                    descriptor_type: DescriptorType::CombinedImageSampler,
-                    elements: WriteDescriptorSetElements::ImageInfo(
-                        vec![
-                            DescriptorImageInfo {
-                                sampler: Some(self.sampler.clone()),
-                                image_view: Some(self.diffuse_image_view.clone()),
-                                image_layout: ImageLayout::ShaderReadOnlyOptimal,
-                            }
-                        ]
-                    ),
-                    chain: None,
-                },
-                WriteDescriptorSet {
-                    dst_set: self.descriptor_set.clone(),
-                    dst_binding: 2,
-                    dst_array_element: 0, // only have 1 element
-                    descriptor_type: DescriptorType::CombinedImageSampler,
-                    elements: WriteDescriptorSetElements::ImageInfo(
-                        vec![
-                            DescriptorImageInfo {
-                                sampler: Some(self.sampler.clone()),
-                                image_view: Some(self.normals_image_view.clone()),
-                                image_layout: ImageLayout::ShaderReadOnlyOptimal,
-                            }
-                        ]
-                    ),
-                    chain: None,
-                },
-                WriteDescriptorSet {
-                    dst_set: self.descriptor_set.clone(),
-                    dst_binding: 3,
-                    dst_array_element: 0, // only have 1 element
-                    descriptor_type: DescriptorType::CombinedImageSampler,
-                    elements: WriteDescriptorSetElements::ImageInfo(
-                        vec![
-                            DescriptorImageInfo {
-                                sampler: Some(self.sampler.clone()),
-                                image_view: Some(self.material_image_view.clone()),
-                                image_layout: ImageLayout::ShaderReadOnlyOptimal,
-                            }
-                        ]
-                    ),
-                    chain: None,
-                },
-            ]),
-            None
-        );
+        let rol = ImageLayout::ShaderReadOnlyOptimal;
+
+        DescriptorSetWriter::new(self.descriptor_set.clone())
+            .sampled_image(0, self.depth_image_view.clone(), rol)
+            .sampled_image(1, self.diffuse_image_view.clone(), rol)
+            .sampled_image(2, self.normals_image_view.clone(), rol)
+            .sampled_image(3, self.material_image_view.clone(), rol)
+            // The binding must be valid even when no AO texture is set. The
+            // shader won't read it in that case, so any image view will do.
+            .image(4, self.sampler.clone(), match self.ao_image_view {
+                Some(ref view) => view.clone(),
+                None => self.material_image_view.clone(),
+            }, rol)
+            // As with AO, but this must be a cube view
+            .image(5, self.env_sampler.clone(), match self.environment_image_views {
+                Some((ref view, _)) => view.clone(),
+                None => self.dummy_cube_view.clone(),
+            }, rol)
+            .image(6, self.sampler.clone(), match self.environment_image_views {
+                Some((_, ref view)) => view.clone(),
+                None => self.material_image_view.clone(),
+            }, rol)
+            .image(7, self.shadow_sampler.clone(), self.shadow_image_view.clone(), rol)
+            .sampler(8, self.sampler.clone())
+            .flush();
     }
 
     pub fn record(&self, command_buffer: CommandBuffer,
@@ -295,6 +484,125 @@ impl ShadeGfx {
     }
 }
 
+fn build_pipeline(device: &Device,
+                  pipeline_cache: &PipelineCache,
+                  render_pass: RenderPass,
+                  viewport: Viewport,
+                  scissors: Rect2D,
+                  desc_layout: DescriptorSetLayout,
+                  params_layout: DescriptorSetLayout,
+                  reversed_depth_buffer: bool,
+                  ao_enabled: bool,
+                  ibl_enabled: bool,
+                  shadows_enabled: bool,
+                  linear_depth: bool,
+                  hdr_ceiling: f32,
+                  material_packing: MaterialPacking)
+                  -> Result<(PipelineLayout, Pipeline), Error>
+{
+    let channels = material_packing.channels();
+    if channels.iter().any(|&c| c > 3) {
+        return Err(Error::General(
+            format!("Material channels {:?} are not all within 0 - 3", channels)));
+    }
+
+    let vertex_shader = vertex_shader(device)?;
+
+    let fragment_shader = fragment_shader(device)?;
+
+    let fragment_spec = SpecializationInfo {
+        map_entries: vec![
+            SpecializationMapEntry { // near depth
+                constant_id: 0,
+                offset: 0,
+                size: ::std::mem::size_of::<f32>(),
+            },
+            SpecializationMapEntry { // far depth
+                constant_id: 1,
+                offset: 1 * ::std::mem::size_of::<f32>() as u32,
+                size: ::std::mem::size_of::<f32>(),
+            },
+            SpecializationMapEntry { // ao texture enabled
+                constant_id: 2,
+                offset: 2 * ::std::mem::size_of::<f32>() as u32,
+                size: ::std::mem::size_of::<i32>(),
+            },
+            SpecializationMapEntry { // environment lighting enabled
+                constant_id: 3,
+                offset: 3 * ::std::mem::size_of::<f32>() as u32,
+                size: ::std::mem::size_of::<i32>(),
+            },
+            SpecializationMapEntry { // shadow map enabled
+                constant_id: 4,
+                offset: 4 * ::std::mem::size_of::<f32>() as u32,
+                size: ::std::mem::size_of::<i32>(),
+            },
+            SpecializationMapEntry { // output ceiling
+                constant_id: 5,
+                offset: 5 * ::std::mem::size_of::<f32>() as u32,
+                size: ::std::mem::size_of::<f32>(),
+            },
+            SpecializationMapEntry { // roughness channel
+                constant_id: 6,
+                offset: 6 * ::std::mem::size_of::<f32>() as u32,
+                size: ::std::mem::size_of::<i32>(),
+            },
+            SpecializationMapEntry { // metallicity channel
+                constant_id: 7,
+                offset: 7 * ::std::mem::size_of::<f32>() as u32,
+                size: ::std::mem::size_of::<i32>(),
+            },
+            SpecializationMapEntry { // ambient occlusion channel
+                constant_id: 8,
+                offset: 8 * ::std::mem::size_of::<f32>() as u32,
+                size: ::std::mem::size_of::<i32>(),
+            },
+            SpecializationMapEntry { // cavity channel
+                constant_id: 9,
+                offset: 9 * ::std::mem::size_of::<f32>() as u32,
+                size: ::std::mem::size_of::<i32>(),
+            },
+        ],
+        // near, far, ao_enabled, ibl_enabled, shadows_enabled, hdr_ceiling,
+        // then the material channels
+        data: {
+            let (near, far) = if reversed_depth_buffer { (1.0_f32, 0.0_f32) } else { (0.0, 1.0) };
+            let d: [u32; 10] = [near.to_bits(), far.to_bits(), ao_enabled as u32,
+                                ibl_enabled as u32, shadows_enabled as u32,
+                                hdr_ceiling.to_bits(),
+                                channels[0], channels[1], channels[2], channels[3]];
+            unsafe {
+                ::std::slice::from_raw_parts(
+                    d.as_ptr() as *const u8,
+                    10 * ::std::mem::size_of::<u32>()).to_vec()
+            }
+        }
+    };
+
+    super::pipeline::create(
+        device, Some(pipeline_cache), viewport, scissors,
+        reversed_depth_buffer,
+        render_pass, vec![desc_layout,
+                          params_layout],
+        Some(vertex_shader), None, Some(fragment_shader), Some(fragment_spec),
+        None,
+        PrimitiveTopology::TriangleList,
+        false, // no primitive restart
+        1.0, // line width
+        CullModeFlags::NONE, FrontFace::Clockwise,
+        false, // no depth clamp
+        DepthHandling::None, // no depth attachment (we use as input herein)
+        None, // no depth bias
+        None, // no sample shading
+        if linear_depth {
+            vec![BlendMode::Off, BlendMode::Off]
+        } else {
+            vec![BlendMode::Off]
+        },
+        ColorComponentFlags::R | ColorComponentFlags::G | ColorComponentFlags::B,
+        vec![])
+}
+
 fn vertex_shader(device: &Device) -> Result<ShaderModule, Error>
 {
     let bytes: &[u8] = glsl_vs!(r#"
@@ -349,9 +657,21 @@ fn fragment_shader(device: &Device)
 
 layout(constant_id = 0) const float depth_near = 0.0;
 layout(constant_id = 1) const float depth_far = 1.0;
+layout(constant_id = 2) const int ao_enabled = 0;
+layout(constant_id = 3) const int ibl_enabled = 0;
+layout(constant_id = 4) const int shadows_enabled = 0;
+// Largest output value (the shading format's maximum, or lower)
+layout(constant_id = 5) const float hdr_ceiling = 65504.0;
+// Material g-buffer channels (0 = r through 3 = a)
+layout(constant_id = 6) const int roughness_channel = 0;
+layout(constant_id = 7) const int metallicity_channel = 1;
+layout(constant_id = 8) const int ao_channel = 2;
+layout(constant_id = 9) const int cavity_channel = 3;
 
 layout (set = 1, binding = 0) uniform ParamsUBO {
   mat4 inv_projection;
+  mat4 inv_view;
+  mat4 shadow_view_projection;
   vec4 dlight_directions[2];
   vec4 dlight_irradiances[2];
   float bloom_strength;
@@ -359,16 +679,44 @@ layout (set = 1, binding = 0) uniform ParamsUBO {
   float blur_level;
   float ambient;
   float white_level;
+  int tonemapper;
+  float shadow_bias;
+  float focus_distance;
+  float aperture;
+  float star_brightness;
+  float star_size;
+  float near_plane;
+  float far_plane;
+  float depth_linearize_scale;
+  float depth_linearize_offset;
+  float exposure;
+  mat4 prev_inv_projection;
+  mat4 prev_inv_view;
+  vec2 taa_jitter;
+  float taa_feedback;
+  uint motion_vectors;
+  mat4 view_projection;
+  mat4 prev_view_projection;
+  vec4 background_color;
+  float alpha_cutoff;
 } params;
 
-layout (set = 0, binding = 0) uniform sampler2D depthbuffer; // D32_SFloat
-layout (set = 0, binding = 1) uniform sampler2D diffusemap;  // A2B10G10R10_UNorm_Pack32
-layout (set = 0, binding = 2) uniform sampler2D normalsmap;  // A2B10G10R10_UNorm_Pack32
-layout (set = 0, binding = 3) uniform sampler2D materialmap; // R8G8B8_UNorm
+// The g-buffers are separate images sharing one sampler (binding 8)
+layout (set = 0, binding = 0) uniform texture2D depthbuffer; // D32_SFloat
+layout (set = 0, binding = 1) uniform texture2D diffusemap;  // A2B10G10R10_UNorm_Pack32 (default)
+layout (set = 0, binding = 2) uniform texture2D normalsmap;  // A2B10G10R10_UNorm_Pack32 (default)
+layout (set = 0, binding = 3) uniform texture2D materialmap; // R8G8B8A8_UNorm (default)
+layout (set = 0, binding = 4) uniform sampler2D aomap;       // optional, r-channel
+layout (set = 0, binding = 5) uniform samplerCube envmap;    // optional, prefiltered
+layout (set = 0, binding = 6) uniform sampler2D brdflut;     // optional, rg: scale, bias
+layout (set = 0, binding = 7) uniform sampler2D shadowmap;   // D32_SFloat, from dlight 0
+layout (set = 0, binding = 8) uniform sampler gbuffersampler;
 
 layout(location = 0) in vec2 uv;
 
 layout(location = 0) out vec4 out_color; // can be >1.0, post will handle it.
+// Only stored if the linear depth target is enabled
+layout(location = 1) out float out_linear_depth;
 
 const float pi = 3.14159265359;
 
@@ -404,30 +752,73 @@ vec3 shadingSpecularGGX(vec3 N, vec3 V, vec3 L, float roughness, vec3 F0)
     return D * F * G / 4.0;
 }
 
+// Normals are stored as n * 0.5 + 0.5 in every g-buffer format, including
+// float formats, so this decode does not depend on the configured format.
 vec4 decode_normal(vec4 n) {
   return normalize(vec4((n.xyz - 0.5) * 2, 0.0));
 }
 
+// Fraction (0.0 to 1.0) of the primary light reaching a view-space position,
+// filtered over 3x3 shadow map texels (PCF)
+float shadow_factor(vec4 position) {
+  vec4 world = params.inv_view * vec4(position.xyz / position.w, 1.0);
+  vec4 light_clip = params.shadow_view_projection * world;
+  vec3 light_ndc = light_clip.xyz / light_clip.w;
+  vec2 shadow_uv = light_ndc.xy * 0.5 + 0.5;
+
+  // Outside the shadow map is unshadowed
+  if (any(lessThan(shadow_uv, vec2(0.0))) || any(greaterThan(shadow_uv, vec2(1.0)))
+      || light_ndc.z < 0.0 || light_ndc.z > 1.0) {
+    return 1.0;
+  }
+
+  // Positive when the fragment is further from the light than the occluder,
+  // whichever way the depth buffer runs
+  float direction = sign(depth_far - depth_near);
+  vec2 texel = 1.0 / vec2(textureSize(shadowmap, 0));
+  float lit = 0.0;
+  for (int x = -1; x <= 1; x++) {
+    for (int y = -1; y <= 1; y++) {
+      float occluder = texture(shadowmap, shadow_uv + vec2(x, y) * texel).r;
+      if ((light_ndc.z - occluder) * direction <= params.shadow_bias) {
+        lit += 1.0;
+      }
+    }
+  }
+  return lit / 9.0;
+}
+
 void main() {
   // Reconstruct view-space position of the fragment
-  float fragdepth = texture(depthbuffer, uv).r;
+  float fragdepth = texture(sampler2D(depthbuffer, gbuffersampler), uv).r;
   vec4 clipPos;
   clipPos.xy = (2.0 * uv) - 1;
   clipPos.z = (fragdepth - depth_near) / (depth_far - depth_near);
   clipPos.w = 1.0;
   vec4 position = params.inv_projection * clipPos;
   vec3 V = normalize(-position.xyz);
+  out_linear_depth = abs(position.z / position.w);
 
-  // Sample the textures
-  vec4 materials_sample = texture(materialmap, uv);
-  float roughness = materials_sample.r;
-  float metallicity = materials_sample.g;
-  float ao = materials_sample.b;
-  float cavity = materials_sample.a;
-  vec3 albedo = texture(diffusemap, uv).rgb;
-  vec3 N = decode_normal(texture(normalsmap, uv)).rgb;
+  // Nothing was drawn here
+  if (fragdepth == depth_far) {
+    out_color = level(vec4(min(params.background_color.rgb, hdr_ceiling), 1.0));
+    return;
+  }
 
-  vec3 ambient_level = vec3(params.ambient, params.ambient, params.ambient);
+  // Sample the textures
+  vec4 materials_sample = texture(sampler2D(materialmap, gbuffersampler), uv);
+  float roughness = materials_sample[roughness_channel];
+  float metallicity = materials_sample[metallicity_channel];
+  float ao = materials_sample[ao_channel];
+  float cavity = materials_sample[cavity_channel];
+  vec3 albedo = texture(sampler2D(diffusemap, gbuffersampler), uv).rgb;
+  vec3 N = decode_normal(texture(sampler2D(normalsmap, gbuffersampler), uv)).rgb;
+
+  float ao_level = 1.0;
+  if (ao_enabled != 0) {
+    ao_level = texture(aomap, uv).r;
+  }
+  vec3 ambient_level = vec3(params.ambient, params.ambient, params.ambient) * ao_level;
 
   // Prepare terms we re-use
   vec3 diffuse = albedo * (1 - metallicity) * ao;
@@ -435,6 +826,17 @@ void main() {
   // Start with ambient component
   vec3 color = ambient_level * diffuse;
 
+  // Add environment reflections. Rougher surfaces sample blurrier
+  // (higher) mip levels of the prefiltered environment.
+  if (ibl_enabled != 0) {
+    vec3 R = (params.inv_view * vec4(reflect(-V, N), 0.0)).xyz;
+    float lod = roughness * float(textureQueryLevels(envmap) - 1);
+    vec3 prefiltered = textureLod(envmap, R, lod).rgb;
+    vec2 brdf = texture(brdflut, vec2(max(dot(N, V), 0.0), roughness)).rg;
+    vec3 F0 = mix(vec3(0.04), albedo, metallicity);
+    color += prefiltered * (F0 * brdf.x + brdf.y) * cavity * ao * ao_level;
+  }
+
   // Add each lights contribution
   for (int i=0; i<=1; i++) {
     if (params.dlight_irradiances[i].xyz == vec3(0.0, 0.0, 0.0)) {
@@ -447,6 +849,13 @@ void main() {
     vec3 L = params.dlight_directions[i].xyz;
     float lambert = max(0.0, dot(L, N));
 
+    // Only the primary light casts shadows
+    if (shadows_enabled != 0 && i == 0) {
+      float shadow = shadow_factor(position);
+      light *= shadow;
+      light_intensity *= shadow;
+    }
+
     // Add diffuse part
     color += diffuse * lambert * light;
 
@@ -456,8 +865,8 @@ void main() {
   }
 
   // Level the output (still allows >1.0 but sets base exposure/white_level)
-  // Limit to maximum f16 to not wrap around.
-  out_color = level(vec4(min(color, 65504), 1.0));
+  // Limit to the ceiling so values don't overflow to infinity.
+  out_color = level(vec4(min(color, hdr_ceiling), 1.0));
 }
 "#);
 
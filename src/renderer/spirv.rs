@@ -0,0 +1,437 @@
+
+use std::collections::HashMap;
+use std::ops::Range;
+use dacite::core::DescriptorType;
+use crate::error::Error;
+
+// Just enough SPIR-V reflection to cross-check a shader against the layouts
+// a pipeline is created with (see Config::validate_pipelines): the
+// descriptor bindings it declares and the bytes of its push constant block.
+
+const MAGIC: u32 = 0x0723_0203;
+
+// Opcodes
+const OP_DECORATE: u16 = 71;
+const OP_MEMBER_DECORATE: u16 = 72;
+const OP_TYPE_BOOL: u16 = 20;
+const OP_TYPE_INT: u16 = 21;
+const OP_TYPE_FLOAT: u16 = 22;
+const OP_TYPE_VECTOR: u16 = 23;
+const OP_TYPE_MATRIX: u16 = 24;
+const OP_TYPE_IMAGE: u16 = 25;
+const OP_TYPE_SAMPLER: u16 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u16 = 27;
+const OP_TYPE_ARRAY: u16 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u16 = 29;
+const OP_TYPE_STRUCT: u16 = 30;
+const OP_TYPE_POINTER: u16 = 32;
+const OP_CONSTANT: u16 = 43;
+const OP_VARIABLE: u16 = 59;
+
+// Decorations
+const BUFFER_BLOCK: u32 = 3;
+const ROW_MAJOR: u32 = 4;
+const ARRAY_STRIDE: u32 = 6;
+const MATRIX_STRIDE: u32 = 7;
+const BINDING: u32 = 33;
+const DESCRIPTOR_SET: u32 = 34;
+const OFFSET: u32 = 35;
+
+// Storage classes
+const UNIFORM_CONSTANT: u32 = 0;
+const UNIFORM: u32 = 2;
+const PUSH_CONSTANT: u32 = 9;
+const STORAGE_BUFFER: u32 = 12;
+
+// Image dimensions
+const DIM_BUFFER: u32 = 5;
+const DIM_SUBPASS_DATA: u32 = 6;
+
+// The kind of descriptor a shader variable needs.  Buffers may be bound
+// with either the plain or the dynamic descriptor type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DescriptorKind {
+    Sampler,
+    CombinedImageSampler,
+    SampledImage,
+    StorageImage,
+    UniformTexelBuffer,
+    StorageTexelBuffer,
+    UniformBuffer,
+    StorageBuffer,
+    InputAttachment,
+}
+
+impl DescriptorKind {
+    pub fn matches(&self, descriptor_type: DescriptorType) -> bool {
+        match (*self, descriptor_type) {
+            (DescriptorKind::Sampler, DescriptorType::Sampler) => true,
+            (DescriptorKind::CombinedImageSampler, DescriptorType::CombinedImageSampler) => true,
+            (DescriptorKind::SampledImage, DescriptorType::SampledImage) => true,
+            (DescriptorKind::StorageImage, DescriptorType::StorageImage) => true,
+            (DescriptorKind::UniformTexelBuffer, DescriptorType::UniformTexelBuffer) => true,
+            (DescriptorKind::StorageTexelBuffer, DescriptorType::StorageTexelBuffer) => true,
+            (DescriptorKind::UniformBuffer, DescriptorType::UniformBuffer) => true,
+            (DescriptorKind::UniformBuffer, DescriptorType::UniformBufferDynamic) => true,
+            (DescriptorKind::StorageBuffer, DescriptorType::StorageBuffer) => true,
+            (DescriptorKind::StorageBuffer, DescriptorType::StorageBufferDynamic) => true,
+            (DescriptorKind::InputAttachment, DescriptorType::InputAttachment) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ShaderBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub kind: DescriptorKind,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ShaderInterface {
+    pub bindings: Vec<ShaderBinding>,
+    // The bytes of push constants the shader's block declares, if it has one
+    pub push_constants: Option<Range<u32>>,
+}
+
+enum Type {
+    Scalar(u32), // bytes
+    Vector(u32, u32), // component type, count
+    Matrix(u32, u32), // column type, column count
+    Image(u32, u32), // dim, sampled
+    Sampler,
+    SampledImage,
+    Array(u32, u32), // element type, length constant
+    RuntimeArray(u32), // element type
+    Struct(Vec<u32>), // member types
+    Pointer(u32, u32), // storage class, type
+}
+
+#[derive(Default)]
+struct Decorations {
+    set: Option<u32>,
+    binding: Option<u32>,
+    buffer_block: bool,
+    array_stride: Option<u32>,
+}
+
+#[derive(Default, Clone)]
+struct MemberDecorations {
+    offset: Option<u32>,
+    matrix_stride: Option<u32>,
+    row_major: bool,
+}
+
+#[derive(Default)]
+struct Module {
+    types: HashMap<u32, Type>,
+    constants: HashMap<u32, u32>,
+    decorations: HashMap<u32, Decorations>,
+    member_decorations: HashMap<(u32, u32), MemberDecorations>,
+    variables: Vec<(u32, u32, u32)>, // pointer type, id, storage class
+}
+
+pub fn reflect(code: &[u8]) -> Result<ShaderInterface, Error>
+{
+    if code.len() % 4 != 0 || code.len() < 20 {
+        return Err(Error::General("SPIR-V code is truncated".to_owned()));
+    }
+
+    let mut words: Vec<u32> = code.chunks(4)
+        .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect();
+    if words[0] == MAGIC.swap_bytes() {
+        for word in words.iter_mut() {
+            *word = word.swap_bytes();
+        }
+    } else if words[0] != MAGIC {
+        return Err(Error::General("Not SPIR-V code (bad magic number)".to_owned()));
+    }
+
+    let module = parse(&words[5..])?;
+
+    let mut interface: ShaderInterface = Default::default();
+    for &(pointer_type, id, storage_class) in &module.variables {
+        let pointee = match module.types.get(&pointer_type) {
+            Some(&Type::Pointer(_, pointee)) => pointee,
+            _ => continue,
+        };
+
+        if storage_class == PUSH_CONSTANT {
+            let end = module.size_of(pointee, None)?;
+            let start = match module.types.get(&pointee) {
+                Some(&Type::Struct(ref members)) => (0..members.len() as u32)
+                    .filter_map(|m| module.member_decorations.get(&(pointee, m)))
+                    .filter_map(|d| d.offset)
+                    .min().unwrap_or(0),
+                _ => 0,
+            };
+            interface.push_constants = Some(start..end);
+            continue;
+        }
+
+        let kind = match module.descriptor_kind(pointee, storage_class) {
+            Some(kind) => kind,
+            None => continue, // not a resource (e.g. shader inputs and outputs)
+        };
+        let decorations = module.decorations.get(&id);
+        interface.bindings.push(ShaderBinding {
+            set: decorations.and_then(|d| d.set).unwrap_or(0),
+            binding: decorations.and_then(|d| d.binding).unwrap_or(0),
+            kind: kind,
+        });
+    }
+
+    Ok(interface)
+}
+
+fn parse(mut words: &[u32]) -> Result<Module, Error>
+{
+    let mut module: Module = Default::default();
+
+    while !words.is_empty() {
+        let opcode = (words[0] & 0xFFFF) as u16;
+        let count = (words[0] >> 16) as usize;
+        if count == 0 || count > words.len() {
+            return Err(Error::General("Malformed SPIR-V instruction".to_owned()));
+        }
+        let operands = &words[1..count];
+        words = &words[count..];
+
+        // Every instruction we care about has a result (or target) id.
+        // OpTypeBool and OpTypeSampler have nothing else, so the other
+        // arms check the operands they need.
+        if operands.is_empty() {
+            continue;
+        }
+
+        match opcode {
+            OP_DECORATE if operands.len() >= 2 => {
+                let d = module.decorations.entry(operands[0]).or_insert_with(Default::default);
+                let literal = operands.get(2).cloned();
+                match operands[1] {
+                    DESCRIPTOR_SET => d.set = literal,
+                    BINDING => d.binding = literal,
+                    BUFFER_BLOCK => d.buffer_block = true,
+                    ARRAY_STRIDE => d.array_stride = literal,
+                    _ => { }
+                }
+            },
+            OP_MEMBER_DECORATE if operands.len() >= 3 => {
+                let d = module.member_decorations.entry((operands[0], operands[1]))
+                    .or_insert_with(Default::default);
+                let literal = operands.get(3).cloned();
+                match operands[2] {
+                    OFFSET => d.offset = literal,
+                    MATRIX_STRIDE => d.matrix_stride = literal,
+                    ROW_MAJOR => d.row_major = true,
+                    _ => { }
+                }
+            },
+            OP_TYPE_BOOL => { module.types.insert(operands[0], Type::Scalar(4)); },
+            OP_TYPE_INT | OP_TYPE_FLOAT if operands.len() >= 2 => {
+                module.types.insert(operands[0], Type::Scalar(operands[1] / 8));
+            },
+            OP_TYPE_VECTOR if operands.len() >= 3 => {
+                module.types.insert(operands[0], Type::Vector(operands[1], operands[2]));
+            },
+            OP_TYPE_MATRIX if operands.len() >= 3 => {
+                module.types.insert(operands[0], Type::Matrix(operands[1], operands[2]));
+            },
+            OP_TYPE_IMAGE if operands.len() >= 7 => {
+                module.types.insert(operands[0], Type::Image(operands[2], operands[6]));
+            },
+            OP_TYPE_SAMPLER => { module.types.insert(operands[0], Type::Sampler); },
+            OP_TYPE_SAMPLED_IMAGE if operands.len() >= 2 => { module.types.insert(operands[0], Type::SampledImage); },
+            OP_TYPE_ARRAY if operands.len() >= 3 => {
+                module.types.insert(operands[0], Type::Array(operands[1], operands[2]));
+            },
+            OP_TYPE_RUNTIME_ARRAY if operands.len() >= 2 => {
+                module.types.insert(operands[0], Type::RuntimeArray(operands[1]));
+            },
+            OP_TYPE_STRUCT => {
+                module.types.insert(operands[0], Type::Struct(operands[1..].to_vec()));
+            },
+            OP_TYPE_POINTER if operands.len() >= 3 => {
+                module.types.insert(operands[0], Type::Pointer(operands[1], operands[2]));
+            },
+            OP_CONSTANT if operands.len() >= 3 => {
+                module.constants.insert(operands[1], operands[2]);
+            },
+            OP_VARIABLE if operands.len() >= 3 => {
+                module.variables.push((operands[0], operands[1], operands[2]));
+            },
+            _ => { }
+        }
+    }
+
+    Ok(module)
+}
+
+impl Module {
+    fn descriptor_kind(&self, type_id: u32, storage_class: u32) -> Option<DescriptorKind>
+    {
+        match self.types.get(&type_id)? {
+            // Arrays of resources are bound as one binding with a count
+            &Type::Array(element, _) | &Type::RuntimeArray(element) =>
+                self.descriptor_kind(element, storage_class),
+            &Type::Struct(_) => match storage_class {
+                STORAGE_BUFFER => Some(DescriptorKind::StorageBuffer),
+                UNIFORM => {
+                    let buffer_block = self.decorations.get(&type_id)
+                        .map(|d| d.buffer_block).unwrap_or(false);
+                    if buffer_block {
+                        Some(DescriptorKind::StorageBuffer)
+                    } else {
+                        Some(DescriptorKind::UniformBuffer)
+                    }
+                },
+                _ => None,
+            },
+            _ if storage_class != UNIFORM_CONSTANT => None,
+            &Type::Sampler => Some(DescriptorKind::Sampler),
+            &Type::SampledImage => Some(DescriptorKind::CombinedImageSampler),
+            &Type::Image(dim, sampled) => Some(match (dim, sampled) {
+                (DIM_SUBPASS_DATA, _) => DescriptorKind::InputAttachment,
+                (DIM_BUFFER, 2) => DescriptorKind::StorageTexelBuffer,
+                (DIM_BUFFER, _) => DescriptorKind::UniformTexelBuffer,
+                (_, 2) => DescriptorKind::StorageImage,
+                _ => DescriptorKind::SampledImage,
+            }),
+            _ => None,
+        }
+    }
+
+    // Size in bytes of a type laid out with explicit offsets and strides (as
+    // push constant blocks are). Matrices take their stride from the
+    // struct member that holds them.
+    fn size_of(&self, type_id: u32, member: Option<&MemberDecorations>) -> Result<u32, Error>
+    {
+        let unknown = || Error::General(
+            format!("SPIR-V type {} has no known size", type_id));
+
+        match self.types.get(&type_id).ok_or_else(unknown)? {
+            &Type::Scalar(bytes) => Ok(bytes),
+            &Type::Vector(component, count) => Ok(self.size_of(component, None)? * count),
+            &Type::Matrix(column, columns) => {
+                let rows = match self.types.get(&column) {
+                    Some(&Type::Vector(_, rows)) => rows,
+                    _ => return Err(unknown()),
+                };
+                match member {
+                    Some(&MemberDecorations { matrix_stride: Some(stride), row_major, .. }) =>
+                        Ok(stride * if row_major { rows } else { columns }),
+                    _ => Ok(self.size_of(column, None)? * columns),
+                }
+            },
+            &Type::Array(element, length) => {
+                let length = *self.constants.get(&length).ok_or_else(unknown)?;
+                match self.decorations.get(&type_id).and_then(|d| d.array_stride) {
+                    Some(stride) => Ok(stride * length),
+                    None => Ok(self.size_of(element, member)? * length),
+                }
+            },
+            &Type::Struct(ref members) => {
+                let mut size = 0;
+                let mut offset = 0;
+                for (index, &member_type) in members.iter().enumerate() {
+                    let decorations = self.member_decorations.get(&(type_id, index as u32));
+                    if let Some(o) = decorations.and_then(|d| d.offset) {
+                        offset = o;
+                    }
+                    offset += self.size_of(member_type, decorations)?;
+                    size = size.max(offset);
+                }
+                Ok(size)
+            },
+            _ => Err(unknown()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reflect, DescriptorKind, ShaderInterface};
+
+    fn kind(interface: &ShaderInterface, set: u32, binding: u32) -> Option<DescriptorKind> {
+        interface.bindings.iter()
+            .find(|b| b.set == set && b.binding == binding)
+            .map(|b| b.kind)
+    }
+
+    #[test]
+    fn combined_image_sampler() {
+        let bytes: &[u8] = glsl_fs!(r#"#version 450
+layout (set = 1, binding = 2) uniform sampler2D tex;
+layout (location = 0) in vec2 uv;
+layout (location = 0) out vec4 color;
+void main() {
+  color = texture(tex, uv);
+}
+"#);
+        let interface = reflect(bytes).unwrap();
+        assert_eq!(interface.bindings.len(), 1);
+        assert_eq!(kind(&interface, 1, 2), Some(DescriptorKind::CombinedImageSampler));
+        assert!(interface.push_constants.is_none());
+    }
+
+    #[test]
+    fn separate_sampler_and_image() {
+        let bytes: &[u8] = glsl_fs!(r#"#version 450
+layout (set = 0, binding = 0) uniform texture2D image;
+layout (set = 0, binding = 8) uniform sampler smp;
+layout (location = 0) in vec2 uv;
+layout (location = 0) out vec4 color;
+void main() {
+  color = texture(sampler2D(image, smp), uv);
+}
+"#);
+        let interface = reflect(bytes).unwrap();
+        assert_eq!(interface.bindings.len(), 2);
+        assert_eq!(kind(&interface, 0, 0), Some(DescriptorKind::SampledImage));
+        assert_eq!(kind(&interface, 0, 8), Some(DescriptorKind::Sampler));
+    }
+
+    #[test]
+    fn uniform_buffer() {
+        let bytes: &[u8] = glsl_vs!(r#"#version 450
+layout (set = 0, binding = 0) uniform ParamsUBO {
+  mat4 view_projection;
+  vec4 tint;
+} params;
+layout (location = 0) in vec3 pos;
+layout (location = 0) out vec4 color;
+void main() {
+  color = params.tint;
+  gl_Position = params.view_projection * vec4(pos, 1.0);
+}
+"#);
+        let interface = reflect(bytes).unwrap();
+        assert_eq!(interface.bindings.len(), 1);
+        assert_eq!(kind(&interface, 0, 0), Some(DescriptorKind::UniformBuffer));
+    }
+
+    #[test]
+    fn push_constants() {
+        let bytes: &[u8] = glsl_vs!(r#"#version 450
+layout (push_constant) uniform Push {
+  mat4 model;
+  float scale;
+} push;
+layout (location = 0) in vec3 pos;
+void main() {
+  gl_Position = push.model * vec4(pos * push.scale, 1.0);
+}
+"#);
+        let interface = reflect(bytes).unwrap();
+        assert!(interface.bindings.is_empty());
+        assert_eq!(interface.push_constants, Some(0..68));
+    }
+
+    #[test]
+    fn not_spirv() {
+        assert!(reflect(&[0; 3]).is_err());
+        assert!(reflect(&[0; 20]).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+
+use dacite::core::{Device, DescriptorSet, DescriptorSetLayout, CommandBuffer,
+                   RenderPass, Viewport, Rect2D, PipelineBindPoint, Pipeline,
+                   PipelineLayout, PrimitiveTopology, CullModeFlags, FrontFace,
+                   ShaderModuleCreateFlags, ShaderModuleCreateInfo, ShaderModule,
+                   ColorComponentFlags, PipelineCache};
+use crate::error::Error;
+use crate::vertex::{VulkanVertex, StarVertex};
+use super::mesh::VulkanMesh;
+use super::{DepthHandling, BlendMode};
+
+// Draws a starfield (a mesh of StarVertex) as additive point sprites at the
+// start of the transparent pass, so stars land in the shading image and
+// bloom.  Each vertex's `pos` is the direction to the star in world space
+// (stars are infinitely far away) and `xyz` is its CIE XYZ irradiance.
+// Brightness and size come from Params (see Renderer::set_star_params).
+pub struct StarGfx {
+    pipeline: Pipeline,
+    pipeline_layout: PipelineLayout,
+    pipeline_cache: PipelineCache,
+    render_pass: RenderPass,
+    viewport: Viewport,
+    scissors: Rect2D,
+    params_layout: DescriptorSetLayout,
+    mesh: Option<VulkanMesh>,
+}
+
+impl StarGfx {
+    pub fn new(device: &Device,
+               pipeline_cache: PipelineCache,
+               render_pass: RenderPass,
+               viewport: Viewport,
+               scissors: Rect2D,
+               params_layout: DescriptorSetLayout,
+               reversed_depth_buffer: bool)
+               -> Result<StarGfx, Error>
+    {
+        let (pipeline_layout, pipeline) = build_pipeline(
+            device, &pipeline_cache, render_pass.clone(), viewport, scissors.clone(),
+            params_layout.clone(), reversed_depth_buffer)?;
+
+        Ok(StarGfx {
+            pipeline: pipeline,
+            pipeline_layout: pipeline_layout,
+            pipeline_cache: pipeline_cache,
+            render_pass: render_pass,
+            viewport: viewport,
+            scissors: scissors,
+            params_layout: params_layout,
+            mesh: None,
+        })
+    }
+
+    // Switch the depth convention (see Config::reversed_depth_buffer). This
+    // rebuilds the pipeline, so the caller must ensure it is not in use and
+    // re-record command buffers.
+    pub fn set_reversed_depth_buffer(&mut self, device: &Device,
+                                     reversed_depth_buffer: bool)
+        -> Result<(), Error>
+    {
+        let (pipeline_layout, pipeline) = build_pipeline(
+            device, &self.pipeline_cache, self.render_pass.clone(), self.viewport,
+            self.scissors.clone(), self.params_layout.clone(), reversed_depth_buffer)?;
+
+        self.pipeline_layout = pipeline_layout;
+        self.pipeline = pipeline;
+
+        Ok(())
+    }
+
+    pub fn set_mesh(&mut self, mesh: Option<VulkanMesh>) {
+        self.mesh = mesh;
+    }
+
+    pub fn record(&self, command_buffer: CommandBuffer,
+                  params_desc_set: DescriptorSet)
+    {
+        let mesh = match self.mesh {
+            Some(ref mesh) => mesh,
+            None => return,
+        };
+
+        command_buffer.bind_pipeline(PipelineBindPoint::Graphics, &self.pipeline);
+
+        command_buffer.bind_descriptor_sets(
+            PipelineBindPoint::Graphics,
+            &self.pipeline_layout,
+            0, // starting with first set
+            &[params_desc_set],
+            None,
+        );
+
+        // Points need no indices
+        command_buffer.bind_vertex_buffers(0, &[mesh.vertex_buffer.inner()], &[0]);
+        command_buffer.draw(mesh.num_vertices, 1, 0, 0);
+    }
+}
+
+fn build_pipeline(device: &Device,
+                  pipeline_cache: &PipelineCache,
+                  render_pass: RenderPass,
+                  viewport: Viewport,
+                  scissors: Rect2D,
+                  params_layout: DescriptorSetLayout,
+                  reversed_depth_buffer: bool)
+                  -> Result<(PipelineLayout, Pipeline), Error>
+{
+    let vertex_shader = vertex_shader(device)?;
+    let fragment_shader = fragment_shader(device)?;
+
+    super::pipeline::create(
+        device, Some(pipeline_cache), viewport, scissors,
+        reversed_depth_buffer,
+        render_pass, vec![params_layout],
+        Some(vertex_shader), None, Some(fragment_shader), None,
+        Some(StarVertex::get_input_state_create_info()),
+        PrimitiveTopology::PointList,
+        false, // no primitive restart
+        1.0, // line width
+        CullModeFlags::NONE, FrontFace::Clockwise,
+        false, // no depth clamp
+        DepthHandling::Some(true, false, None), // behind everything opaque
+        None, // no depth bias
+        None, // no sample shading
+        vec![BlendMode::Add],
+        ColorComponentFlags::R | ColorComponentFlags::G | ColorComponentFlags::B,
+        vec![])
+}
+
+fn vertex_shader(device: &Device) -> Result<ShaderModule, Error>
+{
+    let bytes: &[u8] = glsl_vs!(r#"
+#version 450
+
+#extension GL_ARB_separate_shader_objects : enable
+#extension GL_ARB_shading_language_420pack : enable
+
+layout (set = 0, binding = 0) uniform UBO
+{
+  mat4 inv_projection;
+  mat4 inv_view;
+  mat4 shadow_view_projection;
+  vec4 dlight_directions[2];
+  vec4 dlight_irradiances[2];
+  float bloom_strength;
+  float bloom_cliff;
+  float blur_level;
+  float ambient;
+  float white_level;
+  int tonemapper;
+  float shadow_bias;
+  float focus_distance;
+  float aperture;
+  float star_brightness;
+  float star_size;
+} params;
+
+layout (location = 0) in vec3 inPos;
+layout (location = 1) in vec3 inXYZ;
+
+layout (location = 0) out vec3 outColor;
+
+out gl_PerVertex
+{
+  vec4 gl_Position;
+  float gl_PointSize;
+};
+
+void main()
+{
+  // inv_view is a rigid transform, so its rotation transposed is the view
+  // rotation. Stars have no position, only a direction (w = 0).
+  vec3 direction = transpose(mat3(params.inv_view)) * normalize(inPos);
+  gl_Position = inverse(params.inv_projection) * vec4(direction, 0.0);
+
+  // Pin stars to the far plane (the viewport handles depth reversal)
+  gl_Position.z = gl_Position.w;
+
+  gl_PointSize = params.star_size;
+
+  // CIE XYZ to linear sRGB (Rec. 709 primaries, D65)
+  const mat3 xyz_to_rgb = mat3(
+     3.2404542, -0.9692660,  0.0556434,
+    -1.5371385,  1.8760108, -0.2040259,
+    -0.4985314,  0.0415560,  1.0572252
+  );
+  outColor = max(xyz_to_rgb * inXYZ, vec3(0.0)) * params.star_brightness;
+}
+"#);
+
+    let create_info = ShaderModuleCreateInfo {
+        flags: ShaderModuleCreateFlags::empty(),
+        code: bytes.to_vec(),
+        chain: None,
+    };
+
+    Ok(device.create_shader_module(&create_info, None)?)
+}
+
+fn fragment_shader(device: &Device) -> Result<ShaderModule, Error>
+{
+    let bytes: &[u8] = glsl_fs!(r#"#version 450
+
+#extension GL_ARB_separate_shader_objects : enable
+#extension GL_ARB_shading_language_420pack : enable
+
+layout (location = 0) in vec3 inColor;
+
+layout (location = 0) out vec4 outFragColor;
+
+void main()
+{
+  // A round sprite that fades towards its edge
+  float r = length(gl_PointCoord - vec2(0.5)) * 2.0;
+  if (r > 1.0) {
+    discard;
+  }
+  float falloff = 1.0 - r * r;
+  outFragColor = vec4(inColor * falloff, 1.0);
+}
+"#);
+
+    let create_info = ShaderModuleCreateInfo {
+        flags: ShaderModuleCreateFlags::empty(),
+        code: bytes.to_vec(),
+        chain: None,
+    };
+
+    Ok(device.create_shader_module(&create_info, None)?)
+}
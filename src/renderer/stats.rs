@@ -1,12 +1,17 @@
 
+use std::collections::HashMap;
 use std::time::{Instant, Duration};
 use dacite::core::QueryResult;
 use crate::renderer::{Timestamp, TS_QUERY_COUNT};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 pub struct Timings {
     pub frame: f32,
     pub cpu: f32,
+    // False if the device cannot do timestamp queries or Config::gpu_timing
+    // is off, in which case the GPU timings below are all zero rather than
+    // measured.
+    pub gpu_available: bool,
     pub render: f32,
     pub geometry: f32,
     pub shading: f32,
@@ -21,6 +26,7 @@ impl Timings {
         Timings {
             frame: 0.0,
             cpu: 0.0,
+            gpu_available: false,
             render: 0.0,
             geometry: 0.0,
             shading: 0.0,
@@ -34,11 +40,21 @@ impl Timings {
 
     pub fn one(
         frame_duration: &Duration,
-        query_results: &[QueryResult; TS_QUERY_COUNT as usize],
+        query_results: Option<&[QueryResult; TS_QUERY_COUNT as usize]>,
         cputime_ms: f32,
         timestamp_period: f32)
         -> Timings
     {
+        let query_results = match query_results {
+            Some(qr) => qr,
+            None => {
+                let mut timings = Timings::new();
+                timings.frame = duration_to_milliseconds(frame_duration);
+                timings.cpu = cputime_ms;
+                return timings;
+            }
+        };
+
         let qr: Vec<u32> = query_results.iter().map(|r| {
             match r {
                 &QueryResult::U32(u) => u,
@@ -54,6 +70,7 @@ impl Timings {
         Timings {
             frame: duration_to_milliseconds(frame_duration),
             cpu: cputime_ms,
+            gpu_available: true,
             render: to_ms(Timestamp::FullStart, Timestamp::FullEnd),
             geometry: to_ms(Timestamp::GeometryStart, Timestamp::GeometryEnd),
             shading: to_ms(Timestamp::ShadingStart, Timestamp::ShadingEnd),
@@ -68,6 +85,7 @@ impl Timings {
     pub fn accumulate(&mut self, other: &Timings) {
         self.frame += other.frame;
         self.cpu += other.cpu;
+        self.gpu_available = other.gpu_available;
         self.render += other.render;
         self.geometry += other.geometry;
         self.shading += other.shading;
@@ -77,14 +95,72 @@ impl Timings {
         self.post += other.post;
         self.ui += other.ui;
     }
+
+    pub fn scale(&mut self, factor: f32) {
+        self.frame *= factor;
+        self.cpu *= factor;
+        self.render *= factor;
+        self.geometry *= factor;
+        self.shading *= factor;
+        self.transparent *= factor;
+        self.blur1 *= factor;
+        self.blur2 *= factor;
+        self.post *= factor;
+        self.ui *= factor;
+    }
+
+    // Move each timing towards `other` by the fraction `amount`
+    pub fn blend(&mut self, other: &Timings, amount: f32) {
+        self.scale(1.0 - amount);
+        let mut other = *other;
+        other.scale(amount);
+        self.accumulate(&other);
+    }
+}
+
+// How Stats::smoothed is computed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmoothingMode {
+    // The per-frame average over each 60 frame window, replaced wholesale
+    // every 60 frames
+    Windowed,
+    // An exponential moving average updated every frame. Each frame the
+    // previous average is weighted by `decay` (0.0 to 1.0, higher is smoother)
+    // and the new frame by `1.0 - decay`.
+    ExponentialMovingAverage { decay: f32 },
+}
+
+impl Default for SmoothingMode {
+    fn default() -> SmoothingMode {
+        SmoothingMode::Windowed
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Stats {
     pub last_updated: Instant,
 
+    // Index of the frame currently being prepared (starts at 0)
+    pub frame_index: u64,
+    // Time since the render loop started, as of the start of this frame
+    pub elapsed: Duration,
+
+    // The most recent single frame (not averaged)
+    pub latest: Timings,
+    // Per-frame timings smoothed according to `smoothing`
+    pub smoothed: Timings,
+    pub smoothing: SmoothingMode,
+    // Sums (not averages) over the last complete 60 and 600 frame windows
     pub timings_60: Timings,
     pub timings_600: Timings,
+    // Milliseconds from the start of the most recent frame to each plugin
+    // timestamp, by label (see Renderer::plugin_timestamps)
+    pub plugin_timestamps: HashMap<String, f32>,
+
+    // The windows in progress
+    window_60: Timings,
+    window_600: Timings,
+    window_frames: u64,
 }
 
 impl Default for Stats {
@@ -92,16 +168,99 @@ impl Default for Stats {
         Stats {
             last_updated: Instant::now(),
 
+            frame_index: 0,
+            elapsed: Duration::new(0, 0),
+
+            latest: Timings::new(),
+            smoothed: Timings::new(),
+            smoothing: Default::default(),
             timings_60: Timings::new(),
             timings_600: Timings::new(),
+            plugin_timestamps: HashMap::new(),
+
+            window_60: Timings::new(),
+            window_600: Timings::new(),
+            window_frames: 0,
         }
     }
 }
 
 impl Stats {
+    pub fn update_frame(&mut self, frame_index: u64, elapsed: Duration)
+    {
+        self.frame_index = frame_index;
+        self.elapsed = elapsed;
+    }
+
+    pub fn elapsed_seconds(&self) -> f32
+    {
+        duration_to_milliseconds(&self.elapsed) * 0.001
+    }
+
+    pub fn set_smoothing(&mut self, smoothing: SmoothingMode)
+    {
+        self.smoothing = match smoothing {
+            SmoothingMode::ExponentialMovingAverage { decay } =>
+                SmoothingMode::ExponentialMovingAverage {
+                    decay: decay.max(0.0).min(1.0)
+                },
+            other => other,
+        };
+    }
+
+    // Discard all timings, including the windows in progress, so that
+    // averages start afresh.  The frame index, elapsed time and smoothing
+    // mode are kept.
+    pub fn reset(&mut self)
+    {
+        *self = Stats {
+            frame_index: self.frame_index,
+            elapsed: self.elapsed,
+            smoothing: self.smoothing,
+            ..Default::default()
+        };
+    }
+
+    // Record a frame's timings, completing the 60 and 600 frame windows
+    // as they fill
+    pub fn update(&mut self, timings: Timings)
+    {
+        self.update_1(timings);
+        self.window_60.accumulate(&timings);
+        self.window_600.accumulate(&timings);
+        self.window_frames += 1;
+
+        if self.window_frames % 600 == 0 {
+            let pass = ::std::mem::replace(&mut self.window_600, Timings::new());
+            self.update_600(pass);
+        }
+        if self.window_frames % 60 == 0 {
+            let pass = ::std::mem::replace(&mut self.window_60, Timings::new());
+            self.update_60(pass);
+        }
+    }
+
+    pub fn update_1(&mut self, timings: Timings)
+    {
+        self.latest = timings;
+        if let SmoothingMode::ExponentialMovingAverage { decay } = self.smoothing {
+            if self.smoothed.frame == 0.0 {
+                // Nothing to average with yet
+                self.smoothed = timings;
+            } else {
+                self.smoothed.blend(&timings, 1.0 - decay);
+            }
+            self.last_updated = Instant::now();
+        }
+    }
+
     pub fn update_60(&mut self, timings: Timings)
     {
         self.timings_60 = timings;
+        if self.smoothing == SmoothingMode::Windowed {
+            self.smoothed = timings;
+            self.smoothed.scale(1.0 / 60.0);
+        }
         self.last_updated = Instant::now();
     }
 
@@ -12,6 +12,8 @@ pub struct SurfaceData {
     pub surface_formats: Vec<SurfaceFormatKhr>,
     pub min_image_count: u32,
     pub present_mode: PresentModeKhr,
+    // every present mode the surface supports
+    pub present_modes: Vec<PresentModeKhr>,
     pub needs_gamma: bool,
     // TODO: SurfaceTransformFlagsKhr
     // TODO: ImageUsageFlags
@@ -37,10 +39,8 @@ impl SurfaceData {
                 None => cmp::max(capabilities.min_image_count, 3),
             }
         };
-        let present_mode = {
-            let present_modes = physical_device.get_surface_present_modes_khr(surface)?;
-            get_present_mode(&present_modes)
-        };
+        let present_modes = physical_device.get_surface_present_modes_khr(surface)?;
+        let present_mode = get_present_mode(&present_modes);
 
         // Choose the best surface format available
         let ranking = |f: Format| -> u32 {
@@ -89,6 +89,7 @@ impl SurfaceData {
             surface_formats: surface_formats,
             min_image_count: min_image_count,
             present_mode: present_mode,
+            present_modes: present_modes,
             needs_gamma: needs_gamma,
         })
     }
@@ -98,6 +99,7 @@ impl SurfaceData {
                   surface: &SurfaceKhr) -> Result<(), Error>
     {
         self.capabilities = physical_device.get_surface_capabilities_khr(surface)?;
+        self.present_modes = physical_device.get_surface_present_modes_khr(surface)?;
         Ok(())
     }
 
@@ -1,4 +1,5 @@
 
+use std::sync::{Arc, Mutex};
 use dacite::core::{PhysicalDevice, Device, Extent2D, Format, SharingMode};
 use dacite::khr_surface::{SurfaceKhr, ColorSpaceKhr};
 use dacite::khr_swapchain::SwapchainKhr;
@@ -78,17 +79,15 @@ impl SwapchainData {
     pub fn rebuild(&mut self,
                    physical_device: &PhysicalDevice,
                    device: &Device,
-                   surface: &SurfaceKhr)
+                   surface: &SurfaceKhr,
+                   preferred_extent: Extent2D)
                    -> Result<(), Error>
     {
         // Update surface data
         self.surface_data.update(physical_device, surface)?;
 
-        self.extent = self.surface_data.capabilities.current_extent
-            .unwrap_or(Extent2D { // the 'or' shouldn't ever actually happen
-                width: 1280,
-                height: 1024,
-            });
+        // Most surfaces dictate their extent; some let us choose
+        self.extent = self.surface_data.get_surface_extent(preferred_extent);
 
         // Rebuild swapchain
         self.swapchain = {
@@ -144,7 +143,7 @@ fn build_images(
     -> Result<Vec<ImageWrap>, Error>
 {
     use dacite::core::{ComponentMapping, ImageUsageFlags, ImageTiling,
-                       Extent3D};
+                       Extent3D, ImageLayout};
 
     let images = {
         let mut images = swapchain.get_images_khr()?;
@@ -166,6 +165,8 @@ fn build_images(
                 block: None,
                 solo: None,
                 swizzle: ComponentMapping::identity(),
+                view_cache: Arc::new(Mutex::new(None)),
+                tracked_layout: Arc::new(Mutex::new(Some(ImageLayout::Undefined))),
             }
         }).collect();
 
@@ -0,0 +1,524 @@
+
+use dacite::core::{Device, DescriptorPool, DescriptorSet, DescriptorSetLayout,
+                   DescriptorSetLayoutBinding, ImageView, ImageLayout, Sampler,
+                   DescriptorType, CommandBuffer, RenderPass, Viewport, Rect2D,
+                   PipelineBindPoint, Pipeline, PipelineLayout, PrimitiveTopology,
+                   CullModeFlags, FrontFace, ShaderModuleCreateFlags,
+                   ShaderModuleCreateInfo, ShaderModule, ColorComponentFlags,
+                   SpecializationInfo, SpecializationMapEntry, PipelineCache};
+use crate::error::Error;
+use super::target_data::TargetData;
+use super::descriptor_set_writer::DescriptorSetWriter;
+use super::{DepthHandling, BlendMode};
+
+// How much of the reprojected history is kept each frame.  Higher values
+// smooth more (and converge on more jitter positions), but ghost more.
+pub const TAA_FEEDBACK: f32 = 0.9;
+
+// The number of jitter positions cycled through (a Halton(2,3) sequence)
+pub const TAA_JITTER_PHASES: u32 = 8;
+
+// The projection jitter for frame `phase` of the sequence, as an offset in
+// normalized device coordinates for a target of `width` x `height` pixels
+// (within half a pixel of the pixel center in each direction).
+pub fn taa_jitter(phase: u32, width: u32, height: u32) -> [f32; 2]
+{
+    let halton = |mut index: u32, base: u32| {
+        let mut f = 1.0;
+        let mut r = 0.0;
+        while index > 0 {
+            f /= base as f32;
+            r += f * (index % base) as f32;
+            index /= base;
+        }
+        r
+    };
+
+    // (index 0 of the sequence is 0, which isn't centered, so skip it)
+    let index = phase % TAA_JITTER_PHASES + 1;
+    [(halton(index, 2) - 0.5) * 2.0 / width as f32,
+     (halton(index, 3) - 0.5) * 2.0 / height as f32]
+}
+
+pub struct TaaGfx {
+    pipeline: Pipeline,
+    pipeline_layout: PipelineLayout,
+    pipeline_cache: PipelineCache,
+    descriptor_set: DescriptorSet,
+    desc_layout: DescriptorSetLayout,
+    params_layout: DescriptorSetLayout,
+    render_pass: RenderPass,
+    viewport: Viewport,
+    scissors: Rect2D,
+    shading_image_view: ImageView,
+    history_image_view: ImageView,
+    depth_image_view: ImageView,
+    velocity_image_view: ImageView,
+    sampler: Sampler,
+    nearest_sampler: Sampler,
+}
+
+impl TaaGfx {
+    pub fn new(device: &Device,
+               pipeline_cache: PipelineCache,
+               descriptor_pool: DescriptorPool,
+               target_data: &TargetData,
+               render_pass: RenderPass,
+               viewport: Viewport,
+               scissors: Rect2D,
+               params_layout: DescriptorSetLayout,
+               reversed_depth_buffer: bool)
+               -> Result<TaaGfx, Error>
+    {
+        // Linear filtering, as the history is sampled between texels
+        let sampler = {
+            use dacite::core::{SamplerCreateInfo, SamplerMipmapMode, SamplerAddressMode,
+                               BorderColor, Filter, CompareOp};
+
+            device.create_sampler(&SamplerCreateInfo {
+                flags: Default::default(),
+                mag_filter: Filter::Linear,
+                min_filter: Filter::Linear,
+                mipmap_mode: SamplerMipmapMode::Nearest,
+                address_mode_u: SamplerAddressMode::ClampToEdge,
+                address_mode_v: SamplerAddressMode::ClampToEdge,
+                address_mode_w: SamplerAddressMode::ClampToEdge,
+                mip_lod_bias: 0.0,
+                anisotropy_enable: false,
+                max_anisotropy: 1.0,
+                compare_enable: false,
+                compare_op: CompareOp::Never,
+                min_lod: 0.0,
+                max_lod: 0.0,
+                border_color: BorderColor::FloatOpaqueBlack,
+                unnormalized_coordinates: false,
+                chain: None
+            }, None)?
+        };
+
+        // Depth (and velocity, which depth stands in for when there is no
+        // velocity target) is read per pixel, and depth formats need not
+        // support linear filtering, so these are not filtered.
+        let nearest_sampler = {
+            use dacite::core::{SamplerCreateInfo, SamplerMipmapMode, SamplerAddressMode,
+                               BorderColor, Filter, CompareOp};
+
+            device.create_sampler(&SamplerCreateInfo {
+                flags: Default::default(),
+                mag_filter: Filter::Nearest,
+                min_filter: Filter::Nearest,
+                mipmap_mode: SamplerMipmapMode::Nearest,
+                address_mode_u: SamplerAddressMode::ClampToEdge,
+                address_mode_v: SamplerAddressMode::ClampToEdge,
+                address_mode_w: SamplerAddressMode::ClampToEdge,
+                mip_lod_bias: 0.0,
+                anisotropy_enable: false,
+                max_anisotropy: 1.0,
+                compare_enable: false,
+                compare_op: CompareOp::Never,
+                min_lod: 0.0,
+                max_lod: 0.0,
+                border_color: BorderColor::FloatOpaqueBlack,
+                unnormalized_coordinates: false,
+                chain: None
+            }, None)?
+        };
+
+        let (shading_image_view, history_image_view, depth_image_view,
+             velocity_image_view) = image_views(device, target_data)?;
+
+        let desc_bindings = {
+            use dacite::core::ShaderStageFlags;
+            vec![
+                DescriptorSetLayoutBinding { // current frame (shading image)
+                    binding: 0,
+                    descriptor_type: DescriptorType::CombinedImageSampler,
+                    descriptor_count: 1,
+                    stage_flags: ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: vec![],
+                },
+                DescriptorSetLayoutBinding { // history
+                    binding: 1,
+                    descriptor_type: DescriptorType::CombinedImageSampler,
+                    descriptor_count: 1,
+                    stage_flags: ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: vec![],
+                },
+                DescriptorSetLayoutBinding { // depth, for reprojection
+                    binding: 2,
+                    descriptor_type: DescriptorType::CombinedImageSampler,
+                    descriptor_count: 1,
+                    stage_flags: ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: vec![],
+                },
+                DescriptorSetLayoutBinding { // velocity (if any)
+                    binding: 3,
+                    descriptor_type: DescriptorType::CombinedImageSampler,
+                    descriptor_count: 1,
+                    stage_flags: ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: vec![],
+                },
+            ]
+        };
+
+        let desc_layout = {
+            use dacite::core::DescriptorSetLayoutCreateInfo;
+
+            let create_info = DescriptorSetLayoutCreateInfo {
+                flags: Default::default(),
+                bindings: desc_bindings.clone(),
+                chain: None,
+            };
+            device.create_descriptor_set_layout(&create_info, None)?
+        };
+
+        let descriptor_set = {
+            use dacite::core::DescriptorSetAllocateInfo;
+
+            let alloc_info = DescriptorSetAllocateInfo {
+                descriptor_pool: descriptor_pool.clone(),
+                set_layouts: vec![desc_layout.clone()],
+                chain: None,
+            };
+
+            let mut descriptor_sets = DescriptorPool::allocate_descriptor_sets(&alloc_info)?;
+            descriptor_sets.pop().unwrap()
+        };
+
+        let (pipeline_layout, pipeline) = build_pipeline(
+            device, &pipeline_cache, render_pass.clone(), viewport, scissors.clone(),
+            desc_layout.clone(), params_layout.clone(), reversed_depth_buffer)?;
+
+        let mut taa_gfx = TaaGfx {
+            pipeline: pipeline,
+            pipeline_layout: pipeline_layout,
+            pipeline_cache: pipeline_cache,
+            descriptor_set: descriptor_set,
+            desc_layout: desc_layout,
+            params_layout: params_layout,
+            render_pass: render_pass,
+            viewport: viewport,
+            scissors: scissors,
+            shading_image_view: shading_image_view,
+            history_image_view: history_image_view,
+            depth_image_view: depth_image_view,
+            velocity_image_view: velocity_image_view,
+            sampler: sampler,
+            nearest_sampler: nearest_sampler,
+        };
+
+        taa_gfx.write();
+
+        Ok(taa_gfx)
+    }
+
+    pub fn rebuild(&mut self, device: &Device, target_data: &TargetData)
+        -> Result<(), Error>
+    {
+        let (shading_image_view, history_image_view, depth_image_view,
+             velocity_image_view) = image_views(device, target_data)?;
+        self.shading_image_view = shading_image_view;
+        self.history_image_view = history_image_view;
+        self.depth_image_view = depth_image_view;
+        self.velocity_image_view = velocity_image_view;
+
+        self.write();
+
+        Ok(())
+    }
+
+    // Switch the depth convention (see Config::reversed_depth_buffer). This
+    // rebuilds the pipeline, so the caller must ensure it is not in use and
+    // re-record command buffers.
+    pub fn set_reversed_depth_buffer(&mut self, device: &Device,
+                                     reversed_depth_buffer: bool)
+        -> Result<(), Error>
+    {
+        let (pipeline_layout, pipeline) = build_pipeline(
+            device, &self.pipeline_cache, self.render_pass.clone(), self.viewport,
+            self.scissors.clone(), self.desc_layout.clone(), self.params_layout.clone(),
+            reversed_depth_buffer)?;
+
+        self.pipeline_layout = pipeline_layout;
+        self.pipeline = pipeline;
+
+        Ok(())
+    }
+
+    fn write(&mut self)
+    {
+        let rol = ImageLayout::ShaderReadOnlyOptimal;
+
+        DescriptorSetWriter::new(self.descriptor_set.clone())
+            .image(0, self.sampler.clone(), self.shading_image_view.clone(), rol)
+            .image(1, self.sampler.clone(), self.history_image_view.clone(), rol)
+            .image(2, self.nearest_sampler.clone(), self.depth_image_view.clone(), rol)
+            .image(3, self.nearest_sampler.clone(), self.velocity_image_view.clone(), rol)
+            .flush();
+    }
+
+    pub fn record(&self, command_buffer: CommandBuffer,
+                  params_desc_set: DescriptorSet)
+    {
+        // Bind our pipeline
+        command_buffer.bind_pipeline(PipelineBindPoint::Graphics, &self.pipeline);
+
+        command_buffer.bind_descriptor_sets(
+            PipelineBindPoint::Graphics,
+            &self.pipeline_layout,
+            0, // starting with first set
+            &[self.descriptor_set.clone(),
+              params_desc_set],
+            None,
+        );
+
+        command_buffer.draw(3, 1, 0, 0);
+    }
+}
+
+fn build_pipeline(device: &Device,
+                  pipeline_cache: &PipelineCache,
+                  render_pass: RenderPass,
+                  viewport: Viewport,
+                  scissors: Rect2D,
+                  desc_layout: DescriptorSetLayout,
+                  params_layout: DescriptorSetLayout,
+                  reversed_depth_buffer: bool)
+                  -> Result<(PipelineLayout, Pipeline), Error>
+{
+    let vertex_shader = vertex_shader(device)?;
+    let fragment_shader = fragment_shader(device)?;
+    let fragment_spec = SpecializationInfo {
+        map_entries: vec![
+            SpecializationMapEntry { // reversed depth buffer
+                constant_id: 0,
+                offset: 0,
+                size: ::std::mem::size_of::<u32>(),
+            },
+        ],
+        data: (reversed_depth_buffer as u32).to_ne_bytes().to_vec(),
+    };
+
+    super::pipeline::create(
+        device, Some(pipeline_cache), viewport, scissors,
+        true, // reversed depth buffer irrelevant (no depth attachment)
+        render_pass, vec![
+            desc_layout,
+            params_layout,
+        ],
+        Some(vertex_shader), None, Some(fragment_shader), Some(fragment_spec),
+        None,
+        PrimitiveTopology::TriangleList,
+        false, // no primitive restart
+        1.0, // line width
+        CullModeFlags::NONE, FrontFace::Clockwise,
+        false, // no depth clamp
+        DepthHandling::None,
+        None, // no depth bias
+        None, // no sample shading
+        vec![BlendMode::Off],
+        ColorComponentFlags::R | ColorComponentFlags::G | ColorComponentFlags::B
+            | ColorComponentFlags::A,
+        vec![])
+}
+
+fn image_views(device: &Device, target_data: &TargetData)
+               -> Result<(ImageView, ImageView, ImageView, ImageView), Error>
+{
+    let history_image = match target_data.history_image {
+        Some(ref image) => image,
+        None => return Err(Error::General(
+            "TAA requires the history target".to_owned())),
+    };
+
+    let depth_image_view = target_data.depth_image.get_image_view(device)?;
+
+    // Without a velocity target, the depth image stands in so the binding
+    // is valid (the shader doesn't read it, see Params::motion_vectors)
+    let velocity_image_view = match target_data.velocity_image {
+        Some(ref image) => image.get_image_view(device)?,
+        None => depth_image_view.clone(),
+    };
+
+    Ok((target_data.shading_image.get_image_view(device)?,
+        history_image.get_image_view(device)?,
+        depth_image_view,
+        velocity_image_view))
+}
+
+fn vertex_shader(device: &Device) -> Result<ShaderModule, Error>
+{
+    let bytes: &[u8] = glsl_vs!(r#"
+#version 450
+
+#extension GL_ARB_separate_shader_objects : enable
+#extension GL_ARB_shading_language_420pack : enable
+
+layout (set = 1, binding = 0) uniform ParamsUBO {
+  mat4 inv_projection;
+  mat4 inv_view;
+  mat4 shadow_view_projection;
+  vec4 dlight_directions[2];
+  vec4 dlight_irradiances[2];
+  float bloom_strength;
+  float bloom_cliff;
+  float blur_level;
+  float ambient;
+  float white_level;
+  int tonemapper;
+  float shadow_bias;
+  float focus_distance;
+  float aperture;
+  float star_brightness;
+  float star_size;
+  float near_plane;
+  float far_plane;
+  float depth_linearize_scale;
+  float depth_linearize_offset;
+  float exposure;
+  mat4 prev_inv_projection;
+  mat4 prev_inv_view;
+  vec2 taa_jitter;
+  float taa_feedback;
+} params;
+
+layout (location = 0) out vec2 outUV;
+// From this frame's clip space to the previous frame's
+layout (location = 1) flat out mat4 outReproject;
+
+out gl_PerVertex
+{
+  vec4 gl_Position;
+};
+
+void main()
+{
+  outUV = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+  gl_Position = vec4(outUV * 2.0f - 1.0f, 0.0f, 1.0f);
+
+  // The same for every pixel, so worked out here rather than per fragment
+  outReproject = inverse(params.prev_inv_projection) * inverse(params.prev_inv_view)
+    * params.inv_view * params.inv_projection;
+}
+"#);
+
+    let create_info = ShaderModuleCreateInfo {
+        flags: ShaderModuleCreateFlags::empty(),
+        code: bytes.to_vec(),
+        chain: None,
+    };
+
+    Ok(device.create_shader_module(&create_info, None)?)
+}
+
+fn fragment_shader(device: &Device) -> Result<ShaderModule, Error>
+{
+    let bytes: &[u8] = glsl_fs!(r#"
+#version 450
+
+#extension GL_ARB_separate_shader_objects : enable
+#extension GL_ARB_shading_language_420pack : enable
+
+layout(constant_id = 0) const int reversed_depth = 0;
+
+layout (set = 0, binding = 0) uniform sampler2D currentTex;
+layout (set = 0, binding = 1) uniform sampler2D historyTex;
+layout (set = 0, binding = 2) uniform sampler2D depthTex;
+layout (set = 0, binding = 3) uniform sampler2D velocityTex;
+
+layout (set = 1, binding = 0) uniform ParamsUBO {
+  mat4 inv_projection;
+  mat4 inv_view;
+  mat4 shadow_view_projection;
+  vec4 dlight_directions[2];
+  vec4 dlight_irradiances[2];
+  float bloom_strength;
+  float bloom_cliff;
+  float blur_level;
+  float ambient;
+  float white_level;
+  int tonemapper;
+  float shadow_bias;
+  float focus_distance;
+  float aperture;
+  float star_brightness;
+  float star_size;
+  float near_plane;
+  float far_plane;
+  float depth_linearize_scale;
+  float depth_linearize_offset;
+  float exposure;
+  mat4 prev_inv_projection;
+  mat4 prev_inv_view;
+  vec2 taa_jitter;
+  float taa_feedback;
+  uint motion_vectors;
+} params;
+
+layout (location = 0) in vec2 inUV;
+layout (location = 1) flat in mat4 inReproject;
+
+layout (location = 0) out vec4 outColor;
+
+void main()
+{
+  vec3 current = texture(currentTex, inUV).rgb;
+
+  // No history yet (the first frame, or just after a rebuild)
+  if (params.taa_feedback <= 0.0) {
+    outColor = vec4(current, 1.0);
+    return;
+  }
+
+  // The history is clamped to the range of the current 3x3 neighbourhood,
+  // which rejects history that no longer matches (disocclusion, lighting
+  // changes) instead of ghosting
+  vec2 texel = 1.0 / vec2(textureSize(currentTex, 0));
+  vec3 nmin = current;
+  vec3 nmax = current;
+  for (int y = -1; y <= 1; y++) {
+    for (int x = -1; x <= 1; x++) {
+      vec3 c = texture(currentTex, inUV + vec2(x, y) * texel).rgb;
+      nmin = min(nmin, c);
+      nmax = max(nmax, c);
+    }
+  }
+
+  // Reproject this pixel's surface into the previous frame.  Projections
+  // are never reversed (the viewport does that), so neither is this depth.
+  // The surface was drawn offset by the jitter, so take that back out.
+  float depth = texture(depthTex, inUV).r;
+  if (reversed_depth == 1) {
+    depth = 1.0 - depth;
+  }
+  vec4 clip = vec4(inUV * 2.0 - 1.0 - params.taa_jitter, depth, 1.0);
+  vec4 prev = inReproject * clip;
+  vec2 prevUV = (prev.xy / prev.w) * 0.5 + 0.5;
+
+  // Geometry that wrote its own motion (which includes the camera's) follows
+  // that instead.  The background was not drawn, so only depth covers it.
+  if (params.motion_vectors != 0 && depth < 1.0) {
+    prevUV = inUV - texture(velocityTex, inUV).rg;
+    prev.w = 1.0;
+  }
+
+  if (prev.w <= 0.0
+      || any(lessThan(prevUV, vec2(0.0)))
+      || any(greaterThan(prevUV, vec2(1.0)))) {
+    outColor = vec4(current, 1.0);
+    return;
+  }
+
+  vec3 history = clamp(texture(historyTex, prevUV).rgb, nmin, nmax);
+  outColor = vec4(mix(current, history, params.taa_feedback), 1.0);
+}
+"#);
+
+    let create_info = ShaderModuleCreateInfo {
+        flags: ShaderModuleCreateFlags::empty(),
+        code: bytes.to_vec(),
+        chain: None,
+    };
+
+    Ok(device.create_shader_module(&create_info, None)?)
+}
@@ -1,5 +1,5 @@
 
-use dacite::core::{Device, Extent2D, CommandBuffer, ImageLayout, AccessFlags,
+use dacite::core::{Device, Extent2D, Format, CommandBuffer, ImageLayout, AccessFlags,
                    PipelineStageFlags, ImageAspectFlags, OptionalMipLevels,
                    OptionalArrayLayers, ImageSubresourceRange, ImageMemoryBarrier,
                    QueueFamilyIndex, DependencyFlags};
@@ -8,23 +8,50 @@ use super::image_wrap::{ImageWrap, ImageWrapType};
 use super::memory::{Memory, Lifetime};
 use super::commander::Commander;
 use super::setup::requirements::{DEPTH_FORMAT,
-                                 DIFFUSE_FORMAT,
-                                 NORMALS_FORMAT,
-                                 MATERIAL_FORMAT,
-                                 SHADING_FORMAT,
-                                 BLUR_FORMAT};
+                                 LINEAR_DEPTH_FORMAT,
+                                 VELOCITY_FORMAT,
+                                 ENTITY_ID_FORMAT};
+use crate::config::Config;
+use super::{GBufferLoadOp, GeometryLoadOps};
 
 /*
 Depth:			D32_SFloat
-Diffuse:		A2B10G10R10_UNorm_Pack32
-Normal:			A2B10G10R10_UNorm_Pack32  in view space (eye space)
-Material:		R8G8B8A8_UNorm
+Diffuse:		A2B10G10R10_UNorm_Pack32 (configurable)
+Normal:			A2B10G10R10_UNorm_Pack32 (configurable) in view space (eye space)
+  normals are always encoded as n * 0.5 + 0.5, whatever the format, so
+  plugins write them the same way with a float format (which simply gains
+  precision)
+Material:		R8G8B8A8_UNorm (configurable)
   r-channel is used for "roughness"
   g-channel is used for "metallicity"
   b-channel is used for "ambient occlusion"
   a-channel is used for "cavity"
-Shading:                R16G16B16A16_SFloat (goes overbright)
-Blur:                   R16G16B16A16_SFloat (goes overbright)
+  by default; Config::material_packing rearranges them
+Velocity:               R16G16_SFloat (optional, Config::motion_vectors)
+  this pixel's screen motion since the previous frame, in texture coordinates
+  (current uv - previous uv), written by geometry plugins and cleared to zero.
+  Left in ShaderReadOnlyOptimal after the geometry pass.
+Entity ID:              R32_UInt (optional, Config::entity_id_target)
+  the ID of the object drawn at this pixel, written by geometry plugins and
+  cleared to zero (nothing).  Left in ColorAttachmentOptimal, for
+  Renderer::pick to read back.
+Shading:                R16G16B16A16_SFloat (configurable, goes overbright)
+Linear depth:           R32_SFloat (optional, Config::linear_depth_target)
+  positive view-space distance along the view axis, written by the shading
+  pass (from the depth buffer) and left in ShaderReadOnlyOptimal for the
+  transparent pass onwards.  Background pixels hold the far plane distance,
+  which is infinite with an infinite projection.
+Blur:                   same as Shading (goes overbright)
+  with Config::bloom_use_compute, the compute blur uses the shading and blur
+  images as storage images in General layout
+TAA, TAA history:       same as Shading (optional, Config::taa_enabled)
+  the TAA pass resolves the shading image and the history into the TAA
+  image, which is then copied over both of them.  The history is left in
+  ShaderReadOnlyOptimal between frames.
+
+All of the above are at the render extent, which may be smaller than the
+swapchain extent (see Renderer::set_resolution_scale).  The ui depth buffer
+is at the swapchain extent since the ui pass renders at full resolution.
  */
 
 const STD_COLOR_SUBRESOURCE_RANGE: ImageSubresourceRange = ImageSubresourceRange {
@@ -42,6 +69,27 @@ const STD_DEPTH_SUBRESOURCE_RANGE: ImageSubresourceRange = ImageSubresourceRange
     layer_count: OptionalArrayLayers::ArrayLayers(1),
 };
 
+// The geometry pass and shading color attachment formats, chosen in the
+// Config
+#[derive(Debug, Clone, Copy)]
+pub struct GBufferFormats {
+    pub diffuse: Format,
+    pub normals: Format,
+    pub material: Format,
+    pub shading: Format,
+}
+
+impl GBufferFormats {
+    pub fn from_config(config: &Config) -> GBufferFormats {
+        GBufferFormats {
+            diffuse: config.diffuse_format.format(),
+            normals: config.normals_format.format(),
+            material: config.material_format.format(),
+            shading: config.shading_format.format(),
+        }
+    }
+}
+
 pub struct TargetData {
     pub blur_image: ImageWrap,
     pub shading_image: ImageWrap,
@@ -49,6 +97,14 @@ pub struct TargetData {
     pub normals_image: ImageWrap,
     pub diffuse_image: ImageWrap,
     pub depth_image: ImageWrap,
+    pub ui_depth_image: ImageWrap,
+    pub linear_depth_image: Option<ImageWrap>,
+    pub taa_image: Option<ImageWrap>,
+    pub history_image: Option<ImageWrap>,
+    pub velocity_image: Option<ImageWrap>,
+    pub entity_id_image: Option<ImageWrap>,
+    pub formats: GBufferFormats,
+    pub compute_blur: bool,
     pub extent: Extent2D
 }
 
@@ -56,12 +112,22 @@ impl TargetData {
     pub fn create(device: &Device,
                   memory: &mut Memory,
                   commander: &Commander,
-                  extent: Extent2D)
+                  formats: GBufferFormats,
+                  linear_depth: bool,
+                  compute_blur: bool,
+                  taa: bool,
+                  motion_vectors: bool,
+                  entity_id: bool,
+                  extent: Extent2D,
+                  ui_extent: Extent2D)
                   -> Result<TargetData, Error>
     {
         let (depth_image, diffuse_image, normals_image, material_image,
-             shading_image, blur_image) =
-            build_images(device, memory, commander, extent)?;
+             shading_image, blur_image, ui_depth_image, linear_depth_image,
+             taa_image, history_image, velocity_image, entity_id_image) =
+            build_images(device, memory, commander, formats, linear_depth,
+                         compute_blur, taa, motion_vectors, entity_id,
+                         extent, ui_extent)?;
 
         Ok(TargetData {
             blur_image: blur_image,
@@ -70,6 +136,14 @@ impl TargetData {
             normals_image: normals_image,
             diffuse_image: diffuse_image,
             depth_image: depth_image,
+            ui_depth_image: ui_depth_image,
+            linear_depth_image: linear_depth_image,
+            taa_image: taa_image,
+            history_image: history_image,
+            velocity_image: velocity_image,
+            entity_id_image: entity_id_image,
+            formats: formats,
+            compute_blur: compute_blur,
             extent: extent
         })
     }
@@ -78,71 +152,100 @@ impl TargetData {
                    device: &Device,
                    memory: &mut Memory,
                    commander: &Commander,
-                   extent: Extent2D)
+                   extent: Extent2D,
+                   ui_extent: Extent2D)
                    -> Result<(), Error>
     {
         self.extent = extent;
 
         // Rebuild images
         let (depth_image, diffuse_image, normals_image, material_image,
-             shading_image, blur_image) =
-            build_images(device, memory, commander, extent)?;
+             shading_image, blur_image, ui_depth_image, linear_depth_image,
+             taa_image, history_image, velocity_image, entity_id_image) =
+            build_images(device, memory, commander, self.formats,
+                         self.linear_depth_image.is_some(), self.compute_blur,
+                         self.taa_image.is_some(), self.velocity_image.is_some(),
+                         self.entity_id_image.is_some(), extent, ui_extent)?;
         self.depth_image = depth_image;
         self.diffuse_image = diffuse_image;
         self.normals_image = normals_image;
         self.material_image = material_image;
         self.shading_image = shading_image;
         self.blur_image = blur_image;
+        self.ui_depth_image = ui_depth_image;
+        self.linear_depth_image = linear_depth_image;
+        self.taa_image = taa_image;
+        self.history_image = history_image;
+        self.velocity_image = velocity_image;
+        self.entity_id_image = entity_id_image;
 
         Ok(())
     }
 
-    pub fn transition_for_geometry(&mut self, command_buffer: CommandBuffer)
+    pub fn transition_for_geometry(&mut self, command_buffer: CommandBuffer,
+                                   load_ops: GeometryLoadOps)
                                    -> Result<(), Error>
     {
         // write and read depth: depth never needs transition
 
-        // write diffuse, normal, and material
-        let diffuse_barrier = ImageMemoryBarrier {
-            src_access_mask: Default::default(),
-            dst_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
-            old_layout: ImageLayout::Undefined,
-            new_layout: ImageLayout::ColorAttachmentOptimal,
-            src_queue_family_index: QueueFamilyIndex::Ignored,
-            dst_queue_family_index: QueueFamilyIndex::Ignored,
-            image: self.diffuse_image.image.clone(),
-            subresource_range: STD_COLOR_SUBRESOURCE_RANGE,
-            chain: None
+        // write diffuse, normal, and material.  Those that are loaded come
+        // from the shading pass reads of the previous frame; the rest are
+        // cleared or overwritten, so their contents can be discarded.
+        let barrier = |image: &ImageWrap, load_op: GBufferLoadOp| {
+            let (src_access_mask, dst_access_mask, old_layout) = match load_op {
+                GBufferLoadOp::Load => (
+                    AccessFlags::SHADER_READ,
+                    AccessFlags::COLOR_ATTACHMENT_READ | AccessFlags::COLOR_ATTACHMENT_WRITE,
+                    ImageLayout::ShaderReadOnlyOptimal),
+                _ => (
+                    Default::default(),
+                    AccessFlags::COLOR_ATTACHMENT_WRITE,
+                    ImageLayout::Undefined),
+            };
+            image.track_layout(old_layout, ImageLayout::ColorAttachmentOptimal);
+            ImageMemoryBarrier {
+                src_access_mask: src_access_mask,
+                dst_access_mask: dst_access_mask,
+                old_layout: old_layout,
+                new_layout: ImageLayout::ColorAttachmentOptimal,
+                src_queue_family_index: QueueFamilyIndex::Ignored,
+                dst_queue_family_index: QueueFamilyIndex::Ignored,
+                image: image.image.clone(),
+                subresource_range: STD_COLOR_SUBRESOURCE_RANGE,
+                chain: None
+            }
         };
-        let normals_barrier = ImageMemoryBarrier {
-            src_access_mask: Default::default(),
-            dst_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
-            old_layout: ImageLayout::Undefined,
-            new_layout: ImageLayout::ColorAttachmentOptimal,
-            src_queue_family_index: QueueFamilyIndex::Ignored,
-            dst_queue_family_index: QueueFamilyIndex::Ignored,
-            image: self.normals_image.image.clone(),
-            subresource_range: STD_COLOR_SUBRESOURCE_RANGE,
-            chain: None
-        };
-        let material_barrier = ImageMemoryBarrier {
-            src_access_mask: Default::default(),
-            dst_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
-            old_layout: ImageLayout::Undefined,
-            new_layout: ImageLayout::ColorAttachmentOptimal,
-            src_queue_family_index: QueueFamilyIndex::Ignored,
-            dst_queue_family_index: QueueFamilyIndex::Ignored,
-            image: self.material_image.image.clone(),
-            subresource_range: STD_COLOR_SUBRESOURCE_RANGE,
-            chain: None
+        let mut barriers = vec![
+            barrier(&self.diffuse_image, load_ops.diffuse),
+            barrier(&self.normals_image, load_ops.normals),
+            barrier(&self.material_image, load_ops.material),
+        ];
+
+        // write velocity (always cleared)
+        if let Some(ref velocity_image) = self.velocity_image {
+            barriers.push(barrier(velocity_image, GBufferLoadOp::Clear));
+        }
+
+        // write entity IDs (always cleared)
+        if let Some(ref entity_id_image) = self.entity_id_image {
+            barriers.push(barrier(entity_id_image, GBufferLoadOp::Clear));
+        }
+
+        let src_stage = if load_ops.diffuse == GBufferLoadOp::Load
+            || load_ops.normals == GBufferLoadOp::Load
+            || load_ops.material == GBufferLoadOp::Load
+        {
+            PipelineStageFlags::FRAGMENT_SHADER
+        } else {
+            PipelineStageFlags::TOP_OF_PIPE
         };
         command_buffer.pipeline_barrier(
-            PipelineStageFlags::TOP_OF_PIPE,
+            src_stage,
             PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
             DependencyFlags::empty(),
             None, //memory barriers
             None , //buffer memory barriers
-            Some(&[diffuse_barrier, normals_barrier, material_barrier])); //image memory barriers
+            Some(&barriers)); //image memory barriers
 
         Ok(())
     }
@@ -163,6 +266,8 @@ impl TargetData {
             subresource_range: STD_DEPTH_SUBRESOURCE_RANGE,
             chain: None
         };
+        self.depth_image.track_layout(ImageLayout::DepthStencilAttachmentOptimal,
+                                      ImageLayout::ShaderReadOnlyOptimal);
         command_buffer.pipeline_barrier(
             PipelineStageFlags::EARLY_FRAGMENT_TESTS,
             PipelineStageFlags::FRAGMENT_SHADER,
@@ -183,6 +288,8 @@ impl TargetData {
             subresource_range: STD_COLOR_SUBRESOURCE_RANGE,
             chain: None
         };
+        self.diffuse_image.track_layout(ImageLayout::ColorAttachmentOptimal,
+                                        ImageLayout::ShaderReadOnlyOptimal);
         let normals_barrier = ImageMemoryBarrier {
             src_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
             dst_access_mask: AccessFlags::SHADER_READ,
@@ -194,6 +301,8 @@ impl TargetData {
             subresource_range: STD_COLOR_SUBRESOURCE_RANGE,
             chain: None
         };
+        self.normals_image.track_layout(ImageLayout::ColorAttachmentOptimal,
+                                        ImageLayout::ShaderReadOnlyOptimal);
         let material_barrier = ImageMemoryBarrier {
             src_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
             dst_access_mask: AccessFlags::SHADER_READ,
@@ -205,13 +314,34 @@ impl TargetData {
             subresource_range: STD_COLOR_SUBRESOURCE_RANGE,
             chain: None
         };
+        self.material_image.track_layout(ImageLayout::ColorAttachmentOptimal,
+                                         ImageLayout::ShaderReadOnlyOptimal);
+        let mut barriers = vec![diffuse_barrier, normals_barrier, material_barrier];
+
+        // and velocity (read by TAA and post effects from here on)
+        if let Some(ref velocity_image) = self.velocity_image {
+            barriers.push(ImageMemoryBarrier {
+                src_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
+                dst_access_mask: AccessFlags::SHADER_READ,
+                old_layout: ImageLayout::ColorAttachmentOptimal,
+                new_layout: ImageLayout::ShaderReadOnlyOptimal,
+                src_queue_family_index: QueueFamilyIndex::Ignored,
+                dst_queue_family_index: QueueFamilyIndex::Ignored,
+                image: velocity_image.image.clone(),
+                subresource_range: STD_COLOR_SUBRESOURCE_RANGE,
+                chain: None
+            });
+            velocity_image.track_layout(ImageLayout::ColorAttachmentOptimal,
+                                        ImageLayout::ShaderReadOnlyOptimal);
+        }
+
         command_buffer.pipeline_barrier(
             PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
             PipelineStageFlags::FRAGMENT_SHADER,
             DependencyFlags::empty(),
             None, //memory barriers
             None , //buffer memory barriers
-            Some(&[diffuse_barrier, normals_barrier, material_barrier])); //image memory barriers
+            Some(&barriers)); //image memory barriers
 
         // write shading
         let shading_barrier = ImageMemoryBarrier {
@@ -225,6 +355,8 @@ impl TargetData {
             subresource_range: STD_COLOR_SUBRESOURCE_RANGE,
             chain: None
         };
+        self.shading_image.track_layout(ImageLayout::Undefined,
+                                        ImageLayout::ColorAttachmentOptimal);
         command_buffer.pipeline_barrier(
             PipelineStageFlags::TOP_OF_PIPE,
             PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
@@ -252,6 +384,8 @@ impl TargetData {
             subresource_range: STD_DEPTH_SUBRESOURCE_RANGE,
             chain: None
         };
+        self.depth_image.track_layout(ImageLayout::ShaderReadOnlyOptimal,
+                                      ImageLayout::DepthStencilAttachmentOptimal);
         command_buffer.pipeline_barrier(
             PipelineStageFlags::FRAGMENT_SHADER,
             PipelineStageFlags::EARLY_FRAGMENT_TESTS,
@@ -265,6 +399,127 @@ impl TargetData {
         Ok(())
     }
 
+    pub fn transition_for_taa(&mut self, command_buffer: CommandBuffer)
+                              -> Result<(), Error>
+    {
+        // read shading:
+        self.shading_image.transition_layout(
+            command_buffer.clone(),
+            ImageLayout::ColorAttachmentOptimal, ImageLayout::ShaderReadOnlyOptimal,
+            AccessFlags::COLOR_ATTACHMENT_WRITE, AccessFlags::SHADER_READ,
+            PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, PipelineStageFlags::FRAGMENT_SHADER,
+            STD_COLOR_SUBRESOURCE_RANGE)?;
+
+        // read depth (for reprojection):
+        self.depth_image.transition_layout(
+            command_buffer.clone(),
+            ImageLayout::DepthStencilAttachmentOptimal, ImageLayout::ShaderReadOnlyOptimal,
+            AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                | AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            AccessFlags::SHADER_READ,
+            PipelineStageFlags::LATE_FRAGMENT_TESTS, PipelineStageFlags::FRAGMENT_SHADER,
+            STD_DEPTH_SUBRESOURCE_RANGE)?;
+
+        // read history: already there.
+
+        // write taa:
+        if let Some(ref mut taa_image) = self.taa_image {
+            taa_image.transition_layout(
+                command_buffer,
+                ImageLayout::Undefined, ImageLayout::ColorAttachmentOptimal,
+                Default::default(), AccessFlags::COLOR_ATTACHMENT_WRITE,
+                PipelineStageFlags::TOP_OF_PIPE, PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                STD_COLOR_SUBRESOURCE_RANGE)?;
+        }
+
+        Ok(())
+    }
+
+    // Copy the TAA result over the shading image (for the rest of the frame)
+    // and the history (for the next frame), and return every image to the
+    // layout the passes after TAA expect
+    pub fn copy_taa_result(&mut self, command_buffer: CommandBuffer)
+                           -> Result<(), Error>
+    {
+        use dacite::core::{ImageCopy, ImageSubresourceLayers, Offset3D, Extent3D};
+
+        let (taa_image, history_image) = match (self.taa_image.as_mut(),
+                                                self.history_image.as_mut()) {
+            (Some(taa), Some(history)) => (taa, history),
+            _ => return Ok(()),
+        };
+
+        taa_image.transition_layout(
+            command_buffer.clone(),
+            ImageLayout::ColorAttachmentOptimal, ImageLayout::TransferSrcOptimal,
+            AccessFlags::COLOR_ATTACHMENT_WRITE, AccessFlags::TRANSFER_READ,
+            PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, PipelineStageFlags::TRANSFER,
+            STD_COLOR_SUBRESOURCE_RANGE)?;
+        self.shading_image.transition_layout(
+            command_buffer.clone(),
+            ImageLayout::ShaderReadOnlyOptimal, ImageLayout::TransferDstOptimal,
+            AccessFlags::SHADER_READ, AccessFlags::TRANSFER_WRITE,
+            PipelineStageFlags::FRAGMENT_SHADER, PipelineStageFlags::TRANSFER,
+            STD_COLOR_SUBRESOURCE_RANGE)?;
+        history_image.transition_layout(
+            command_buffer.clone(),
+            ImageLayout::ShaderReadOnlyOptimal, ImageLayout::TransferDstOptimal,
+            AccessFlags::SHADER_READ, AccessFlags::TRANSFER_WRITE,
+            PipelineStageFlags::FRAGMENT_SHADER, PipelineStageFlags::TRANSFER,
+            STD_COLOR_SUBRESOURCE_RANGE)?;
+
+        let layers = ImageSubresourceLayers {
+            aspect_mask: ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let region = ImageCopy {
+            src_subresource: layers.clone(),
+            src_offset: Offset3D { x: 0, y: 0, z: 0 },
+            dst_subresource: layers,
+            dst_offset: Offset3D { x: 0, y: 0, z: 0 },
+            extent: Extent3D { width: self.extent.width, height: self.extent.height, depth: 1 },
+        };
+        command_buffer.copy_image(
+            &taa_image.image, ImageLayout::TransferSrcOptimal,
+            &self.shading_image.image, ImageLayout::TransferDstOptimal,
+            &[region.clone()]);
+        command_buffer.copy_image(
+            &taa_image.image, ImageLayout::TransferSrcOptimal,
+            &history_image.image, ImageLayout::TransferDstOptimal,
+            &[region]);
+
+        // shading, as the blur passes expect it:
+        self.shading_image.transition_layout(
+            command_buffer.clone(),
+            ImageLayout::TransferDstOptimal, ImageLayout::ColorAttachmentOptimal,
+            AccessFlags::TRANSFER_WRITE,
+            AccessFlags::COLOR_ATTACHMENT_READ | AccessFlags::COLOR_ATTACHMENT_WRITE,
+            PipelineStageFlags::TRANSFER, PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            STD_COLOR_SUBRESOURCE_RANGE)?;
+
+        // history, for the next frame:
+        history_image.transition_layout(
+            command_buffer.clone(),
+            ImageLayout::TransferDstOptimal, ImageLayout::ShaderReadOnlyOptimal,
+            AccessFlags::TRANSFER_WRITE, AccessFlags::SHADER_READ,
+            PipelineStageFlags::TRANSFER, PipelineStageFlags::FRAGMENT_SHADER,
+            STD_COLOR_SUBRESOURCE_RANGE)?;
+
+        // depth, as transition_for_post expects it:
+        self.depth_image.transition_layout(
+            command_buffer,
+            ImageLayout::ShaderReadOnlyOptimal, ImageLayout::DepthStencilAttachmentOptimal,
+            AccessFlags::SHADER_READ,
+            AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                | AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            PipelineStageFlags::FRAGMENT_SHADER, PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+            STD_DEPTH_SUBRESOURCE_RANGE)?;
+
+        Ok(())
+    }
+
     pub fn transition_for_blurh(&mut self, command_buffer: CommandBuffer)
                                  -> Result<(), Error>
     {
@@ -333,15 +588,68 @@ impl TargetData {
         Ok(())
     }
 
-    pub fn transition_for_post(&mut self, command_buffer: CommandBuffer)
-                               -> Result<(), Error>
+    pub fn transition_for_compute_blurh(&mut self, command_buffer: CommandBuffer)
+                                        -> Result<(), Error>
     {
         // read shading:
         self.shading_image.transition_layout(
             command_buffer.clone(),
             ImageLayout::ColorAttachmentOptimal, ImageLayout::ShaderReadOnlyOptimal,
             AccessFlags::COLOR_ATTACHMENT_WRITE, AccessFlags::SHADER_READ,
-            PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, PipelineStageFlags::FRAGMENT_SHADER,
+            PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, PipelineStageFlags::COMPUTE_SHADER,
+            STD_COLOR_SUBRESOURCE_RANGE)?;
+
+        // write blur (as a storage image):
+        self.blur_image.transition_layout(
+            command_buffer,
+            ImageLayout::Undefined, ImageLayout::General,
+            Default::default(), AccessFlags::SHADER_WRITE,
+            PipelineStageFlags::TOP_OF_PIPE, PipelineStageFlags::COMPUTE_SHADER,
+            STD_COLOR_SUBRESOURCE_RANGE)?;
+
+        Ok(())
+    }
+
+    pub fn transition_for_compute_blurv(&mut self, command_buffer: CommandBuffer)
+                                        -> Result<(), Error>
+    {
+        // read blur:
+        self.blur_image.transition_layout(
+            command_buffer.clone(),
+            ImageLayout::General, ImageLayout::ShaderReadOnlyOptimal,
+            AccessFlags::SHADER_WRITE, AccessFlags::SHADER_READ,
+            PipelineStageFlags::COMPUTE_SHADER, PipelineStageFlags::COMPUTE_SHADER,
+            STD_COLOR_SUBRESOURCE_RANGE)?;
+
+        // read and write shading (as a storage image):
+        self.shading_image.transition_layout(
+            command_buffer,
+            ImageLayout::ShaderReadOnlyOptimal, ImageLayout::General,
+            AccessFlags::SHADER_READ, AccessFlags::SHADER_READ | AccessFlags::SHADER_WRITE,
+            PipelineStageFlags::COMPUTE_SHADER, PipelineStageFlags::COMPUTE_SHADER,
+            STD_COLOR_SUBRESOURCE_RANGE)?;
+
+        Ok(())
+    }
+
+    pub fn transition_for_post(&mut self, command_buffer: CommandBuffer)
+                               -> Result<(), Error>
+    {
+        // read shading (last written by the blur merge):
+        let (old_layout, src_access, src_stage) = if self.compute_blur {
+            (ImageLayout::General, AccessFlags::SHADER_WRITE,
+             PipelineStageFlags::COMPUTE_SHADER)
+        } else {
+            (ImageLayout::ColorAttachmentOptimal, AccessFlags::COLOR_ATTACHMENT_WRITE,
+             PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        };
+        self.shading_image.transition_layout(
+            command_buffer.clone(),
+            old_layout, ImageLayout::ShaderReadOnlyOptimal,
+            src_access, AccessFlags::SHADER_READ,
+            src_stage,
+            // (and the auto-exposure measurement)
+            PipelineStageFlags::FRAGMENT_SHADER | PipelineStageFlags::COMPUTE_SHADER,
             ImageSubresourceRange {
                 aspect_mask: ImageAspectFlags::COLOR,
                 base_mip_level: 0,
@@ -350,11 +658,63 @@ impl TargetData {
                 layer_count: OptionalArrayLayers::ArrayLayers(1),
             })?;
 
+        // read depth (for depth of field):
+        let depth_barrier = ImageMemoryBarrier {
+            src_access_mask: AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                | AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            dst_access_mask: AccessFlags::SHADER_READ,
+            old_layout: ImageLayout::DepthStencilAttachmentOptimal,
+            new_layout: ImageLayout::ShaderReadOnlyOptimal,
+            src_queue_family_index: QueueFamilyIndex::Ignored,
+            dst_queue_family_index: QueueFamilyIndex::Ignored,
+            image: self.depth_image.image.clone(),
+            subresource_range: STD_DEPTH_SUBRESOURCE_RANGE,
+            chain: None
+        };
+        self.depth_image.track_layout(ImageLayout::DepthStencilAttachmentOptimal,
+                                      ImageLayout::ShaderReadOnlyOptimal);
+        command_buffer.pipeline_barrier(
+            PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            PipelineStageFlags::FRAGMENT_SHADER,
+            DependencyFlags::empty(),
+            None, //memory barriers
+            None , //buffer memory barriers
+            Some(&[depth_barrier])); //image memory barriers
+
         // (write swapchain): not handled here
 
         Ok(())
     }
 
+    pub fn transition_after_post(&mut self, command_buffer: CommandBuffer)
+                                 -> Result<(), Error>
+    {
+        // Reinstate the depth buffer for the next frame's geometry pass
+        let depth_barrier = ImageMemoryBarrier {
+            src_access_mask: AccessFlags::SHADER_READ,
+            dst_access_mask: AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                | AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            old_layout: ImageLayout::ShaderReadOnlyOptimal,
+            new_layout: ImageLayout::DepthStencilAttachmentOptimal,
+            src_queue_family_index: QueueFamilyIndex::Ignored,
+            dst_queue_family_index: QueueFamilyIndex::Ignored,
+            image: self.depth_image.image.clone(),
+            subresource_range: STD_DEPTH_SUBRESOURCE_RANGE,
+            chain: None
+        };
+        self.depth_image.track_layout(ImageLayout::ShaderReadOnlyOptimal,
+                                      ImageLayout::DepthStencilAttachmentOptimal);
+        command_buffer.pipeline_barrier(
+            PipelineStageFlags::FRAGMENT_SHADER,
+            PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+            DependencyFlags::empty(),
+            None, //memory barriers
+            None , //buffer memory barriers
+            Some(&[depth_barrier])); //image memory barriers
+
+        Ok(())
+    }
+
     pub fn transition_for_ui(&mut self, _command_buffer: CommandBuffer)
                              -> Result<(), Error>
     {
@@ -370,12 +730,21 @@ fn build_images(
     device: &Device,
     memory: &mut Memory,
     commander: &Commander,
-    extent: Extent2D)
-    -> Result<(ImageWrap, ImageWrap, ImageWrap, ImageWrap, ImageWrap, ImageWrap), Error>
+    formats: GBufferFormats,
+    linear_depth: bool,
+    compute_blur: bool,
+    taa: bool,
+    motion_vectors: bool,
+    entity_id: bool,
+    extent: Extent2D,
+    ui_extent: Extent2D)
+    -> Result<(ImageWrap, ImageWrap, ImageWrap, ImageWrap, ImageWrap, ImageWrap,
+               ImageWrap, Option<ImageWrap>, Option<ImageWrap>, Option<ImageWrap>,
+               Option<ImageWrap>, Option<ImageWrap>), Error>
 {
     use dacite::core::{ComponentMapping, ImageUsageFlags, ImageTiling, Extent3D};
 
-    let mut make = |format,iwtype,usage,name| {
+    let mut make = |format,iwtype,usage,name,extent: Extent2D| {
         ImageWrap::new(
             device,memory,format,
             ComponentMapping::identity(),
@@ -394,8 +763,8 @@ fn build_images(
         let mut depth_image_wrap = make(
             DEPTH_FORMAT, ImageWrapType::Depth,
             ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | ImageUsageFlags::INPUT_ATTACHMENT
-                | ImageUsageFlags::SAMPLED,
-            "Depth Buffer")?;
+                | ImageUsageFlags::SAMPLED | ImageUsageFlags::TRANSFER_SRC, // for read_depth, capture_gbuffer
+            "Depth Buffer", extent)?;
 
         depth_image_wrap.transition_layout_now(
             device,
@@ -425,36 +794,160 @@ fn build_images(
         depth_image_wrap
     };
 
-    let diffuse_image = make(
-        DIFFUSE_FORMAT, ImageWrapType::Standard,
+    let mut diffuse_image = make(
+        formats.diffuse, ImageWrapType::Standard,
         ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::INPUT_ATTACHMENT
-            | ImageUsageFlags::SAMPLED,
-        "Diffuse g-buffer")?;
+            | ImageUsageFlags::SAMPLED | ImageUsageFlags::TRANSFER_SRC, // for capture_gbuffer
+        "Diffuse g-buffer", extent)?;
 
-    let normals_image = make(
-        NORMALS_FORMAT, ImageWrapType::Standard,
+    let mut normals_image = make(
+        formats.normals, ImageWrapType::Standard,
         ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::INPUT_ATTACHMENT
-            | ImageUsageFlags::SAMPLED,
-        "Normals g-buffer")?;
+            | ImageUsageFlags::SAMPLED | ImageUsageFlags::TRANSFER_SRC, // for capture_gbuffer
+        "Normals g-buffer", extent)?;
 
-    let material_image = make(
-        MATERIAL_FORMAT, ImageWrapType::Standard,
+    let mut material_image = make(
+        formats.material, ImageWrapType::Standard,
         ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::INPUT_ATTACHMENT
-            | ImageUsageFlags::SAMPLED,
-        "Materials g-buffer")?;
+            | ImageUsageFlags::SAMPLED | ImageUsageFlags::TRANSFER_SRC, // for capture_gbuffer
+        "Materials g-buffer", extent)?;
+
+    // Start the g-buffers in the layout each frame leaves them in, so that
+    // the geometry pass can load them from the first frame on (see
+    // GeometryLoadOps).  Their contents are undefined until then.
+    for image in &mut [&mut diffuse_image, &mut normals_image, &mut material_image] {
+        image.transition_layout_now(
+            device,
+            ImageLayout::Undefined, ImageLayout::ShaderReadOnlyOptimal,
+            Default::default(),
+            AccessFlags::SHADER_READ,
+            PipelineStageFlags::TOP_OF_PIPE,
+            PipelineStageFlags::FRAGMENT_SHADER,
+            STD_COLOR_SUBRESOURCE_RANGE,
+            commander
+        )?;
+    }
+
+    // (storage usage only when needed, as it can disable framebuffer
+    // compression on some hardware)
+    let blur_usage = if compute_blur {
+        ImageUsageFlags::STORAGE
+    } else {
+        ImageUsageFlags::empty()
+    };
+
+    // (TAA copies its result back over the shading image)
+    let taa_usage = if taa {
+        ImageUsageFlags::TRANSFER_DST
+    } else {
+        ImageUsageFlags::empty()
+    };
 
     let shading_image = make(
-        SHADING_FORMAT, ImageWrapType::Standard,
+        formats.shading, ImageWrapType::Standard,
         ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::INPUT_ATTACHMENT
-            | ImageUsageFlags::SAMPLED,
-        "Shading Target")?;
+            | ImageUsageFlags::SAMPLED | blur_usage | taa_usage,
+        "Shading Target", extent)?;
 
     let blur_image = make(
-        BLUR_FORMAT, ImageWrapType::Standard,
+        formats.shading, ImageWrapType::Standard,
         ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::INPUT_ATTACHMENT
-            | ImageUsageFlags::SAMPLED,
-        "Blur Target")?;
+            | ImageUsageFlags::SAMPLED | blur_usage,
+        "Blur Target", extent)?;
+
+    let ui_depth_image = {
+        let mut depth_image_wrap = make(
+            DEPTH_FORMAT, ImageWrapType::Depth,
+            ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            "UI Depth Buffer", ui_extent)?;
+
+        depth_image_wrap.transition_layout_now(
+            device,
+            ImageLayout::Undefined, ImageLayout::DepthStencilAttachmentOptimal,
+            Default::default(),
+            AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                | AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            PipelineStageFlags::TOP_OF_PIPE,
+            PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+            STD_DEPTH_SUBRESOURCE_RANGE,
+            commander
+        )?;
+
+        depth_image_wrap
+    };
+
+    // The shading pass clears this each frame, so it needs no initial layout
+    let linear_depth_image = if linear_depth {
+        Some(make(
+            LINEAR_DEPTH_FORMAT, ImageWrapType::Standard,
+            ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::SAMPLED,
+            "Linear Depth Target", extent)?)
+    } else {
+        None
+    };
+
+    let (taa_image, history_image) = if taa {
+        let taa_image = make(
+            formats.shading, ImageWrapType::Standard,
+            ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::TRANSFER_SRC,
+            "TAA Target", extent)?;
+
+        // Start the history in the layout each frame leaves it in.  Its
+        // contents are undefined, but aren't used until a frame has been
+        // resolved into it (see Params::taa_feedback).
+        let mut history_image = make(
+            formats.shading, ImageWrapType::Standard,
+            ImageUsageFlags::SAMPLED | ImageUsageFlags::TRANSFER_DST,
+            "TAA History", extent)?;
+        history_image.transition_layout_now(
+            device,
+            ImageLayout::Undefined, ImageLayout::ShaderReadOnlyOptimal,
+            Default::default(),
+            AccessFlags::SHADER_READ,
+            PipelineStageFlags::TOP_OF_PIPE,
+            PipelineStageFlags::FRAGMENT_SHADER,
+            STD_COLOR_SUBRESOURCE_RANGE,
+            commander
+        )?;
+
+        (Some(taa_image), Some(history_image))
+    } else {
+        (None, None)
+    };
+
+    // The geometry pass clears this each frame, so it needs no initial layout
+    let velocity_image = if motion_vectors {
+        Some(make(
+            VELOCITY_FORMAT, ImageWrapType::Standard,
+            ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::SAMPLED,
+            "Velocity g-buffer", extent)?)
+    } else {
+        None
+    };
+
+    // The geometry pass clears this each frame, but Renderer::pick may read
+    // it before the first frame, so it starts in the layout frames leave it
+    let entity_id_image = if entity_id {
+        let mut entity_id_image = make(
+            ENTITY_ID_FORMAT, ImageWrapType::Standard,
+            ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::TRANSFER_SRC, // for pick
+            "Entity ID g-buffer", extent)?;
+        entity_id_image.transition_layout_now(
+            device,
+            ImageLayout::Undefined, ImageLayout::ColorAttachmentOptimal,
+            Default::default(),
+            AccessFlags::COLOR_ATTACHMENT_WRITE,
+            PipelineStageFlags::TOP_OF_PIPE,
+            PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            STD_COLOR_SUBRESOURCE_RANGE,
+            commander
+        )?;
+        Some(entity_id_image)
+    } else {
+        None
+    };
 
     Ok((depth_image, diffuse_image, normals_image, material_image,
-        shading_image, blur_image))
+        shading_image, blur_image, ui_depth_image, linear_depth_image,
+        taa_image, history_image, velocity_image, entity_id_image))
 }
@@ -0,0 +1,114 @@
+
+use std::sync::{Arc, Mutex};
+use dacite::core::{CommandBuffer, Viewport};
+use super::Pass;
+
+struct Viewports {
+    render: Viewport, // geometry and transparent passes
+    ui: Viewport,
+    shadow: Viewport,
+    reversed_depth_buffer: bool,
+}
+
+// Lets plugins narrow the viewport depth range for some of their draws,
+// e.g. to force UI geometry or decals into a thin depth band.  Get one from
+// Renderer::viewport_depth_range(), keep it in the plugin, and call set()
+// from the record_* callbacks, then reset() when done, since the viewport is
+// dynamic state that persists through the rest of the command buffer.
+//
+// Depths are given in the conventional sense, with 0.0 at the near plane
+// and 1.0 at the far plane, whether or not Config::reversed_depth_buffer is
+// set; the renderer reverses the viewport's min_depth and max_depth itself
+// when it is.  The viewports always match the current render extent, so
+// the handle stays valid across resizes.
+//
+// Reflection probe passes (Pass::Probe) are not supported, since each
+// probe has its own extent.
+#[derive(Clone)]
+pub struct ViewportDepthRange {
+    viewports: Arc<Mutex<Viewports>>,
+}
+
+impl ViewportDepthRange {
+    pub(crate) fn new(render: Viewport, ui: Viewport, shadow: Viewport,
+                      reversed_depth_buffer: bool)
+                      -> ViewportDepthRange
+    {
+        ViewportDepthRange {
+            viewports: Arc::new(Mutex::new(Viewports {
+                render: render,
+                ui: ui,
+                shadow: shadow,
+                reversed_depth_buffer: reversed_depth_buffer,
+            })),
+        }
+    }
+
+    // Set the viewport for `pass` with depths from `near` to `far`
+    // (each within 0.0 to 1.0)
+    pub fn set(&self, command_buffer: &CommandBuffer, pass: Pass, near: f32, far: f32)
+    {
+        let viewports = self.viewports.lock().unwrap();
+        let mut viewport = match Self::viewport(&viewports, pass) {
+            Some(viewport) => viewport,
+            None => return,
+        };
+
+        let near = near.max(0.0).min(1.0);
+        let far = far.max(0.0).min(1.0);
+        if viewports.reversed_depth_buffer {
+            viewport.min_depth = 1.0 - near;
+            viewport.max_depth = 1.0 - far;
+        } else {
+            viewport.min_depth = near;
+            viewport.max_depth = far;
+        }
+
+        command_buffer.set_viewport(0, &[viewport]);
+    }
+
+    // Restore the renderer's viewport for `pass`
+    pub fn reset(&self, command_buffer: &CommandBuffer, pass: Pass)
+    {
+        let viewports = self.viewports.lock().unwrap();
+        if let Some(viewport) = Self::viewport(&viewports, pass) {
+            command_buffer.set_viewport(0, &[viewport]);
+        }
+    }
+
+    // Called by the renderer whenever its viewports change
+    pub(crate) fn update(&self, render: Viewport, ui: Viewport)
+    {
+        let mut viewports = self.viewports.lock().unwrap();
+        viewports.render = render;
+        viewports.ui = ui;
+    }
+
+    // Called by the renderer when the depth convention changes (see
+    // Renderer::set_reversed_depth)
+    pub(crate) fn set_reversed_depth_buffer(&self, reversed_depth_buffer: bool)
+    {
+        let mut viewports = self.viewports.lock().unwrap();
+        viewports.reversed_depth_buffer = reversed_depth_buffer;
+        let (min_depth, max_depth) = if reversed_depth_buffer { (1.0, 0.0) } else { (0.0, 1.0) };
+        viewports.render.min_depth = min_depth;
+        viewports.render.max_depth = max_depth;
+        viewports.ui.min_depth = min_depth;
+        viewports.ui.max_depth = max_depth;
+        viewports.shadow.min_depth = min_depth;
+        viewports.shadow.max_depth = max_depth;
+    }
+
+    fn viewport(viewports: &Viewports, pass: Pass) -> Option<Viewport>
+    {
+        match pass {
+            Pass::Geometry | Pass::Transparent => Some(viewports.render),
+            Pass::Ui => Some(viewports.ui),
+            Pass::Shadow => Some(viewports.shadow),
+            Pass::Probe => {
+                warn!("Viewport depth ranges are not supported in probe passes");
+                None
+            },
+        }
+    }
+}
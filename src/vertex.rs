@@ -1,8 +1,33 @@
 
 pub use siege_mesh::{ColoredVertex, StandardVertex, GuiRectangleVertex, GrayboxVertex,
                      CheapV1Vertex, CheapV2Vertex, StarVertex, CubemapVertex};
+// For Renderer::register_vertex_deserializer
+pub use siege_mesh::{Vertex, Mesh};
 use dacite::core::{PipelineVertexInputStateCreateInfo, Format};
 
+// A particle or billboard, supplied once per instance rather than per vertex.
+//
+// Particles are generated at runtime and never persisted, so unlike the other
+// vertex types this one lives here rather than in siege_mesh and has no
+// deserialize wiring.
+//
+// Bind a buffer of these at binding 0 in a transparent-pass pipeline with no
+// vertex geometry of its own, and draw 6 vertices per instance
+// (`command_buffer.draw(6, particle_count, 0, 0)`).  The vertex shader
+// expands each particle into a camera-facing quad using gl_VertexIndex to
+// select the corner, offsetting `pos` (view space) by `size` along the view x
+// and y axes.
+// `color` is straight (not premultiplied) alpha, and `age` is left to the
+// shader to interpret (e.g. for fading or texture atlas selection).
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParticleVertex {
+    pub pos: [f32; 3],
+    pub size: f32,
+    pub color: [f32; 4],
+    pub age: f32,
+}
+
 // Simple offset_of macro akin to C++ offsetof
 #[macro_export]
 macro_rules! offset_of {
@@ -302,3 +327,48 @@ impl VulkanVertex for CubemapVertex {
         }
     }
 }
+
+impl VulkanVertex for ParticleVertex {
+    fn get_input_state_create_info() -> PipelineVertexInputStateCreateInfo {
+        use dacite::core::{VertexInputBindingDescription,
+                           VertexInputRate, VertexInputAttributeDescription};
+
+        PipelineVertexInputStateCreateInfo {
+            flags: Default::default(),
+            vertex_binding_descriptions: vec![
+                VertexInputBindingDescription {
+                    binding: 0_u32,
+                    stride: ::std::mem::size_of::<ParticleVertex>() as u32,
+                    input_rate: VertexInputRate::Instance,
+                },
+            ],
+            vertex_attribute_descriptions: vec![
+                VertexInputAttributeDescription {
+                    location: 0,
+                    binding: 0,
+                    format: Format::R32G32B32_SFloat,
+                    offset: offset_of!(ParticleVertex, pos) as u32,
+                },
+                VertexInputAttributeDescription {
+                    location: 1,
+                    binding: 0,
+                    format: Format::R32_SFloat,
+                    offset: offset_of!(ParticleVertex, size) as u32,
+                },
+                VertexInputAttributeDescription {
+                    location: 2,
+                    binding: 0,
+                    format: Format::R32G32B32A32_SFloat,
+                    offset: offset_of!(ParticleVertex, color) as u32,
+                },
+                VertexInputAttributeDescription {
+                    location: 3,
+                    binding: 0,
+                    format: Format::R32_SFloat,
+                    offset: offset_of!(ParticleVertex, age) as u32,
+                },
+            ],
+            chain: None,
+        }
+    }
+}